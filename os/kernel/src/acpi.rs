@@ -0,0 +1,181 @@
+//! Just enough of ACPI to find tables and to power off the machine.
+//!
+//! The bootloader tells us where the RSDP is. It points at the RSDT or XSDT,
+//! which list the physical addresses of all of the other tables.
+
+use bytemuck::{Pod, Zeroable};
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::memory::phys_to_virt;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The remaining fields only exist from revision 2 onwards.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header that every system description table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The root table, with the size of each entry: 4 bytes for the RSDT, 8 for the XSDT.
+static ROOT: Once<(&'static [u8], usize)> = Once::new();
+
+pub fn init(boot_info: &'static bootloader_api::BootInfo) {
+    let Some(&rsdp_addr) = boot_info.rsdp_addr.as_ref() else {
+        return;
+    };
+    let rsdp: Rsdp = read_physical(rsdp_addr);
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        table_at(rsdp.xsdt_address).map(|table| (table, 8))
+    } else {
+        table_at(rsdp.rsdt_address as u64).map(|table| (table, 4))
+    };
+    if let Some(root) = root {
+        ROOT.call_once(|| root);
+    }
+}
+
+fn read_physical<T: Pod>(addr: u64) -> T {
+    unsafe {
+        phys_to_virt(PhysAddr::new(addr))
+            .as_ptr::<T>()
+            .read_unaligned()
+    }
+}
+
+/// Returns the whole table at the given physical address, if its checksum is correct.
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let header: SdtHeader = read_physical(addr);
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(PhysAddr::new(addr)).as_ptr::<u8>(),
+            header.length as usize,
+        )
+    };
+    let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    (sum == 0 && table.len() >= size_of::<SdtHeader>()).then_some(table)
+}
+
+/// Finds the table with the given signature, such as `b"FACP"`.
+/// The returned slice includes the table's header.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let &(root, entry_size) = ROOT.get()?;
+    root[size_of::<SdtHeader>()..]
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            let mut addr = [0; 8];
+            addr[..entry_size].copy_from_slice(entry);
+            u64::from_le_bytes(addr)
+        })
+        .filter_map(table_at)
+        .find(|table| &table[0..4] == signature)
+}
+
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        table.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(table: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        table.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Offsets of fields in the FADT.
+mod fadt {
+    pub const DSDT: usize = 40;
+    pub const PM1A_CONTROL_BLOCK: usize = 64;
+    pub const PM1B_CONTROL_BLOCK: usize = 68;
+    pub const X_DSDT: usize = 140;
+}
+
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// Puts the machine into the S5 (soft off) sleep state.
+/// Returns if ACPI is unavailable or the machine did not turn off.
+pub fn power_off() {
+    let Some(fadt) = find_table(b"FACP") else {
+        return;
+    };
+    let dsdt = read_u64(fadt, fadt::X_DSDT)
+        .filter(|&addr| addr != 0)
+        .or_else(|| read_u32(fadt, fadt::DSDT).map(u64::from))
+        .and_then(table_at);
+    let Some((sleep_type_a, sleep_type_b)) = dsdt.and_then(s5_sleep_types) else {
+        return;
+    };
+
+    for (block, sleep_type) in [
+        (read_u32(fadt, fadt::PM1A_CONTROL_BLOCK), sleep_type_a),
+        (read_u32(fadt, fadt::PM1B_CONTROL_BLOCK), sleep_type_b),
+    ] {
+        if let Some(block) = block.filter(|&block| block != 0) {
+            let mut port = Port::<u16>::new(block as u16);
+            unsafe {
+                port.write(sleep_type << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+            }
+        }
+    }
+}
+
+/// Finds the sleep type values for the S5 state in the DSDT.
+///
+/// Rather than running an AML interpreter, this looks for the bytecode for a definition of the form
+/// `Name (_S5, Package () { a, b, ... })`, which is how every firmware that we care about defines it.
+fn s5_sleep_types(dsdt: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0a;
+
+    let aml = &dsdt[size_of::<SdtHeader>()..];
+    let position = aml.windows(5).position(|window| {
+        window[1..] == *b"_S5_" && (window[0] == NAME_OP || window[0] == b'\\')
+    })?;
+    let mut rest = &aml[position + 5..];
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // The top two bits of the package length encoding give the number of extra length bytes.
+    let length_bytes = (*rest.get(1)? >> 6) as usize + 1;
+    // Skip the opcode, the package length, and the number of elements.
+    rest = rest.get(1 + length_bytes + 1..)?;
+
+    let mut read_value = || {
+        let value = if *rest.first()? == BYTE_PREFIX {
+            let value = *rest.get(1)?;
+            rest = &rest[2..];
+            value
+        } else {
+            let value = rest[0];
+            rest = &rest[1..];
+            value
+        };
+        Some(value as u16)
+    };
+    Some((read_value()?, read_value()?))
+}
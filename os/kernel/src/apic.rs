@@ -0,0 +1,71 @@
+//! The local APIC of the processor, which receives message signalled interrupts from PCI devices.
+//!
+//! The legacy [crate::pic] still delivers every other hardware interrupt. It is wired to the
+//! local APIC's LINT0 pin, which we keep in "virtual wire" mode so that those interrupts pass
+//! straight through. Only interrupts that arrive as messages are acknowledged here.
+
+use spin::Once;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+use crate::memory::mmio::Mmio;
+
+/// The vector raised when an interrupt disappears before the processor accepts it.
+/// These are not acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The model specific register holding the physical address of the registers.
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Offsets of registers.
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS_INTERRUPT: usize = 0xf0;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// The physical address that devices write to in order to raise an interrupt.
+const MESSAGE_ADDRESS: u64 = 0xfee0_0000;
+
+static REGISTERS: Once<Mmio> = Once::new();
+
+/// Enables the local APIC, leaving the legacy interrupt controller connected to it.
+pub fn init() {
+    let mut msr = Msr::new(APIC_BASE_MSR);
+    let base = unsafe { msr.read() };
+    unsafe { msr.write(base | APIC_BASE_ENABLE) };
+    let registers =
+        Mmio::map(PhysAddr::new(base & APIC_BASE_ADDRESS_MASK), 0x1000).expect("out of memory");
+
+    registers.write::<u32>(TASK_PRIORITY, 0);
+    registers.write::<u32>(LVT_LINT0, DELIVERY_EXTINT);
+    registers.write::<u32>(LVT_LINT1, DELIVERY_NMI);
+    registers.write::<u32>(SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    REGISTERS.call_once(|| registers);
+}
+
+fn registers() -> &'static Mmio {
+    REGISTERS.get().expect("local APIC not yet initialised")
+}
+
+/// The identifier of this processor's local APIC.
+pub fn id() -> u8 {
+    (registers().read::<u32>(ID) >> 24) as u8
+}
+
+/// Signals that the interrupt that is currently being handled has been handled.
+pub fn end_of_interrupt() {
+    registers().write::<u32>(END_OF_INTERRUPT, 0);
+}
+
+/// The address and data that a device should write to raise the given vector on this processor,
+/// with fixed delivery and edge triggering.
+pub fn message(vector: u8) -> (u64, u32) {
+    (MESSAGE_ADDRESS | (id() as u64) << 12, vector as u32)
+}
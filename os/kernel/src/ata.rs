@@ -0,0 +1,139 @@
+//! ATA disks, which are what QEMU attaches with `-drive if=ide`.
+//!
+//! On the default `pc` machine they sit behind an IDE controller, which we drive one word at a
+//! time through I/O ports ([pio]). On `q35` they sit behind an [ahci] controller, which moves
+//! data by itself. Either way, the disk understands the same commands.
+
+pub mod ahci;
+pub mod pio;
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    block::{self, BlockDevice},
+    pci, serial_println,
+};
+
+/// Commands.
+pub const IDENTIFY: u8 = 0xec;
+pub const READ_SECTORS: u8 = 0x20;
+pub const READ_SECTORS_EXT: u8 = 0x24;
+pub const WRITE_SECTORS: u8 = 0x30;
+pub const WRITE_SECTORS_EXT: u8 = 0x34;
+pub const READ_DMA: u8 = 0xc8;
+pub const READ_DMA_EXT: u8 = 0x25;
+pub const WRITE_DMA: u8 = 0xca;
+pub const WRITE_DMA_EXT: u8 = 0x35;
+pub const FLUSH_CACHE: u8 = 0xe7;
+pub const FLUSH_CACHE_EXT: u8 = 0xea;
+
+/// Bits of the status register.
+pub const STATUS_ERROR: u8 = 1 << 0;
+pub const STATUS_DATA_REQUEST: u8 = 1 << 3;
+pub const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+pub const STATUS_READY: u8 = 1 << 6;
+pub const STATUS_BUSY: u8 = 1 << 7;
+
+/// Selects LBA addressing in the device register.
+pub const DEVICE_LBA: u8 = 1 << 6;
+
+/// The highest sector that a 28-bit command can reach, plus one.
+pub const LBA28_LIMIT: u64 = 1 << 28;
+
+/// What a disk says about itself in response to [IDENTIFY].
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub model: String,
+    pub serial: String,
+    pub sector_count: u64,
+    pub sector_size: usize,
+    /// Whether the disk understands the 48-bit commands, which reach past [LBA28_LIMIT].
+    pub lba48: bool,
+}
+
+impl Identity {
+    pub fn parse(words: &[u16; 256]) -> Self {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            (0..4).fold(0, |count, i| count | (words[100 + i] as u64) << (16 * i))
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        // Word 106 is valid if its top two bits are 01, and then says whether the sectors are bigger
+        // than the usual 512 bytes.
+        let sector_size = if words[106] & 0xc000 == 0x4000 && words[106] & (1 << 12) != 0 {
+            2 * (words[117] as usize | (words[118] as usize) << 16)
+        } else {
+            512
+        };
+        Self {
+            model: string(&words[27..47]),
+            serial: string(&words[10..20]),
+            sector_count,
+            sector_size,
+            lba48,
+        }
+    }
+}
+
+/// Strings are stored with the two bytes of each word swapped, padded with spaces.
+fn string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+/// The number of ATA disks found so far, which gives each its name.
+static DISKS: AtomicU8 = AtomicU8::new(0);
+
+/// Names a disk found by either driver, in the order that they are found, and sets it up.
+fn add_disk(identity: &Identity, disk: Arc<dyn BlockDevice>) {
+    let name = format!(
+        "sd{}",
+        (b'a' + DISKS.fetch_add(1, Ordering::Relaxed)) as char
+    );
+    serial_println!(
+        "ATA {}: {} (serial {}), {} sectors of {} bytes{}",
+        name,
+        identity.model,
+        identity.serial,
+        identity.sector_count,
+        identity.sector_size,
+        if identity.lba48 { ", LBA48" } else { "" }
+    );
+    block::add_disk(&name, disk);
+}
+
+/// Registers the drivers for both kinds of controller.
+pub fn init() {
+    pci::register_driver(&pio::DRIVER);
+    pci::register_driver(&ahci::DRIVER);
+}
+
+#[test_case]
+fn test_identity() {
+    let mut words = [0x2020; 256];
+    // "QEMU HARDDISK", with the bytes of each word swapped.
+    for (i, pair) in b"QEMU HARDDISK ".chunks(2).enumerate() {
+        words[27 + i] = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    words[60] = 0x5678;
+    words[61] = 0x0123;
+    words[83] = 0;
+    words[106] = 0;
+    let identity = Identity::parse(&words);
+    assert_eq!(identity.model, "QEMU HARDDISK");
+    assert_eq!(identity.serial, "");
+    assert_eq!(identity.sector_count, 0x0123_5678);
+    assert_eq!(identity.sector_size, 512);
+    assert!(!identity.lba48);
+
+    words[83] = 1 << 10;
+    words[100..104].copy_from_slice(&[0x0004, 0x0003, 0x0002, 0x0001]);
+    words[106] = 0x4000 | 1 << 12;
+    words[117] = 2048;
+    let identity = Identity::parse(&words);
+    assert_eq!(identity.sector_count, 0x0001_0002_0003_0004);
+    assert_eq!(identity.sector_size, 4096);
+    assert!(identity.lba48);
+}
@@ -0,0 +1,422 @@
+//! AHCI controllers, which move data to and from SATA disks by themselves.
+//!
+//! Each port of the controller has a list of up to 32 commands in memory. A command points at
+//! a table holding a frame information structure (FIS), which is the ATA command as it is sent
+//! over the wire, and a list of the memory regions to move data to or from. When the disk
+//! answers, the controller copies the FIS that it sent back into memory, and sets the port's
+//! interrupt status. We only use one command slot, since requests are made one at a time.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+use super::{
+    Identity, DEVICE_LBA, FLUSH_CACHE, FLUSH_CACHE_EXT, IDENTIFY, LBA28_LIMIT, READ_DMA,
+    READ_DMA_EXT, STATUS_BUSY, STATUS_DATA_REQUEST, STATUS_ERROR, WRITE_DMA, WRITE_DMA_EXT,
+};
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+    memory::{dma::DmaBuffer, mmio::Mmio},
+    pci::{self, msi, PciDevice},
+    sync, trap,
+};
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    matches: &[pci::Match::Interface(0x01, 0x06, 0x01)],
+    probe,
+};
+
+/// Offsets of the controller's registers.
+const CAPABILITIES: usize = 0x00;
+const GLOBAL_CONTROL: usize = 0x04;
+const INTERRUPT_STATUS: usize = 0x08;
+const PORTS_IMPLEMENTED: usize = 0x0c;
+
+const CAPABILITIES_64_BIT: u32 = 1 << 31;
+const GLOBAL_CONTROL_INTERRUPTS: u32 = 1 << 1;
+const GLOBAL_CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+/// Each port has this many bytes of registers, starting at [PORTS_OFFSET].
+const PORTS_OFFSET: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+/// Offsets of a port's registers.
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_FIS: usize = 0x08;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+/// Bits of a port's command register.
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+/// Bits of a port's interrupt status: a FIS arrived from the disk, or the disk reported an error.
+const INTERRUPT_D2H_FIS: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP_FIS: u32 = 1 << 1;
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+/// A disk is present and the link is up.
+const SATA_STATUS_PRESENT: u32 = 0x3;
+const SATA_STATUS_ACTIVE: u32 = 0x1 << 8;
+/// Identifies an ATA disk, rather than a CD drive.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+/// Where everything lives in the port's memory.
+/// The command list must be 1 KiB aligned, the received FISes 256 bytes aligned,
+/// and the command table 128 bytes aligned.
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+const DATA_OFFSET: usize = 0x1000;
+/// The most that a single command will move.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// Offsets in the command table.
+const TABLE_FIS: usize = 0x00;
+const TABLE_PRDT: usize = 0x80;
+
+/// Bits of the first word of a command header.
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDT_LENGTH_SHIFT: u32 = 16;
+
+/// A register FIS from host to device.
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+const FIS_H2D_LENGTH: usize = 20;
+
+/// How many times to check a port before giving up on it.
+const TIMEOUT_POLLS: usize = 10_000_000;
+
+/// The disks found so far.
+static DISKS: Mutex<Vec<Arc<AhciDisk>>> = Mutex::new(Vec::new());
+
+/// The interrupt status of each port, gathered by the interrupt handler.
+struct Events([AtomicU32; 32]);
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    pci.enable_bus_master();
+    let hba = pci.map_bar(5)?;
+    hba.write(
+        GLOBAL_CONTROL,
+        hba.read::<u32>(GLOBAL_CONTROL) | GLOBAL_CONTROL_AHCI_ENABLE,
+    );
+    let wide = hba.read::<u32>(CAPABILITIES) & CAPABILITIES_64_BIT != 0;
+    let implemented = hba.read::<u32>(PORTS_IMPLEMENTED);
+    // Controllers with few ports have room for the registers of only those ports.
+    let port_count = ((hba.size() - PORTS_OFFSET) / PORT_SIZE).min(32);
+    let ports = hba.slice(PORTS_OFFSET, port_count * PORT_SIZE);
+
+    // Take each port's interrupt status in the handler, so the controller stops interrupting.
+    let events = Arc::new(Events([const { AtomicU32::new(0) }; 32]));
+    let handler_events = events.clone();
+    let handler = move || {
+        let pending = hba.read::<u32>(INTERRUPT_STATUS);
+        for port in (0..port_count).filter(|port| pending & 1 << port != 0) {
+            let status = ports.read::<u32>(port * PORT_SIZE + PORT_INTERRUPT_STATUS);
+            ports.write(port * PORT_SIZE + PORT_INTERRUPT_STATUS, status);
+            handler_events.0[port].fetch_or(status, Ordering::Relaxed);
+        }
+        hba.write(INTERRUPT_STATUS, pending);
+    };
+    let interrupts = match msi::allocate_vector(handler) {
+        Ok(vector) if pci.enable_msi(vector).is_ok() => true,
+        Ok(vector) => {
+            trap::free_msi_vector(vector);
+            false
+        }
+        Err(_) => false,
+    };
+    if interrupts {
+        hba.write(
+            GLOBAL_CONTROL,
+            hba.read::<u32>(GLOBAL_CONTROL) | GLOBAL_CONTROL_INTERRUPTS,
+        );
+    }
+
+    let mut found = 0;
+    for index in (0..port_count).filter(|port| implemented & 1 << port != 0) {
+        let registers = ports.slice(index * PORT_SIZE, PORT_SIZE);
+        let status = registers.read::<u32>(PORT_SATA_STATUS);
+        if status & 0xf != SATA_STATUS_PRESENT || status & 0xf00 != SATA_STATUS_ACTIVE {
+            continue;
+        }
+        if registers.read::<u32>(PORT_SIGNATURE) != SIGNATURE_ATA {
+            continue;
+        }
+        let mut port = Port {
+            registers,
+            memory: DmaBuffer::new(DATA_OFFSET + MAX_TRANSFER).map_err(|_| Errno::ENOMEM)?,
+            events: events.clone(),
+            index,
+        };
+        if !wide && port.memory.phys(0).as_u64() >> 32 != 0 {
+            return Err(Errno::ENOMEM);
+        }
+        port.start(interrupts)?;
+        let identity = match port.identify() {
+            Ok(identity) => identity,
+            Err(errno) => {
+                crate::serial_println!("AHCI port {}: IDENTIFY failed: {:?}", index, errno);
+                continue;
+            }
+        };
+        let disk = Arc::new(AhciDisk {
+            port: Mutex::new(port),
+            identity: identity.clone(),
+        });
+        DISKS.lock().push(disk.clone());
+        super::add_disk(&identity, disk);
+        found += 1;
+    }
+    if found == 0 {
+        return Err(Errno::ENODEV);
+    }
+    Ok(())
+}
+
+struct Port {
+    registers: Mmio,
+    /// The command list, received FISes, command table and bounce buffer.
+    memory: DmaBuffer,
+    events: Arc<Events>,
+    index: usize,
+}
+
+impl Port {
+    fn wait(&self, condition: impl Fn(&Self) -> bool) -> Result<(), Errno> {
+        for _ in 0..TIMEOUT_POLLS {
+            if condition(self) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Errno::EIO)
+    }
+
+    fn command_register(&self) -> u32 {
+        self.registers.read(PORT_COMMAND)
+    }
+
+    /// Stops the port from processing commands and receiving FISes.
+    fn stop(&self) -> Result<(), Errno> {
+        self.registers
+            .write(PORT_COMMAND, self.command_register() & !COMMAND_START);
+        self.wait(|port| port.command_register() & COMMAND_LIST_RUNNING == 0)?;
+        self.registers
+            .write(PORT_COMMAND, self.command_register() & !COMMAND_FIS_RECEIVE);
+        self.wait(|port| port.command_register() & COMMAND_FIS_RUNNING == 0)
+    }
+
+    /// Points the port at our memory and starts it.
+    fn start(&mut self, interrupts: bool) -> Result<(), Errno> {
+        self.stop()?;
+        for (register, offset) in [
+            (PORT_COMMAND_LIST, COMMAND_LIST_OFFSET),
+            (PORT_FIS, RECEIVED_FIS_OFFSET),
+        ] {
+            let addr = self.memory.phys(offset).as_u64();
+            self.registers.write(register, addr as u32);
+            self.registers.write(register + 4, (addr >> 32) as u32);
+        }
+        self.registers.write::<u32>(PORT_SATA_ERROR, u32::MAX);
+        self.registers.write::<u32>(PORT_INTERRUPT_STATUS, u32::MAX);
+        let enabled = if interrupts {
+            INTERRUPT_D2H_FIS | INTERRUPT_PIO_SETUP_FIS | INTERRUPT_TASK_FILE_ERROR
+        } else {
+            0
+        };
+        self.registers.write(PORT_INTERRUPT_ENABLE, enabled);
+
+        self.registers
+            .write(PORT_COMMAND, self.command_register() | COMMAND_FIS_RECEIVE);
+        self.wait(|port| {
+            port.registers.read::<u32>(PORT_TASK_FILE) as u8 & (STATUS_BUSY | STATUS_DATA_REQUEST)
+                == 0
+        })?;
+        self.registers
+            .write(PORT_COMMAND, self.command_register() | COMMAND_START);
+        Ok(())
+    }
+
+    /// Takes the port's interrupt status, whether the interrupt handler has seen it or not.
+    fn take_events(&self) -> u32 {
+        let status = self.registers.read::<u32>(PORT_INTERRUPT_STATUS);
+        self.registers.write(PORT_INTERRUPT_STATUS, status);
+        status | self.events.0[self.index].swap(0, Ordering::Relaxed)
+    }
+
+    /// Sends a command, with `len` bytes of data to or from the bounce buffer, and waits for it to finish.
+    fn issue(
+        &mut self,
+        command: u8,
+        sector: u64,
+        count: u16,
+        len: usize,
+        write: bool,
+    ) -> Result<(), Errno> {
+        let lba48 = matches!(command, READ_DMA_EXT | WRITE_DMA_EXT | FLUSH_CACHE_EXT);
+        let device = match command {
+            IDENTIFY => 0,
+            _ if lba48 => DEVICE_LBA,
+            _ => DEVICE_LBA | (sector >> 24) as u8 & 0x0f,
+        };
+        let lba = sector.to_le_bytes();
+        let mut fis = [0; FIS_H2D_LENGTH];
+        fis[..8].copy_from_slice(&[
+            FIS_TYPE_H2D,
+            FIS_COMMAND,
+            command,
+            0,
+            lba[0],
+            lba[1],
+            lba[2],
+            device,
+        ]);
+        if lba48 {
+            fis[8..11].copy_from_slice(&lba[3..6]);
+        }
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+
+        let table = COMMAND_TABLE_OFFSET;
+        self.memory.write_bytes(table + TABLE_FIS, &fis);
+        self.memory
+            .write::<u64>(table + TABLE_PRDT, self.memory.phys(DATA_OFFSET).as_u64());
+        self.memory.write::<u32>(table + TABLE_PRDT + 8, 0);
+        self.memory
+            .write::<u32>(table + TABLE_PRDT + 12, len.saturating_sub(1) as u32);
+
+        let prdt_length = if len == 0 { 0 } else { 1 };
+        let mut flags = (FIS_H2D_LENGTH / 4) as u32 | prdt_length << HEADER_PRDT_LENGTH_SHIFT;
+        if write {
+            flags |= HEADER_WRITE;
+        }
+        self.memory.write::<u32>(COMMAND_LIST_OFFSET, flags);
+        self.memory.write::<u32>(COMMAND_LIST_OFFSET + 4, 0);
+        self.memory
+            .write::<u64>(COMMAND_LIST_OFFSET + 8, self.memory.phys(table).as_u64());
+
+        self.take_events();
+        self.registers.write::<u32>(PORT_COMMAND_ISSUE, 1);
+        let failed = sync::wait_for_device(|| {
+            if self.take_events() & INTERRUPT_TASK_FILE_ERROR != 0 {
+                Some(true)
+            } else if self.registers.read::<u32>(PORT_COMMAND_ISSUE) & 1 == 0 {
+                Some(false)
+            } else {
+                None
+            }
+        });
+        let status = self.registers.read::<u32>(PORT_TASK_FILE) as u8;
+        if failed || status & STATUS_ERROR != 0 {
+            // The port stops after an error, and must be restarted before it will take more commands.
+            let interrupts = self.registers.read::<u32>(PORT_INTERRUPT_ENABLE) != 0;
+            self.start(interrupts)?;
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    fn identify(&mut self) -> Result<Identity, Errno> {
+        self.issue(IDENTIFY, 0, 0, 512, false)?;
+        let mut words = [0; 256];
+        self.memory
+            .read_bytes(DATA_OFFSET, bytemuck::cast_slice_mut(&mut words));
+        Ok(Identity::parse(&words))
+    }
+}
+
+pub struct AhciDisk {
+    port: Mutex<Port>,
+    identity: Identity,
+}
+
+impl AhciDisk {
+    fn commands(&self, sector: u64, len: usize) -> (u8, u8) {
+        let end = sector + (len / self.identity.sector_size) as u64;
+        if end > LBA28_LIMIT {
+            (READ_DMA_EXT, WRITE_DMA_EXT)
+        } else {
+            (READ_DMA, WRITE_DMA)
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_size(&self) -> usize {
+        self.identity.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.identity.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let sectors_per_chunk = MAX_TRANSFER / self.identity.sector_size;
+        let mut port = self.port.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let start = sector + (i * sectors_per_chunk) as u64;
+            let count = (chunk.len() / self.identity.sector_size) as u16;
+            let (read, _) = self.commands(start, chunk.len());
+            port.issue(read, start, count, chunk.len(), false)?;
+            port.memory.read_bytes(DATA_OFFSET, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let sectors_per_chunk = MAX_TRANSFER / self.identity.sector_size;
+        let mut port = self.port.lock();
+        for (i, chunk) in buf.chunks(MAX_TRANSFER).enumerate() {
+            let start = sector + (i * sectors_per_chunk) as u64;
+            let count = (chunk.len() / self.identity.sector_size) as u16;
+            let (_, write) = self.commands(start, chunk.len());
+            port.memory.write_bytes(DATA_OFFSET, chunk);
+            port.issue(write, start, count, chunk.len(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        let command = if self.identity.lba48 {
+            FLUSH_CACHE_EXT
+        } else {
+            FLUSH_CACHE
+        };
+        self.port.lock().issue(command, 0, 0, 0, false)
+    }
+}
+
+#[test_case]
+fn test_ahci() {
+    use alloc::vec;
+
+    // Only the `q35` machine has an AHCI controller, where the first disk is the one we booted from.
+    let Some(boot) = DISKS.lock().first().cloned() else {
+        return;
+    };
+    let mut buf = vec![0; MAX_TRANSFER + 1024];
+    boot.read_sectors(0, &mut buf).unwrap();
+    assert_eq!(buf[510..512], [0x55, 0xaa]);
+    assert_eq!(&buf[512..520], b"EFI PART");
+    let mut again = vec![0; 1024];
+    boot.port
+        .lock()
+        .issue(READ_DMA_EXT, 0, 2, 1024, false)
+        .unwrap();
+    boot.port.lock().memory.read_bytes(DATA_OFFSET, &mut again);
+    assert_eq!(again, buf[..1024]);
+    boot.flush().unwrap();
+}
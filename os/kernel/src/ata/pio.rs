@@ -0,0 +1,355 @@
+//! IDE controllers, through which the processor moves every word of data itself.
+//!
+//! Each controller has two channels, each of which can have two drives. The drives on a channel
+//! share its registers, so only one of them can be busy at a time. A channel in "compatibility"
+//! mode uses the ports that the original PC AT did; otherwise its ports are given by BARs.
+//! We poll rather than using interrupts, since we would have to wait for the data anyway.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use super::{
+    Identity, DEVICE_LBA, FLUSH_CACHE, FLUSH_CACHE_EXT, IDENTIFY, LBA28_LIMIT, READ_SECTORS,
+    READ_SECTORS_EXT, STATUS_BUSY, STATUS_DATA_REQUEST, STATUS_DEVICE_FAULT, STATUS_ERROR,
+    WRITE_SECTORS, WRITE_SECTORS_EXT,
+};
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+    pci::{self, PciDevice},
+};
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "ata-pio",
+    matches: &[pci::Match::Class(0x01, 0x01)],
+    probe,
+};
+
+/// The command and control ports of each channel in compatibility mode.
+const COMPATIBILITY_PORTS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+
+/// Bits of the programming interface that say that a channel is in native mode.
+const NATIVE_MODE: [u8; 2] = [1 << 0, 1 << 2];
+
+/// Offsets of the command registers.
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DEVICE: u16 = 6;
+const COMMAND: u16 = 7;
+
+/// The bits of the device register that are always set, and the bit that selects the second drive.
+const DEVICE_ALWAYS: u8 = 0xa0;
+const DEVICE_SECOND: u8 = 1 << 4;
+
+/// Stops the channel from raising interrupts, in the control register.
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+/// How many times to check the status before giving up on the drive.
+const TIMEOUT_POLLS: usize = 10_000_000;
+
+/// The most sectors that one command moves.
+const MAX_SECTORS: usize = 256;
+
+/// The disks found so far.
+static DISKS: Mutex<Vec<Arc<PioDisk>>> = Mutex::new(Vec::new());
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    let mut found = 0;
+    for (index, &(command, control)) in COMPATIBILITY_PORTS.iter().enumerate() {
+        let (command, control) = if pci.prog_if & NATIVE_MODE[index] != 0 {
+            // The control block BAR covers four ports, of which the control register is the third.
+            (pci.io_bar(2 * index)?, pci.io_bar(2 * index + 1)? + 2)
+        } else {
+            (command, control)
+        };
+        let channel = Channel { command, control };
+        // Nothing drives the bus of a channel with no drives, so it reads as all ones.
+        if channel.status() == 0xff {
+            continue;
+        }
+        unsafe { Port::new(control).write(CONTROL_NO_INTERRUPTS) };
+
+        let channel = Arc::new(Mutex::new(channel));
+        for drive in 0..2 {
+            let Some(words) = channel.lock().identify(drive) else {
+                continue;
+            };
+            let identity = Identity::parse(&words);
+            let disk = Arc::new(PioDisk {
+                channel: channel.clone(),
+                drive,
+                identity: identity.clone(),
+            });
+            DISKS.lock().push(disk.clone());
+            super::add_disk(&identity, disk);
+            found += 1;
+        }
+    }
+    if found == 0 {
+        return Err(Errno::ENODEV);
+    }
+    Ok(())
+}
+
+struct Channel {
+    command: u16,
+    control: u16,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.command + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.command + register).write(value) }
+    }
+
+    fn status(&self) -> u8 {
+        self.read(COMMAND)
+    }
+
+    /// Gives the drive 400ns to put its status on the bus,
+    /// by reading the alternate status register, which has no side effects.
+    fn delay(&self) {
+        for _ in 0..4 {
+            unsafe { Port::<u8>::new(self.control).read() };
+        }
+    }
+
+    fn select(&self, drive: u8, bits: u8) {
+        let second = if drive == 1 { DEVICE_SECOND } else { 0 };
+        self.write(DEVICE, DEVICE_ALWAYS | second | bits);
+        self.delay();
+    }
+
+    /// Waits until the drive is no longer busy, and returns its status.
+    fn wait_not_busy(&self) -> Result<u8, Errno> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = self.status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(Errno::EIO)
+    }
+
+    /// Waits until the drive is ready to move the next sector of data.
+    fn wait_data(&self) -> Result<(), Errno> {
+        loop {
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+                return Err(Errno::EIO);
+            }
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Asks the given drive to describe itself, if it is an ATA disk.
+    /// CD drives and the like answer differently, and are ignored.
+    fn identify(&self, drive: u8) -> Option<[u16; 256]> {
+        self.select(drive, 0);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.write(COMMAND, IDENTIFY);
+        self.delay();
+        if self.status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // Packet devices put their signature here instead of answering.
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+        let mut words = [0; 256];
+        let mut data = Port::<u16>::new(self.command + DATA);
+        for word in &mut words {
+            *word = unsafe { data.read() };
+        }
+        Some(words)
+    }
+
+    /// Sends a command that takes an address and a sector count.
+    fn command(&self, drive: u8, command: u8, sector: u64, count: usize, lba48: bool) {
+        if lba48 {
+            self.select(drive, DEVICE_LBA);
+            // The high bytes go first, and are pushed back when the low bytes are written.
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, (sector >> 24) as u8);
+            self.write(LBA_MID, (sector >> 32) as u8);
+            self.write(LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            self.select(drive, DEVICE_LBA | (sector >> 24) as u8 & 0x0f);
+        }
+        // A count of zero means the largest possible, which is 256 for 28-bit commands.
+        self.write(SECTOR_COUNT, count as u8);
+        self.write(LBA_LOW, sector as u8);
+        self.write(LBA_MID, (sector >> 8) as u8);
+        self.write(LBA_HIGH, (sector >> 16) as u8);
+        self.write(COMMAND, command);
+        self.delay();
+    }
+}
+
+pub struct PioDisk {
+    channel: Arc<Mutex<Channel>>,
+    drive: u8,
+    identity: Identity,
+}
+
+impl PioDisk {
+    /// Reads or writes up to [MAX_SECTORS] sectors.
+    fn transfer(&self, sector: u64, buf: Transfer, lba48: bool) -> Result<(), Errno> {
+        let words = self.identity.sector_size / 2;
+        let channel = self.channel.lock();
+        channel.wait_not_busy()?;
+        let mut data = Port::<u16>::new(channel.command + DATA);
+        match buf {
+            Transfer::Read(buf) => {
+                let command = if lba48 {
+                    READ_SECTORS_EXT
+                } else {
+                    READ_SECTORS
+                };
+                let count = buf.len() / self.identity.sector_size;
+                channel.command(self.drive, command, sector, count, lba48);
+                for sector in buf.chunks_exact_mut(self.identity.sector_size) {
+                    channel.wait_data()?;
+                    for i in 0..words {
+                        let word = unsafe { data.read() };
+                        sector[2 * i..2 * i + 2].copy_from_slice(&word.to_le_bytes());
+                    }
+                }
+            }
+            Transfer::Write(buf) => {
+                let command = if lba48 {
+                    WRITE_SECTORS_EXT
+                } else {
+                    WRITE_SECTORS
+                };
+                let count = buf.len() / self.identity.sector_size;
+                channel.command(self.drive, command, sector, count, lba48);
+                for sector in buf.chunks_exact(self.identity.sector_size) {
+                    channel.wait_data()?;
+                    for i in 0..words {
+                        let word = u16::from_le_bytes([sector[2 * i], sector[2 * i + 1]]);
+                        unsafe { data.write(word) };
+                    }
+                }
+            }
+        }
+        let status = channel.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    /// Whether a command must use 48-bit addresses to reach the end of the given range.
+    fn needs_lba48(&self, sector: u64, len: usize) -> bool {
+        sector + (len / self.identity.sector_size) as u64 > LBA28_LIMIT
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl BlockDevice for PioDisk {
+    fn sector_size(&self) -> usize {
+        self.identity.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.identity.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let chunk_size = MAX_SECTORS * self.identity.sector_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let start = sector + (i * MAX_SECTORS) as u64;
+            let lba48 = self.needs_lba48(start, chunk.len());
+            self.transfer(start, Transfer::Read(chunk), lba48)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let chunk_size = MAX_SECTORS * self.identity.sector_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let start = sector + (i * MAX_SECTORS) as u64;
+            let lba48 = self.needs_lba48(start, chunk.len());
+            self.transfer(start, Transfer::Write(chunk), lba48)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        let channel = self.channel.lock();
+        channel.wait_not_busy()?;
+        channel.select(self.drive, 0);
+        let command = if self.identity.lba48 {
+            FLUSH_CACHE_EXT
+        } else {
+            FLUSH_CACHE
+        };
+        channel.write(COMMAND, command);
+        channel.delay();
+        let status = channel.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_ata_pio() {
+    use alloc::vec;
+
+    let disks = DISKS.lock().clone();
+    // Under QEMU's `pc` machine, the first disk is the one we booted from, which has a GPT.
+    let Some(boot) = disks.first() else {
+        return;
+    };
+    let mut buf = vec![0; 2 * 512];
+    boot.read_sectors(0, &mut buf).unwrap();
+    assert_eq!(buf[510..512], [0x55, 0xaa]);
+    assert_eq!(&buf[512..520], b"EFI PART");
+    // The 48-bit commands read the same sectors.
+    if boot.identity.lba48 {
+        let mut again = vec![0; 2 * 512];
+        boot.transfer(0, Transfer::Read(&mut again), true).unwrap();
+        assert_eq!(again, buf);
+    }
+
+    // The test runner attaches the ext2 image as the second disk, throwing away any changes.
+    let Some(ext2) = disks.get(1) else {
+        return;
+    };
+    let data: Vec<u8> = (0..300 * 512).map(|i| (i / 7) as u8).collect();
+    let mut saved = vec![0; data.len()];
+    ext2.read_sectors(0, &mut saved).unwrap();
+    assert_eq!(saved[1024 + 56..1024 + 58], 0xef53u16.to_le_bytes());
+    // Nothing lives in the first two sectors of an ext2 filesystem.
+    ext2.write_sectors(0, &data[..1024]).unwrap();
+    ext2.flush().unwrap();
+    let mut back = vec![0; data.len()];
+    ext2.read_sectors(0, &mut back).unwrap();
+    assert_eq!(back[..1024], data[..1024]);
+    assert_eq!(back[1024..], saved[1024..]);
+    ext2.write_sectors(0, &saved[..1024]).unwrap();
+}
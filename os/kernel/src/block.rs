@@ -0,0 +1,257 @@
+//! Block devices, such as disks, which are read and written in whole sectors.
+//!
+//! Filesystems that live on a disk use a [BlockDevice], so they work the same way on any disk,
+//! and on disk images kept in memory. [read_bytes] and [write_bytes] let them ignore sector
+//! boundaries when that is more convenient.
+//!
+//! Disk drivers hand each disk that they find to [add_disk]. This puts the disk behind the
+//! [cache], and splits it into its [partition]s, each of which is a block device of its own.
+//! The disk and its partitions appear in `/dev`, and any filesystems on them that the kernel
+//! knows what to do with are mounted.
+
+pub mod cache;
+pub mod partition;
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+
+use spin::Mutex;
+
+use crate::{
+    errno::Errno,
+    fs::{
+        self,
+        devfs::{self, Device},
+        FileType,
+    },
+    human_units::HumanBytes,
+    println,
+};
+
+/// A device that stores an array of fixed-size sectors.
+pub trait BlockDevice: Send + Sync {
+    /// The size of a sector in bytes, which is a power of two and at least 512.
+    fn sector_size(&self) -> usize;
+
+    /// The number of sectors on the device.
+    fn sector_count(&self) -> u64;
+
+    /// Reads whole sectors, starting with the given one, into `buf`.
+    /// The length of `buf` is a multiple of the sector size.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno>;
+
+    /// Writes whole sectors from `buf`, starting with the given one.
+    /// The length of `buf` is a multiple of the sector size.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno>;
+
+    /// Waits until everything written so far is stored permanently.
+    fn flush(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// Checks that sectors from `sector` for `len` bytes are a whole number of sectors on the device.
+pub fn check_range(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), Errno> {
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err(Errno::EINVAL);
+    }
+    let count = (len / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(Errno::ENXIO),
+    }
+}
+
+/// Reads bytes from any offset on a device.
+/// Sectors that are only partly wanted are read into a separate buffer.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+    let sector_size = device.sector_size();
+    let mut sector = offset / sector_size as u64;
+    let mut skip = (offset % sector_size as u64) as usize;
+    let mut done = 0;
+    let mut partial = Vec::new();
+    while done < buf.len() {
+        let remaining = buf.len() - done;
+        if skip == 0 && remaining >= sector_size {
+            let whole = remaining - remaining % sector_size;
+            device.read_sectors(sector, &mut buf[done..done + whole])?;
+            done += whole;
+            sector += (whole / sector_size) as u64;
+        } else {
+            partial.resize(sector_size, 0);
+            device.read_sectors(sector, &mut partial)?;
+            let len = remaining.min(sector_size - skip);
+            buf[done..done + len].copy_from_slice(&partial[skip..skip + len]);
+            done += len;
+            sector += 1;
+            skip = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Writes bytes at any offset on a device.
+/// Sectors that are only partly overwritten are read first, so the rest of them is kept.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+    let sector_size = device.sector_size();
+    let mut sector = offset / sector_size as u64;
+    let mut skip = (offset % sector_size as u64) as usize;
+    let mut done = 0;
+    let mut partial = Vec::new();
+    while done < buf.len() {
+        let remaining = buf.len() - done;
+        if skip == 0 && remaining >= sector_size {
+            let whole = remaining - remaining % sector_size;
+            device.write_sectors(sector, &buf[done..done + whole])?;
+            done += whole;
+            sector += (whole / sector_size) as u64;
+        } else {
+            partial.resize(sector_size, 0);
+            device.read_sectors(sector, &mut partial)?;
+            let len = remaining.min(sector_size - skip);
+            partial[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+            device.write_sectors(sector, &partial)?;
+            done += len;
+            sector += 1;
+            skip = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Sets up a disk found by a driver, with a name such as `vda`.
+/// Its partitions are named after it, such as `vda1`, or `nvme0n1p1` for `nvme0n1`.
+pub fn add_disk(name: &str, device: Arc<dyn BlockDevice>) {
+    let disk: Arc<dyn BlockDevice> = cache::CachedDevice::new(device);
+    let size = disk.sector_count() * disk.sector_size() as u64;
+    println!("Disk {name}: {}", HumanBytes(size as usize));
+    let partitions = partition::scan(&*disk).unwrap_or_else(|errno| {
+        println!("Could not read the partition table of {name}: {errno:?}");
+        Vec::new()
+    });
+    register(name, disk.clone());
+    if partitions.is_empty() {
+        fs::mount_disk(name, disk);
+        return;
+    }
+    for info in partitions {
+        // Names that end in a number, such as `nvme0n1`, are followed by a `p`.
+        let separator = match name.ends_with(|c: char| c.is_ascii_digit()) {
+            true => "p",
+            false => "",
+        };
+        let partition_name = format!("{name}{separator}{}", info.number);
+        let size = HumanBytes((info.count * disk.sector_size() as u64) as usize);
+        match info.name.is_empty() {
+            true => println!("  {partition_name}: {size}, {}", info.kind),
+            false => println!(
+                "  {partition_name}: {size}, {} \"{}\"",
+                info.kind, info.name
+            ),
+        }
+        let partition = Arc::new(info.device(disk.clone()));
+        register(&partition_name, partition.clone());
+        fs::mount_disk(&partition_name, partition);
+    }
+}
+
+/// Adds a block device to `/dev`.
+fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    if let Err(errno) = devfs::register(name, Arc::new(DeviceFile(device))) {
+        println!("Could not add {name} to /dev: {errno:?}");
+    }
+}
+
+/// A block device as a file in `/dev`, which can be read and written at any offset.
+struct DeviceFile(Arc<dyn BlockDevice>);
+
+impl DeviceFile {
+    /// How much of a transfer at the given offset fits before the end of the device.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        let size = self.size();
+        len.min(size.saturating_sub(offset) as usize)
+    }
+}
+
+impl Device for DeviceFile {
+    fn kind(&self) -> FileType {
+        FileType::BlockDevice
+    }
+
+    fn size(&self) -> u64 {
+        self.0.sector_count() * self.0.sector_size() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let len = self.clamp(offset, buf.len());
+        read_bytes(&*self.0, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(Errno::ENOSPC);
+        }
+        write_bytes(&*self.0, offset, &buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// A block device whose sectors are kept in memory, such as a disk image.
+pub struct MemoryDisk {
+    sector_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryDisk {
+    /// A disk of the given size, filled with zeroes.
+    pub fn new(sector_size: usize, sectors: u64) -> Self {
+        Self::from_bytes(sector_size, vec![0; sector_size * sectors as usize])
+    }
+
+    /// A disk holding an image, whose length must be a multiple of the sector size.
+    pub fn from_bytes(sector_size: usize, data: Vec<u8>) -> Self {
+        assert!(sector_size.is_power_of_two() && data.len().is_multiple_of(sector_size));
+        Self {
+            sector_size,
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / self.sector_size) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        check_range(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        check_range(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_unaligned_bytes() {
+    let disk = MemoryDisk::new(512, 4);
+    let data: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+    write_bytes(&disk, 300, &data).unwrap();
+    let mut buf = vec![0xff; 1300];
+    read_bytes(&disk, 250, &mut buf).unwrap();
+    assert_eq!(&buf[..50], &[0; 50]);
+    assert_eq!(&buf[50..1250], &data[..]);
+    assert_eq!(&buf[1250..], &[0; 50]);
+    assert_eq!(read_bytes(&disk, 2000, &mut buf), Err(Errno::ENXIO));
+}
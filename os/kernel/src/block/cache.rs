@@ -0,0 +1,334 @@
+//! The buffer cache, which keeps recently used sectors of every disk in memory.
+//!
+//! A [CachedDevice] sits in front of a disk. Reads are answered from the cache when they can be,
+//! and sectors that aren't cached are read from the disk in as few requests as possible.
+//! Writes only go to the cache, which marks the sectors as dirty. Dirty sectors are written back
+//! when they are evicted to make room, when the device is flushed, and when [BufferCache::sync]
+//! is called before the machine is turned off.
+//!
+//! All devices share the same cache, and the sectors that were used least recently are evicted first.
+//! The cache stays locked while it reads from and writes to the disks behind it.
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::{check_range, BlockDevice};
+use crate::{errno::Errno, serial_println};
+
+/// The cache that every [CachedDevice] uses unless it is given another one.
+pub static CACHE: BufferCache = BufferCache::new(4 * 1024 * 1024);
+
+/// A set of cached sectors, which takes up at most a fixed number of bytes.
+pub struct BufferCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+/// A sector is identified by the ID of its [CachedDevice] and its sector number.
+type Key = (u64, u64);
+
+struct Inner {
+    buffers: BTreeMap<Key, Buffer>,
+    /// The key of every buffer, by when it was last used, oldest first.
+    recent: BTreeMap<u64, Key>,
+    /// The devices behind the caches, so that dirty sectors can be written back.
+    devices: BTreeMap<u64, Arc<dyn BlockDevice>>,
+    /// Counts up every time a buffer is used.
+    clock: u64,
+    /// The total size of the buffers, in bytes.
+    size: usize,
+}
+
+struct Buffer {
+    data: Box<[u8]>,
+    /// Whether the buffer has been changed since it was read from or written to the device.
+    dirty: bool,
+    used: u64,
+}
+
+impl BufferCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                buffers: BTreeMap::new(),
+                recent: BTreeMap::new(),
+                devices: BTreeMap::new(),
+                clock: 0,
+                size: 0,
+            }),
+        }
+    }
+
+    /// Writes back every dirty sector, and flushes every device.
+    pub fn sync(&self) -> Result<(), Errno> {
+        let ids: Vec<u64> = self.inner.lock().devices.keys().copied().collect();
+        for id in ids {
+            self.flush(id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty sectors of a device, in runs of consecutive sectors, and flushes it.
+    fn flush(&self, id: u64) -> Result<(), Errno> {
+        let mut inner = self.inner.lock();
+        let Some(device) = inner.devices.get(&id).cloned() else {
+            return Ok(());
+        };
+        let mut run: Option<(u64, Vec<u8>)> = None;
+        let mut written = Vec::new();
+        for (&(_, sector), buffer) in inner.buffers.range((id, 0)..=(id, u64::MAX)) {
+            if !buffer.dirty {
+                continue;
+            }
+            let sector_size = buffer.data.len() as u64;
+            match &mut run {
+                Some((start, data)) if *start + data.len() as u64 / sector_size == sector => {
+                    data.extend_from_slice(&buffer.data);
+                }
+                _ => {
+                    if let Some((start, data)) = run.take() {
+                        device.write_sectors(start, &data)?;
+                    }
+                    run = Some((sector, buffer.data.to_vec()));
+                }
+            }
+            written.push(sector);
+        }
+        if let Some((start, data)) = run {
+            device.write_sectors(start, &data)?;
+        }
+        for sector in written {
+            if let Some(buffer) = inner.buffers.get_mut(&(id, sector)) {
+                buffer.dirty = false;
+            }
+        }
+        drop(inner);
+        device.flush()
+    }
+}
+
+impl Inner {
+    /// Returns a cached sector, and marks it as the most recently used.
+    fn get(&mut self, key: Key) -> Option<&mut Buffer> {
+        self.clock += 1;
+        let buffer = self.buffers.get_mut(&key)?;
+        self.recent.remove(&buffer.used);
+        self.recent.insert(self.clock, key);
+        buffer.used = self.clock;
+        Some(buffer)
+    }
+
+    /// Puts a sector in the cache, evicting others to make room for it.
+    fn insert(&mut self, capacity: usize, key: Key, data: &[u8], dirty: bool) -> Result<(), Errno> {
+        if let Some(buffer) = self.get(key) {
+            buffer.data.copy_from_slice(data);
+            buffer.dirty |= dirty;
+            return Ok(());
+        }
+        while self.size + data.len() > capacity && !self.buffers.is_empty() {
+            self.evict()?;
+        }
+        self.clock += 1;
+        self.recent.insert(self.clock, key);
+        self.buffers.insert(
+            key,
+            Buffer {
+                data: data.into(),
+                dirty,
+                used: self.clock,
+            },
+        );
+        self.size += data.len();
+        Ok(())
+    }
+
+    /// Removes the least recently used sector, writing it back first if it is dirty.
+    fn evict(&mut self) -> Result<(), Errno> {
+        let Some((_, key)) = self.recent.pop_first() else {
+            return Ok(());
+        };
+        let buffer = self.buffers.remove(&key).unwrap();
+        if buffer.dirty {
+            let (id, sector) = key;
+            if let Err(errno) = self.devices[&id].write_sectors(sector, &buffer.data) {
+                // Keep the sector, so that it isn't lost.
+                self.recent.insert(buffer.used, key);
+                self.buffers.insert(key, buffer);
+                return Err(errno);
+            }
+        }
+        self.size -= buffer.data.len();
+        Ok(())
+    }
+
+    /// Forgets every sector of a device, without writing anything back.
+    fn remove_device(&mut self, id: u64) {
+        let keys: Vec<Key> = self
+            .buffers
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            let buffer = self.buffers.remove(&key).unwrap();
+            self.recent.remove(&buffer.used);
+            self.size -= buffer.data.len();
+        }
+        self.devices.remove(&id);
+    }
+}
+
+/// A block device whose sectors are kept in a [BufferCache].
+pub struct CachedDevice {
+    id: u64,
+    device: Arc<dyn BlockDevice>,
+    cache: &'static BufferCache,
+}
+
+impl CachedDevice {
+    /// Puts a device behind the shared [CACHE].
+    pub fn new(device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Self::with_cache(device, &CACHE)
+    }
+
+    pub fn with_cache(device: Arc<dyn BlockDevice>, cache: &'static BufferCache) -> Arc<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        cache.inner.lock().devices.insert(id, device.clone());
+        Arc::new(Self { id, device, cache })
+    }
+}
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        if let Err(errno) = self.cache.flush(self.id) {
+            serial_println!("Could not write back cached sectors: {:?}", errno);
+        }
+        self.cache.inner.lock().remove_device(self.id);
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        check_range(self, sector, buf.len())?;
+        let sector_size = self.sector_size();
+        let count = buf.len() / sector_size;
+        let mut inner = self.cache.inner.lock();
+        let mut index = 0;
+        while index < count {
+            let part = &mut buf[index * sector_size..];
+            if let Some(buffer) = inner.get((self.id, sector + index as u64)) {
+                part[..sector_size].copy_from_slice(&buffer.data);
+                index += 1;
+                continue;
+            }
+            // Read every sector up to the next cached one at once.
+            let mut run = 1;
+            while index + run < count
+                && !inner
+                    .buffers
+                    .contains_key(&(self.id, sector + (index + run) as u64))
+            {
+                run += 1;
+            }
+            let part = &mut part[..run * sector_size];
+            self.device.read_sectors(sector + index as u64, part)?;
+            for (offset, data) in part.chunks(sector_size).enumerate() {
+                let key = (self.id, sector + (index + offset) as u64);
+                inner.insert(self.cache.capacity, key, data, false)?;
+            }
+            index += run;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        check_range(self, sector, buf.len())?;
+        let mut inner = self.cache.inner.lock();
+        for (index, data) in buf.chunks(self.sector_size()).enumerate() {
+            let key = (self.id, sector + index as u64);
+            inner.insert(self.cache.capacity, key, data, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        self.cache.flush(self.id)
+    }
+}
+
+#[test_case]
+fn test_cache() {
+    use alloc::vec;
+
+    use super::MemoryDisk;
+
+    /// Counts the requests that reach the disk.
+    struct Counting {
+        disk: MemoryDisk,
+        reads: AtomicU64,
+        writes: AtomicU64,
+    }
+
+    impl BlockDevice for Counting {
+        fn sector_size(&self) -> usize {
+            self.disk.sector_size()
+        }
+
+        fn sector_count(&self) -> u64 {
+            self.disk.sector_count()
+        }
+
+        fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.disk.read_sectors(sector, buf)
+        }
+
+        fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.disk.write_sectors(sector, buf)
+        }
+    }
+
+    static SMALL: BufferCache = BufferCache::new(4 * 512);
+    let counting = Arc::new(Counting {
+        disk: MemoryDisk::new(512, 16),
+        reads: AtomicU64::new(0),
+        writes: AtomicU64::new(0),
+    });
+    let cached = CachedDevice::with_cache(counting.clone(), &SMALL);
+    let mut buf = vec![0; 3 * 512];
+
+    // The first read goes to the disk in one request, and the second doesn't need it.
+    cached.read_sectors(0, &mut buf).unwrap();
+    cached.read_sectors(1, &mut buf[..512]).unwrap();
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 1);
+
+    // Writes stay in the cache until it is flushed.
+    cached.write_sectors(1, &[7; 2 * 512]).unwrap();
+    assert_eq!(counting.writes.load(Ordering::Relaxed), 0);
+    cached.read_sectors(0, &mut buf).unwrap();
+    assert_eq!(&buf[512..], &[7; 2 * 512]);
+    cached.flush().unwrap();
+    assert_eq!(counting.writes.load(Ordering::Relaxed), 1);
+    counting.disk.read_sectors(0, &mut buf).unwrap();
+    assert_eq!(&buf[512..], &[7; 2 * 512]);
+
+    // A dirty sector is written back when it is evicted.
+    cached.write_sectors(10, &[9; 512]).unwrap();
+    cached.read_sectors(11, &mut buf).unwrap();
+    cached.read_sectors(14, &mut buf[..2 * 512]).unwrap();
+    assert_eq!(counting.writes.load(Ordering::Relaxed), 2);
+    counting.disk.read_sectors(10, &mut buf[..512]).unwrap();
+    assert_eq!(&buf[..512], &[9; 512]);
+}
@@ -0,0 +1,486 @@
+//! Partition tables, which split a disk into several block devices.
+//!
+//! Disks either have a GUID partition table (GPT), or the older master boot record (MBR).
+//! A GPT disk still has an MBR, which has a single protective partition covering the whole disk,
+//! so that older tools don't think that the disk is empty.
+//!
+//! The GPT header is at the second sector, and a backup copy is at the last sector.
+//! The backup is used if the first copy is damaged.
+//! MBR disks can have an extended partition, which holds a chain of logical partitions.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{read_bytes, BlockDevice};
+use crate::errno::Errno;
+
+/// A globally unique identifier, as it is stored on disk, with the first three fields little-endian.
+#[derive(Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// Parses a GUID written in the usual way, such as `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+    const fn parse(text: &str) -> Self {
+        let text = text.as_bytes();
+        assert!(text.len() == 36);
+        // Where each byte is in the text, in the order that the bytes are stored.
+        const ORDER: [usize; 16] = [6, 4, 2, 0, 11, 9, 16, 14, 19, 21, 24, 26, 28, 30, 32, 34];
+        let mut bytes = [0; 16];
+        let mut i = 0;
+        while i < 16 {
+            bytes[i] = hex_digit(text[ORDER[i]]) << 4 | hex_digit(text[ORDER[i] + 1]);
+            i += 1;
+        }
+        Self(bytes)
+    }
+
+    const fn is_zero(&self) -> bool {
+        u128::from_ne_bytes(self.0) == 0
+    }
+}
+
+const fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => panic!("not a hex digit"),
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The type of the EFI system partition, which holds the bootloader and the kernel.
+pub const EFI_SYSTEM: Guid = Guid::parse("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+/// The type of partition that Linux uses for its filesystems, such as ext2.
+pub const LINUX_FILESYSTEM: Guid = Guid::parse("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+/// The type of partition that Windows uses for its filesystems, such as FAT.
+pub const BASIC_DATA: Guid = Guid::parse("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+
+/// What a partition is for, as recorded in the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl PartitionType {
+    pub fn is_efi_system(self) -> bool {
+        self == Self::Mbr(MBR_EFI_SYSTEM) || self == Self::Gpt(EFI_SYSTEM)
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Mbr(kind) => write!(f, "{kind:#04x}"),
+            Self::Gpt(EFI_SYSTEM) => write!(f, "EFI system"),
+            Self::Gpt(LINUX_FILESYSTEM) => write!(f, "Linux filesystem"),
+            Self::Gpt(BASIC_DATA) => write!(f, "basic data"),
+            Self::Gpt(guid) => write!(f, "{guid}"),
+        }
+    }
+}
+
+/// A partition found in a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Numbered from 1 in the order of the table. Logical partitions on MBR disks start at 5.
+    pub number: usize,
+    pub kind: PartitionType,
+    /// The first sector.
+    pub start: u64,
+    /// The number of sectors.
+    pub count: u64,
+    /// The name of a GPT partition, which is empty for MBR partitions.
+    pub name: String,
+}
+
+impl PartitionInfo {
+    /// The partition as a block device of its own.
+    pub fn device(&self, disk: Arc<dyn BlockDevice>) -> Partition {
+        Partition {
+            disk,
+            start: self.start,
+            count: self.count,
+        }
+    }
+}
+
+/// Part of a disk, with its sectors numbered from the start of the partition.
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        super::check_range(self, sector, buf.len())?;
+        self.disk.read_sectors(self.start + sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        super::check_range(self, sector, buf.len())?;
+        self.disk.write_sectors(self.start + sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        self.disk.flush()
+    }
+}
+
+/// An entry in the partition table of an MBR or an extended boot record.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct MbrEntry {
+    /// 0x80 if the partition is bootable, or otherwise zero.
+    status: u8,
+    first_chs: [u8; 3],
+    kind: u8,
+    last_chs: [u8; 3],
+    first_sector: u32,
+    sectors: u32,
+}
+
+const MBR_TABLE: usize = 446;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_EMPTY: u8 = 0x00;
+const MBR_EFI_SYSTEM: u8 = 0xef;
+/// Covers the whole of a GPT disk.
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// The most logical partitions to follow, in case the chain loops.
+const MAX_LOGICAL: usize = 128;
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc32: u32,
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GptEntry {
+    /// Zero if the entry is unused.
+    kind: Guid,
+    unique: Guid,
+    first_lba: u64,
+    /// The last sector, which is part of the partition.
+    last_lba: u64,
+    attributes: u64,
+    /// 36 UTF-16 code units, padded with zeroes.
+    name: [[u16; 4]; 9],
+}
+
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+/// More entries than this are assumed to be a mistake.
+const MAX_GPT_ENTRIES: u32 = 1024;
+
+/// Finds the partitions on a disk, and returns an empty list if it has no partition table.
+pub fn scan(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, Errno> {
+    let mut mbr = [0; 512];
+    read_bytes(disk, 0, &mut mbr)?;
+    let entries = match mbr_entries(disk, &mbr) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+    if entries.iter().any(|entry| entry.kind == MBR_PROTECTIVE) {
+        return scan_gpt(disk);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.kind == MBR_EMPTY {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&entry.kind) {
+            scan_logical(disk, entry.first_sector as u64, &mut partitions)?;
+            continue;
+        }
+        partitions.push(mbr_partition(index + 1, entry, 0));
+    }
+    Ok(partitions)
+}
+
+/// The entries of the partition table in a master or extended boot record,
+/// or `None` if the sector doesn't have a valid table.
+/// Filesystems that take up the whole disk also end with the signature,
+/// but the rest of the table is unlikely to make sense for them.
+fn mbr_entries(disk: &dyn BlockDevice, sector: &[u8; 512]) -> Option<[MbrEntry; 4]> {
+    if sector[510..] != MBR_SIGNATURE {
+        return None;
+    }
+    let entries: [MbrEntry; 4] = bytemuck::pod_read_unaligned(&sector[MBR_TABLE..510]);
+    let valid = entries.iter().all(|entry| {
+        let end = entry.first_sector as u64 + entry.sectors as u64;
+        (entry.status == 0 || entry.status == 0x80)
+            && (entry.kind == MBR_EMPTY || (entry.sectors > 0 && end <= disk.sector_count()))
+    });
+    let used = entries.iter().any(|entry| entry.kind != MBR_EMPTY);
+    (valid && used).then_some(entries)
+}
+
+fn mbr_partition(number: usize, entry: &MbrEntry, offset: u64) -> PartitionInfo {
+    PartitionInfo {
+        number,
+        kind: PartitionType::Mbr(entry.kind),
+        start: offset + entry.first_sector as u64,
+        count: entry.sectors as u64,
+        name: String::new(),
+    }
+}
+
+/// Follows the chain of extended boot records in an extended partition.
+/// Each one describes a logical partition, relative to itself,
+/// and where the next one is, relative to the start of the extended partition.
+fn scan_logical(
+    disk: &dyn BlockDevice,
+    extended: u64,
+    partitions: &mut Vec<PartitionInfo>,
+) -> Result<(), Errno> {
+    let mut record = extended;
+    for number in 5..5 + MAX_LOGICAL {
+        let mut sector = [0; 512];
+        read_bytes(disk, record * disk.sector_size() as u64, &mut sector)?;
+        let Some([logical, next, ..]) = mbr_entries(disk, &sector) else {
+            break;
+        };
+        if logical.kind != MBR_EMPTY {
+            partitions.push(mbr_partition(number, &logical, record));
+        }
+        if !MBR_EXTENDED.contains(&next.kind) {
+            break;
+        }
+        record = extended + next.first_sector as u64;
+    }
+    Ok(())
+}
+
+fn scan_gpt(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, Errno> {
+    let last = disk.sector_count() - 1;
+    let header = match read_gpt_header(disk, 1)? {
+        Some(header) => header,
+        None => read_gpt_header(disk, last)?.ok_or(Errno::EIO)?,
+    };
+    let entry_size = header.entry_size as usize;
+    let mut table = vec![0; header.entry_count as usize * entry_size];
+    let sector_size = disk.sector_size() as u64;
+    read_bytes(disk, header.entries_lba * sector_size, &mut table)?;
+    if crc32(&table) != header.entries_crc32 {
+        return Err(Errno::EIO);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, bytes) in table.chunks(entry_size).enumerate() {
+        let entry: GptEntry = bytemuck::pod_read_unaligned(&bytes[..size_of::<GptEntry>()]);
+        if entry.kind.is_zero() {
+            continue;
+        }
+        if entry.first_lba > entry.last_lba || entry.last_lba > last {
+            return Err(Errno::EIO);
+        }
+        let name = entry.name.as_flattened();
+        let len = name
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(name.len());
+        partitions.push(PartitionInfo {
+            number: index + 1,
+            kind: PartitionType::Gpt(entry.kind),
+            start: entry.first_lba,
+            count: entry.last_lba - entry.first_lba + 1,
+            name: String::from_utf16_lossy(&name[..len]),
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads a GPT header, and returns `None` if it is missing or damaged.
+fn read_gpt_header(disk: &dyn BlockDevice, lba: u64) -> Result<Option<GptHeader>, Errno> {
+    let sector_size = disk.sector_size();
+    let mut sector = vec![0; sector_size];
+    disk.read_sectors(lba, &mut sector)?;
+    let header: GptHeader = bytemuck::pod_read_unaligned(&sector[..size_of::<GptHeader>()]);
+    let header_size = header.header_size as usize;
+    if header.signature != GPT_SIGNATURE
+        || header.current_lba != lba
+        || !(size_of::<GptHeader>()..=sector_size).contains(&header_size)
+        || header.entry_count > MAX_GPT_ENTRIES
+        || (header.entry_size as usize) < size_of::<GptEntry>()
+    {
+        return Ok(None);
+    }
+    // The checksum covers the header with the checksum field set to zero.
+    sector[16..20].fill(0);
+    match crc32(&sector[..header_size]) == header.header_crc32 {
+        true => Ok(Some(header)),
+        false => Ok(None),
+    }
+}
+
+/// The CRC-32 checksum used by GPT, which is the same one as zip and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test_case]
+fn test_partition_tables() {
+    use super::MemoryDisk;
+
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(
+        alloc::format!("{}", EFI_SYSTEM),
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+    );
+
+    let write_mbr = |image: &mut [u8], at: usize, entries: &[(u8, u32, u32)]| {
+        for (index, &(kind, first_sector, sectors)) in entries.iter().enumerate() {
+            let entry = MbrEntry {
+                kind,
+                first_sector,
+                sectors,
+                ..Zeroable::zeroed()
+            };
+            let offset = at + MBR_TABLE + index * 16;
+            image[offset..offset + 16].copy_from_slice(bytemuck::bytes_of(&entry));
+        }
+        image[at + 510..at + 512].copy_from_slice(&MBR_SIGNATURE);
+    };
+
+    // An MBR disk with a primary partition, and an extended partition holding two logical ones.
+    let mut image = vec![0; 200 * 512];
+    write_mbr(&mut image, 0, &[(0x83, 1, 49), (0x05, 50, 150)]);
+    write_mbr(&mut image, 50 * 512, &[(0x0c, 10, 40), (0x05, 60, 90)]);
+    write_mbr(&mut image, 110 * 512, &[(0x83, 1, 89)]);
+    let partitions = scan(&MemoryDisk::from_bytes(512, image)).unwrap();
+    let layout: Vec<_> = partitions
+        .iter()
+        .map(|partition| {
+            (
+                partition.number,
+                partition.kind,
+                partition.start,
+                partition.count,
+            )
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, PartitionType::Mbr(0x83), 1, 49),
+            (5, PartitionType::Mbr(0x0c), 60, 40),
+            (6, PartitionType::Mbr(0x83), 111, 89),
+        ]
+    );
+
+    // A GPT disk with two partitions, whose primary header is damaged.
+    let sectors = 100;
+    let mut image = vec![0; sectors * 512];
+    write_mbr(&mut image, 0, &[(MBR_PROTECTIVE, 1, sectors as u32 - 1)]);
+    let mut table = vec![0; 128 * 4];
+    for (index, (kind, first, last, name)) in [
+        (EFI_SYSTEM, 34, 49, "EFI"),
+        (LINUX_FILESYSTEM, 50, 97, "root"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut entry = GptEntry {
+            kind,
+            first_lba: first,
+            last_lba: last,
+            ..Zeroable::zeroed()
+        };
+        for (slot, unit) in entry
+            .name
+            .as_flattened_mut()
+            .iter_mut()
+            .zip(name.encode_utf16())
+        {
+            *slot = unit;
+        }
+        table[index * 128..][..128].copy_from_slice(bytemuck::bytes_of(&entry));
+    }
+    let backup_entries = sectors - 2;
+    image[backup_entries * 512..][..512].copy_from_slice(&table);
+    let mut header = GptHeader {
+        signature: GPT_SIGNATURE,
+        revision: 0x10000,
+        header_size: size_of::<GptHeader>() as u32,
+        current_lba: sectors as u64 - 1,
+        backup_lba: 1,
+        first_usable_lba: 34,
+        last_usable_lba: sectors as u64 - 3,
+        entries_lba: backup_entries as u64,
+        entry_count: 4,
+        entry_size: 128,
+        entries_crc32: crc32(&table),
+        ..Zeroable::zeroed()
+    };
+    header.header_crc32 = crc32(bytemuck::bytes_of(&header));
+    image[(sectors - 1) * 512..][..size_of::<GptHeader>()]
+        .copy_from_slice(bytemuck::bytes_of(&header));
+    image[512..520].copy_from_slice(b"EFI PART");
+    let partitions = scan(&MemoryDisk::from_bytes(512, image)).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert!(partitions[0].kind.is_efi_system());
+    assert_eq!((partitions[0].start, partitions[0].count), (34, 16));
+    assert_eq!(partitions[1].kind, PartitionType::Gpt(LINUX_FILESYSTEM));
+    assert_eq!(partitions[1].name, "root");
+}
@@ -0,0 +1,130 @@
+//! Capabilities: unforgeable references to kernel objects.
+//!
+//! A process can only use a kernel object through a handle, which is an index into its
+//! [CapabilityTable]. Each capability carries [Rights] that limit what its holder may do with it.
+//! A process gets capabilities when it is spawned, by creating objects, and by receiving them in
+//! messages; it can hand out copies with fewer rights, but never more.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    errno::Errno,
+    ipc::{Endpoint, Reply},
+};
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u64 {
+        /// May send messages and make calls.
+        const SEND = 1 << 0;
+        /// May receive messages.
+        const RECEIVE = 1 << 1;
+        /// May pass capabilities along with messages.
+        const GRANT = 1 << 2;
+    }
+}
+
+pub enum Capability {
+    Endpoint(Arc<Endpoint>, Rights),
+    /// The right to reply to a call, exactly once.
+    Reply(Reply),
+}
+
+impl Capability {
+    /// Copies this capability with the given rights,
+    /// which must be a subset of the rights that it already has.
+    pub fn duplicate(&self, rights: Rights) -> Result<Self, Errno> {
+        match self {
+            Capability::Endpoint(endpoint, own_rights) if own_rights.contains(rights) => {
+                Ok(Capability::Endpoint(endpoint.clone(), rights))
+            }
+            Capability::Endpoint(..) => Err(Errno::EPERM),
+            // Only one reply can ever be sent.
+            Capability::Reply(_) => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Returns the endpoint that this capability refers to, if it has all of the given rights.
+    pub fn endpoint(&self, rights: Rights) -> Result<&Arc<Endpoint>, Errno> {
+        match self {
+            Capability::Endpoint(endpoint, own_rights) if own_rights.contains(rights) => {
+                Ok(endpoint)
+            }
+            Capability::Endpoint(..) => Err(Errno::EPERM),
+            Capability::Reply(_) => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// The most capabilities that a process can hold.
+pub const MAX_CAPABILITIES: usize = 1024;
+
+/// The capabilities held by a process.
+#[derive(Default)]
+pub struct CapabilityTable {
+    capabilities: Vec<Option<Capability>>,
+}
+
+impl CapabilityTable {
+    pub fn get(&self, handle: u64) -> Result<&Capability, Errno> {
+        self.capabilities
+            .get(handle as usize)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Stores the capability in the lowest unused handle, and returns it.
+    pub fn insert(&mut self, capability: Capability) -> Result<u64, Errno> {
+        let handle = match self.capabilities.iter().position(Option::is_none) {
+            Some(handle) => handle,
+            None if self.capabilities.len() < MAX_CAPABILITIES => {
+                self.capabilities.push(None);
+                self.capabilities.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.capabilities[handle] = Some(capability);
+        Ok(handle as u64)
+    }
+
+    /// Removes the capability with the given handle, and returns it.
+    pub fn remove(&mut self, handle: u64) -> Result<Capability, Errno> {
+        self.capabilities
+            .get_mut(handle as usize)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)
+    }
+}
+
+#[test_case]
+fn test_capability_rights() {
+    let mut table = CapabilityTable::default();
+    let endpoint = table
+        .insert(Capability::Endpoint(Endpoint::new(), Rights::all()))
+        .unwrap();
+
+    // Rights can be dropped, but not regained.
+    let send_only = table
+        .get(endpoint)
+        .unwrap()
+        .duplicate(Rights::SEND)
+        .unwrap();
+    assert!(send_only.endpoint(Rights::SEND).is_ok());
+    assert_eq!(
+        send_only.endpoint(Rights::RECEIVE).err(),
+        Some(Errno::EPERM)
+    );
+    assert_eq!(send_only.duplicate(Rights::all()).err(), Some(Errno::EPERM));
+
+    let send_only = table.insert(send_only).unwrap();
+    assert_eq!(send_only, 1);
+    table.remove(endpoint).unwrap();
+    assert_eq!(table.get(endpoint).err(), Some(Errno::EBADF));
+    // Handles are reused.
+    assert_eq!(
+        table
+            .insert(Capability::Endpoint(Endpoint::new(), Rights::SEND))
+            .unwrap(),
+        endpoint
+    );
+}
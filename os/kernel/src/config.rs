@@ -0,0 +1,53 @@
+//! Settings from `funcos.cfg`, which the build puts on the boot partition next to the kernel.
+//!
+//! The file has one `key = value` setting on each line.
+//! Blank lines and lines starting with `#` are ignored.
+
+use alloc::{collections::btree_map::BTreeMap, string::String};
+
+use spin::Mutex;
+
+use crate::{fs, serial_println};
+
+/// Where the settings are read from, once the boot partition is mounted at `/boot`.
+pub const CONFIG_PATH: &str = "/boot/funcos.cfg";
+
+static SETTINGS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Reads the settings file, replacing any settings that were read before.
+pub fn load() {
+    let contents = match fs::root().and_then(|root| fs::read(&root, CONFIG_PATH)) {
+        Ok(contents) => contents,
+        Err(errno) => {
+            serial_println!("Could not read {}: {:?}", CONFIG_PATH, errno);
+            return;
+        }
+    };
+    let text = String::from_utf8_lossy(&contents);
+    let mut settings = SETTINGS.lock();
+    settings.clear();
+    settings.extend(parse(&text).map(|(key, value)| (key.into(), value.into())));
+    serial_println!("Read {} settings from {}.", settings.len(), CONFIG_PATH);
+}
+
+/// The value of a setting, if it was set.
+pub fn get(key: &str) -> Option<String> {
+    SETTINGS.lock().get(key).cloned()
+}
+
+/// The settings in a file, in order. Lines that aren't settings or comments are skipped.
+fn parse(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(key, _)| !key.is_empty())
+}
+
+#[test_case]
+fn test_parse() {
+    let text = "# A comment\n\nhostname = funcos\n  init=/bin/sh  \nnonsense\n= nothing\n";
+    let settings: alloc::vec::Vec<_> = parse(text).collect();
+    assert_eq!(settings, [("hostname", "funcos"), ("init", "/bin/sh")]);
+}
@@ -0,0 +1,433 @@
+//! Intel's 8254x gigabit Ethernet controllers, better known as the e1000,
+//! which is the network card that QEMU emulates unless told otherwise.
+//!
+//! The card receives frames into a ring of descriptors, each pointing at a buffer,
+//! and sends frames from another ring. Software owns the descriptors from the head of a ring,
+//! which the card advances as it goes, up to the tail, which we advance to hand descriptors over.
+//! The card sets a "descriptor done" bit in each descriptor that it has finished with.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Mutex;
+
+use crate::{
+    errno::Errno,
+    memory::{dma::DmaBuffer, mmio::Mmio},
+    net::{self, MacAddress, NetworkInterface, ReceiveCallback, Receiver},
+    pci::{self, msi, PciDevice},
+    sync::{self, IrqMutex},
+    trap,
+};
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "e1000",
+    matches: &[
+        // 82540EM, which QEMU calls `e1000`.
+        pci::Match::Device(0x8086, 0x100e),
+        // 82545EM, which QEMU calls `e1000-82545em`.
+        pci::Match::Device(0x8086, 0x100f),
+    ],
+    probe,
+};
+
+/// Offsets of the card's registers.
+const CONTROL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EEPROM_READ: usize = 0x0014;
+const INTERRUPT_CAUSE: usize = 0x00c0;
+const INTERRUPT_MASK_SET: usize = 0x00d0;
+const INTERRUPT_MASK_CLEAR: usize = 0x00d8;
+const RECEIVE_CONTROL: usize = 0x0100;
+const TRANSMIT_CONTROL: usize = 0x0400;
+const TRANSMIT_IPG: usize = 0x0410;
+const RECEIVE_RING: usize = 0x2800;
+const TRANSMIT_RING: usize = 0x3800;
+const MULTICAST_TABLE: usize = 0x5200;
+const RECEIVE_ADDRESS_LOW: usize = 0x5400;
+const RECEIVE_ADDRESS_HIGH: usize = 0x5404;
+
+/// Offsets of the registers that describe each ring, from [RECEIVE_RING] or [TRANSMIT_RING].
+const RING_BASE_LOW: usize = 0x00;
+const RING_BASE_HIGH: usize = 0x04;
+const RING_LENGTH: usize = 0x08;
+const RING_HEAD: usize = 0x10;
+const RING_TAIL: usize = 0x18;
+
+const CONTROL_AUTO_SPEED: u32 = 1 << 5;
+const CONTROL_SET_LINK_UP: u32 = 1 << 6;
+const CONTROL_RESET: u32 = 1 << 26;
+
+const STATUS_LINK_UP: u32 = 1 << 1;
+
+const EEPROM_READ_START: u32 = 1 << 0;
+const EEPROM_READ_DONE: u32 = 1 << 4;
+const EEPROM_READ_ADDRESS_SHIFT: u32 = 8;
+const EEPROM_READ_DATA_SHIFT: u32 = 16;
+
+/// Causes of interrupts: the link changed, the receive ring is running low or has overrun,
+/// or a frame arrived.
+const INTERRUPT_LINK_STATUS: u32 = 1 << 2;
+const INTERRUPT_RECEIVE_LOW: u32 = 1 << 4;
+const INTERRUPT_RECEIVE_OVERRUN: u32 = 1 << 6;
+const INTERRUPT_RECEIVE_TIMER: u32 = 1 << 7;
+const INTERRUPTS: u32 = INTERRUPT_LINK_STATUS
+    | INTERRUPT_RECEIVE_LOW
+    | INTERRUPT_RECEIVE_OVERRUN
+    | INTERRUPT_RECEIVE_TIMER;
+
+/// Enable receiving broadcast frames into 2 KiB buffers, and strip the frame check sequence.
+const RECEIVE_ENABLE: u32 = 1 << 1;
+const RECEIVE_BROADCAST: u32 = 1 << 15;
+const RECEIVE_STRIP_CRC: u32 = 1 << 26;
+
+/// Enable sending, padding short frames, with the recommended collision settings.
+const TRANSMIT_ENABLE: u32 = 1 << 1;
+const TRANSMIT_PAD_SHORT: u32 = 1 << 3;
+const TRANSMIT_COLLISION_THRESHOLD: u32 = 0x0f << 4;
+const TRANSMIT_COLLISION_DISTANCE: u32 = 0x40 << 12;
+/// The gaps between frames that the manual recommends for copper links.
+const TRANSMIT_IPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RECEIVE_ADDRESS_VALID: u32 = 1 << 31;
+
+/// Bits of a descriptor's status: the card is done with it, and it holds the end of a frame.
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const DESCRIPTOR_END_OF_PACKET: u8 = 1 << 1;
+
+/// Bits of a transmit descriptor's command: this is the end of the frame, add the frame check
+/// sequence, and report when done.
+const COMMAND_END_OF_PACKET: u8 = 1 << 0;
+const COMMAND_INSERT_FCS: u8 = 1 << 1;
+const COMMAND_REPORT_STATUS: u8 = 1 << 3;
+
+/// The number of descriptors in each ring. Rings must be a multiple of 128 bytes long.
+const RING_SIZE: usize = 64;
+const BUFFER_SIZE: usize = 2048;
+/// Where the buffers start in each ring's memory, after the descriptors.
+const BUFFERS_OFFSET: usize = 4096;
+
+/// The EEPROM words that hold the MAC address.
+const EEPROM_MAC: u32 = 0;
+
+/// How many times to check the card before giving up on it.
+const TIMEOUT_POLLS: usize = 1_000_000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+struct ReceiveDescriptor {
+    addr: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+struct TransmitDescriptor {
+    addr: u64,
+    len: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// The cards found so far.
+static CARDS: Mutex<Vec<Arc<E1000>>> = Mutex::new(Vec::new());
+
+/// The registers of the cards that interrupt on a legacy line, which the handler must quieten.
+static INTERRUPTING: IrqMutex<Vec<Mmio>> = IrqMutex::new(Vec::new());
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    pci.enable_bus_master();
+    let registers = pci.map_bar(0)?;
+
+    registers.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    registers.write(CONTROL, registers.read::<u32>(CONTROL) | CONTROL_RESET);
+    if !poll_until(|| registers.read::<u32>(CONTROL) & CONTROL_RESET == 0) {
+        return Err(Errno::EIO);
+    }
+    registers.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    registers.write(
+        CONTROL,
+        registers.read::<u32>(CONTROL) | CONTROL_SET_LINK_UP | CONTROL_AUTO_SPEED,
+    );
+
+    let mut mac = [0; 6];
+    for (word, bytes) in mac.chunks_mut(2).enumerate() {
+        let value = read_eeprom(&registers, EEPROM_MAC + word as u32).ok_or(Errno::EIO)?;
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    let mac = MacAddress(mac);
+    registers.write(
+        RECEIVE_ADDRESS_LOW,
+        u32::from_le_bytes([mac.0[0], mac.0[1], mac.0[2], mac.0[3]]),
+    );
+    registers.write(
+        RECEIVE_ADDRESS_HIGH,
+        u16::from_le_bytes([mac.0[4], mac.0[5]]) as u32 | RECEIVE_ADDRESS_VALID,
+    );
+    for i in 0..128 {
+        registers.write::<u32>(MULTICAST_TABLE + 4 * i, 0);
+    }
+
+    let receive = Ring::new()?;
+    for i in 0..RING_SIZE {
+        receive.memory.write(
+            i * size_of::<ReceiveDescriptor>(),
+            ReceiveDescriptor {
+                addr: receive.buffer_phys(i),
+                ..Default::default()
+            },
+        );
+    }
+    receive.start(&registers, RECEIVE_RING);
+    // Every descriptor but the one at the head belongs to the card.
+    registers.write(RECEIVE_RING + RING_TAIL, RING_SIZE as u32 - 1);
+    registers.write(
+        RECEIVE_CONTROL,
+        RECEIVE_ENABLE | RECEIVE_BROADCAST | RECEIVE_STRIP_CRC,
+    );
+
+    let transmit = Ring::new()?;
+    for i in 0..RING_SIZE {
+        // Descriptors that have never been used are as good as done.
+        transmit.memory.write(
+            i * size_of::<TransmitDescriptor>(),
+            TransmitDescriptor {
+                addr: transmit.buffer_phys(i),
+                status: DESCRIPTOR_DONE,
+                ..Default::default()
+            },
+        );
+    }
+    transmit.start(&registers, TRANSMIT_RING);
+    registers.write(TRANSMIT_IPG, TRANSMIT_IPG_COPPER);
+    registers.write(
+        TRANSMIT_CONTROL,
+        TRANSMIT_ENABLE
+            | TRANSMIT_PAD_SHORT
+            | TRANSMIT_COLLISION_THRESHOLD
+            | TRANSMIT_COLLISION_DISTANCE,
+    );
+
+    // Reading the interrupt cause clears it, which makes the card stop interrupting.
+    let handler = move || {
+        registers.read::<u32>(INTERRUPT_CAUSE);
+        net::wake();
+    };
+    let interrupts = match msi::allocate_vector(handler) {
+        Ok(vector) if pci.enable_msi(vector).is_ok() => true,
+        result => {
+            if let Ok(vector) = result {
+                trap::free_msi_vector(vector);
+            }
+            INTERRUPTING.lock().push(registers);
+            pci.add_interrupt_handler(interrupt).is_ok()
+        }
+    };
+    if interrupts {
+        registers.read::<u32>(INTERRUPT_CAUSE);
+        registers.write(INTERRUPT_MASK_SET, INTERRUPTS);
+    }
+
+    let card = Arc::new(E1000 {
+        registers,
+        mac,
+        receive: Mutex::new(receive),
+        transmit: Mutex::new(transmit),
+        receiver: Receiver::new(),
+    });
+    CARDS.lock().push(card.clone());
+    net::add_interface(card);
+    Ok(())
+}
+
+/// The handler for cards that share legacy interrupt lines.
+fn interrupt() {
+    for registers in INTERRUPTING.lock().iter() {
+        if registers.read::<u32>(INTERRUPT_CAUSE) != 0 {
+            net::wake();
+        }
+    }
+}
+
+fn poll_until(mut condition: impl FnMut() -> bool) -> bool {
+    for _ in 0..TIMEOUT_POLLS {
+        if condition() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Reads a 16-bit word from the EEPROM, or returns `None` if the card has no EEPROM.
+fn read_eeprom(registers: &Mmio, word: u32) -> Option<u16> {
+    registers.write(
+        EEPROM_READ,
+        word << EEPROM_READ_ADDRESS_SHIFT | EEPROM_READ_START,
+    );
+    let mut value = 0;
+    poll_until(|| {
+        value = registers.read::<u32>(EEPROM_READ);
+        value & EEPROM_READ_DONE != 0
+    })
+    .then_some((value >> EEPROM_READ_DATA_SHIFT) as u16)
+}
+
+pub struct E1000 {
+    registers: Mmio,
+    mac: MacAddress,
+    receive: Mutex<Ring>,
+    transmit: Mutex<Ring>,
+    receiver: Receiver,
+}
+
+/// A ring of descriptors, followed by the buffers that they point at.
+struct Ring {
+    memory: DmaBuffer,
+    /// The next descriptor for software to look at.
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Result<Self, Errno> {
+        Ok(Self {
+            memory: DmaBuffer::new(BUFFERS_OFFSET + RING_SIZE * BUFFER_SIZE)
+                .map_err(|_| Errno::ENOMEM)?,
+            next: 0,
+        })
+    }
+
+    fn buffer_phys(&self, index: usize) -> u64 {
+        self.memory
+            .phys(BUFFERS_OFFSET + index * BUFFER_SIZE)
+            .as_u64()
+    }
+
+    /// Tells the card where the ring is, with its head and tail at the start.
+    fn start(&self, registers: &Mmio, ring: usize) {
+        let base = self.memory.phys(0).as_u64();
+        registers.write(ring + RING_BASE_LOW, base as u32);
+        registers.write(ring + RING_BASE_HIGH, (base >> 32) as u32);
+        // Both kinds of descriptor are 16 bytes long.
+        registers.write(
+            ring + RING_LENGTH,
+            (RING_SIZE * size_of::<ReceiveDescriptor>()) as u32,
+        );
+        registers.write(ring + RING_HEAD, 0);
+        registers.write(ring + RING_TAIL, 0);
+    }
+}
+
+impl E1000 {
+    /// Takes the next frame that has arrived, and gives its descriptor back to the card.
+    /// Frames with errors, or that don't fit in a buffer, are dropped and returned empty.
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut receive = self.receive.lock();
+        let index = receive.next;
+        let offset = index * size_of::<ReceiveDescriptor>();
+        let descriptor = receive.memory.read::<ReceiveDescriptor>(offset);
+        if descriptor.status & DESCRIPTOR_DONE == 0 {
+            return None;
+        }
+
+        let complete = descriptor.status & DESCRIPTOR_END_OF_PACKET != 0 && descriptor.errors == 0;
+        let frame = if complete {
+            let mut frame = vec![0; descriptor.len as usize];
+            receive
+                .memory
+                .read_bytes(BUFFERS_OFFSET + index * BUFFER_SIZE, &mut frame);
+            frame
+        } else {
+            Vec::new()
+        };
+        receive.memory.write(
+            offset,
+            ReceiveDescriptor {
+                addr: descriptor.addr,
+                ..Default::default()
+            },
+        );
+        receive.next = (index + 1) % RING_SIZE;
+        self.registers.write(RECEIVE_RING + RING_TAIL, index as u32);
+        Some(frame)
+    }
+}
+
+impl NetworkInterface for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read::<u32>(STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Errno> {
+        if frame.len() > net::MAX_FRAME_SIZE {
+            return Err(Errno::EMSGSIZE);
+        }
+        if !self.link_up() {
+            return Err(Errno::ENETDOWN);
+        }
+
+        let mut transmit = self.transmit.lock();
+        let index = transmit.next;
+        let offset = index * size_of::<TransmitDescriptor>();
+        // The card is still sending the frame that was here last time round the ring.
+        let descriptor = sync::wait_for_device(|| {
+            let descriptor = transmit.memory.read::<TransmitDescriptor>(offset);
+            (descriptor.status & DESCRIPTOR_DONE != 0).then_some(descriptor)
+        });
+        transmit
+            .memory
+            .write_bytes(BUFFERS_OFFSET + index * BUFFER_SIZE, frame);
+        transmit.memory.write(
+            offset,
+            TransmitDescriptor {
+                addr: descriptor.addr,
+                len: frame.len() as u16,
+                command: COMMAND_END_OF_PACKET | COMMAND_INSERT_FCS | COMMAND_REPORT_STATUS,
+                ..Default::default()
+            },
+        );
+        transmit.next = (index + 1) % RING_SIZE;
+        self.registers
+            .write(TRANSMIT_RING + RING_TAIL, transmit.next as u32);
+        Ok(())
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        self.receiver.set(callback);
+    }
+
+    fn poll(&self) -> usize {
+        let mut count = 0;
+        while let Some(frame) = self.receive() {
+            if !frame.is_empty() {
+                self.receiver.deliver(&frame);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[test_case]
+fn test_e1000() {
+    // The test runner gives QEMU's user-mode network an e1000.
+    let Some(card) = CARDS.lock().first().cloned() else {
+        return;
+    };
+    net::test_card(&*card);
+}
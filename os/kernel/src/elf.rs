@@ -0,0 +1,120 @@
+//! Parsing of 64-bit little-endian ELF executables.
+
+use bytemuck::{Pod, Zeroable};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3e;
+
+pub const TYPE_EXECUTABLE: u16 = 2;
+
+pub const SEGMENT_LOAD: u32 = 1;
+pub const SEGMENT_TLS: u32 = 7;
+
+pub const SEGMENT_EXECUTABLE: u32 = 1;
+pub const SEGMENT_WRITABLE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    pub section_header_size: u16,
+    pub section_header_count: u16,
+    pub section_names_index: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Unsupported,
+    /// A program header refers to data outside the file.
+    BadSegment,
+}
+
+/// An ELF file whose headers have been checked to lie within the file.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: ElfHeader = read(data, 0).ok_or(ElfError::TooShort)?;
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != CLASS_64
+            || header.ident[5] != DATA_LITTLE_ENDIAN
+            || header.machine != MACHINE_X86_64
+            || header.program_header_size as usize != size_of::<ProgramHeader>()
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = Self { data, header };
+        for index in 0..header.program_header_count as usize {
+            let program_header = elf.program_header(index).ok_or(ElfError::TooShort)?;
+            let end = program_header.offset.checked_add(program_header.file_size);
+            if end.is_none_or(|end| end > data.len() as u64)
+                || program_header.file_size > program_header.memory_size
+            {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    fn program_header(&self, index: usize) -> Option<ProgramHeader> {
+        let offset = index
+            .checked_mul(size_of::<ProgramHeader>())?
+            .checked_add(usize::try_from(self.header.program_header_offset).ok()?)?;
+        read(self.data, offset)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.program_header_count as usize).map(|index| {
+            self.program_header(index)
+                .expect("program headers were checked in Elf::parse")
+        })
+    }
+
+    /// The bytes of the given segment that are stored in the file.
+    pub fn segment_data(&self, program_header: &ProgramHeader) -> &'a [u8] {
+        let start = program_header.offset as usize;
+        &self.data[start..start + program_header.file_size as usize]
+    }
+}
+
+fn read<T: Pod>(data: &[u8], offset: usize) -> Option<T> {
+    let bytes = data.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(bytemuck::pod_read_unaligned(bytes))
+}
@@ -0,0 +1,240 @@
+//! Open files, and the table of file descriptors that each process has.
+//!
+//! Anything that a process can read from or write to through a file descriptor implements [File].
+//! File descriptors are indices into the process's [FileTable], which holds shared references to
+//! the open files, so several descriptors (in one process or many) can refer to the same file.
+//!
+//! Files whose reads and writes can block say whether they are ready through [File::poll],
+//! and call [notify_ready] when that might have changed, so that [wait_ready] can wait for
+//! any of several files at once.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bytemuck::{Pod, Zeroable};
+use x86_64::PhysAddr;
+
+use crate::{
+    errno::Errno,
+    fs::{DirEntry, Stat, Whence},
+    memory::shared::SharedMemory,
+    net::socket::Socket,
+    print,
+    scheduler::WaitQueue,
+    timer,
+};
+
+/// What [File::poll] reports, with the same values as Linux.
+/// The file can be read from, or written to, without blocking.
+pub const POLLIN: u16 = 0x001;
+pub const POLLOUT: u16 = 0x004;
+/// Something went wrong, such as a connection being refused or a pipe having no readers left.
+pub const POLLERR: u16 = 0x008;
+/// The other end has gone away.
+pub const POLLHUP: u16 = 0x010;
+/// The file descriptor isn't open. This is only reported by the `poll` system call.
+pub const POLLNVAL: u16 = 0x020;
+
+/// A file descriptor, and the events to wait for on it, as passed to the `poll` system call.
+/// The events that happened are written back into `revents`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: u16,
+    pub revents: u16,
+}
+
+pub trait File: Send + Sync {
+    /// Reads some bytes into `buf`, blocking until at least one byte is available.
+    /// Returns zero at the end of the file.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    /// Writes some bytes from `buf`, and returns how many were written.
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    /// Reads bytes from the given offset into `buf`, for files with random access.
+    /// This is used to map private copies of the file into memory.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    /// The memory that holds the contents of this file, for files that can be mapped shared.
+    fn shared_memory(&self) -> Result<Arc<SharedMemory>, Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// The physical memory behind a device that can be mapped shared, as its start address and length.
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// Carries out a request that is specific to a device.
+    fn ioctl(&self, _request: u64, _arg: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOTTY)
+    }
+
+    /// Moves the position that the next read or write happens at, and returns the new position.
+    fn seek(&self, _offset: i64, _whence: Whence) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    /// Information about the file, for files in a filesystem.
+    fn stat(&self) -> Result<Stat, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Lists a directory from the current position, passing each entry to `accept` until it
+    /// returns false. The position moves past every entry that was accepted.
+    fn read_dir(&self, _accept: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Sets the size of a regular file that is open for writing.
+    fn truncate(&self, _size: u64) -> Result<(), Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Which of [POLLIN], [POLLOUT], [POLLERR] and [POLLHUP] apply to the file now.
+    /// Files that never block are always ready.
+    fn poll(&self) -> u16 {
+        POLLIN | POLLOUT
+    }
+
+    /// The socket, if this file is one.
+    fn as_socket(&self) -> Option<&Socket> {
+        None
+    }
+}
+
+/// Woken whenever a file might have become ready, and when the earliest deadline passes.
+static READY: WaitQueue = WaitQueue::new();
+/// The earliest tick that anything in [wait_ready] is waiting for.
+static DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub fn init() {
+    timer::add_tick_handler(tick);
+}
+
+fn tick() {
+    if timer::ticks() >= DEADLINE.load(Ordering::Relaxed) {
+        DEADLINE.store(u64::MAX, Ordering::Relaxed);
+        READY.wake_all();
+    }
+}
+
+/// Tells [wait_ready] to check its condition again, because a file might have become ready.
+/// This doesn't allocate, so interrupt handlers can call it when input arrives.
+pub fn notify_ready() {
+    READY.wake_all();
+}
+
+/// Blocks until `condition` returns `Some`, checking it again whenever a file might have become
+/// ready. Gives up and returns `None` once the timer reaches `deadline`, if there is one,
+/// or fails with [Errno::EINTR] if the process has a signal to handle first.
+pub fn wait_ready<T>(
+    deadline: Option<u64>,
+    mut condition: impl FnMut() -> Option<T>,
+) -> Result<Option<T>, Errno> {
+    READY.wait_interruptible(|| {
+        if let Some(result) = condition() {
+            return Some(Some(result));
+        }
+        let deadline = deadline?;
+        if timer::ticks() >= deadline {
+            return Some(None);
+        }
+        DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+        None
+    })
+}
+
+/// The screen and serial port. Reading from the console always returns end of file.
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        print::print_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// The most file descriptors that a process can have open.
+pub const MAX_FILES: usize = 256;
+
+/// The file descriptors of a process.
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    /// A table with the console open as standard input, output and error.
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+        Self {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    /// The number of file descriptors that are open.
+    pub fn count(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<dyn File>, Errno> {
+        self.files
+            .get(fd as usize)
+            .and_then(Option::clone)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Stores the file in the lowest unused file descriptor, and returns it.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<u64, Errno> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.files[fd] = Some(file);
+        Ok(fd as u64)
+    }
+
+    /// Makes `new_fd` refer to the same file as `old_fd`, and returns whatever `new_fd` referred to
+    /// before. That file is closed once it is dropped, if nothing else refers to it.
+    pub fn duplicate_to(
+        &mut self,
+        old_fd: u64,
+        new_fd: u64,
+    ) -> Result<Option<Arc<dyn File>>, Errno> {
+        let file = self.get(old_fd)?;
+        let new_fd = new_fd as usize;
+        if new_fd >= MAX_FILES {
+            return Err(Errno::EBADF);
+        }
+        if new_fd >= self.files.len() {
+            self.files.resize(new_fd + 1, None);
+        }
+        Ok(self.files[new_fd].replace(file))
+    }
+
+    /// Removes a file descriptor. The file is closed once nothing else refers to it.
+    pub fn close(&mut self, fd: u64) -> Result<(), Errno> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .map(drop)
+            .ok_or(Errno::EBADF)
+    }
+}
@@ -0,0 +1,217 @@
+//! Hardware floating point and SIMD registers for user programs.
+//!
+//! The kernel itself is built for a soft-float target, so it never touches these registers.
+//! User programs are built to use SSE, so the kernel enables the FPU at boot,
+//! and gives each user task its own copy of the registers.
+//! The scheduler saves the registers when a user task switches away,
+//! and restores them when a different user task next runs.
+//!
+//! If the processor supports `XSAVE`, the AVX registers are enabled and saved as well.
+//! Otherwise, only the x87 and SSE registers are saved, with `FXSAVE`.
+
+use alloc::{boxed::Box, vec};
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Once;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+use crate::serial_println;
+
+/// The size of the area saved by `FXSAVE`.
+const FXSAVE_AREA_SIZE: usize = 512;
+
+/// The value of `MXCSR` after reset: every exception masked, rounding to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Where `MXCSR`, and the mask of the bits in it that can be set, are in the `FXSAVE` area.
+/// The start of the `XSAVE` area has the same layout.
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_MASK_OFFSET: usize = 28;
+
+/// The mask to use if the processor reports zero.
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+
+/// Set if the registers are saved with `XSAVE` rather than `FXSAVE`.
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// The registers as they are when a program starts.
+static INITIAL_STATE: Once<FpuState> = Once::new();
+
+/// `XSAVE` needs its save area to be aligned to 64 bytes.
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct Chunk([u8; 64]);
+
+/// A saved copy of the floating point and SIMD registers.
+#[derive(Clone)]
+pub struct FpuState {
+    area: Box<[Chunk]>,
+}
+
+/// The x87 and SSE registers, in the layout that `FXSAVE` stores them in.
+/// This is how signal frames hold them, so it has no alignment of its own.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct FxsaveArea([u8; FXSAVE_AREA_SIZE]);
+
+/// `FXSAVE` and `FXRSTOR` need their area to be aligned to 16 bytes.
+#[repr(C, align(16))]
+struct AlignedFxsaveArea(FxsaveArea);
+
+/// Enables the FPU and SSE, and AVX if there is `XSAVE` support to save it with.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let features = __cpuid(1);
+    let has_xsave = features.ecx & (1 << 26) != 0;
+    let has_avx = features.ecx & (1 << 28) != 0;
+    let mut area_size = FXSAVE_AREA_SIZE;
+    if has_xsave {
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_avx {
+            components |= XCr0Flags::AVX;
+        }
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+        // This is the size needed for the components that are now enabled.
+        area_size = __cpuid_count(0xd, 0).ebx as usize;
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+    serial_println!(
+        "FPU enabled, saving {} bytes per task with {}.",
+        area_size,
+        if has_xsave { "XSAVE" } else { "FXSAVE" }
+    );
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+        asm!("ldmxcsr [{}]", in(reg) &DEFAULT_MXCSR, options(nostack, readonly));
+    }
+    INITIAL_STATE.call_once(|| {
+        let mut state = FpuState {
+            area: vec![Chunk([0; 64]); area_size.div_ceil(64)].into_boxed_slice(),
+        };
+        unsafe { state.save() };
+        state
+    });
+}
+
+impl FpuState {
+    /// The registers as they are when a program starts.
+    pub fn initial() -> Self {
+        INITIAL_STATE
+            .get()
+            .expect("FPU not yet initialised")
+            .clone()
+    }
+
+    /// Copies the registers into this state.
+    ///
+    /// # Safety
+    ///
+    /// The FPU must have been initialised.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+
+    /// Loads the registers from this state.
+    ///
+    /// # Safety
+    ///
+    /// The FPU must have been initialised.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, readonly));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+        }
+    }
+}
+
+impl FxsaveArea {
+    /// Copies the x87 and SSE registers that are loaded now.
+    ///
+    /// # Safety
+    ///
+    /// The FPU must have been initialised.
+    pub unsafe fn save() -> Self {
+        let mut aligned = AlignedFxsaveArea(Self::zeroed());
+        asm!("fxsave64 [{}]", in(reg) &mut aligned, options(nostack));
+        aligned.0
+    }
+
+    /// Whether the registers can be loaded. Setting reserved bits in `MXCSR` would fault.
+    pub fn is_valid(&self) -> bool {
+        let initial = &INITIAL_STATE.get().expect("FPU not yet initialised").area[0].0;
+        let mask = match read_u32(initial, MXCSR_MASK_OFFSET) {
+            0 => DEFAULT_MXCSR_MASK,
+            mask => mask,
+        };
+        read_u32(&self.0, MXCSR_OFFSET) & !mask == 0
+    }
+
+    /// Loads the registers.
+    ///
+    /// # Safety
+    ///
+    /// The FPU must have been initialised, and the registers must be [valid](Self::is_valid).
+    pub unsafe fn restore(&self) {
+        let aligned = AlignedFxsaveArea(*self);
+        asm!("fxrstor64 [{}]", in(reg) &aligned, options(nostack, readonly));
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[test_case]
+fn test_fpu_state_round_trip() {
+    fn mxcsr() -> u32 {
+        let mut value = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+        value
+    }
+    fn set_mxcsr(value: u32) {
+        unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly)) };
+    }
+
+    let mut state = FpuState::initial();
+    // Round towards zero.
+    set_mxcsr(DEFAULT_MXCSR | 0x6000);
+    unsafe { state.save() };
+    unsafe { FpuState::initial().restore() };
+    assert_eq!(mxcsr(), DEFAULT_MXCSR);
+    unsafe { state.restore() };
+    assert_eq!(mxcsr(), DEFAULT_MXCSR | 0x6000);
+    set_mxcsr(DEFAULT_MXCSR);
+
+    let mut area = unsafe { FxsaveArea::save() };
+    assert!(area.is_valid());
+    area.0[MXCSR_OFFSET + 3] = 0xff;
+    assert!(!area.is_valid());
+}
@@ -0,0 +1,755 @@
+//! The virtual filesystem, which joins every mounted filesystem into a single tree.
+//!
+//! Each filesystem implements [FileSystem], and presents its files and directories as [Inode]s.
+//! Paths are walked through [Dentry]s, which remember the name that each inode was reached by
+//! and the directory that it was found in. This is how `..` and mount points work the same way
+//! for every filesystem: a filesystem only has to look up names in its own directories.
+//!
+//! A filesystem is mounted over a directory, and hides that directory's contents while it is mounted.
+//! The first filesystem is mounted at `/`, and every absolute path starts from its root.
+//!
+//! The functions here take the directory that relative paths start from, so the kernel can use them
+//! directly as well as on behalf of a process, whose current directory is passed in by the system calls.
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod open_file;
+pub mod path;
+pub mod procfs;
+pub mod tarfs;
+pub mod tmpfs;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytemuck::{Pod, Zeroable};
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+
+use crate::{
+    block::BlockDevice,
+    errno::Errno,
+    file::{File, POLLIN, POLLOUT},
+    memory::shared::SharedMemory,
+};
+
+use devfs::DevFs;
+use ext2::Ext2Fs;
+use fat::FatFs;
+pub use open_file::{OpenFile, OpenFlags, Whence};
+pub use path::{lookup, lookup_parent};
+use procfs::ProcFs;
+use tmpfs::TmpFs;
+
+/// The kinds of file, with the values that Linux uses in directory entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileType {
+    Fifo = 1,
+    CharDevice = 2,
+    Directory = 4,
+    BlockDevice = 6,
+    Regular = 8,
+    Symlink = 10,
+    Socket = 12,
+}
+
+/// Information about a file, as written out by the `stat` system call.
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct Stat {
+    /// The ID of the mount that the file is in. This is filled in by the VFS.
+    pub device: u64,
+    pub inode: u64,
+    /// A [FileType].
+    pub kind: u32,
+    /// The permission bits.
+    pub mode: u32,
+    /// The number of hard links to the file.
+    pub links: u64,
+    pub size: u64,
+    /// The number of 512-byte blocks that the file takes up.
+    pub blocks: u64,
+    /// Timestamps, in nanoseconds. See [now].
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+impl Stat {
+    /// Information about a file that only has a type and a size.
+    pub fn new(inode: u64, kind: FileType, mode: u32, size: u64) -> Self {
+        Self {
+            inode,
+            kind: kind as u32,
+            mode,
+            links: 1,
+            size,
+            blocks: size.div_ceil(512),
+            ..Default::default()
+        }
+    }
+}
+
+/// The time to record in file timestamps.
+/// There is no real-time clock yet, so this counts nanoseconds since boot.
+pub fn now() -> u64 {
+    crate::timer::uptime().as_nanos() as u64
+}
+
+/// An entry in a directory, as listed by [Inode::read_dir].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    /// A short name for the kind of filesystem, such as `tmpfs`.
+    fn name(&self) -> &'static str;
+
+    /// The root directory.
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// A file, directory or other object in a filesystem.
+///
+/// Inodes are identified by their inode number, which must be unique within their filesystem,
+/// so that the VFS can tell when a directory has something mounted over it.
+/// Operations that don't make sense for the kind of inode are never called by the VFS.
+/// By default, operations that change a filesystem fail with [Errno::EROFS],
+/// so read-only filesystems don't need to implement them.
+pub trait Inode: Any + Send + Sync {
+    fn kind(&self) -> FileType;
+
+    /// Information about the file. The device is filled in by the VFS.
+    fn stat(&self) -> Stat;
+
+    /// Reads bytes from the given offset into `buf`, and returns how many were read.
+    /// Returns zero at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Writes bytes from `buf` at the given offset, extending the file if needed.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Sets the size of a regular file, either cutting off its end or extending it with zeroes.
+    fn truncate(&self, _size: u64) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// The memory that holds the contents of this file, for files that can be mapped shared.
+    fn shared_memory(&self) -> Result<Arc<SharedMemory>, Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// The physical memory behind a device file that can be mapped shared, such as a framebuffer,
+    /// as its start address and its length in bytes.
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// Carries out a request that is specific to a device file.
+    /// `arg` is often the address of a structure in the calling process's memory.
+    fn ioctl(&self, _request: u64, _arg: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOTTY)
+    }
+
+    /// See [File::poll]. Only device files can block, so everything else is always ready.
+    fn poll(&self) -> u16 {
+        POLLIN | POLLOUT
+    }
+
+    /// Finds the entry with the given name in a directory.
+    /// The name is never `.` or `..`, which the VFS deals with itself.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Returns the entry at the given index in a directory, or `None` if there are no more.
+    /// The entries must not include `.` and `..`, which are added by the VFS.
+    fn read_dir(&self, _index: usize) -> Result<Option<DirEntry>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Creates an empty regular file or directory, which has no entry with this name yet.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u32) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Creates a symbolic link to `target`.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Adds a hard link to a file in the same filesystem, which is not a directory.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Removes an entry that is not a directory.
+    fn unlink(&self, _name: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Removes an empty directory.
+    fn rmdir(&self, _name: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Moves an entry to `new_name` in `new_parent`, which is a directory in the same filesystem,
+    /// replacing whatever was there. The VFS has checked that a directory is not moved into itself.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &Arc<dyn Inode>,
+        _new_name: &str,
+    ) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// The target of a symbolic link.
+    fn read_link(&self) -> Result<String, Errno> {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// A filesystem that has been mounted into the tree.
+pub struct Mount {
+    id: u64,
+    fs: Arc<dyn FileSystem>,
+    root: Arc<dyn Inode>,
+    /// The directory that this is mounted over, or `None` for the filesystem at `/`.
+    mount_point: Option<Arc<Dentry>>,
+    /// The mount ID and inode number of the mount point.
+    covers: Option<(u64, u64)>,
+}
+
+impl Mount {
+    /// A number that identifies this mount, which is used as the device number of its files.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn fs(&self) -> &Arc<dyn FileSystem> {
+        &self.fs
+    }
+
+    /// The path that this is mounted at.
+    pub fn path(&self) -> String {
+        self.mount_point
+            .as_ref()
+            .map_or_else(|| "/".into(), |dentry| dentry.path())
+    }
+}
+
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+static ROOT: Once<Arc<Dentry>> = Once::new();
+
+/// Mounts the filesystems that are always there, once the root filesystem has been mounted.
+pub fn init() {
+    devfs::init();
+    if let Err(errno) = mount("/dev", Arc::new(DevFs)) {
+        crate::serial_println!("Could not mount devfs at /dev: {:?}", errno);
+    }
+    if let Err(errno) = mount("/proc", Arc::new(ProcFs)) {
+        crate::serial_println!("Could not mount procfs at /proc: {:?}", errno);
+    }
+    if let Err(errno) = mount("/tmp", TmpFs::new()) {
+        crate::serial_println!("Could not mount tmpfs at /tmp: {:?}", errno);
+    }
+}
+
+/// Mounts the EFI system partition at `/boot`, and reads the configuration files on it.
+/// The build puts the kernel there, along with the files in the `boot` directory.
+pub fn mount_boot(fs: Arc<FatFs>) -> Result<(), Errno> {
+    mount("/boot", fs)?;
+    crate::config::load();
+    Ok(())
+}
+
+/// Mounts the filesystem on a disk or partition, if the kernel knows where it goes.
+/// The first FAT filesystem is taken to be the boot partition, and mounted with [mount_boot].
+/// The first ext2 filesystem is mounted at `/mnt`.
+pub fn mount_disk(name: &str, device: Arc<dyn BlockDevice>) {
+    let mounted = |path: &str| MOUNTS.lock().iter().any(|mount| mount.path() == path);
+    let (path, result) = if let Ok(fs) = FatFs::new(device.clone()) {
+        if mounted("/boot") {
+            return;
+        }
+        ("/boot", mount_boot(fs))
+    } else if let Ok(fs) = Ext2Fs::new(device) {
+        if mounted("/mnt") {
+            return;
+        }
+        ("/mnt", mount("/mnt", fs))
+    } else {
+        return;
+    };
+    match result {
+        Ok(()) => {
+            crate::serial_println!("Mounted {} at {}.", name, path);
+        }
+        Err(errno) => {
+            crate::serial_println!("Could not mount {} at {}: {:?}", name, path, errno);
+        }
+    }
+}
+
+/// The root directory, or [Errno::ENOENT] if nothing has been mounted at `/` yet.
+pub fn root() -> Result<Arc<Dentry>, Errno> {
+    ROOT.get().cloned().ok_or(Errno::ENOENT)
+}
+
+/// Makes a mount without recording it, so it is found only through the dentries made from it.
+fn new_mount(fs: Arc<dyn FileSystem>, mount_point: Option<Arc<Dentry>>) -> Arc<Mount> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let covers = mount_point
+        .as_ref()
+        .map(|dentry| (dentry.mount.id, dentry.inode.stat().inode));
+    Arc::new(Mount {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        root: fs.root(),
+        fs,
+        mount_point,
+        covers,
+    })
+}
+
+/// Makes a mount and records it, so that lookups through its mount point find it.
+fn add_mount(fs: Arc<dyn FileSystem>, mount_point: Option<Arc<Dentry>>) -> Arc<Mount> {
+    let mount = new_mount(fs, mount_point);
+    MOUNTS.lock().push(mount.clone());
+    mount
+}
+
+/// Mounts a filesystem over the directory at the given absolute path.
+/// The first filesystem must be mounted at `/`, and nothing else can be mounted there afterwards.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Errno> {
+    if path.trim_end_matches('/').is_empty() {
+        let mut mounted = false;
+        ROOT.call_once(|| {
+            mounted = true;
+            Arc::new(Dentry {
+                name: String::new(),
+                inode: fs.root(),
+                mount: add_mount(fs.clone(), None),
+                parent: None,
+            })
+        });
+        return if mounted { Ok(()) } else { Err(Errno::EBUSY) };
+    }
+    if !path.starts_with('/') {
+        return Err(Errno::EINVAL);
+    }
+    let mount_point = lookup(&root()?, path, true)?;
+    mount_over(mount_point, fs)?;
+    Ok(())
+}
+
+/// Mounts a filesystem over a directory, and returns the new mount's root.
+fn mount_over(mount_point: Arc<Dentry>, fs: Arc<dyn FileSystem>) -> Result<Arc<Dentry>, Errno> {
+    if mount_point.inode.kind() != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    let Some(parent) = mount_point.parent.clone() else {
+        return Err(Errno::EBUSY);
+    };
+    let mount = add_mount(fs, Some(mount_point.clone()));
+    Ok(Arc::new(Dentry {
+        name: mount_point.name.clone(),
+        inode: mount.root.clone(),
+        mount,
+        parent: Some(parent),
+    }))
+}
+
+/// An inode, as reached by a path.
+///
+/// Dentries hold their parent directory, so a chain of dentries records the whole path
+/// that was walked to reach an inode, across any mount points on the way.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// The mount that the inode belongs to.
+    mount: Arc<Mount>,
+    /// This is `None` for the root.
+    parent: Option<Arc<Dentry>>,
+}
+
+impl Dentry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn mount(&self) -> &Arc<Mount> {
+        &self.mount
+    }
+
+    pub fn kind(&self) -> FileType {
+        self.inode.kind()
+    }
+
+    /// Information about the file, including the device.
+    pub fn stat(&self) -> Stat {
+        Stat {
+            device: self.mount.id,
+            ..self.inode.stat()
+        }
+    }
+
+    /// The directory containing this one. The root is its own parent.
+    pub fn parent(self: &Arc<Self>) -> Arc<Dentry> {
+        self.parent.clone().unwrap_or_else(|| self.clone())
+    }
+
+    /// The absolute path of this dentry.
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self;
+        while let Some(parent) = &dentry.parent {
+            names.push(dentry.name.as_str());
+            dentry = parent;
+        }
+        if names.is_empty() {
+            return "/".into();
+        }
+        names.iter().rev().flat_map(|name| ["/", name]).collect()
+    }
+
+    /// Whether this is the root of a filesystem that is mounted over a directory.
+    pub fn is_mount_root(&self) -> bool {
+        self.parent
+            .as_ref()
+            .is_some_and(|parent| !Arc::ptr_eq(&parent.mount, &self.mount))
+    }
+
+    /// Whether this and the other dentry refer to the same file.
+    pub fn same_file(&self, other: &Dentry) -> bool {
+        self.mount.id == other.mount.id && self.inode.stat().inode == other.inode.stat().inode
+    }
+
+    /// Looks up a name in this directory, moving into any filesystems mounted over the result.
+    pub fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Errno> {
+        let inode = self.inode.lookup(name)?;
+        let mut dentry = self.new_child(name, inode);
+        while let Some(mount) = dentry.covering_mount() {
+            dentry = Arc::new(Dentry {
+                name: name.into(),
+                inode: mount.root.clone(),
+                mount,
+                parent: Some(self.clone()),
+            });
+        }
+        Ok(dentry)
+    }
+
+    /// Makes a dentry for an inode that was found in this directory.
+    fn new_child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: name.into(),
+            inode,
+            mount: self.mount.clone(),
+            parent: Some(self.clone()),
+        })
+    }
+
+    /// The most recent mount over this directory, if any.
+    fn covering_mount(&self) -> Option<Arc<Mount>> {
+        if self.inode.kind() != FileType::Directory {
+            return None;
+        }
+        let key = (self.mount.id, self.inode.stat().inode);
+        MOUNTS
+            .lock()
+            .iter()
+            .rev()
+            .find(|mount| mount.covers == Some(key))
+            .cloned()
+    }
+}
+
+/// Opens the file at the given path.
+/// With [OpenFlags::CREATE], a regular file is created if there is nothing at the path.
+pub fn open(
+    at: &Arc<Dentry>,
+    path: &str,
+    flags: OpenFlags,
+    mode: u32,
+) -> Result<Arc<OpenFile>, Errno> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let dentry = if flags.contains(OpenFlags::CREATE) {
+        let (dir, name) = lookup_parent(at, path)?;
+        match dir.child(name) {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(Errno::EEXIST),
+            Ok(existing) if follow && existing.kind() == FileType::Symlink => {
+                lookup(at, path, true)?
+            }
+            Ok(existing) => existing,
+            Err(Errno::ENOENT) => {
+                let inode = dir.inode.create(name, FileType::Regular, mode)?;
+                dir.new_child(name, inode)
+            }
+            Err(errno) => return Err(errno),
+        }
+    } else {
+        lookup(at, path, follow)?
+    };
+    OpenFile::open(dentry, flags)
+}
+
+/// Reads the whole of the regular file at the given path.
+pub fn read(at: &Arc<Dentry>, path: &str) -> Result<Vec<u8>, Errno> {
+    let file = open(at, path, OpenFlags::empty(), 0)?;
+    if file.dentry().kind() != FileType::Regular {
+        return Err(Errno::EACCES);
+    }
+    let mut contents = vec![0; file.dentry().stat().size as usize];
+    let mut len = 0;
+    loop {
+        // Some files, such as those in `/proc`, are longer than their size says.
+        if len == contents.len() {
+            contents.resize(len + 4096, 0);
+        }
+        match file.read_at(len as u64, &mut contents[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    contents.truncate(len);
+    Ok(contents)
+}
+
+/// Returns information about the file at the given path.
+/// If `follow` is false and the path names a symbolic link, this describes the link itself.
+pub fn stat(at: &Arc<Dentry>, path: &str, follow: bool) -> Result<Stat, Errno> {
+    Ok(lookup(at, path, follow)?.stat())
+}
+
+/// Creates a directory.
+pub fn mkdir(at: &Arc<Dentry>, path: &str, mode: u32) -> Result<(), Errno> {
+    let (dir, name) = lookup_parent(at, path)?;
+    if dir.child(name).is_ok() {
+        return Err(Errno::EEXIST);
+    }
+    dir.inode.create(name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+/// Removes a name for a file that is not a directory.
+/// The file itself is removed once it has no other names and is no longer open.
+pub fn unlink(at: &Arc<Dentry>, path: &str) -> Result<(), Errno> {
+    let (dir, name) = lookup_parent(at, path)?;
+    let target = dir.child(name)?;
+    if target.is_mount_root() {
+        return Err(Errno::EBUSY);
+    }
+    if target.kind() == FileType::Directory {
+        return Err(Errno::EISDIR);
+    }
+    dir.inode.unlink(name)
+}
+
+/// Removes an empty directory.
+pub fn rmdir(at: &Arc<Dentry>, path: &str) -> Result<(), Errno> {
+    let (dir, name) = lookup_parent(at, path)?;
+    let target = dir.child(name)?;
+    if target.kind() != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    if target.is_mount_root() {
+        return Err(Errno::EBUSY);
+    }
+    dir.inode.rmdir(name)
+}
+
+/// Creates a symbolic link at `path` to `target`.
+/// The target is not checked, and is resolved relative to the link's directory when it is followed.
+pub fn symlink(at: &Arc<Dentry>, target: &str, path: &str) -> Result<(), Errno> {
+    if target.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let (dir, name) = lookup_parent(at, path)?;
+    if dir.child(name).is_ok() {
+        return Err(Errno::EEXIST);
+    }
+    dir.inode.symlink(name, target)?;
+    Ok(())
+}
+
+/// Returns the target of the symbolic link at the given path.
+pub fn read_link(at: &Arc<Dentry>, path: &str) -> Result<String, Errno> {
+    lookup(at, path, false)?.inode.read_link()
+}
+
+/// Adds a new name, `new_path`, for the file at `old_path`.
+pub fn link(at: &Arc<Dentry>, old_path: &str, new_path: &str) -> Result<(), Errno> {
+    let old = lookup(at, old_path, false)?;
+    if old.kind() == FileType::Directory {
+        return Err(Errno::EPERM);
+    }
+    let (dir, name) = lookup_parent(at, new_path)?;
+    if dir.mount.id != old.mount.id {
+        return Err(Errno::EXDEV);
+    }
+    if dir.child(name).is_ok() {
+        return Err(Errno::EEXIST);
+    }
+    dir.inode.link(name, &old.inode)
+}
+
+/// Moves the file at `old_path` to `new_path`, within one filesystem.
+pub fn rename(at: &Arc<Dentry>, old_path: &str, new_path: &str) -> Result<(), Errno> {
+    let (old_dir, old_name) = lookup_parent(at, old_path)?;
+    let (new_dir, new_name) = lookup_parent(at, new_path)?;
+    let source = old_dir.child(old_name)?;
+    if source.is_mount_root() {
+        return Err(Errno::EBUSY);
+    }
+    if old_dir.mount.id != new_dir.mount.id {
+        return Err(Errno::EXDEV);
+    }
+    if let Ok(existing) = new_dir.child(new_name) {
+        if existing.is_mount_root() {
+            return Err(Errno::EBUSY);
+        }
+        if existing.same_file(&source) {
+            return Ok(());
+        }
+    }
+    // A directory can't be moved into itself.
+    if source.kind() == FileType::Directory {
+        let mut ancestor = new_dir.clone();
+        loop {
+            if ancestor.same_file(&source) {
+                return Err(Errno::EINVAL);
+            }
+            match &ancestor.parent {
+                Some(parent) => ancestor = parent.clone(),
+                None => break,
+            }
+        }
+    }
+    old_dir.inode.rename(old_name, &new_dir.inode, new_name)
+}
+
+/// A small in-memory filesystem for testing the VFS.
+#[cfg(test)]
+pub(crate) mod test_fs {
+    use alloc::{collections::btree_map::BTreeMap, string::ToString};
+
+    use super::*;
+
+    pub struct Node {
+        number: u64,
+        kind: FileType,
+        children: Mutex<BTreeMap<String, Arc<Node>>>,
+        target: String,
+    }
+
+    impl Node {
+        fn new(kind: FileType, target: &str) -> Arc<Self> {
+            static NEXT: AtomicU64 = AtomicU64::new(1);
+            Arc::new(Self {
+                number: NEXT.fetch_add(1, Ordering::Relaxed),
+                kind,
+                children: Mutex::default(),
+                target: target.into(),
+            })
+        }
+
+        fn insert(&self, name: &str, node: Arc<Node>) -> Arc<dyn Inode> {
+            self.children.lock().insert(name.into(), node.clone());
+            node
+        }
+    }
+
+    impl Inode for Node {
+        fn kind(&self) -> FileType {
+            self.kind
+        }
+
+        fn stat(&self) -> Stat {
+            Stat::new(self.number, self.kind, 0o755, 0)
+        }
+
+        fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+            Ok(0)
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+            let node = self.children.lock().get(name).cloned();
+            node.map(|node| node as Arc<dyn Inode>).ok_or(Errno::ENOENT)
+        }
+
+        fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+            Ok(self
+                .children
+                .lock()
+                .iter()
+                .nth(index)
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    inode: node.number,
+                    kind: node.kind,
+                }))
+        }
+
+        fn create(&self, name: &str, kind: FileType, _mode: u32) -> Result<Arc<dyn Inode>, Errno> {
+            Ok(self.insert(name, Node::new(kind, "")))
+        }
+
+        fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Errno> {
+            Ok(self.insert(name, Node::new(FileType::Symlink, target)))
+        }
+
+        fn read_link(&self) -> Result<String, Errno> {
+            Ok(self.target.to_string())
+        }
+    }
+
+    pub struct TestFs(Arc<Node>);
+
+    impl TestFs {
+        pub fn new() -> Arc<Self> {
+            Arc::new(Self(Node::new(FileType::Directory, "")))
+        }
+    }
+
+    impl FileSystem for TestFs {
+        fn name(&self) -> &'static str {
+            "testfs"
+        }
+
+        fn root(&self) -> Arc<dyn Inode> {
+            self.0.clone()
+        }
+    }
+
+    /// A tree made from a filesystem that is not mounted anywhere, so tests don't depend on `/`.
+    pub fn detached_root(fs: Arc<dyn FileSystem>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: String::new(),
+            inode: fs.root(),
+            mount: new_mount(fs, None),
+            parent: None,
+        })
+    }
+
+    pub fn mount_over(mount_point: Arc<Dentry>, fs: Arc<dyn FileSystem>) -> Arc<Dentry> {
+        super::mount_over(mount_point, fs).unwrap()
+    }
+}
@@ -0,0 +1,406 @@
+//! A filesystem of device files, which is mounted at `/dev`.
+//!
+//! Drivers add their devices with [register], and each one appears as a file in the root directory.
+//! Opening a device file gives an ordinary [super::OpenFile], whose reads and writes are passed to
+//! the [Device]. The kernel's own devices are registered by [init]:
+//!
+//! * `console` writes to the screen and reads from the keyboard.
+//! * `ttyS0` writes to and reads from the first serial port.
+//! * `fb0` is the framebuffer, which can also be mapped into memory.
+//! * `null` throws away everything written to it, and is always at end of file.
+//! * `zero` reads as zeroes.
+//! * `random` reads as random bytes.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytemuck::{Pod, Zeroable};
+use spin::{Lazy, Mutex};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    errno::Errno,
+    file::{POLLIN, POLLOUT},
+    keyboard, memory, serial, syscall,
+    terminal_video::TerminalVideoBuffer,
+};
+
+/// Something that can be read from or written to through a file in `/dev`.
+/// Stream devices, such as the console, ignore the offset.
+pub trait Device: Send + Sync {
+    fn kind(&self) -> FileType {
+        FileType::CharDevice
+    }
+
+    /// The size to report for devices with random access. Stream devices are empty.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// See [Inode::device_memory].
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// See [Inode::ioctl].
+    fn ioctl(&self, _request: u64, _arg: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOTTY)
+    }
+
+    /// See [Inode::poll]. Devices whose reads can block must say when they would.
+    fn poll(&self) -> u16 {
+        POLLIN | POLLOUT
+    }
+}
+
+/// The inode number of the root directory. Devices are numbered from the next one up.
+const ROOT_INODE: u64 = 1;
+
+/// Every registered device, by name.
+static DEVICES: Mutex<BTreeMap<String, Arc<DeviceInode>>> = Mutex::new(BTreeMap::new());
+
+/// Adds a device to `/dev` with the given name.
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), Errno> {
+    static NEXT_INODE: AtomicU64 = AtomicU64::new(ROOT_INODE + 1);
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(Errno::EEXIST);
+    }
+    let inode = NEXT_INODE.fetch_add(1, Ordering::Relaxed);
+    devices.insert(name.into(), Arc::new(DeviceInode { inode, device }));
+    Ok(())
+}
+
+/// Registers the kernel's own devices.
+pub fn init() {
+    let devices: [(&str, Arc<dyn Device>); 5] = [
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+        ("random", Arc::new(Random)),
+        ("console", Arc::new(Console)),
+        ("ttyS0", Arc::new(Serial)),
+    ];
+    let framebuffer = FrameBuffer::from_terminal().map(|fb| ("fb0", Arc::new(fb) as _));
+    for (name, device) in devices.into_iter().chain(framebuffer) {
+        if let Err(errno) = register(name, device) {
+            crate::serial_println!("Could not register /dev/{}: {:?}", name, errno);
+        }
+    }
+}
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevDir)
+    }
+}
+
+struct DevDir;
+
+impl Inode for DevDir {
+    fn kind(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::new(ROOT_INODE, FileType::Directory, 0o755, 0)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let device = DEVICES.lock().get(name).cloned().ok_or(Errno::ENOENT)?;
+        Ok(device)
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .nth(index)
+            .map(|(name, device)| DirEntry {
+                name: name.clone(),
+                inode: device.inode,
+                kind: device.kind(),
+            }))
+    }
+}
+
+struct DeviceInode {
+    inode: u64,
+    device: Arc<dyn Device>,
+}
+
+impl Inode for DeviceInode {
+    fn kind(&self) -> FileType {
+        self.device.kind()
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::new(self.inode, self.device.kind(), 0o666, self.device.size())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.device.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        self.device.write_at(offset, buf)
+    }
+
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        self.device.device_memory()
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, Errno> {
+        self.device.ioctl(request, arg)
+    }
+
+    fn poll(&self) -> u16 {
+        self.device.poll()
+    }
+}
+
+struct Null;
+
+impl Device for Null {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+}
+
+struct Zero;
+
+impl Device for Zero {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+}
+
+/// Random bytes from the CPU's `RDRAND` instruction if it has one.
+/// Otherwise, they come from a pseudorandom generator seeded with the time stamp counter,
+/// which is fine for games but not for keys.
+struct Random;
+
+static HAS_RDRAND: Lazy<bool> = Lazy::new(|| core::arch::x86_64::__cpuid(1).ecx & (1 << 30) != 0);
+
+fn rdrand() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!(
+            "rdrand {value}",
+            "setc {success}",
+            value = out(reg) value,
+            success = out(reg_byte) success,
+            options(nomem, nostack),
+        );
+    }
+    (success != 0).then_some(value)
+}
+
+/// The state of an `xorshift64*` generator, which must never be zero.
+static XORSHIFT: Mutex<u64> = Mutex::new(0);
+
+fn xorshift() -> u64 {
+    let mut state = XORSHIFT.lock();
+    if *state == 0 {
+        *state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    }
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+impl Device for Random {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        for chunk in buf.chunks_mut(8) {
+            // `RDRAND` can fail if it is asked for too much too quickly, so retry a few times.
+            let value = (0..10)
+                .find_map(|_| HAS_RDRAND.then(rdrand).flatten())
+                .unwrap_or_else(xorshift);
+            chunk.copy_from_slice(&value.to_ne_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    /// Writing is allowed, but doesn't add anything to the randomness.
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+}
+
+struct Console;
+
+impl Device for Console {
+    /// Reads the bytes typed on the keyboard, exactly as they were typed.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        keyboard::INPUT.read(buf)
+    }
+
+    fn poll(&self) -> u16 {
+        keyboard::INPUT.poll()
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        TerminalVideoBuffer::try_with_default(|terminal| terminal.put_string(buf));
+        Ok(buf.len())
+    }
+}
+
+struct Serial;
+
+impl Device for Serial {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        serial::COM1_INPUT.read(buf)
+    }
+
+    fn poll(&self) -> u16 {
+        serial::COM1_INPUT.poll()
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let mut serial = serial::COM1_SERIAL.lock();
+        for &byte in buf {
+            serial.send(byte);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Asks the framebuffer for a [FrameBufferInfo].
+pub const FB_GET_INFO: u64 = 0x4600;
+
+/// The layout of the framebuffer. Each pixel is a little-endian `0xAARRGGBB` value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FrameBufferInfo {
+    /// The size of the screen in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The number of bytes from the start of one row to the start of the next.
+    pub pitch: u32,
+    pub bits_per_pixel: u32,
+}
+
+/// The pixels on the screen, in rows of `pitch` bytes.
+///
+/// This is the same memory that the kernel's terminal draws into,
+/// so anything drawn here can be drawn over by the terminal, and the other way around.
+struct FrameBuffer {
+    addr: VirtAddr,
+    phys: PhysAddr,
+    info: FrameBufferInfo,
+}
+
+impl FrameBuffer {
+    /// Finds the framebuffer that the default terminal draws into, if there is one.
+    fn from_terminal() -> Option<Self> {
+        let (addr, info) = TerminalVideoBuffer::try_with_default(|terminal| {
+            let video = terminal.video_buffer();
+            let info = FrameBufferInfo {
+                width: video.width() as u32,
+                height: video.height() as u32,
+                pitch: video.pitch() as u32,
+                bits_per_pixel: 32,
+            };
+            (VirtAddr::from_ptr(video.as_mut_ptr()), info)
+        })?;
+        let phys = memory::kernel_virt_to_phys(addr)?;
+        Some(Self { addr, phys, info })
+    }
+
+    fn len(&self) -> u64 {
+        self.info.pitch as u64 * self.info.height as u64
+    }
+
+    /// The part of the framebuffer that an access to `len` bytes at `offset` touches.
+    fn range(&self, offset: u64, len: usize) -> (*mut u8, usize) {
+        let offset = offset.min(self.len());
+        let len = len.min((self.len() - offset) as usize);
+        ((self.addr + offset).as_mut_ptr(), len)
+    }
+}
+
+impl Device for FrameBuffer {
+    fn size(&self) -> u64 {
+        self.len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let (pixels, len) = self.range(offset, buf.len());
+        unsafe { core::ptr::copy_nonoverlapping(pixels, buf.as_mut_ptr(), len) };
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let (pixels, len) = self.range(offset, buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(Errno::ENOSPC);
+        }
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), pixels, len) };
+        Ok(len)
+    }
+
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        Ok((self.phys, self.len()))
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, Errno> {
+        match request {
+            FB_GET_INFO => syscall::write_user(arg, &self.info).map(|()| 0),
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+}
+
+#[test_case]
+fn test_devfs() {
+    use super::test_fs::detached_root;
+    use super::{open, OpenFlags};
+    use crate::file::File;
+
+    let root = detached_root(Arc::new(DevFs));
+    let null = open(&root, "null", OpenFlags::READ_WRITE, 0).unwrap();
+    assert_eq!(null.write(b"gone"), Ok(4));
+    assert_eq!(null.read(&mut [0; 4]), Ok(0));
+    assert_eq!(null.stat().unwrap().kind, FileType::CharDevice as u32);
+
+    let mut buf = [1; 16];
+    let zero = open(&root, "zero", OpenFlags::empty(), 0).unwrap();
+    assert_eq!(zero.read(&mut buf), Ok(16));
+    assert_eq!(buf, [0; 16]);
+    let random = open(&root, "random", OpenFlags::empty(), 0).unwrap();
+    assert_eq!(random.read(&mut buf[..13]), Ok(13));
+    assert_ne!(buf[..13], [0; 13]);
+    assert_eq!(random.ioctl(FB_GET_INFO, 0), Err(Errno::ENOTTY));
+    assert_eq!(
+        open(&root, "missing", OpenFlags::empty(), 0).err(),
+        Some(Errno::ENOENT)
+    );
+}
@@ -0,0 +1,1560 @@
+//! The second extended filesystem, the traditional filesystem for a Unix-like root.
+//!
+//! The disk is split into block groups. Each group has a bitmap of its free blocks, a bitmap of
+//! its free inodes and a table of inodes, and the group descriptors after the superblock say where
+//! these are. An inode holds the numbers of a file's first twelve blocks, followed by a singly,
+//! a doubly and a triply indirect block, which hold the numbers of the blocks after that.
+//! Directories are files made of variable-length entries, each holding an inode number and a name.
+//!
+//! Changes are written straight to the disk. Filesystems with features that change the layout
+//! of the disk, such as extents, can't be mounted. Filesystems with unknown features that only
+//! matter when writing are mounted read-only. Directory indexes are not kept up to date, so a
+//! directory that is changed stops being marked as indexed, and is read as a plain list afterwards.
+//!
+//! A file that is removed while it is open keeps its blocks until it is closed.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use bytemuck::{Pod, Zeroable};
+use spin::{Mutex, MutexGuard};
+
+use super::{now, DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+};
+
+/// Where the superblock is, in bytes from the start of the device.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+
+/// The start of the superblock, which is all that we use of it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    reserved_blocks_count: u32,
+    free_blocks_count: u32,
+    free_inodes_count: u32,
+    first_data_block: u32,
+    /// The block size is 1024 shifted left by this.
+    log_block_size: u32,
+    log_fragment_size: u32,
+    blocks_per_group: u32,
+    fragments_per_group: u32,
+    inodes_per_group: u32,
+    mount_time: u32,
+    write_time: u32,
+    mount_count: u16,
+    max_mount_count: u16,
+    magic: u16,
+    state: u16,
+    errors: u16,
+    minor_revision: u16,
+    last_check: u32,
+    check_interval: u32,
+    creator_os: u32,
+    revision: u32,
+    default_reserved_uid: u16,
+    default_reserved_gid: u16,
+    // The rest is only there from revision 1 onwards.
+    first_inode: u32,
+    inode_size: u16,
+    block_group: u16,
+    compatible_features: u32,
+    incompatible_features: u32,
+    read_only_compatible_features: u32,
+}
+
+/// Directory entries record the type of file, so listing a directory doesn't need its inodes.
+const INCOMPATIBLE_FILETYPE: u32 = 0x0002;
+/// Only some groups have backup copies of the superblock.
+const READ_ONLY_SPARSE_SUPER: u32 = 0x0001;
+/// Files can be larger than 4 GiB.
+const READ_ONLY_LARGE_FILE: u32 = 0x0002;
+
+/// The root directory's inode number.
+const ROOT_INODE: u32 = 2;
+/// The first inode number that isn't reserved, before revision 1.
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct GroupDescriptor {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks_count: u16,
+    free_inodes_count: u16,
+    used_dirs_count: u16,
+    pad: u16,
+    reserved: [u8; 12],
+}
+
+/// The number of block numbers in an inode.
+const INODE_BLOCKS: usize = 15;
+/// The number of blocks that an inode points to directly, before the indirect blocks.
+const DIRECT_BLOCKS: usize = 12;
+
+/// The start of an inode, which is all that we use of it. Inodes may be bigger on disk.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct RawInode {
+    mode: u16,
+    uid: u16,
+    size: u32,
+    accessed: u32,
+    changed: u32,
+    modified: u32,
+    deleted: u32,
+    gid: u16,
+    links: u16,
+    /// The number of 512-byte sectors used, including indirect blocks.
+    sectors: u32,
+    flags: u32,
+    os_specific: u32,
+    block: [u32; INODE_BLOCKS],
+    generation: u32,
+    file_acl: u32,
+    /// The top half of the size of a regular file.
+    size_high: u32,
+    fragment_address: u32,
+    os_specific_2: [u8; 12],
+}
+
+/// Set on directories that have a hash index.
+const INDEX_FLAG: u32 = 0x1000;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFIFO: u16 = 0x1000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+const S_IFSOCK: u16 = 0xc000;
+
+/// Symbolic links shorter than this are kept in the inode's block numbers, rather than in a block.
+const FAST_SYMLINK_MAX: usize = INODE_BLOCKS * 4;
+
+impl RawInode {
+    fn kind(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFIFO => FileType::Fifo,
+            S_IFCHR => FileType::CharDevice,
+            S_IFDIR => FileType::Directory,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFLNK => FileType::Symlink,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Regular,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self.kind() {
+            FileType::Regular => ((self.size_high as u64) << 32) | self.size as u64,
+            _ => self.size as u64,
+        }
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.size = size as u32;
+        if self.kind() == FileType::Regular {
+            self.size_high = (size >> 32) as u32;
+        }
+    }
+
+    /// Whether this is a symbolic link whose target is kept in the inode.
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = if self.file_acl != 0 {
+            block_size / 512
+        } else {
+            0
+        };
+        self.kind() == FileType::Symlink && self.sectors as u64 == acl_sectors
+    }
+
+    /// Records a change to the inode, and to the contents too if `modified` is set.
+    fn touch(&mut self, modified: bool) {
+        self.changed = seconds();
+        if modified {
+            self.modified = self.changed;
+        }
+    }
+}
+
+/// The time to store in timestamps, in seconds.
+/// There is no real-time clock yet, so this counts from boot, like [now].
+fn seconds() -> u32 {
+    (now() / 1_000_000_000) as u32
+}
+
+/// The file type in a directory entry, for filesystems with [INCOMPATIBLE_FILETYPE].
+fn dirent_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::Regular => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Fifo => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+    }
+}
+
+/// The header of a directory entry, which is followed by the name.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct RawDirEntry {
+    /// Zero if the entry is unused.
+    inode: u32,
+    /// The distance to the next entry.
+    record_len: u16,
+    name_len: u8,
+    file_type: u8,
+}
+
+const DIR_ENTRY_HEADER: usize = size_of::<RawDirEntry>();
+const MAX_NAME: usize = 255;
+
+/// The space that a directory entry with a name of the given length needs.
+fn record_len(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+/// Reads the header of the directory entry at an offset in a block of a directory,
+/// checking that the entry fits in the block and has room for its name.
+fn read_dir_entry(contents: &[u8], offset: usize) -> Result<RawDirEntry, Errno> {
+    let bytes = contents
+        .get(offset..offset + DIR_ENTRY_HEADER)
+        .ok_or(Errno::EIO)?;
+    let header: RawDirEntry = bytemuck::pod_read_unaligned(bytes);
+    let len = header.record_len as usize;
+    if !len.is_multiple_of(4)
+        || len < record_len(header.name_len as usize)
+        || offset + len > contents.len()
+    {
+        return Err(Errno::EIO);
+    }
+    Ok(header)
+}
+
+/// An entry in a directory, and where it is.
+struct Found {
+    inode: u32,
+    name: String,
+    file_type: u8,
+    /// The index of the directory's block that it is in.
+    block: u64,
+    /// Where it is in that block.
+    offset: usize,
+}
+
+/// The layout of a filesystem, and the state shared by its inodes.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    inode_size: u64,
+    inodes_per_group: u32,
+    blocks_per_group: u32,
+    first_data_block: u32,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_inode: u32,
+    /// Whether directory entries record the file type.
+    filetype: bool,
+    read_only: bool,
+    state: Mutex<State>,
+    /// Inodes with no links that were dropped while the state was locked, which still need freeing.
+    orphans: Mutex<Vec<u32>>,
+}
+
+struct State {
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+    /// The inodes in use.
+    inodes: BTreeMap<u32, Weak<Ext2Inode>>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Errno> {
+        let mut superblock = Superblock::zeroed();
+        block::read_bytes(
+            &*device,
+            SUPERBLOCK_OFFSET,
+            bytemuck::bytes_of_mut(&mut superblock),
+        )?;
+        if superblock.magic != MAGIC
+            || superblock.log_block_size > 6
+            || superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+        {
+            return Err(Errno::EINVAL);
+        }
+        let (first_inode, inode_size) = match superblock.revision {
+            0 => (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE),
+            _ => (superblock.first_inode, superblock.inode_size),
+        };
+        if superblock.incompatible_features & !INCOMPATIBLE_FILETYPE != 0
+            || (inode_size as usize) < size_of::<RawInode>()
+            || !inode_size.is_power_of_two()
+        {
+            return Err(Errno::EINVAL);
+        }
+        let block_size = 1024 << superblock.log_block_size;
+        let read_only = superblock.read_only_compatible_features
+            & !(READ_ONLY_SPARSE_SUPER | READ_ONLY_LARGE_FILE)
+            != 0;
+        let device_size = device.sector_count() * device.sector_size() as u64;
+        // Each group's bitmaps take up a single block.
+        let bits_per_block = block_size * 8;
+        if superblock.blocks_count as u64 * block_size > device_size
+            || superblock.first_data_block >= superblock.blocks_count
+            || superblock.blocks_per_group as u64 > bits_per_block
+            || superblock.inodes_per_group as u64 > bits_per_block
+        {
+            return Err(Errno::EINVAL);
+        }
+
+        let group_count = (superblock.blocks_count - superblock.first_data_block)
+            .div_ceil(superblock.blocks_per_group);
+        if superblock.inodes_count as u64 > group_count as u64 * superblock.inodes_per_group as u64
+        {
+            return Err(Errno::EINVAL);
+        }
+        let mut groups = vec![GroupDescriptor::zeroed(); group_count as usize];
+        // The group descriptors are in the block after the superblock.
+        let table = (superblock.first_data_block as u64 + 1) * block_size;
+        block::read_bytes(&*device, table, bytemuck::cast_slice_mut(&mut groups))?;
+
+        Ok(Self {
+            device,
+            block_size,
+            inode_size: inode_size as u64,
+            inodes_per_group: superblock.inodes_per_group,
+            blocks_per_group: superblock.blocks_per_group,
+            first_data_block: superblock.first_data_block,
+            blocks_count: superblock.blocks_count,
+            inodes_count: superblock.inodes_count,
+            first_inode,
+            filetype: superblock.incompatible_features & INCOMPATIBLE_FILETYPE != 0,
+            read_only,
+            state: Mutex::new(State {
+                superblock,
+                groups,
+                inodes: BTreeMap::new(),
+            }),
+            orphans: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Locks the filesystem, first freeing any inodes that were left to be freed.
+    fn lock(&self) -> Result<MutexGuard<'_, State>, Errno> {
+        let mut state = self.state.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for number in orphans {
+            self.release(&mut state, number)?;
+        }
+        Ok(state)
+    }
+
+    /// Locks the filesystem in order to change it.
+    fn lock_for_writing(&self) -> Result<MutexGuard<'_, State>, Errno> {
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        self.lock()
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), Errno> {
+        block::read_bytes(&*self.device, block as u64 * self.block_size, buf)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), Errno> {
+        block::write_bytes(&*self.device, block as u64 * self.block_size, buf)
+    }
+
+    /// The number of block numbers that fit in a block.
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    fn read_pointer(&self, block: u32, index: u64) -> Result<u32, Errno> {
+        let mut bytes = [0; 4];
+        let offset = block as u64 * self.block_size + index * 4;
+        block::read_bytes(&*self.device, offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_pointer(&self, block: u32, index: u64, value: u32) -> Result<(), Errno> {
+        let offset = block as u64 * self.block_size + index * 4;
+        block::write_bytes(&*self.device, offset, &value.to_le_bytes())
+    }
+
+    fn inode_offset(&self, state: &State, number: u32) -> Result<u64, Errno> {
+        if number == 0 || number > self.inodes_count {
+            return Err(Errno::EIO);
+        }
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        let table = state.groups[group as usize].inode_table as u64;
+        Ok(table * self.block_size + index as u64 * self.inode_size)
+    }
+
+    fn read_inode(&self, state: &State, number: u32) -> Result<RawInode, Errno> {
+        let mut raw = RawInode::zeroed();
+        let offset = self.inode_offset(state, number)?;
+        block::read_bytes(&*self.device, offset, bytemuck::bytes_of_mut(&mut raw))?;
+        Ok(raw)
+    }
+
+    fn write_inode(&self, state: &State, number: u32, raw: &RawInode) -> Result<(), Errno> {
+        let offset = self.inode_offset(state, number)?;
+        block::write_bytes(&*self.device, offset, bytemuck::bytes_of(raw))
+    }
+
+    fn write_superblock(&self, state: &State) -> Result<(), Errno> {
+        block::write_bytes(
+            &*self.device,
+            SUPERBLOCK_OFFSET,
+            bytemuck::bytes_of(&state.superblock),
+        )
+    }
+
+    /// Marks the filesystem as having files larger than 4 GiB, which older drivers can't write.
+    fn allow_large_files(&self, state: &mut State) -> Result<(), Errno> {
+        let features = &mut state.superblock.read_only_compatible_features;
+        if *features & READ_ONLY_LARGE_FILE != 0 {
+            return Ok(());
+        }
+        if state.superblock.revision == 0 {
+            return Err(Errno::EFBIG);
+        }
+        *features |= READ_ONLY_LARGE_FILE;
+        self.write_superblock(state)
+    }
+
+    fn write_group(&self, state: &State, group: usize) -> Result<(), Errno> {
+        let offset = (self.first_data_block as u64 + 1) * self.block_size
+            + (group * size_of::<GroupDescriptor>()) as u64;
+        block::write_bytes(
+            &*self.device,
+            offset,
+            bytemuck::bytes_of(&state.groups[group]),
+        )
+    }
+
+    /// Finds a clear bit in a bitmap block, among the first `count`, and sets it.
+    fn take_bit(&self, bitmap: u32, count: u32) -> Result<Option<u32>, Errno> {
+        let mut bits = vec![0; self.block_size as usize];
+        self.read_block(bitmap, &mut bits)?;
+        let Some(bit) = (0..count).find(|&bit| bits[bit as usize / 8] & (1 << (bit % 8)) == 0)
+        else {
+            return Ok(None);
+        };
+        bits[bit as usize / 8] |= 1 << (bit % 8);
+        let offset = bitmap as u64 * self.block_size + bit as u64 / 8;
+        block::write_bytes(&*self.device, offset, &bits[bit as usize / 8..][..1])?;
+        Ok(Some(bit))
+    }
+
+    /// Clears a bit in a bitmap block, and returns [Errno::EIO] if it was already clear.
+    fn clear_bit(&self, bitmap: u32, bit: u32) -> Result<(), Errno> {
+        let offset = bitmap as u64 * self.block_size + bit as u64 / 8;
+        let mut byte = [0];
+        block::read_bytes(&*self.device, offset, &mut byte)?;
+        if byte[0] & (1 << (bit % 8)) == 0 {
+            return Err(Errno::EIO);
+        }
+        byte[0] &= !(1 << (bit % 8));
+        block::write_bytes(&*self.device, offset, &byte)
+    }
+
+    /// The number of blocks in a group, which is smaller for the last group.
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let start = self.first_data_block + group as u32 * self.blocks_per_group;
+        self.blocks_per_group.min(self.blocks_count - start)
+    }
+
+    /// The groups to look in for free space, starting with the preferred one.
+    fn groups_from(state: &State, preferred: usize) -> impl Iterator<Item = usize> {
+        let count = state.groups.len();
+        (0..count).map(move |offset| (preferred + offset) % count)
+    }
+
+    /// Allocates a block, preferably in the given group. Its contents are left as they were.
+    fn allocate_block(&self, state: &mut State, preferred: usize) -> Result<u32, Errno> {
+        for group in Self::groups_from(state, preferred) {
+            if state.groups[group].free_blocks_count == 0 {
+                continue;
+            }
+            let bitmap = state.groups[group].block_bitmap;
+            if let Some(bit) = self.take_bit(bitmap, self.blocks_in_group(group))? {
+                state.groups[group].free_blocks_count -= 1;
+                state.superblock.free_blocks_count =
+                    state.superblock.free_blocks_count.saturating_sub(1);
+                self.write_group(state, group)?;
+                self.write_superblock(state)?;
+                return Ok(self.first_data_block + group as u32 * self.blocks_per_group + bit);
+            }
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<(), Errno> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(Errno::EIO);
+        }
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        self.clear_bit(state.groups[group].block_bitmap, bit)?;
+        state.groups[group].free_blocks_count += 1;
+        state.superblock.free_blocks_count += 1;
+        self.write_group(state, group)?;
+        self.write_superblock(state)
+    }
+
+    /// Allocates an inode number, preferably in the given group.
+    fn allocate_inode(
+        &self,
+        state: &mut State,
+        preferred: usize,
+        directory: bool,
+    ) -> Result<u32, Errno> {
+        for group in Self::groups_from(state, preferred) {
+            if state.groups[group].free_inodes_count == 0 {
+                continue;
+            }
+            let bitmap = state.groups[group].inode_bitmap;
+            if let Some(bit) = self.take_bit(bitmap, self.inodes_per_group)? {
+                let number = group as u32 * self.inodes_per_group + bit + 1;
+                if number < self.first_inode {
+                    // The reserved inodes should already be marked as used.
+                    return Err(Errno::EIO);
+                }
+                state.groups[group].free_inodes_count -= 1;
+                if directory {
+                    state.groups[group].used_dirs_count += 1;
+                }
+                state.superblock.free_inodes_count =
+                    state.superblock.free_inodes_count.saturating_sub(1);
+                self.write_group(state, group)?;
+                self.write_superblock(state)?;
+                return Ok(number);
+            }
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    fn free_inode(&self, state: &mut State, number: u32, directory: bool) -> Result<(), Errno> {
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        self.clear_bit(
+            state.groups[group].inode_bitmap,
+            (number - 1) % self.inodes_per_group,
+        )?;
+        state.groups[group].free_inodes_count += 1;
+        if directory {
+            state.groups[group].used_dirs_count =
+                state.groups[group].used_dirs_count.saturating_sub(1);
+        }
+        state.superblock.free_inodes_count += 1;
+        self.write_group(state, group)?;
+        self.write_superblock(state)
+    }
+
+    fn group_of(&self, number: u32) -> usize {
+        ((number - 1) / self.inodes_per_group) as usize
+    }
+
+    /// Frees an inode that has no links left, along with its blocks.
+    fn release(&self, state: &mut State, number: u32) -> Result<(), Errno> {
+        let mut raw = self.read_inode(state, number)?;
+        if raw.links != 0 || raw.deleted != 0 {
+            return Ok(());
+        }
+        if !raw.is_fast_symlink(self.block_size) {
+            self.free_blocks_from(state, &mut raw, 0)?;
+        }
+        if raw.file_acl != 0 {
+            // Extended attribute blocks can be shared, so they are left for fsck to clean up.
+            raw.file_acl = 0;
+        }
+        // Until there is a real clock, the time could be small enough to look like an inode number
+        // in the list of orphaned inodes that ext3 keeps, so it is kept above them.
+        raw.deleted = seconds().max(self.inodes_count + 1);
+        raw.set_size(0);
+        self.write_inode(state, number, &raw)?;
+        self.free_inode(state, number, raw.kind() == FileType::Directory)
+    }
+
+    /// Where a block of a file is found: the slot in the inode, and then the index in each
+    /// level of indirect block.
+    fn block_path(&self, index: u64) -> Result<(usize, Vec<u64>), Errno> {
+        let per = self.pointers_per_block();
+        let mut index = index;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, Vec::new()));
+        }
+        index -= DIRECT_BLOCKS as u64;
+        if index < per {
+            return Ok((DIRECT_BLOCKS, vec![index]));
+        }
+        index -= per;
+        if index < per * per {
+            return Ok((DIRECT_BLOCKS + 1, vec![index / per, index % per]));
+        }
+        index -= per * per;
+        if index < per * per * per {
+            return Ok((
+                DIRECT_BLOCKS + 2,
+                vec![index / (per * per), index / per % per, index % per],
+            ));
+        }
+        Err(Errno::EFBIG)
+    }
+
+    /// The block that holds the given block of a file, or zero if there is a hole there.
+    fn block_at(&self, raw: &RawInode, index: u64) -> Result<u32, Errno> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = raw.block[slot];
+        for &index in &path {
+            if block == 0 {
+                break;
+            }
+            block = self.read_pointer(block, index)?;
+        }
+        Ok(block)
+    }
+
+    /// Returns the block that holds the given block of a file, allocating it and any indirect
+    /// blocks on the way if needed. Also returns whether the data block is new.
+    fn block_at_or_allocate(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        group: usize,
+        index: u64,
+    ) -> Result<(u32, bool), Errno> {
+        let (slot, path) = self.block_path(index)?;
+        let sectors = (self.block_size / 512) as u32;
+        let zeroes = vec![0; self.block_size as usize];
+        let mut new = false;
+        if raw.block[slot] == 0 {
+            raw.block[slot] = self.allocate_block(state, group)?;
+            raw.sectors += sectors;
+            new = true;
+            if !path.is_empty() {
+                self.write_block(raw.block[slot], &zeroes)?;
+            }
+        }
+        let mut block = raw.block[slot];
+        for (level, &index) in path.iter().enumerate() {
+            let next = self.read_pointer(block, index)?;
+            if next != 0 {
+                block = next;
+                new = false;
+                continue;
+            }
+            let allocated = self.allocate_block(state, group)?;
+            raw.sectors += sectors;
+            if level + 1 < path.len() {
+                self.write_block(allocated, &zeroes)?;
+            }
+            self.write_pointer(block, index, allocated)?;
+            block = allocated;
+            new = true;
+        }
+        Ok((block, new))
+    }
+
+    /// Frees every block of a file from the given index onwards, along with any indirect blocks
+    /// that are no longer needed.
+    fn free_blocks_from(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        first: u64,
+    ) -> Result<(), Errno> {
+        let per = self.pointers_per_block();
+        let mut freed = 0;
+        // The first index that each slot in the inode covers, and how deep its tree is.
+        let mut start = 0;
+        for slot in 0..INODE_BLOCKS {
+            let level = slot.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let span = per.pow(level);
+            if raw.block[slot] != 0
+                && start + span > first
+                && self.free_tree(
+                    state,
+                    raw.block[slot],
+                    level,
+                    first.saturating_sub(start),
+                    &mut freed,
+                )?
+            {
+                raw.block[slot] = 0;
+            }
+            start += span;
+        }
+        // A corrupted inode can claim fewer sectors than it has, so this stops at zero.
+        raw.sectors = raw
+            .sectors
+            .saturating_sub(freed * (self.block_size / 512) as u32);
+        Ok(())
+    }
+
+    /// Frees the blocks of a tree of indirect blocks from the given index within it onwards,
+    /// and returns whether the whole tree was freed.
+    fn free_tree(
+        &self,
+        state: &mut State,
+        block: u32,
+        level: u32,
+        first: u64,
+        freed: &mut u32,
+    ) -> Result<bool, Errno> {
+        if level == 0 {
+            if first > 0 {
+                return Ok(false);
+            }
+            self.free_block(state, block)?;
+            *freed += 1;
+            return Ok(true);
+        }
+        let per = self.pointers_per_block();
+        let span = per.pow(level - 1);
+        let mut pointers = vec![0u32; per as usize];
+        self.read_block(block, bytemuck::cast_slice_mut(&mut pointers))?;
+        let mut changed = false;
+        for (index, pointer) in pointers.iter_mut().enumerate() {
+            let start = index as u64 * span;
+            if *pointer == 0 || start + span <= first {
+                continue;
+            }
+            if self.free_tree(
+                state,
+                *pointer,
+                level - 1,
+                first.saturating_sub(start),
+                freed,
+            )? {
+                *pointer = 0;
+                changed = true;
+            }
+        }
+        if pointers.iter().all(|&pointer| pointer == 0) {
+            self.free_block(state, block)?;
+            *freed += 1;
+            return Ok(true);
+        }
+        if changed {
+            self.write_block(block, bytemuck::cast_slice(&pointers))?;
+        }
+        Ok(false)
+    }
+
+    /// Returns the inode with the given number, reading it if it isn't in use.
+    fn inode(self: &Arc<Self>, state: &mut State, number: u32) -> Result<Arc<Ext2Inode>, Errno> {
+        if let Some(inode) = state.inodes.get(&number).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let raw = self.read_inode(state, number)?;
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(Ext2Inode {
+            volume: self.clone(),
+            number,
+            raw: Mutex::new(raw),
+        });
+        state.inodes.insert(number, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+}
+
+/// An ext2 filesystem on a block device.
+pub struct Ext2Fs {
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and group descriptors,
+    /// and returns [Errno::EINVAL] if they don't describe an ext2 filesystem that we can use.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, Errno> {
+        let volume = Arc::new(Volume::new(device)?);
+        let root = volume.inode(&mut *volume.lock()?, ROOT_INODE)?;
+        if root.raw.lock().kind() != FileType::Directory {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Arc::new(Self { root }))
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    raw: Mutex<RawInode>,
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let raw = self.raw.get_mut();
+        if raw.links != 0 || raw.deleted != 0 {
+            return;
+        }
+        // The inode was removed while it was in use, and can be freed now.
+        // If the filesystem is locked, this was dropped by one of its own operations,
+        // so it is left for the next one.
+        match self.volume.state.try_lock() {
+            Some(mut state) => {
+                if let Err(errno) = self.volume.release(&mut state, self.number) {
+                    crate::serial_println!(
+                        "Could not free ext2 inode {}: {:?}",
+                        self.number,
+                        errno
+                    );
+                }
+            }
+            None => self.volume.orphans.lock().push(self.number),
+        }
+    }
+}
+
+impl Ext2Inode {
+    fn store(&self, state: &State, raw: &RawInode) -> Result<(), Errno> {
+        self.volume.write_inode(state, self.number, raw)
+    }
+
+    fn group(&self) -> usize {
+        self.volume.group_of(self.number)
+    }
+
+    fn read_data(&self, raw: &RawInode, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let volume = &self.volume;
+        let len = buf.len().min(raw.size().saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % volume.block_size;
+            let chunk = (len - done).min((volume.block_size - within) as usize);
+            let part = &mut buf[done..done + chunk];
+            match volume.block_at(raw, position / volume.block_size)? {
+                0 => part.fill(0),
+                block => block::read_bytes(
+                    &*volume.device,
+                    block as u64 * volume.block_size + within,
+                    part,
+                )?,
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Writes to the blocks of a file, allocating them as needed, without changing its size.
+    fn write_data(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), Errno> {
+        let volume = self.volume.clone();
+        let block_size = volume.block_size as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % volume.block_size) as usize;
+            let chunk = (buf.len() - done).min(block_size - within);
+            let index = position / volume.block_size;
+            let (block, new) = volume.block_at_or_allocate(state, raw, self.group(), index)?;
+            if new && chunk < block_size {
+                // The rest of a new block must read as zeroes.
+                let mut contents = vec![0; block_size];
+                contents[within..within + chunk].copy_from_slice(&buf[done..done + chunk]);
+                volume.write_block(block, &contents)?;
+            } else {
+                block::write_bytes(
+                    &*volume.device,
+                    block as u64 * volume.block_size + within as u64,
+                    &buf[done..done + chunk],
+                )?;
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Sets the size of a file, freeing any blocks after the new end.
+    fn resize(&self, state: &mut State, raw: &mut RawInode, size: u64) -> Result<(), Errno> {
+        let volume = self.volume.clone();
+        let old_size = raw.size();
+        if size < old_size {
+            volume.free_blocks_from(state, raw, size.div_ceil(volume.block_size))?;
+            // The rest of the last block must be zeroes, in case the file grows again.
+            let within = size % volume.block_size;
+            if within != 0 {
+                let block = volume.block_at(raw, size / volume.block_size)?;
+                if block != 0 {
+                    let zeroes = vec![0; (volume.block_size - within) as usize];
+                    block::write_bytes(
+                        &*volume.device,
+                        block as u64 * volume.block_size + within,
+                        &zeroes,
+                    )?;
+                }
+            }
+        }
+        raw.set_size(size);
+        Ok(())
+    }
+
+    /// The entries in a directory, including `.` and `..`.
+    fn entries(&self, raw: &RawInode) -> Result<Vec<Found>, Errno> {
+        let volume = &self.volume;
+        let block_size = volume.block_size as usize;
+        let mut found = Vec::new();
+        let mut contents = vec![0; block_size];
+        for index in 0..raw.size().div_ceil(volume.block_size) {
+            match volume.block_at(raw, index)? {
+                0 => continue,
+                block => volume.read_block(block, &mut contents)?,
+            }
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= block_size {
+                let header = read_dir_entry(&contents, offset)?;
+                if header.inode != 0 {
+                    let name = &contents[offset + DIR_ENTRY_HEADER..][..header.name_len as usize];
+                    found.push(Found {
+                        inode: header.inode,
+                        name: String::from_utf8_lossy(name).into(),
+                        file_type: header.file_type,
+                        block: index,
+                        offset,
+                    });
+                }
+                offset += header.record_len as usize;
+            }
+        }
+        Ok(found)
+    }
+
+    fn find(&self, raw: &RawInode, name: &str) -> Result<Found, Errno> {
+        self.entries(raw)?
+            .into_iter()
+            .find(|found| found.name == name)
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn read_entry_block(&self, raw: &RawInode, index: u64) -> Result<(u32, Vec<u8>), Errno> {
+        let block = self.volume.block_at(raw, index)?;
+        let mut contents = vec![0; self.volume.block_size as usize];
+        self.volume.read_block(block, &mut contents)?;
+        Ok((block, contents))
+    }
+
+    /// Adds an entry to a directory, using spare space at the end of an entry if there is some,
+    /// or otherwise a new block at the end.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        name: &str,
+        inode: u32,
+        kind: FileType,
+    ) -> Result<(), Errno> {
+        if name.len() > MAX_NAME {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        let block_size = self.volume.block_size as usize;
+        let needed = record_len(name.len());
+        let file_type = if self.volume.filetype {
+            dirent_type(kind)
+        } else {
+            0
+        };
+        let write = |contents: &mut [u8], offset: usize, record_len: usize| {
+            let header = RawDirEntry {
+                inode,
+                record_len: record_len as u16,
+                name_len: name.len() as u8,
+                file_type,
+            };
+            contents[offset..offset + DIR_ENTRY_HEADER]
+                .copy_from_slice(bytemuck::bytes_of(&header));
+            contents[offset + DIR_ENTRY_HEADER..][..name.len()].copy_from_slice(name.as_bytes());
+        };
+        // Changing the directory would leave any hash index out of date.
+        raw.flags &= !INDEX_FLAG;
+        raw.touch(true);
+
+        let blocks = raw.size().div_ceil(self.volume.block_size);
+        for index in 0..blocks {
+            if self.volume.block_at(raw, index)? == 0 {
+                continue;
+            }
+            let (block, mut contents) = self.read_entry_block(raw, index)?;
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= block_size {
+                let header = read_dir_entry(&contents, offset)?;
+                let len = header.record_len as usize;
+                let used = match header.inode {
+                    0 => 0,
+                    _ => record_len(header.name_len as usize),
+                };
+                if len - used >= needed {
+                    if used > 0 {
+                        let mut shortened = header;
+                        shortened.record_len = used as u16;
+                        contents[offset..offset + DIR_ENTRY_HEADER]
+                            .copy_from_slice(bytemuck::bytes_of(&shortened));
+                    }
+                    write(&mut contents, offset + used, len - used);
+                    return self.volume.write_block(block, &contents);
+                }
+                offset += len;
+            }
+        }
+
+        let mut contents = vec![0; block_size];
+        write(&mut contents, 0, block_size);
+        self.write_data(state, raw, blocks * self.volume.block_size, &contents)?;
+        raw.set_size((blocks + 1) * self.volume.block_size);
+        Ok(())
+    }
+
+    /// Removes an entry from a directory, by joining it onto the entry before it.
+    fn remove_entry(&self, raw: &mut RawInode, found: &Found) -> Result<(), Errno> {
+        let (block, mut contents) = self.read_entry_block(raw, found.block)?;
+        let removed = read_dir_entry(&contents, found.offset)?;
+        let mut offset = 0;
+        let mut previous = None;
+        while offset < found.offset {
+            previous = Some(offset);
+            offset += read_dir_entry(&contents, offset)?.record_len as usize;
+        }
+        match previous {
+            Some(previous) => {
+                let mut joined = read_dir_entry(&contents, previous)?;
+                joined.record_len += removed.record_len;
+                contents[previous..previous + DIR_ENTRY_HEADER]
+                    .copy_from_slice(bytemuck::bytes_of(&joined));
+            }
+            // The first entry in a block can't be joined onto anything, so it is marked unused.
+            None => contents[found.offset..found.offset + 4].fill(0),
+        }
+        raw.flags &= !INDEX_FLAG;
+        raw.touch(true);
+        self.volume.write_block(block, &contents)
+    }
+
+    /// Points the `..` entry of this directory at a new parent.
+    fn set_parent(&self, raw: &RawInode, parent: u32) -> Result<(), Errno> {
+        let found = self.find(raw, "..")?;
+        let (block, mut contents) = self.read_entry_block(raw, found.block)?;
+        contents[found.offset..found.offset + 4].copy_from_slice(&parent.to_le_bytes());
+        self.volume.write_block(block, &contents)
+    }
+
+    /// Makes a new inode, and adds an entry for it to this directory.
+    fn new_child(
+        &self,
+        state: &mut State,
+        name: &str,
+        mode: u16,
+        setup: impl FnOnce(&Ext2Inode, &mut State, &mut RawInode) -> Result<(), Errno>,
+    ) -> Result<Arc<Ext2Inode>, Errno> {
+        let mut raw = self.raw.lock();
+        if name.len() > MAX_NAME {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        if self.find(&raw, name).is_ok() {
+            return Err(Errno::EEXIST);
+        }
+        let directory = mode & S_IFMT == S_IFDIR;
+        let volume = self.volume.clone();
+        let number = volume.allocate_inode(state, self.group(), directory)?;
+        let time = seconds();
+        let mut child_raw = RawInode {
+            mode,
+            links: 1,
+            accessed: time,
+            changed: time,
+            modified: time,
+            ..RawInode::zeroed()
+        };
+        // Anything left in a larger inode from before is cleared.
+        let zeroes = vec![0; volume.inode_size as usize];
+        block::write_bytes(
+            &*volume.device,
+            volume.inode_offset(state, number)?,
+            &zeroes,
+        )?;
+        volume.write_inode(state, number, &child_raw)?;
+        let child = volume.inode(state, number)?;
+        let result = setup(&child, state, &mut child_raw)
+            .and_then(|()| self.add_entry(state, &mut raw, name, number, child_raw.kind()));
+        if let Err(errno) = result {
+            // Undo as much as we can. The new inode is freed once it is dropped.
+            child_raw.links = 0;
+            *child.raw.lock() = child_raw;
+            child.store(state, &child_raw)?;
+            return Err(errno);
+        }
+        *child.raw.lock() = child_raw;
+        child.store(state, &child_raw)?;
+        self.store(state, &raw)?;
+        Ok(child)
+    }
+
+    /// Checks that a directory has nothing in it but `.` and `..`.
+    fn check_empty(&self) -> Result<(), Errno> {
+        let raw = self.raw.lock();
+        match self
+            .entries(&raw)?
+            .iter()
+            .all(|found| found.name == "." || found.name == "..")
+        {
+            true => Ok(()),
+            false => Err(Errno::ENOTEMPTY),
+        }
+    }
+
+    /// Removes an entry after checking the inode it refers to, takes away that inode's links,
+    /// and returns it. Its blocks are freed once it is no longer in use, so it should be dropped
+    /// after the filesystem is unlocked.
+    fn remove(
+        &self,
+        state: &mut State,
+        name: &str,
+        check: impl FnOnce(&Ext2Inode) -> Result<(), Errno>,
+    ) -> Result<Arc<Ext2Inode>, Errno> {
+        let found = self.find(&self.raw.lock(), name)?;
+        let child = self.volume.inode(state, found.inode)?;
+        check(&child)?;
+        let mut raw = self.raw.lock();
+        self.remove_entry(&mut raw, &found)?;
+        let mut child_raw = child.raw.lock();
+        if child_raw.kind() == FileType::Directory {
+            // The child's `.`, and its `..` in this directory.
+            child_raw.links = 0;
+            raw.links = raw.links.saturating_sub(1);
+        } else {
+            child_raw.links = child_raw.links.saturating_sub(1);
+        }
+        child_raw.touch(false);
+        child.store(state, &child_raw)?;
+        self.store(state, &raw)?;
+        drop(child_raw);
+        Ok(child)
+    }
+}
+
+/// Checks that an inode can be replaced by, or removed as, a directory or not.
+fn check_replaceable(inode: &Ext2Inode, directory: bool) -> Result<(), Errno> {
+    let kind = inode.raw.lock().kind();
+    match (kind == FileType::Directory, directory) {
+        (true, true) => inode.check_empty(),
+        (true, false) => Err(Errno::EISDIR),
+        (false, true) => Err(Errno::ENOTDIR),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn kind(&self) -> FileType {
+        self.raw.lock().kind()
+    }
+
+    fn stat(&self) -> Stat {
+        let raw = self.raw.lock();
+        let nanos = |seconds: u32| seconds as u64 * 1_000_000_000;
+        Stat {
+            links: raw.links as u64,
+            blocks: raw.sectors as u64,
+            accessed: nanos(raw.accessed),
+            modified: nanos(raw.modified),
+            changed: nanos(raw.changed),
+            ..Stat::new(
+                self.number as u64,
+                raw.kind(),
+                (raw.mode & !S_IFMT) as u32,
+                raw.size(),
+            )
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let _state = self.volume.lock()?;
+        let raw = self.raw.lock();
+        self.read_data(&raw, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        let mut raw = self.raw.lock();
+        let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.volume.block_path((end - 1) / self.volume.block_size)?;
+        if end > u32::MAX as u64 {
+            self.volume.allow_large_files(&mut state)?;
+        }
+        let result = self.write_data(&mut state, &mut raw, offset, buf);
+        // Even if the disk filled up, whatever was allocated is recorded.
+        if result.is_ok() && end > raw.size() {
+            raw.set_size(end);
+        }
+        raw.touch(true);
+        self.store(&state, &raw)?;
+        result.map(|()| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        let mut raw = self.raw.lock();
+        self.volume
+            .block_path(size.saturating_sub(1) / self.volume.block_size)?;
+        if size > u32::MAX as u64 {
+            self.volume.allow_large_files(&mut state)?;
+        }
+        self.resize(&mut state, &mut raw, size)?;
+        raw.touch(true);
+        self.store(&state, &raw)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.lock()?;
+        let found = self.find(&self.raw.lock(), name)?;
+        Ok(self.volume.inode(&mut state, found.inode)?)
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let state = self.volume.lock()?;
+        let entries = self.entries(&self.raw.lock())?;
+        let Some(found) = entries
+            .into_iter()
+            .filter(|found| found.name != "." && found.name != "..")
+            .nth(index)
+        else {
+            return Ok(None);
+        };
+        let kind = match found.file_type {
+            1 => FileType::Regular,
+            2 => FileType::Directory,
+            3 => FileType::CharDevice,
+            4 => FileType::BlockDevice,
+            5 => FileType::Fifo,
+            6 => FileType::Socket,
+            7 => FileType::Symlink,
+            _ => self.volume.read_inode(&state, found.inode)?.kind(),
+        };
+        Ok(Some(DirEntry {
+            name: found.name,
+            inode: found.inode as u64,
+            kind,
+        }))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        let permissions = (mode & 0o7777) as u16;
+        let child = match kind {
+            FileType::Regular => {
+                self.new_child(&mut state, name, S_IFREG | permissions, |_, _, _| Ok(()))?
+            }
+            FileType::Directory => {
+                let parent = self.number;
+                let child = self.new_child(
+                    &mut state,
+                    name,
+                    S_IFDIR | permissions,
+                    |child, state, raw| {
+                        raw.links = 2;
+                        child.add_entry(state, raw, ".", child.number, FileType::Directory)?;
+                        child.add_entry(state, raw, "..", parent, FileType::Directory)
+                    },
+                )?;
+                let mut raw = self.raw.lock();
+                raw.links += 1;
+                self.store(&state, &raw)?;
+                child
+            }
+            _ => return Err(Errno::EINVAL),
+        };
+        Ok(child)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        if target.len() >= self.volume.block_size as usize {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        let child = self.new_child(&mut state, name, S_IFLNK | 0o777, |child, state, raw| {
+            if target.len() < FAST_SYMLINK_MAX {
+                bytemuck::cast_slice_mut::<u32, u8>(&mut raw.block)[..target.len()]
+                    .copy_from_slice(target.as_bytes());
+            } else {
+                child.write_data(state, raw, 0, target.as_bytes())?;
+            }
+            raw.set_size(target.len() as u64);
+            Ok(())
+        })?;
+        Ok(child)
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), Errno> {
+        let inode = downcast(inode)?;
+        let mut state = self.volume.lock_for_writing()?;
+        let mut raw = self.raw.lock();
+        if self.find(&raw, name).is_ok() {
+            return Err(Errno::EEXIST);
+        }
+        let mut target = inode.raw.lock();
+        if target.links == u16::MAX {
+            return Err(Errno::EMLINK);
+        }
+        self.add_entry(&mut state, &mut raw, name, inode.number, target.kind())?;
+        target.links += 1;
+        target.touch(false);
+        inode.store(&state, &target)?;
+        self.store(&state, &raw)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        let removed = self.remove(&mut state, name, |inode| check_replaceable(inode, false))?;
+        drop(state);
+        drop(removed);
+        Ok(())
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.volume.lock_for_writing()?;
+        let removed = self.remove(&mut state, name, |inode| check_replaceable(inode, true))?;
+        drop(state);
+        drop(removed);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &Arc<dyn Inode>,
+        new_name: &str,
+    ) -> Result<(), Errno> {
+        let new_parent = downcast(new_parent)?;
+        let mut state = self.volume.lock_for_writing()?;
+        let found = self.find(&self.raw.lock(), old_name)?;
+        let inode = self.volume.inode(&mut state, found.inode)?;
+        let kind = inode.raw.lock().kind();
+        let directory = kind == FileType::Directory;
+        let existing = new_parent.find(&new_parent.raw.lock(), new_name);
+        let mut replaced = None;
+        if let Ok(existing) = existing {
+            if existing.inode == found.inode {
+                // Both names are links to the same file, so there is nothing to do.
+                return Ok(());
+            }
+            let existing = self.volume.inode(&mut state, existing.inode)?;
+            check_replaceable(&existing, directory)?;
+            replaced = Some(new_parent.remove(&mut state, new_name, |_| Ok(()))?);
+        }
+
+        {
+            let mut raw = new_parent.raw.lock();
+            new_parent.add_entry(&mut state, &mut raw, new_name, inode.number, kind)?;
+            if directory && new_parent.number != self.number {
+                raw.links += 1;
+            }
+            new_parent.store(&state, &raw)?;
+        }
+        let mut raw = self.raw.lock();
+        // Adding an entry may have moved entries in the same directory, so find it again.
+        let found = self.find(&raw, old_name)?;
+        self.remove_entry(&mut raw, &found)?;
+        if directory && new_parent.number != self.number {
+            raw.links = raw.links.saturating_sub(1);
+            inode.set_parent(&inode.raw.lock(), new_parent.number)?;
+        }
+        self.store(&state, &raw)?;
+        let mut moved = inode.raw.lock();
+        moved.touch(false);
+        inode.store(&state, &moved)?;
+        drop(state);
+        drop(replaced);
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, Errno> {
+        let _state = self.volume.lock()?;
+        let raw = self.raw.lock();
+        if raw.kind() != FileType::Symlink {
+            return Err(Errno::EINVAL);
+        }
+        let len = raw.size() as usize;
+        let target = if raw.is_fast_symlink(self.volume.block_size) {
+            bytemuck::cast_slice::<u32, u8>(&raw.block)
+                .get(..len)
+                .ok_or(Errno::EIO)?
+                .to_vec()
+        } else {
+            let mut target = vec![0; len];
+            self.read_data(&raw, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| Errno::EINVAL)
+    }
+}
+
+/// Gets at an ext2 inode, which the VFS has checked is in the same filesystem.
+fn downcast(inode: &Arc<dyn Inode>) -> Result<Arc<Ext2Inode>, Errno> {
+    (inode.clone() as Arc<dyn Any + Send + Sync>)
+        .downcast()
+        .map_err(|_| Errno::EXDEV)
+}
+
+/// An empty filesystem with 1024-byte blocks and a single group, laid out as: the boot block,
+/// the superblock, the group descriptors, the block and inode bitmaps, the inode table,
+/// and then the root directory.
+#[cfg(test)]
+fn test_image(blocks: u32) -> Arc<crate::block::MemoryDisk> {
+    const INODES: u32 = 32;
+    const INODE_TABLE: u32 = 5;
+    const ROOT_BLOCK: u32 = INODE_TABLE + INODES * GOOD_OLD_INODE_SIZE as u32 / 1024;
+    let mut image = vec![0; blocks as usize * 1024];
+    let used_blocks = ROOT_BLOCK;
+    let superblock = Superblock {
+        inodes_count: INODES,
+        blocks_count: blocks,
+        free_blocks_count: blocks - 1 - used_blocks,
+        free_inodes_count: INODES - (GOOD_OLD_FIRST_INODE - 1),
+        first_data_block: 1,
+        blocks_per_group: 8192,
+        fragments_per_group: 8192,
+        inodes_per_group: INODES,
+        magic: MAGIC,
+        state: 1,
+        revision: 1,
+        first_inode: GOOD_OLD_FIRST_INODE,
+        inode_size: GOOD_OLD_INODE_SIZE,
+        incompatible_features: INCOMPATIBLE_FILETYPE,
+        ..Zeroable::zeroed()
+    };
+    let group = GroupDescriptor {
+        block_bitmap: 3,
+        inode_bitmap: 4,
+        inode_table: INODE_TABLE,
+        free_blocks_count: superblock.free_blocks_count as u16,
+        free_inodes_count: superblock.free_inodes_count as u16,
+        used_dirs_count: 1,
+        ..Zeroable::zeroed()
+    };
+    let root = RawInode {
+        mode: S_IFDIR | 0o755,
+        size: 1024,
+        links: 2,
+        sectors: 2,
+        block: [ROOT_BLOCK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ..Zeroable::zeroed()
+    };
+    let put = |image: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(&mut image, 1024, bytemuck::bytes_of(&superblock));
+    put(&mut image, 2 * 1024, bytemuck::bytes_of(&group));
+    // Block 1 onwards is in use, and so are the reserved inodes.
+    // Bits past the end of the group are set too, as they don't stand for anything.
+    for bit in (0..used_blocks).chain(blocks - 1..8192) {
+        image[3 * 1024 + bit as usize / 8] |= 1 << (bit % 8);
+    }
+    for bit in (0..GOOD_OLD_FIRST_INODE - 1).chain(INODES..8192) {
+        image[4 * 1024 + bit as usize / 8] |= 1 << (bit % 8);
+    }
+    let root_offset = INODE_TABLE as usize * 1024 + (ROOT_INODE as usize - 1) * 128;
+    put(&mut image, root_offset, bytemuck::bytes_of(&root));
+    for (offset, name, len) in [(0, ".", 12), (12, "..", 1012)] {
+        let entry = RawDirEntry {
+            inode: ROOT_INODE,
+            record_len: len,
+            name_len: name.len() as u8,
+            file_type: dirent_type(FileType::Directory),
+        };
+        let offset = ROOT_BLOCK as usize * 1024 + offset;
+        put(&mut image, offset, bytemuck::bytes_of(&entry));
+        put(&mut image, offset + DIR_ENTRY_HEADER, name.as_bytes());
+    }
+    Arc::new(crate::block::MemoryDisk::from_bytes(512, image))
+}
+
+#[test_case]
+fn test_ext2() {
+    use super::test_fs::detached_root;
+    use super::{link, mkdir, open, read, read_link, rename, rmdir, symlink, unlink, OpenFlags};
+    use crate::file::File;
+
+    let disk = test_image(1024);
+    let fs = Ext2Fs::new(disk.clone()).unwrap();
+    assert_eq!(fs.name(), "ext2");
+    let root = detached_root(fs);
+    let flags = OpenFlags::CREATE | OpenFlags::READ_WRITE;
+
+    // This needs the singly and doubly indirect blocks.
+    let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let file = open(&root, "big", flags, 0o644).unwrap();
+    file.write(&data).unwrap();
+    drop(file);
+    mkdir(&root, "dir", 0o755).unwrap();
+    link(&root, "big", "dir/also big").unwrap();
+    symlink(&root, "also big", "dir/link").unwrap();
+    let long_target = "x/".repeat(100);
+    symlink(&root, &long_target, "long link").unwrap();
+    assert_eq!(rmdir(&root, "dir"), Err(Errno::ENOTEMPTY));
+    rename(&root, "dir", "moved").unwrap();
+    unlink(&root, "big").unwrap();
+
+    // Everything is still there when the disk is mounted again.
+    let root = detached_root(Ext2Fs::new(disk.clone()).unwrap());
+    assert_eq!(read(&root, "moved/link").unwrap(), data);
+    assert_eq!(read_link(&root, "long link").unwrap(), long_target);
+    assert_eq!(read(&root, "big"), Err(Errno::ENOENT));
+    let file = open(&root, "moved/also big", OpenFlags::READ_WRITE, 0).unwrap();
+    file.truncate(100).unwrap();
+    drop(file);
+    assert_eq!(read(&root, "moved/also big").unwrap(), data[..100]);
+    for name in ["moved/also big", "moved/link", "long link"] {
+        unlink(&root, name).unwrap();
+    }
+    rmdir(&root, "moved").unwrap();
+
+    // Removing everything gives back all of the space.
+    let volume = Volume::new(disk).unwrap();
+    let state = volume.state.lock();
+    assert_eq!(state.superblock.free_blocks_count, 1024 - 10);
+    assert_eq!(state.groups[0].free_blocks_count, 1024 - 10);
+    assert_eq!(state.superblock.free_inodes_count, 32 - 10);
+
+    // A group can't hold more inodes than its bitmap has bits for.
+    let disk = test_image(1024);
+    let offset = SUPERBLOCK_OFFSET + core::mem::offset_of!(Superblock, inodes_per_group) as u64;
+    block::write_bytes(&*disk, offset, &(8 * 1024 + 1u32).to_le_bytes()).unwrap();
+    assert_eq!(Ext2Fs::new(disk).err(), Some(Errno::EINVAL));
+}
@@ -0,0 +1,1291 @@
+//! FAT12, FAT16 and FAT32, which are used for the EFI system partition and on removable disks.
+//!
+//! A FAT filesystem splits its data area into clusters, and the file allocation table holds
+//! the number of the cluster after each one, so every file and directory is a chain of clusters.
+//! Directories are arrays of 32-byte entries, each of which holds an 8.3 short name, a size and
+//! the first cluster of a file. Longer names are stored in extra entries before the short one,
+//! thirteen UTF-16 code units at a time. Names are compared without regard to case.
+//!
+//! FAT has no inodes, so an inode here stands for a directory entry, and remembers where on disk
+//! the entry is so that it can be updated. The inodes in use are kept in a table, so every lookup
+//! of a file returns the same inode. There are no permissions, links or timestamps: files have mode
+//! 644, or 444 if they are marked read-only, and directories have mode 755.
+//! Unlike on other filesystems, removing a file that is still open frees its contents straight away.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use bytemuck::{Pod, Zeroable};
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+};
+
+/// The BIOS parameter block at the start of the first sector, which describes the filesystem.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct BootSector {
+    jump: [u8; 3],
+    oem_name: [u8; 8],
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    fat_count: u8,
+    root_entries: u16,
+    total_sectors_16: u16,
+    media: u8,
+    fat_size_16: u16,
+    sectors_per_track: u16,
+    heads: u16,
+    hidden_sectors: u32,
+    total_sectors_32: u32,
+    // The rest is only there on FAT32.
+    fat_size_32: u32,
+    flags: u16,
+    version: u16,
+    root_cluster: u32,
+}
+
+const READ_ONLY: u8 = 0x01;
+const HIDDEN: u8 = 0x02;
+const SYSTEM: u8 = 0x04;
+const VOLUME_ID: u8 = 0x08;
+const DIRECTORY: u8 = 0x10;
+/// The attributes of a long name entry, which old systems skip over.
+const LONG_NAME: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
+
+/// Set in the case byte of a short entry when the name or extension is shown in lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// The first byte of an entry that has been deleted.
+const DELETED: u8 = 0xe5;
+/// The first byte of an entry that has never been used, after which there are only unused entries.
+const END: u8 = 0x00;
+/// Marks the long name entry that comes first on disk, and holds the end of the name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// The number of UTF-16 code units in each long name entry.
+const LONG_NAME_CHARS: usize = 13;
+/// The longest name that a long name can hold, in UTF-16 code units.
+const MAX_NAME: usize = 255;
+
+/// A short directory entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct RawEntry {
+    /// The base name and extension, padded with spaces.
+    name: [u8; 11],
+    attributes: u8,
+    case: u8,
+    created_tenths: u8,
+    created_time: u16,
+    created_date: u16,
+    accessed_date: u16,
+    cluster_high: u16,
+    modified_time: u16,
+    modified_date: u16,
+    cluster_low: u16,
+    size: u32,
+}
+
+/// 1980-01-01, the earliest date FAT can store, which is used since there is no real-time clock.
+const EPOCH_DATE: u16 = (1 << 5) | 1;
+
+impl RawEntry {
+    fn new(name: [u8; 11], case: u8, attributes: u8) -> Self {
+        Self {
+            name,
+            attributes,
+            case,
+            created_date: EPOCH_DATE,
+            accessed_date: EPOCH_DATE,
+            modified_date: EPOCH_DATE,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    fn cluster(&self) -> u32 {
+        ((self.cluster_high as u32) << 16) | self.cluster_low as u32
+    }
+
+    fn set_cluster(&mut self, cluster: u32) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = cluster as u16;
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes & DIRECTORY != 0
+    }
+
+    /// The short name as it is shown, such as `readme.txt`.
+    fn short_name(&self) -> String {
+        let mut name = self.name;
+        // A name really starting with 0xe5 is stored as 0x05, so it doesn't look deleted.
+        if name[0] == 0x05 {
+            name[0] = DELETED;
+        }
+        let show = |part: &[u8], lower: bool| -> String {
+            let part = part.trim_ascii_end();
+            part.iter()
+                .map(|&byte| match lower {
+                    true => byte.to_ascii_lowercase() as char,
+                    false => byte as char,
+                })
+                .collect()
+        };
+        let mut shown = show(&name[..8], self.case & LOWER_CASE_BASE != 0);
+        let extension = show(&name[8..], self.case & LOWER_CASE_EXTENSION != 0);
+        if !extension.is_empty() {
+            shown.push('.');
+            shown.push_str(&extension);
+        }
+        shown
+    }
+}
+
+/// A long name entry, which holds part of a name.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct LongEntry {
+    /// The position of this part in the name, starting from 1, maybe with [LAST_LONG_ENTRY].
+    order: u8,
+    name1: [u16; 5],
+    /// Always [LONG_NAME].
+    attributes: u8,
+    kind: u8,
+    /// The [checksum] of the short name that this belongs to.
+    checksum: u8,
+    name2: [u16; 6],
+    cluster: u16,
+    name3: [u16; 2],
+}
+
+impl LongEntry {
+    fn chars(&self) -> [u16; LONG_NAME_CHARS] {
+        let (name1, name2, name3) = (self.name1, self.name2, self.name3);
+        let mut chars = [0; LONG_NAME_CHARS];
+        chars[..5].copy_from_slice(&name1);
+        chars[5..11].copy_from_slice(&name2);
+        chars[11..].copy_from_slice(&name3);
+        chars
+    }
+}
+
+/// The checksum of a short name, which is stored in its long name entries so that
+/// they can be recognised as stale if an old system renames the file.
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// The layout of a filesystem, and the state shared by its inodes.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    kind: FatKind,
+    cluster_size: u64,
+    /// Where the first copy of the allocation table starts, in bytes.
+    fat_offset: u64,
+    /// The size of each copy of the allocation table, in bytes.
+    fat_size: u64,
+    fat_count: u64,
+    /// Where the root directory of FAT12 and FAT16 is, in bytes. It has a fixed size.
+    root_offset: u64,
+    root_size: u64,
+    /// Where cluster 2, the first cluster, starts.
+    data_offset: u64,
+    /// The number of clusters. They are numbered from 2.
+    cluster_count: u32,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// The inodes in use, by the position of their entry on disk.
+    inodes: BTreeMap<u64, Weak<FatInode>>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<(Self, u32), Errno> {
+        let mut sector = [0; 512];
+        block::read_bytes(&*device, 0, &mut sector)?;
+        if sector[510..] != [0x55, 0xaa] {
+            return Err(Errno::EINVAL);
+        }
+        let boot: BootSector = bytemuck::pod_read_unaligned(&sector[..size_of::<BootSector>()]);
+        let bytes_per_sector = boot.bytes_per_sector as u64;
+        let sectors_per_cluster = boot.sectors_per_cluster as u64;
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || boot.fat_count == 0
+            || boot.reserved_sectors == 0
+        {
+            return Err(Errno::EINVAL);
+        }
+        let total_sectors = match boot.total_sectors_16 {
+            0 => boot.total_sectors_32 as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match boot.fat_size_16 {
+            0 => boot.fat_size_32 as u64,
+            sectors => sectors as u64,
+        };
+        let root_sectors = (boot.root_entries as u64 * 32).div_ceil(bytes_per_sector);
+        let fat_start = boot.reserved_sectors as u64;
+        let root_start = fat_start + boot.fat_count as u64 * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if total_sectors <= data_start
+            || total_sectors * bytes_per_sector
+                > device.sector_count() * device.sector_size() as u64
+        {
+            return Err(Errno::EINVAL);
+        }
+        let cluster_count = (total_sectors - data_start) / sectors_per_cluster;
+        // The kind of FAT is decided by the number of clusters, and nothing else.
+        let kind = match cluster_count {
+            0..4085 => FatKind::Fat12,
+            4085..65525 => FatKind::Fat16,
+            _ => FatKind::Fat32,
+        };
+        let root_cluster = match kind {
+            FatKind::Fat32 => boot.root_cluster,
+            _ => 0,
+        };
+        let volume = Self {
+            device,
+            kind,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset: fat_start * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count: boot.fat_count as u64,
+            root_offset: root_start * bytes_per_sector,
+            root_size: root_sectors * bytes_per_sector,
+            data_offset: data_start * bytes_per_sector,
+            cluster_count: cluster_count.min(0x0fff_fff5) as u32,
+            state: Mutex::new(State {
+                next_free: 2,
+                inodes: BTreeMap::new(),
+            }),
+        };
+        let entries_per_fat = match kind {
+            FatKind::Fat12 => volume.fat_size * 2 / 3,
+            FatKind::Fat16 => volume.fat_size / 2,
+            FatKind::Fat32 => volume.fat_size / 4,
+        };
+        if entries_per_fat < cluster_count + 2
+            || (kind == FatKind::Fat32 && !volume.is_cluster(root_cluster))
+        {
+            return Err(Errno::EINVAL);
+        }
+        Ok((volume, root_cluster))
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    /// The value in the allocation table that marks the end of a chain.
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat12 => 0xfff,
+            FatKind::Fat16 => 0xffff,
+            FatKind::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// Where a cluster's entry is within the allocation table, in bytes.
+    fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.kind {
+            FatKind::Fat12 => cluster + cluster / 2,
+            FatKind::Fat16 => cluster * 2,
+            FatKind::Fat32 => cluster * 4,
+        }
+    }
+
+    /// The entry in the allocation table for a cluster: zero if it is free,
+    /// or the next cluster in its chain.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Errno> {
+        let offset = self.fat_offset + self.fat_entry_offset(cluster);
+        let mut bytes = [0; 4];
+        let len = if self.kind == FatKind::Fat32 { 4 } else { 2 };
+        block::read_bytes(&*self.device, offset, &mut bytes[..len])?;
+        let value = u32::from_le_bytes(bytes);
+        Ok(match self.kind {
+            // Twelve-bit entries are packed in pairs into three bytes.
+            FatKind::Fat12 if cluster % 2 == 1 => value >> 4,
+            FatKind::Fat12 => value & 0xfff,
+            FatKind::Fat16 => value,
+            FatKind::Fat32 => value & 0x0fff_ffff,
+        })
+    }
+
+    /// Sets a cluster's entry in every copy of the allocation table.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Errno> {
+        let entry_offset = self.fat_entry_offset(cluster);
+        let len = if self.kind == FatKind::Fat32 { 4 } else { 2 };
+        for copy in 0..self.fat_count {
+            let offset = self.fat_offset + copy * self.fat_size + entry_offset;
+            let mut bytes = [0; 4];
+            block::read_bytes(&*self.device, offset, &mut bytes[..len])?;
+            let old = u32::from_le_bytes(bytes);
+            let new = match self.kind {
+                FatKind::Fat12 if cluster % 2 == 1 => (old & 0x000f) | (value << 4),
+                FatKind::Fat12 => (old & 0xf000) | value,
+                FatKind::Fat16 => value,
+                // The top four bits are reserved, and must be kept.
+                FatKind::Fat32 => (old & 0xf000_0000) | value,
+            };
+            block::write_bytes(&*self.device, offset, &new.to_le_bytes()[..len])?;
+        }
+        Ok(())
+    }
+
+    /// The clusters in the chain starting with the given one, which is zero for an empty chain.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, Errno> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // A chain can't be longer than the number of clusters, unless it loops.
+            if !self.is_cluster(cluster) || clusters.len() >= self.cluster_count as usize {
+                return Err(Errno::EIO);
+            }
+            clusters.push(cluster);
+            cluster = match self.fat_entry(cluster)? {
+                next if next >= self.end_of_chain() - 7 => 0,
+                next => next,
+            };
+        }
+        Ok(clusters)
+    }
+
+    /// Finds a free cluster, marks it as the end of a chain, and adds it after `previous`.
+    fn allocate(&self, state: &mut State, previous: Option<u32>) -> Result<u32, Errno> {
+        let start = state.next_free.clamp(2, self.cluster_count + 1);
+        let mut free = None;
+        for cluster in (start..self.cluster_count + 2).chain(2..start) {
+            if self.fat_entry(cluster)? == 0 {
+                free = Some(cluster);
+                break;
+            }
+        }
+        let cluster = free.ok_or(Errno::ENOSPC)?;
+        self.set_fat_entry(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        state.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Marks every cluster in a chain as free.
+    fn free(&self, state: &mut State, clusters: &[u32]) -> Result<(), Errno> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        if let Some(&first) = clusters.iter().min() {
+            state.next_free = state.next_free.min(first);
+        }
+        Ok(())
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), Errno> {
+        let zeroes = vec![0; self.cluster_size as usize];
+        block::write_bytes(&*self.device, self.cluster_offset(cluster), &zeroes)
+    }
+
+    /// Returns the inode for the entry at the given position, making it if it isn't in use.
+    fn inode(self: &Arc<Self>, state: &mut State, position: u64, entry: RawEntry) -> Arc<FatInode> {
+        if let Some(inode) = state.inodes.get(&position).and_then(Weak::upgrade) {
+            return inode;
+        }
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(FatInode {
+            volume: self.clone(),
+            number: inode_number(position),
+            directory: entry.is_directory(),
+            node: Mutex::new(Node {
+                position: Some(position),
+                entry,
+                clusters: None,
+            }),
+        });
+        state.inodes.insert(position, Arc::downgrade(&inode));
+        inode
+    }
+}
+
+/// The inode number of the file whose entry is at the given position on disk.
+/// These stay the same between mounts. The root directory, which has no entry, is inode 1.
+fn inode_number(position: u64) -> u64 {
+    position / 32 + 2
+}
+
+/// A filesystem on a block device, which may be any of FAT12, FAT16 and FAT32.
+pub struct FatFs {
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    /// Reads the boot sector, and returns [Errno::EINVAL] if it doesn't describe a FAT filesystem.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, Errno> {
+        let (volume, root_cluster) = Volume::new(device)?;
+        let mut entry = RawEntry::new(*b"           ", 0, DIRECTORY);
+        entry.set_cluster(root_cluster);
+        let root = Arc::new(FatInode {
+            volume: Arc::new(volume),
+            number: 1,
+            directory: true,
+            node: Mutex::new(Node {
+                position: None,
+                entry,
+                clusters: None,
+            }),
+        });
+        Ok(Arc::new(Self { root }))
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.root.volume.kind {
+            FatKind::Fat12 => "fat12",
+            FatKind::Fat16 => "fat16",
+            FatKind::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    number: u64,
+    directory: bool,
+    node: Mutex<Node>,
+}
+
+struct Node {
+    /// Where the short entry is on disk. This is `None` for the root directory,
+    /// and for a file that has been removed.
+    position: Option<u64>,
+    entry: RawEntry,
+    /// The chain of clusters, once it has been read.
+    clusters: Option<Vec<u32>>,
+}
+
+/// An entry in a directory, with its name put back together.
+struct Found {
+    name: String,
+    entry: RawEntry,
+    /// The index of the first entry that belongs to this one, which is a long name entry
+    /// unless the name is short.
+    first_slot: usize,
+    /// The index of the short entry.
+    slot: usize,
+    /// Where the short entry is on disk.
+    position: u64,
+}
+
+/// The raw entries of a directory, and where each of them is on disk.
+struct Slots {
+    entries: Vec<RawEntry>,
+    positions: Vec<u64>,
+}
+
+impl Slots {
+    /// Puts long names back together, and skips unused entries, volume labels, `.` and `..`.
+    fn found(&self) -> Vec<Found> {
+        let mut found = Vec::new();
+        // The long name being put together: its pieces, the next order expected, its checksum,
+        // and where it started.
+        let mut long: Option<(Vec<u16>, u8, u8, usize)> = None;
+        for (slot, entry) in self.entries.iter().enumerate() {
+            match entry.name[0] {
+                END => break,
+                DELETED => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if entry.attributes & LONG_NAME == LONG_NAME {
+                let part: LongEntry = bytemuck::cast(*entry);
+                let order = part.order & !LAST_LONG_ENTRY;
+                long = match long.take() {
+                    _ if part.order & LAST_LONG_ENTRY != 0 && order > 0 => Some((
+                        vec![0; order as usize * LONG_NAME_CHARS],
+                        order,
+                        part.checksum,
+                        slot,
+                    )),
+                    Some((chars, expected, sum, start))
+                        if order == expected && order > 0 && part.checksum == sum =>
+                    {
+                        Some((chars, expected, sum, start))
+                    }
+                    _ => None,
+                };
+                if let Some((chars, expected, _, _)) = &mut long {
+                    let start = (order as usize - 1) * LONG_NAME_CHARS;
+                    chars[start..start + LONG_NAME_CHARS].copy_from_slice(&part.chars());
+                    *expected -= 1;
+                }
+                continue;
+            }
+            let long = long.take();
+            if entry.attributes & VOLUME_ID != 0 || entry.name[0] == b'.' {
+                continue;
+            }
+            let (name, first_slot) = match long {
+                Some((chars, 0, sum, start)) if sum == checksum(&entry.name) => {
+                    let end = chars.iter().position(|&c| c == 0).unwrap_or(chars.len());
+                    let name = char::decode_utf16(chars[..end].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, start)
+                }
+                _ => (entry.short_name(), slot),
+            };
+            found.push(Found {
+                name,
+                entry: *entry,
+                first_slot,
+                slot,
+                position: self.positions[slot],
+            });
+        }
+        found
+    }
+
+    /// Finds an entry by name, ignoring case.
+    fn find(&self, name: &str) -> Option<Found> {
+        self.found()
+            .into_iter()
+            .find(|found| names_match(&found.name, name))
+    }
+
+    /// Finds `count` unused entries in a row.
+    fn find_free(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for (slot, entry) in self.entries.iter().enumerate() {
+            match entry.name[0] {
+                END => return (self.entries.len() - slot + run >= count).then_some(slot - run),
+                DELETED => run += 1,
+                _ => run = 0,
+            }
+            if run == count {
+                return Some(slot + 1 - count);
+            }
+        }
+        None
+    }
+
+    fn short_name_taken(&self, name: &[u8; 11]) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.name == *name && entry.name[0] != DELETED && entry.attributes != LONG_NAME
+        })
+    }
+}
+
+fn names_match(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Characters that can be in a short name, besides letters and digits.
+const SHORT_NAME_SYMBOLS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// Returns the short entry name for a name that fits in 8.3 form without a long name,
+/// along with the case flags that make it show up the same way.
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max: usize| {
+        part.len() <= max
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&byte))
+    };
+    if base.is_empty() || !valid(base, 8) || !valid(extension, 3) || name.ends_with('.') {
+        return None;
+    }
+    // Each part must be all one case, which the case flags can record.
+    let case = |part: &str, flag: u8| {
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let upper = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    let case = case(base, LOWER_CASE_BASE)? | case(extension, LOWER_CASE_EXTENSION)?;
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    short.make_ascii_uppercase();
+    if short[0] == DELETED {
+        short[0] = 0x05;
+    }
+    Some((short, case))
+}
+
+/// Makes up a short name like `LONGNA~1.TXT` for a name that needs a long name entry.
+fn generated_short_name(name: &str, slots: &Slots) -> Result<[u8; 11], Errno> {
+    let simplify = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.to_ascii_uppercase() {
+                c if c.is_ascii_alphanumeric() => c as u8,
+                c if c.is_ascii() && SHORT_NAME_SYMBOLS.contains(&(c as u8)) => c as u8,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rsplit_once('.') {
+        Some((base, extension)) => (simplify(base), simplify(extension)),
+        None => (simplify(trimmed), Vec::new()),
+    };
+    let mut short = [b' '; 11];
+    let extension = &extension[..extension.len().min(3)];
+    short[8..8 + extension.len()].copy_from_slice(extension);
+    for number in 1..1_000_000 {
+        let tail = alloc::format!("~{number}");
+        let base_len = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..base_len].copy_from_slice(&base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !slots.short_name_taken(&short) {
+            return Ok(short);
+        }
+    }
+    Err(Errno::EEXIST)
+}
+
+/// Checks that a name can be stored, and returns it in UTF-16.
+fn check_name(name: &str) -> Result<Vec<u16>, Errno> {
+    let utf16: Vec<u16> = name.encode_utf16().collect();
+    if utf16.len() > MAX_NAME {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    // Trailing dots and spaces are dropped by other systems, so they can't be stored reliably.
+    if name.ends_with(['.', ' ']) || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(utf16)
+}
+
+/// The long name entries for a name, in the order they go on disk.
+fn long_entries(name: &[u16], short: &[u8; 11]) -> Vec<RawEntry> {
+    let count = name.len().div_ceil(LONG_NAME_CHARS);
+    let sum = checksum(short);
+    (1..=count)
+        .rev()
+        .map(|order| {
+            // The name ends with a null if there is room, and the rest is filled with 0xffff.
+            let mut chars = [0xffff; LONG_NAME_CHARS];
+            let part = &name[(order - 1) * LONG_NAME_CHARS..];
+            let len = part.len().min(LONG_NAME_CHARS);
+            chars[..len].copy_from_slice(&part[..len]);
+            if len < LONG_NAME_CHARS {
+                chars[len] = 0;
+            }
+            let mut name1 = [0; 5];
+            let mut name2 = [0; 6];
+            let mut name3 = [0; 2];
+            name1.copy_from_slice(&chars[..5]);
+            name2.copy_from_slice(&chars[5..11]);
+            name3.copy_from_slice(&chars[11..]);
+            let last = if order == count { LAST_LONG_ENTRY } else { 0 };
+            bytemuck::cast(LongEntry {
+                order: order as u8 | last,
+                name1,
+                attributes: LONG_NAME,
+                kind: 0,
+                checksum: sum,
+                name2,
+                cluster: 0,
+                name3,
+            })
+        })
+        .collect()
+}
+
+impl FatInode {
+    /// The chain of clusters, which is read the first time it is needed.
+    fn clusters<'a>(&self, node: &'a mut Node) -> Result<&'a mut Vec<u32>, Errno> {
+        if node.clusters.is_none() {
+            node.clusters = Some(self.volume.chain(node.entry.cluster())?);
+        }
+        Ok(node.clusters.as_mut().expect("clusters were just read"))
+    }
+
+    /// Writes the entry back to the directory that it is in.
+    fn store(&self, node: &Node) -> Result<(), Errno> {
+        match node.position {
+            Some(position) => block::write_bytes(
+                &*self.volume.device,
+                position,
+                bytemuck::bytes_of(&node.entry),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this is the root directory of FAT12 or FAT16, which is not made of clusters.
+    fn is_fixed_root(&self, node: &Node) -> bool {
+        self.number == 1 && node.entry.cluster() == 0
+    }
+
+    /// Where the contents of a directory are on disk, as offsets and lengths.
+    fn extents(&self, node: &mut Node) -> Result<Vec<(u64, u64)>, Errno> {
+        let volume = &self.volume;
+        if self.is_fixed_root(node) {
+            return Ok(vec![(volume.root_offset, volume.root_size)]);
+        }
+        Ok(self
+            .clusters(node)?
+            .iter()
+            .map(|&cluster| (volume.cluster_offset(cluster), volume.cluster_size))
+            .collect())
+    }
+
+    fn slots(&self, node: &mut Node) -> Result<Slots, Errno> {
+        let mut slots = Slots {
+            entries: Vec::new(),
+            positions: Vec::new(),
+        };
+        for (offset, len) in self.extents(node)? {
+            let mut bytes = vec![0; len as usize];
+            block::read_bytes(&*self.volume.device, offset, &mut bytes)?;
+            for (index, chunk) in bytes.chunks_exact(32).enumerate() {
+                slots.entries.push(bytemuck::pod_read_unaligned(chunk));
+                slots.positions.push(offset + index as u64 * 32);
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Reads from the clusters of a file, which must already cover the range.
+    fn read_clusters(&self, node: &mut Node, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let volume = self.volume.clone();
+        let clusters = self.clusters(node)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = (position / volume.cluster_size) as usize;
+            let within = position % volume.cluster_size;
+            let len = (buf.len() - done).min((volume.cluster_size - within) as usize);
+            let cluster = *clusters.get(index).ok_or(Errno::EIO)?;
+            block::read_bytes(
+                &*volume.device,
+                volume.cluster_offset(cluster) + within,
+                &mut buf[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Makes the chain of clusters long enough to hold `size` bytes.
+    /// New clusters are only zeroed for directories, since files write over them.
+    fn grow(&self, state: &mut State, node: &mut Node, size: u64) -> Result<(), Errno> {
+        let volume = self.volume.clone();
+        let needed = size.div_ceil(volume.cluster_size) as usize;
+        let directory = self.directory;
+        let clusters = self.clusters(node)?;
+        let mut first = None;
+        while clusters.len() < needed {
+            let cluster = volume.allocate(state, clusters.last().copied())?;
+            if directory {
+                volume.zero_cluster(cluster)?;
+            }
+            if clusters.is_empty() {
+                first = Some(cluster);
+            }
+            clusters.push(cluster);
+        }
+        if let Some(first) = first {
+            node.entry.set_cluster(first);
+        }
+        Ok(())
+    }
+
+    /// Writes to a file, extending it as needed. Anything between the old end and `offset`
+    /// must already have been filled in.
+    fn write_clusters(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), Errno> {
+        let end = offset + buf.len() as u64;
+        self.grow(state, node, end)?;
+        let volume = self.volume.clone();
+        let clusters = self.clusters(node)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = (position / volume.cluster_size) as usize;
+            let within = position % volume.cluster_size;
+            let len = (buf.len() - done).min((volume.cluster_size - within) as usize);
+            block::write_bytes(
+                &*volume.device,
+                volume.cluster_offset(clusters[index]) + within,
+                &buf[done..done + len],
+            )?;
+            done += len;
+        }
+        node.entry.size = node.entry.size.max(end as u32);
+        Ok(())
+    }
+
+    /// Fills a file with zeroes from its current end up to `size`.
+    fn fill_zeroes(&self, state: &mut State, node: &mut Node, size: u64) -> Result<(), Errno> {
+        let zeroes = vec![0; self.volume.cluster_size as usize];
+        while (node.entry.size as u64) < size {
+            let start = node.entry.size as u64;
+            let len = (size - start).min(zeroes.len() as u64) as usize;
+            self.write_clusters(state, node, start, &zeroes[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Adds entries for a name to this directory, giving the entry a short name,
+    /// and returns where the short entry went along with the entry itself.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        name: &str,
+        mut entry: RawEntry,
+    ) -> Result<(u64, RawEntry), Errno> {
+        let utf16 = check_name(name)?;
+        let mut slots = self.slots(node)?;
+        let mut new = match short_name(name) {
+            Some((short, case)) if !slots.short_name_taken(&short) => {
+                entry.name = short;
+                entry.case = case;
+                Vec::new()
+            }
+            _ => {
+                entry.name = generated_short_name(name, &slots)?;
+                entry.case = 0;
+                long_entries(&utf16, &entry.name)
+            }
+        };
+        new.push(entry);
+        let slot = loop {
+            if let Some(slot) = slots.find_free(new.len()) {
+                break slot;
+            }
+            if self.is_fixed_root(node) {
+                return Err(Errno::ENOSPC);
+            }
+            let size = (slots.entries.len() as u64 + 1) * 32;
+            self.grow(state, node, size)?;
+            slots = self.slots(node)?;
+        };
+        for (entry, &position) in new.iter().zip(&slots.positions[slot..]) {
+            block::write_bytes(&*self.volume.device, position, bytemuck::bytes_of(entry))?;
+        }
+        Ok((slots.positions[slot + new.len() - 1], entry))
+    }
+
+    /// Marks the entries that make up a name as deleted.
+    fn remove_entry(&self, slots: &Slots, found: &Found) -> Result<(), Errno> {
+        for &position in &slots.positions[found.first_slot..=found.slot] {
+            block::write_bytes(&*self.volume.device, position, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Removes an entry after checking it, frees its clusters,
+    /// and marks its inode as removed if it is in use.
+    fn remove(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        name: &str,
+        check: impl FnOnce(&Found) -> Result<(), Errno>,
+    ) -> Result<(), Errno> {
+        let slots = self.slots(node)?;
+        let found = slots.find(name).ok_or(Errno::ENOENT)?;
+        check(&found)?;
+        let clusters = match state
+            .inodes
+            .remove(&found.position)
+            .and_then(|inode| inode.upgrade())
+        {
+            Some(inode) => {
+                let mut node = inode.node.lock();
+                node.position = None;
+                node.entry.size = 0;
+                node.entry.set_cluster(0);
+                node.clusters.replace(Vec::new())
+            }
+            None => None,
+        };
+        let clusters = match clusters {
+            Some(clusters) => clusters,
+            None => self.volume.chain(found.entry.cluster())?,
+        };
+        self.remove_entry(&slots, &found)?;
+        self.volume.free(state, &clusters)
+    }
+
+    /// Checks that a directory found in this one has nothing in it.
+    fn check_empty(&self, state: &mut State, found: &Found) -> Result<(), Errno> {
+        let directory = self.volume.inode(state, found.position, found.entry);
+        let mut node = directory.node.lock();
+        match directory.slots(&mut node)?.found().is_empty() {
+            true => Ok(()),
+            false => Err(Errno::ENOTEMPTY),
+        }
+    }
+}
+
+/// Checks that an entry can be replaced by, or removed as, a directory or not.
+fn check_replaceable(
+    dir: &FatInode,
+    state: &mut State,
+    found: &Found,
+    directory: bool,
+) -> Result<(), Errno> {
+    match (found.entry.is_directory(), directory) {
+        (true, true) => dir.check_empty(state, found),
+        (true, false) => Err(Errno::EISDIR),
+        (false, true) => Err(Errno::ENOTDIR),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for FatInode {
+    fn kind(&self) -> FileType {
+        match self.directory {
+            true => FileType::Directory,
+            false => FileType::Regular,
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        let node = self.node.lock();
+        let mode = match (self.directory, node.entry.attributes & READ_ONLY != 0) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        };
+        let size = node.entry.size as u64;
+        Stat {
+            blocks: size.next_multiple_of(self.volume.cluster_size) / 512,
+            ..Stat::new(self.number, self.kind(), mode, size)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let _state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        let len = buf
+            .len()
+            .min((node.entry.size as u64).saturating_sub(offset) as usize);
+        self.read_clusters(&mut node, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.position.is_none() {
+            return Err(Errno::ENOENT);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        if end > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let result = self
+            .fill_zeroes(&mut state, &mut node, offset)
+            .and_then(|()| self.write_clusters(&mut state, &mut node, offset, buf));
+        // Even if the disk filled up, whatever was allocated is recorded.
+        self.store(&node)?;
+        result.map(|()| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.position.is_none() {
+            return Err(Errno::ENOENT);
+        }
+        if size > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        if size > node.entry.size as u64 {
+            let result = self.fill_zeroes(&mut state, &mut node, size);
+            self.store(&node)?;
+            return result;
+        }
+        let volume = self.volume.clone();
+        let keep = size.div_ceil(volume.cluster_size) as usize;
+        let clusters = self.clusters(&mut node)?;
+        let freed = clusters.split_off(keep.min(clusters.len()));
+        if !freed.is_empty() {
+            match clusters.last() {
+                Some(&last) => volume.set_fat_entry(last, volume.end_of_chain())?,
+                None => node.entry.set_cluster(0),
+            }
+        }
+        node.entry.size = size as u32;
+        self.store(&node)?;
+        volume.free(&mut state, &freed)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.state.lock();
+        let found = self
+            .slots(&mut self.node.lock())?
+            .find(name)
+            .ok_or(Errno::ENOENT)?;
+        Ok(self.volume.inode(&mut state, found.position, found.entry))
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let _state = self.volume.state.lock();
+        let slots = self.slots(&mut self.node.lock())?;
+        Ok(slots.found().into_iter().nth(index).map(|found| DirEntry {
+            inode: inode_number(found.position),
+            kind: match found.entry.is_directory() {
+                true => FileType::Directory,
+                false => FileType::Regular,
+            },
+            name: found.name,
+        }))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        let read_only = if mode & 0o222 == 0 { READ_ONLY } else { 0 };
+        let mut entry = match kind {
+            FileType::Regular => RawEntry::new([0; 11], 0, read_only),
+            FileType::Directory => RawEntry::new([0; 11], 0, DIRECTORY),
+            _ => return Err(Errno::EINVAL),
+        };
+        check_name(name)?;
+        if self.slots(&mut node)?.find(name).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let volume = self.volume.clone();
+        if kind == FileType::Directory {
+            // A new directory has a cluster holding its `.` and `..` entries.
+            let cluster = volume.allocate(&mut state, None)?;
+            let mut dot = RawEntry::new(*b".          ", 0, DIRECTORY);
+            dot.set_cluster(cluster);
+            let mut dot_dot = RawEntry::new(*b"..         ", 0, DIRECTORY);
+            // The root directory is cluster 0 here, even on FAT32.
+            if self.number != 1 {
+                dot_dot.set_cluster(node.entry.cluster());
+            }
+            let mut contents = vec![0; volume.cluster_size as usize];
+            contents[..32].copy_from_slice(bytemuck::bytes_of(&dot));
+            contents[32..64].copy_from_slice(bytemuck::bytes_of(&dot_dot));
+            block::write_bytes(&*volume.device, volume.cluster_offset(cluster), &contents)?;
+            entry.set_cluster(cluster);
+        }
+        let (position, entry) = match self.add_entry(&mut state, &mut node, name, entry) {
+            Ok(added) => added,
+            Err(errno) => {
+                if entry.cluster() != 0 {
+                    volume.free(&mut state, &[entry.cluster()])?;
+                }
+                return Err(errno);
+            }
+        };
+        self.store(&node)?;
+        Ok(volume.inode(&mut state, position, entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        self.remove(&mut state, &mut node, name, |found| {
+            match found.entry.is_directory() {
+                true => Err(Errno::EISDIR),
+                false => Ok(()),
+            }
+        })
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        let found = {
+            let slots = self.slots(&mut self.node.lock())?;
+            slots.find(name).ok_or(Errno::ENOENT)?
+        };
+        check_replaceable(self, &mut state, &found, true)?;
+        let mut node = self.node.lock();
+        self.remove(&mut state, &mut node, name, |_| Ok(()))
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &Arc<dyn Inode>,
+        new_name: &str,
+    ) -> Result<(), Errno> {
+        let new_parent = downcast(new_parent)?;
+        let mut state = self.volume.state.lock();
+        check_name(new_name)?;
+        let slots = self.slots(&mut self.node.lock())?;
+        let found = slots.find(old_name).ok_or(Errno::ENOENT)?;
+        let directory = found.entry.is_directory();
+        let existing = new_parent
+            .slots(&mut new_parent.node.lock())?
+            .find(new_name);
+        if let Some(existing) = existing {
+            check_replaceable(&new_parent, &mut state, &existing, directory)?;
+            new_parent.remove(
+                &mut state,
+                &mut new_parent.node.lock(),
+                new_name,
+                |_| Ok(()),
+            )?;
+        }
+
+        let (new_position, entry) = {
+            let mut node = new_parent.node.lock();
+            let added = new_parent.add_entry(&mut state, &mut node, new_name, found.entry)?;
+            new_parent.store(&node)?;
+            added
+        };
+        // Adding entries doesn't move the old ones, even in the same directory.
+        self.remove_entry(&slots, &found)?;
+
+        if let Some(inode) = state.inodes.remove(&found.position) {
+            if let Some(moved) = inode.upgrade() {
+                let mut node = moved.node.lock();
+                node.position = Some(new_position);
+                node.entry = entry;
+                state.inodes.insert(new_position, inode);
+            }
+        }
+        // A directory's `..` entry has to follow it to its new parent.
+        if directory && new_parent.number != self.number {
+            let parent_cluster = match new_parent.number {
+                1 => 0,
+                _ => new_parent.node.lock().entry.cluster(),
+            };
+            let mut dot_dot = RawEntry::zeroed();
+            let position = self.volume.cluster_offset(entry.cluster()) + 32;
+            block::read_bytes(
+                &*self.volume.device,
+                position,
+                bytemuck::bytes_of_mut(&mut dot_dot),
+            )?;
+            dot_dot.set_cluster(parent_cluster);
+            block::write_bytes(&*self.volume.device, position, bytemuck::bytes_of(&dot_dot))?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets at a FAT inode, which the VFS has checked is in the same filesystem.
+fn downcast(inode: &Arc<dyn Inode>) -> Result<Arc<FatInode>, Errno> {
+    (inode.clone() as Arc<dyn Any + Send + Sync>)
+        .downcast()
+        .map_err(|_| Errno::EXDEV)
+}
+
+/// Formats a small FAT12 filesystem in memory, with one sector per cluster.
+#[cfg(test)]
+fn test_image(sectors: u16) -> Arc<crate::block::MemoryDisk> {
+    let mut image = vec![0; sectors as usize * 512];
+    let boot = BootSector {
+        jump: [0xeb, 0x3c, 0x90],
+        oem_name: *b"FUNCOS  ",
+        bytes_per_sector: 512,
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        fat_count: 2,
+        root_entries: 16,
+        total_sectors_16: sectors,
+        media: 0xf8,
+        fat_size_16: 2,
+        ..Zeroable::zeroed()
+    };
+    image[..size_of::<BootSector>()].copy_from_slice(bytemuck::bytes_of(&boot));
+    image[510..512].copy_from_slice(&[0x55, 0xaa]);
+    // The first two entries of each allocation table are reserved.
+    for fat in [512, 3 * 512] {
+        image[fat..fat + 3].copy_from_slice(&[0xf8, 0xff, 0xff]);
+    }
+    Arc::new(crate::block::MemoryDisk::from_bytes(512, image))
+}
+
+#[test_case]
+fn test_fat() {
+    use super::test_fs::detached_root;
+    use super::{mkdir, open, read, rename, rmdir, unlink, OpenFlags, Whence};
+    use crate::file::File;
+
+    let disk = test_image(200);
+    let fs = FatFs::new(disk.clone()).unwrap();
+    assert_eq!(fs.name(), "fat12");
+    let root = detached_root(fs);
+    let flags = OpenFlags::CREATE | OpenFlags::READ_WRITE;
+
+    let file = open(&root, "README.TXT", flags, 0o644).unwrap();
+    file.write(&[b'x'; 1000]).unwrap();
+    file.truncate(600).unwrap();
+    drop(file);
+    mkdir(&root, "Some Directory", 0o755).unwrap();
+    let name = "Some Directory/a rather long file name.text";
+    let file = open(&root, name, flags, 0o644).unwrap();
+    // Writing past the end leaves a gap of zeroes.
+    file.seek(2000, Whence::Start).unwrap();
+    file.write(b"hello").unwrap();
+    drop(file);
+    assert_eq!(rmdir(&root, "some directory"), Err(Errno::ENOTEMPTY));
+    rename(&root, name, "moved.txt").unwrap();
+    rmdir(&root, "some directory").unwrap();
+
+    // Everything is still there when the disk is mounted again.
+    let root = detached_root(FatFs::new(disk).unwrap());
+    assert_eq!(read(&root, "readme.txt").unwrap(), [b'x'; 600]);
+    let moved = read(&root, "MOVED.TXT").unwrap();
+    assert_eq!(moved.len(), 2005);
+    assert!(moved[..2000].iter().all(|&byte| byte == 0));
+    let mut names = Vec::new();
+    while let Some(entry) = root.inode().read_dir(names.len()).unwrap() {
+        names.push(entry.name);
+    }
+    assert_eq!(names, ["README.TXT", "moved.txt"]);
+    unlink(&root, "moved.txt").unwrap();
+    assert_eq!(read(&root, "moved.txt"), Err(Errno::ENOENT));
+}
@@ -0,0 +1,197 @@
+//! Files that have been opened through the VFS.
+
+use alloc::sync::Arc;
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::{Dentry, DirEntry, FileType, Stat};
+use crate::{errno::Errno, file::File, memory::shared::SharedMemory};
+
+bitflags::bitflags! {
+    /// Flags for opening files, with the same values as Linux.
+    /// Files are opened for reading only unless `WRITE_ONLY` or `READ_WRITE` is given.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        const WRITE_ONLY = 0o1;
+        const READ_WRITE = 0o2;
+        /// Create a regular file if there is nothing at the path.
+        const CREATE = 0o100;
+        /// With `CREATE`, fail if there is already something at the path.
+        const EXCLUSIVE = 0o200;
+        /// Empty a regular file that is opened for writing.
+        const TRUNCATE = 0o1000;
+        /// Write at the end of the file, wherever the position is.
+        const APPEND = 0o2000;
+        /// Fail unless the path names a directory.
+        const DIRECTORY = 0o200000;
+        /// Fail if the path names a symbolic link.
+        const NO_FOLLOW = 0o400000;
+    }
+}
+
+impl OpenFlags {
+    pub fn readable(self) -> bool {
+        !self.contains(Self::WRITE_ONLY)
+    }
+
+    pub fn writable(self) -> bool {
+        self.intersects(Self::WRITE_ONLY | Self::READ_WRITE)
+    }
+}
+
+/// Where a seek is measured from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+
+impl Whence {
+    /// Reads a `whence` argument, as used by Linux.
+    pub fn from_raw(whence: u64) -> Result<Self, Errno> {
+        match whence {
+            0 => Ok(Self::Start),
+            1 => Ok(Self::Current),
+            2 => Ok(Self::End),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// An open file or directory, with its own position.
+///
+/// The position of a directory counts entries rather than bytes,
+/// starting with `.` and `..`.
+pub struct OpenFile {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    position: Mutex<u64>,
+}
+
+impl OpenFile {
+    /// Opens a file that has already been looked up.
+    pub fn open(dentry: Arc<Dentry>, flags: OpenFlags) -> Result<Arc<Self>, Errno> {
+        match dentry.kind() {
+            FileType::Symlink => return Err(Errno::ELOOP),
+            FileType::Directory if flags.writable() => return Err(Errno::EISDIR),
+            FileType::Directory => {}
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(Errno::ENOTDIR),
+            FileType::Regular if flags.writable() && flags.contains(OpenFlags::TRUNCATE) => {
+                dentry.inode().truncate(0)?;
+            }
+            _ => {}
+        }
+        Ok(Arc::new(Self {
+            dentry,
+            flags,
+            position: Mutex::new(0),
+        }))
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+}
+
+fn dot_entry(name: &str, dentry: &Dentry) -> DirEntry {
+    DirEntry {
+        name: name.into(),
+        inode: dentry.inode().stat().inode,
+        kind: FileType::Directory,
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if !self.flags.readable() {
+            return Err(Errno::EBADF);
+        }
+        if self.dentry.kind() == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        // Don't hold the lock while reading, since the filesystem may need to wait for a disk.
+        let position = *self.position.lock();
+        let read = self.dentry.inode().read_at(position, buf)?;
+        *self.position.lock() = position + read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        if !self.flags.writable() {
+            return Err(Errno::EBADF);
+        }
+        let position = if self.flags.contains(OpenFlags::APPEND) {
+            self.dentry.inode().stat().size
+        } else {
+            *self.position.lock()
+        };
+        let written = self.dentry.inode().write_at(position, buf)?;
+        *self.position.lock() = position + written as u64;
+        Ok(written)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        if !self.flags.readable() {
+            return Err(Errno::EBADF);
+        }
+        self.dentry.inode().read_at(offset, buf)
+    }
+
+    fn shared_memory(&self) -> Result<Arc<SharedMemory>, Errno> {
+        self.dentry.inode().shared_memory()
+    }
+
+    fn device_memory(&self) -> Result<(PhysAddr, u64), Errno> {
+        self.dentry.inode().device_memory()
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, Errno> {
+        self.dentry.inode().ioctl(request, arg)
+    }
+
+    fn poll(&self) -> u16 {
+        self.dentry.inode().poll()
+    }
+
+    fn seek(&self, offset: i64, whence: Whence) -> Result<u64, Errno> {
+        let base = match whence {
+            Whence::Start => 0,
+            Whence::Current => *self.position.lock(),
+            Whence::End => self.dentry.inode().stat().size,
+        };
+        let position = base.checked_add_signed(offset).ok_or(Errno::EINVAL)?;
+        *self.position.lock() = position;
+        Ok(position)
+    }
+
+    fn stat(&self) -> Result<Stat, Errno> {
+        Ok(self.dentry.stat())
+    }
+
+    fn read_dir(&self, accept: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Errno> {
+        if self.dentry.kind() != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        loop {
+            let index = *self.position.lock() as usize;
+            let entry = match index {
+                0 => Some(dot_entry(".", &self.dentry)),
+                1 => Some(dot_entry("..", &self.dentry.parent())),
+                _ => self.dentry.inode().read_dir(index - 2)?,
+            };
+            match entry {
+                Some(entry) if accept(&entry) => *self.position.lock() = index as u64 + 1,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        if !self.flags.writable() || self.dentry.kind() != FileType::Regular {
+            return Err(Errno::EINVAL);
+        }
+        self.dentry.inode().truncate(size)
+    }
+}
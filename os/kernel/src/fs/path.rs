@@ -0,0 +1,152 @@
+//! Path resolution.
+//!
+//! Paths are split at slashes, and empty components are ignored, so `a//b/` is the same as `a/b`,
+//! except that a trailing slash requires the path to name a directory.
+//! `.` stays in the same directory, and `..` goes back to the directory that the current one was
+//! reached from, which is the mount point for the root of a mounted filesystem.
+//! Symbolic links are followed as they are reached, with relative targets resolved from the directory
+//! holding the link. Only the last component can be left unfollowed.
+
+use alloc::sync::Arc;
+
+use super::{root, Dentry, FileType};
+use crate::errno::Errno;
+
+/// The longest path that will be resolved.
+pub const MAX_PATH: usize = 4096;
+
+/// The longest name that a directory entry can have.
+pub const MAX_NAME: usize = 255;
+
+/// How many symbolic links may be followed while resolving one path.
+const MAX_SYMLINKS: usize = 40;
+
+/// Finds the dentry for a path, with relative paths starting from `at`.
+/// If `follow` is false and the last component is a symbolic link, the link itself is returned.
+pub fn lookup(at: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, Errno> {
+    walk(at, path, follow, &mut 0)
+}
+
+/// Finds the directory that would hold the last component of a path, and returns it with the last name.
+/// This is used to create and remove entries, so the last component is not looked up.
+pub fn lookup_parent<'a>(at: &Arc<Dentry>, path: &'a str) -> Result<(Arc<Dentry>, &'a str), Errno> {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        // The root has no parent to change.
+        return Err(if path.is_empty() {
+            Errno::ENOENT
+        } else {
+            Errno::EBUSY
+        });
+    }
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(index) => (lookup(at, &trimmed[..=index], true)?, &trimmed[index + 1..]),
+        None => (at.clone(), trimmed),
+    };
+    if dir.kind() != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    if name == "." || name == ".." {
+        return Err(Errno::EINVAL);
+    }
+    if name.len() > MAX_NAME {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok((dir, name))
+}
+
+/// Resolves a path, counting the symbolic links followed in `links`.
+fn walk(
+    at: &Arc<Dentry>,
+    path: &str,
+    follow: bool,
+    links: &mut usize,
+) -> Result<Arc<Dentry>, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    if path.len() > MAX_PATH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let must_be_directory = path.ends_with('/');
+    let mut current = if path.starts_with('/') {
+        root()?
+    } else {
+        at.clone()
+    };
+    let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = components.next() {
+        let last = components.peek().is_none();
+        current = step(&current, name, follow || must_be_directory || !last, links)?;
+    }
+    if must_be_directory && current.kind() != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(current)
+}
+
+/// Moves from a directory to one of its entries.
+fn step(
+    dir: &Arc<Dentry>,
+    name: &str,
+    follow: bool,
+    links: &mut usize,
+) -> Result<Arc<Dentry>, Errno> {
+    if dir.kind() != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    match name {
+        "." => Ok(dir.clone()),
+        ".." => Ok(dir.parent()),
+        _ if name.len() > MAX_NAME => Err(Errno::ENAMETOOLONG),
+        _ => {
+            let child = dir.child(name)?;
+            if !follow || child.kind() != FileType::Symlink {
+                return Ok(child);
+            }
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(Errno::ELOOP);
+            }
+            let target = child.inode().read_link()?;
+            walk(dir, &target, true, links)
+        }
+    }
+}
+
+#[test_case]
+fn test_path_resolution() {
+    use super::test_fs::{detached_root, mount_over, TestFs};
+    use super::{mkdir, symlink};
+
+    let root = detached_root(TestFs::new());
+    mkdir(&root, "a", 0o755).unwrap();
+    mkdir(&root, "a/b", 0o755).unwrap();
+    symlink(&root, "a/b", "up").unwrap();
+    symlink(&root, "../..", "a/b/top").unwrap();
+    symlink(&root, "loop", "loop").unwrap();
+
+    let b = lookup(&root, "a/./b/", true).unwrap();
+    assert_eq!(b.path(), "/a/b");
+    assert_eq!(lookup(&b, "../..", true).unwrap().path(), "/");
+    assert!(lookup(&root, "up/top/a", true)
+        .unwrap()
+        .same_file(&lookup(&root, "a", true).unwrap()));
+    assert_eq!(
+        lookup(&root, "up", false).unwrap().kind(),
+        FileType::Symlink
+    );
+    assert_eq!(lookup(&root, "loop", true).err(), Some(Errno::ELOOP));
+    assert_eq!(lookup(&root, "a/missing", true).err(), Some(Errno::ENOENT));
+    assert_eq!(lookup_parent(&root, "a/b/new").unwrap().1, "new");
+
+    // Mounting over `a` hides `b`, and `..` leads back out of the mounted filesystem.
+    let a = lookup(&root, "a", true).unwrap();
+    let mounted = mount_over(a, TestFs::new());
+    mkdir(&mounted, "c", 0o755).unwrap();
+    assert_eq!(lookup(&root, "a/b", true).err(), Some(Errno::ENOENT));
+    let c = lookup(&root, "a/c", true).unwrap();
+    assert!(c.parent().is_mount_root());
+    assert_eq!(lookup(&c, "../..", true).unwrap().path(), "/");
+    assert_eq!(lookup(&root, "up", true).err(), Some(Errno::ENOENT));
+}
@@ -0,0 +1,467 @@
+//! A filesystem that describes the running kernel, which is mounted at `/proc`.
+//!
+//! Its files are written out afresh whenever they are read, so a file read in several pieces
+//! may change in between. They all have a size of zero, like on Linux.
+//!
+//! * `cpuinfo` describes the processor, as reported by `CPUID`.
+//! * `interrupts` counts how many times each interrupt vector has been raised.
+//! * `kmsg` is the kernel log. Unlike on Linux, reading it doesn't remove anything.
+//! * `meminfo` says how much memory there is, and how much of it is in use.
+//! * `uptime` is the number of seconds since boot.
+//! * `self` is a link to the directory of the process that reads it.
+//! * Each process has a directory named after its process ID, holding `status` and `maps`.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+};
+use core::{
+    arch::x86_64::{__cpuid, CpuidResult},
+    fmt::{self, Write},
+};
+
+use bootloader_api::info::MemoryRegionKind;
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    errno::Errno,
+    kernel_log, keyboard,
+    memory::{
+        frame, heap, page_align_up,
+        vma::{Backing, Protection},
+        PAGE_SIZE,
+    },
+    pic,
+    process::{self, Pid, Process, USER_STACK_TOP},
+    scheduler, serial, syscall, timer, trap,
+};
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcInode(Node::Root))
+    }
+}
+
+type Generator = fn(&mut String) -> fmt::Result;
+
+/// The files in the root directory, and the functions that write them out.
+const FILES: [(&str, Generator); 5] = [
+    ("cpuinfo", cpuinfo),
+    ("interrupts", interrupts),
+    ("kmsg", kmsg),
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+];
+
+/// The files in each process's directory.
+const PROCESS_FILES: [(&str, ProcessFile); 2] =
+    [("maps", ProcessFile::Maps), ("status", ProcessFile::Status)];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ProcessFile {
+    Maps,
+    Status,
+}
+
+#[derive(Clone, Copy)]
+enum Node {
+    Root,
+    SelfLink,
+    /// An index into [FILES].
+    File(usize),
+    Process(Pid),
+    ProcessFile(Pid, ProcessFile),
+}
+
+const ROOT_INODE: u64 = 1;
+const SELF_INODE: u64 = 2;
+const FIRST_FILE_INODE: u64 = 3;
+/// Each process's files are numbered from its directory's inode number, which is a multiple of this.
+const INODES_PER_PROCESS: u64 = 4;
+
+impl Node {
+    fn kind(self) -> FileType {
+        match self {
+            Node::Root | Node::Process(_) => FileType::Directory,
+            Node::SelfLink => FileType::Symlink,
+            Node::File(_) | Node::ProcessFile(..) => FileType::Regular,
+        }
+    }
+
+    fn inode(self) -> u64 {
+        let process_inode = |pid: Pid| (pid.0 as u64 + 1) * INODES_PER_PROCESS;
+        match self {
+            Node::Root => ROOT_INODE,
+            Node::SelfLink => SELF_INODE,
+            Node::File(index) => FIRST_FILE_INODE + index as u64,
+            Node::Process(pid) => process_inode(pid),
+            Node::ProcessFile(pid, ProcessFile::Maps) => process_inode(pid) + 1,
+            Node::ProcessFile(pid, ProcessFile::Status) => process_inode(pid) + 2,
+        }
+    }
+
+    fn entry(self, name: impl Into<String>) -> DirEntry {
+        DirEntry {
+            name: name.into(),
+            inode: self.inode(),
+            kind: self.kind(),
+        }
+    }
+}
+
+/// Finds the process with the given ID, failing if it has exited since it was looked up.
+fn find_process(pid: Pid) -> Result<Arc<Process>, Errno> {
+    process::find(pid).ok_or(Errno::ESRCH)
+}
+
+struct ProcInode(Node);
+
+impl ProcInode {
+    fn generate(&self) -> Result<String, Errno> {
+        let mut text = String::new();
+        let result = match self.0 {
+            Node::File(index) => FILES[index].1(&mut text),
+            Node::ProcessFile(pid, ProcessFile::Status) => status(&find_process(pid)?, &mut text),
+            Node::ProcessFile(pid, ProcessFile::Maps) => maps(&find_process(pid)?, &mut text),
+            _ => return Err(Errno::EINVAL),
+        };
+        result.expect("writing to a string can't fail");
+        Ok(text)
+    }
+}
+
+impl Inode for ProcInode {
+    fn kind(&self) -> FileType {
+        self.0.kind()
+    }
+
+    fn stat(&self) -> Stat {
+        let mode = match self.0.kind() {
+            FileType::Directory => 0o555,
+            FileType::Symlink => 0o777,
+            _ => 0o444,
+        };
+        Stat::new(self.0.inode(), self.0.kind(), mode, 0)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let text = self.generate()?;
+        let start = offset.min(text.len() as u64) as usize;
+        let len = buf.len().min(text.len() - start);
+        buf[..len].copy_from_slice(&text.as_bytes()[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let node = match self.0 {
+            Node::Root if name == "self" => Node::SelfLink,
+            Node::Root => match FILES.iter().position(|&(file, _)| file == name) {
+                Some(index) => Node::File(index),
+                None => {
+                    let pid = Pid(name.parse().map_err(|_| Errno::ENOENT)?);
+                    process::find(pid).ok_or(Errno::ENOENT)?;
+                    Node::Process(pid)
+                }
+            },
+            Node::Process(pid) => {
+                let &(_, file) = PROCESS_FILES
+                    .iter()
+                    .find(|&&(file, _)| file == name)
+                    .ok_or(Errno::ENOENT)?;
+                Node::ProcessFile(pid, file)
+            }
+            _ => return Err(Errno::ENOTDIR),
+        };
+        Ok(Arc::new(ProcInode(node)))
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        match self.0 {
+            Node::Root => {
+                if let Some(&(name, _)) = FILES.get(index) {
+                    return Ok(Some(Node::File(index).entry(name)));
+                }
+                if index == FILES.len() {
+                    return Ok(Some(Node::SelfLink.entry("self")));
+                }
+                let processes = process::all();
+                Ok(processes
+                    .get(index - FILES.len() - 1)
+                    .map(|process| Node::Process(process.pid()).entry(process.pid().to_string())))
+            }
+            Node::Process(pid) => Ok(PROCESS_FILES
+                .get(index)
+                .map(|&(name, file)| Node::ProcessFile(pid, file).entry(name))),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn read_link(&self) -> Result<String, Errno> {
+        match self.0 {
+            Node::SelfLink => scheduler::current_process()
+                .map(|process| process.pid().to_string())
+                .ok_or(Errno::ENOENT),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// Reads a range of `CPUID` leaves into a string, four bytes at a time.
+fn cpuid_string(
+    leaves: impl Iterator<Item = u32>,
+    registers: fn(CpuidResult) -> [u32; 4],
+) -> String {
+    let bytes: alloc::vec::Vec<u8> = leaves
+        .flat_map(|leaf| registers(__cpuid(leaf)))
+        .flat_map(u32::to_le_bytes)
+        .collect();
+    String::from_utf8_lossy(&bytes)
+        .trim_matches(|c: char| c == '\0' || c == ' ')
+        .into()
+}
+
+/// The `CPUID` feature bits that are listed in `cpuinfo`, as leaf, register, bit and name.
+/// Register 0 is `ebx`, 1 is `ecx` and 2 is `edx`.
+const FLAGS: &[(u32, usize, u32, &str)] = &[
+    (1, 2, 0, "fpu"),
+    (1, 2, 4, "tsc"),
+    (1, 2, 5, "msr"),
+    (1, 2, 6, "pae"),
+    (1, 2, 9, "apic"),
+    (1, 2, 15, "cmov"),
+    (1, 2, 19, "clflush"),
+    (1, 2, 23, "mmx"),
+    (1, 2, 24, "fxsr"),
+    (1, 2, 25, "sse"),
+    (1, 2, 26, "sse2"),
+    (1, 1, 0, "pni"),
+    (1, 1, 9, "ssse3"),
+    (1, 1, 12, "fma"),
+    (1, 1, 19, "sse4_1"),
+    (1, 1, 20, "sse4_2"),
+    (1, 1, 23, "popcnt"),
+    (1, 1, 25, "aes"),
+    (1, 1, 26, "xsave"),
+    (1, 1, 28, "avx"),
+    (1, 1, 30, "rdrand"),
+    (1, 1, 31, "hypervisor"),
+    (7, 0, 0, "fsgsbase"),
+    (7, 0, 3, "bmi1"),
+    (7, 0, 5, "avx2"),
+    (7, 0, 7, "smep"),
+    (7, 0, 8, "bmi2"),
+    (7, 0, 16, "avx512f"),
+    (7, 0, 18, "rdseed"),
+    (7, 0, 20, "smap"),
+    (0x8000_0001, 2, 11, "syscall"),
+    (0x8000_0001, 2, 20, "nx"),
+    (0x8000_0001, 2, 26, "pdpe1gb"),
+    (0x8000_0001, 2, 27, "rdtscp"),
+    (0x8000_0001, 2, 29, "lm"),
+];
+
+fn cpuinfo(out: &mut String) -> fmt::Result {
+    let max_leaf = __cpuid(0).eax;
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    let signature = __cpuid(1).eax;
+    let mut family = (signature >> 8) & 0xf;
+    let mut model = (signature >> 4) & 0xf;
+    if family == 0xf {
+        family += (signature >> 20) & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model += ((signature >> 16) & 0xf) << 4;
+    }
+
+    writeln!(out, "processor\t: 0")?;
+    let vendor = cpuid_string(0..1, |result| [result.ebx, result.edx, result.ecx, 0]);
+    writeln!(out, "vendor_id\t: {vendor}")?;
+    writeln!(out, "cpu family\t: {family}")?;
+    writeln!(out, "model\t\t: {model}")?;
+    if max_extended_leaf >= 0x8000_0004 {
+        let name = cpuid_string(0x8000_0002..0x8000_0005, |result| {
+            [result.eax, result.ebx, result.ecx, result.edx]
+        });
+        writeln!(out, "model name\t: {name}")?;
+    }
+    writeln!(out, "stepping\t: {}", signature & 0xf)?;
+    write!(out, "flags\t\t:")?;
+    for &(leaf, register, bit, name) in FLAGS {
+        let available = if leaf >= 0x8000_0000 {
+            leaf <= max_extended_leaf
+        } else {
+            leaf <= max_leaf
+        };
+        if !available {
+            continue;
+        }
+        let result = __cpuid(leaf);
+        if [result.ebx, result.ecx, result.edx][register] & (1 << bit) != 0 {
+            write!(out, " {name}")?;
+        }
+    }
+    writeln!(out)
+}
+
+fn interrupts(out: &mut String) -> fmt::Result {
+    for vector in 0..=u8::MAX {
+        let count = trap::interrupt_count(vector);
+        if count == 0 {
+            continue;
+        }
+        let name = match vector {
+            pic::PIC_1_OFFSET..pic::PIC_2_END => {
+                let irq = vector - pic::PIC_1_OFFSET;
+                let device = match irq {
+                    timer::TIMER_IRQ => " (timer)",
+                    keyboard::KEYBOARD_IRQ => " (keyboard)",
+                    serial::COM1_IRQ => " (COM1)",
+                    _ => "",
+                };
+                format!("IRQ {irq}{device}")
+            }
+            trap::MSI_VECTOR_START..trap::MSI_VECTOR_END => "MSI".into(),
+            syscall::SYSCALL_VECTOR => "system call".into(),
+            _ => trap::exception_name(vector).unwrap_or("unknown").into(),
+        };
+        writeln!(out, "{vector:>4}: {count:>10}  {name}")?;
+    }
+    Ok(())
+}
+
+fn kmsg(out: &mut String) -> fmt::Result {
+    out.push_str(&String::from_utf8_lossy(&kernel_log::contents()));
+    Ok(())
+}
+
+fn meminfo(out: &mut String) -> fmt::Result {
+    let kib = |bytes: u64| bytes / 1024;
+    let frames = frame::stats();
+    let (mut bootloader, mut reserved) = (0, 0);
+    for region in frame::regions() {
+        match region.kind {
+            MemoryRegionKind::Usable => {}
+            MemoryRegionKind::Bootloader => bootloader += region.end - region.start,
+            _ => reserved += region.end - region.start,
+        }
+    }
+    writeln!(
+        out,
+        "MemTotal:   {:>10} kB",
+        kib(frames.total as u64 * PAGE_SIZE)
+    )?;
+    writeln!(
+        out,
+        "MemFree:    {:>10} kB",
+        kib(frames.free() as u64 * PAGE_SIZE)
+    )?;
+    writeln!(
+        out,
+        "MemUsed:    {:>10} kB",
+        kib(frames.used as u64 * PAGE_SIZE)
+    )?;
+    writeln!(out, "KernelHeap: {:>10} kB", kib(heap::size()))?;
+    writeln!(out, "Bootloader: {:>10} kB", kib(bootloader))?;
+    writeln!(out, "Reserved:   {:>10} kB", kib(reserved))
+}
+
+fn uptime(out: &mut String) -> fmt::Result {
+    let uptime = timer::uptime();
+    writeln!(
+        out,
+        "{}.{:02}",
+        uptime.as_secs(),
+        uptime.subsec_millis() / 10
+    )
+}
+
+fn status(process: &Arc<Process>, out: &mut String) -> fmt::Result {
+    let inner = process.lock();
+    let state = if inner.exit_status.is_some() {
+        "exiting"
+    } else if inner.signals.stopped {
+        "stopped"
+    } else {
+        "running"
+    };
+    let mapped: u64 = inner.vmas.iter().map(|vma| vma.end - vma.start).sum();
+    writeln!(out, "Name:\t{}", process.name())?;
+    writeln!(out, "Pid:\t{}", process.pid())?;
+    writeln!(out, "State:\t{state}")?;
+    writeln!(out, "VmSize:\t{} kB", mapped / 1024)?;
+    writeln!(out, "Heap:\t{} kB", (inner.brk - inner.heap_start) / 1024)?;
+    writeln!(out, "Files:\t{}", inner.files.count())?;
+    writeln!(out, "SigPnd:\t{:016x}", inner.signals.pending.0)?;
+    writeln!(out, "SigBlk:\t{:016x}", inner.signals.blocked.0)?;
+    if let Some(cwd) = &inner.cwd {
+        writeln!(out, "Cwd:\t{}", cwd.path())?;
+    }
+    Ok(())
+}
+
+/// Lists the memory areas of a process, one per line, with their addresses, their protection,
+/// whether they are private or shared, the offset into what backs them, and what backs them.
+fn maps(process: &Arc<Process>, out: &mut String) -> fmt::Result {
+    let inner = process.lock();
+    for vma in inner.vmas.iter() {
+        let (shared, offset, name) = match &vma.backing {
+            Backing::Anonymous
+                if vma.start >= inner.heap_start && vma.end <= page_align_up(inner.brk) =>
+            {
+                (false, 0, "[heap]")
+            }
+            Backing::Anonymous if vma.end == USER_STACK_TOP => (false, 0, "[stack]"),
+            Backing::Anonymous => (false, 0, ""),
+            Backing::File { offset, .. } => (false, *offset, "[file]"),
+            Backing::Shared { offset, .. } => (true, *offset, "[shared]"),
+            Backing::Device { start, .. } => (true, start.as_u64(), "[device]"),
+        };
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        writeln!(
+            out,
+            "{:016x}-{:016x} {}{}{}{} {:08x} {}",
+            vma.start,
+            vma.end,
+            flag(vma.protection.contains(Protection::READ), 'r'),
+            flag(vma.protection.contains(Protection::WRITE), 'w'),
+            flag(vma.protection.contains(Protection::EXECUTE), 'x'),
+            if shared { 's' } else { 'p' },
+            offset,
+            name
+        )?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_procfs() {
+    use super::test_fs::detached_root;
+    use super::{lookup, read};
+
+    let root = detached_root(Arc::new(ProcFs));
+    let text = |path| String::from_utf8(read(&root, path).unwrap()).unwrap();
+    assert!(text("meminfo").starts_with("MemTotal:"));
+    assert!(text("cpuinfo").contains("vendor_id"));
+    assert!(text("kmsg").contains("Running"));
+    assert!(text("interrupts").contains("(timer)"));
+    let names: alloc::vec::Vec<_> = (0..)
+        .map_while(|index| root.inode().read_dir(index).unwrap())
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(
+        names[..6],
+        ["cpuinfo", "interrupts", "kmsg", "meminfo", "uptime", "self"]
+    );
+    // The tests don't run in a process.
+    assert_eq!(
+        lookup(&root, "self/status", true).err(),
+        Some(Errno::ENOENT)
+    );
+}
@@ -0,0 +1,272 @@
+//! A read-only filesystem made from a `tar` archive in memory, which is how the ramdisk is mounted.
+//!
+//! The archive is read once when the filesystem is made, into a table of nodes.
+//! File contents are not copied: they are read straight out of the archive.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    errno::Errno,
+    tar::{self, EntryKind, TarError},
+};
+
+enum Contents {
+    File(&'static [u8]),
+    /// The entries of a directory, as indices into the node table.
+    Directory(BTreeMap<String, usize>),
+    Symlink(&'static str),
+}
+
+struct Node {
+    contents: Contents,
+    mode: u32,
+    links: u64,
+}
+
+impl Node {
+    fn new(contents: Contents, mode: u32) -> Self {
+        Self {
+            contents,
+            mode,
+            links: 0,
+        }
+    }
+}
+
+pub struct TarFs {
+    /// The root directory is the first node. A node's inode number is one more than its index.
+    nodes: Arc<Vec<Node>>,
+}
+
+impl TarFs {
+    /// Reads the whole archive.
+    /// Directories that are missing from the archive are made, and entries for things
+    /// other than files, directories and links are skipped.
+    pub fn new(archive: &'static [u8]) -> Result<Arc<Self>, TarError> {
+        let mut nodes = vec![Node::new(Contents::Directory(BTreeMap::new()), 0o755)];
+        for entry in tar::entries(archive) {
+            let entry = entry?;
+            let (dir, name) = match entry.path.rsplit_once('/') {
+                Some((dir, name)) => (make_dirs(&mut nodes, dir), name),
+                None => (0, entry.path.as_str()),
+            };
+            let index = match entry.kind {
+                _ if name.is_empty() => {
+                    // This is the root directory itself.
+                    nodes[0].mode = entry.mode;
+                    continue;
+                }
+                EntryKind::Directory => {
+                    if let Some(existing) = child(&nodes, dir, name) {
+                        if matches!(nodes[existing].contents, Contents::Directory(_)) {
+                            nodes[existing].mode = entry.mode;
+                            continue;
+                        }
+                    }
+                    push(&mut nodes, Contents::Directory(BTreeMap::new()), entry.mode)
+                }
+                EntryKind::File => push(&mut nodes, Contents::File(entry.data), entry.mode),
+                EntryKind::Symlink => push(&mut nodes, Contents::Symlink(entry.link_target), 0o777),
+                EntryKind::HardLink => match find(&nodes, entry.link_target) {
+                    Some(target) if !matches!(nodes[target].contents, Contents::Directory(_)) => {
+                        target
+                    }
+                    _ => continue,
+                },
+                EntryKind::Other(_) => continue,
+            };
+            let Contents::Directory(entries) = &mut nodes[dir].contents else {
+                unreachable!("parents are directories");
+            };
+            if let Some(replaced) = entries.insert(name.into(), index) {
+                nodes[replaced].links -= 1;
+            }
+            nodes[index].links += 1;
+        }
+        Ok(Arc::new(Self {
+            nodes: Arc::new(nodes),
+        }))
+    }
+}
+
+fn push(nodes: &mut Vec<Node>, contents: Contents, mode: u32) -> usize {
+    nodes.push(Node::new(contents, mode));
+    nodes.len() - 1
+}
+
+fn child(nodes: &[Node], dir: usize, name: &str) -> Option<usize> {
+    match &nodes[dir].contents {
+        Contents::Directory(entries) => entries.get(name).copied(),
+        _ => None,
+    }
+}
+
+/// Finds the node at a path relative to the root, without following symbolic links.
+fn find(nodes: &[Node], path: &str) -> Option<usize> {
+    path.trim_start_matches("./")
+        .split('/')
+        .filter(|name| !name.is_empty())
+        .try_fold(0, |dir, name| child(nodes, dir, name))
+}
+
+/// Returns the directory at a path relative to the root, making any directories that are missing.
+/// Anything that is in the way of a directory is replaced.
+fn make_dirs(nodes: &mut Vec<Node>, path: &str) -> usize {
+    let mut dir = 0;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        dir = match child(nodes, dir, name) {
+            Some(next) if matches!(nodes[next].contents, Contents::Directory(_)) => next,
+            existing => {
+                let next = push(nodes, Contents::Directory(BTreeMap::new()), 0o755);
+                nodes[next].links = 1;
+                if let Some(existing) = existing {
+                    nodes[existing].links -= 1;
+                }
+                let Contents::Directory(entries) = &mut nodes[dir].contents else {
+                    unreachable!("parents are directories");
+                };
+                entries.insert(name.into(), next);
+                next
+            }
+        };
+    }
+    dir
+}
+
+impl FileSystem for TarFs {
+    fn name(&self) -> &'static str {
+        "tarfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(TarInode {
+            nodes: self.nodes.clone(),
+            index: 0,
+        })
+    }
+}
+
+struct TarInode {
+    nodes: Arc<Vec<Node>>,
+    index: usize,
+}
+
+impl TarInode {
+    fn node(&self) -> &Node {
+        &self.nodes[self.index]
+    }
+
+    fn at(&self, index: usize) -> Arc<dyn Inode> {
+        Arc::new(TarInode {
+            nodes: self.nodes.clone(),
+            index,
+        })
+    }
+}
+
+fn kind(node: &Node) -> FileType {
+    match node.contents {
+        Contents::File(_) => FileType::Regular,
+        Contents::Directory(_) => FileType::Directory,
+        Contents::Symlink(_) => FileType::Symlink,
+    }
+}
+
+impl Inode for TarInode {
+    fn kind(&self) -> FileType {
+        kind(self.node())
+    }
+
+    fn stat(&self) -> Stat {
+        let node = self.node();
+        let size = match node.contents {
+            Contents::File(data) => data.len() as u64,
+            Contents::Directory(_) => 0,
+            Contents::Symlink(target) => target.len() as u64,
+        };
+        Stat {
+            links: node.links.max(1),
+            ..Stat::new(self.index as u64 + 1, kind(node), node.mode, size)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let Contents::File(data) = self.node().contents else {
+            return Err(Errno::EINVAL);
+        };
+        let start = offset.min(data.len() as u64) as usize;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let index = child(&self.nodes, self.index, name).ok_or(Errno::ENOENT)?;
+        Ok(self.at(index))
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let Contents::Directory(entries) = &self.node().contents else {
+            return Err(Errno::ENOTDIR);
+        };
+        Ok(entries.iter().nth(index).map(|(name, &node)| DirEntry {
+            name: name.clone(),
+            inode: node as u64 + 1,
+            kind: kind(&self.nodes[node]),
+        }))
+    }
+
+    fn read_link(&self) -> Result<String, Errno> {
+        match self.node().contents {
+            Contents::Symlink(target) => Ok(target.into()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+#[test_case]
+fn test_tarfs() {
+    use super::test_fs::detached_root;
+    use super::{lookup, open, OpenFlags};
+    use crate::file::File;
+
+    fn header(name: &str, kind: u8, link_target: &str, data: &[u8]) -> Vec<u8> {
+        let mut block = vec![0; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        let size = alloc::format!("{:011o}", data.len());
+        block[124..135].copy_from_slice(size.as_bytes());
+        block[156] = kind;
+        block[157..157 + link_target.len()].copy_from_slice(link_target.as_bytes());
+        block[257..262].copy_from_slice(b"ustar");
+        block.extend_from_slice(data);
+        block.resize(block.len().next_multiple_of(512), 0);
+        block
+    }
+
+    let archive: Vec<u8> = [
+        header("./", b'5', "", &[]),
+        header("etc/motd", b'0', "", b"hello"),
+        header("etc/issue", b'2', "motd", &[]),
+        header("etc/greeting", b'1', "etc/motd", &[]),
+    ]
+    .concat();
+    let fs = TarFs::new(Vec::leak(archive)).unwrap();
+    let root = detached_root(fs);
+
+    let file = open(&root, "etc/issue", OpenFlags::empty(), 0).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(file.read(&mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"hello");
+    let motd = lookup(&root, "etc/motd", true).unwrap().stat();
+    assert_eq!(motd.links, 2);
+    assert_eq!(motd.mode, 0o644);
+    assert!(lookup(&root, "etc/greeting", true)
+        .unwrap()
+        .same_file(&lookup(&root, "etc/motd", true).unwrap()));
+    assert_eq!(
+        open(&root, "etc/new", OpenFlags::CREATE, 0o644).err(),
+        Some(Errno::EROFS)
+    );
+}
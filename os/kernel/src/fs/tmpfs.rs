@@ -0,0 +1,407 @@
+//! A writable filesystem that keeps everything in memory, which is mounted at `/tmp`.
+//!
+//! The contents of a file are kept in [SharedMemory], so they take up whole pages of physical memory,
+//! which are only allocated once something is written to them, and can be mapped shared.
+//! Everything that a file uses is freed once it has no names left and nothing has it open.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+
+use super::{now, DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::{
+    errno::Errno,
+    memory::{shared::SharedMemory, PAGE_SIZE},
+};
+
+/// The largest that a file can be.
+const MAX_FILE_SIZE: u64 = 1 << 40;
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            root: TmpInode::new(Contents::Directory(Mutex::default()), 0o1777),
+        })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Contents {
+    File(Arc<SharedMemory>),
+    Directory(Mutex<BTreeMap<String, Arc<TmpInode>>>),
+    Symlink(String),
+}
+
+/// The parts of an inode that change.
+struct Metadata {
+    mode: u32,
+    /// The number of names that the inode has.
+    /// For directories, this counts `.` and the `..` of each subdirectory as well.
+    links: u64,
+    size: u64,
+    accessed: u64,
+    modified: u64,
+    changed: u64,
+}
+
+struct TmpInode {
+    number: u64,
+    contents: Contents,
+    metadata: Mutex<Metadata>,
+}
+
+impl TmpInode {
+    fn new(contents: Contents, mode: u32) -> Arc<Self> {
+        static NEXT_NUMBER: AtomicU64 = AtomicU64::new(1);
+        let time = now();
+        let (links, size) = match &contents {
+            Contents::Directory(_) => (2, 0),
+            Contents::Symlink(target) => (1, target.len() as u64),
+            Contents::File(_) => (1, 0),
+        };
+        Arc::new(Self {
+            number: NEXT_NUMBER.fetch_add(1, Ordering::Relaxed),
+            contents,
+            metadata: Mutex::new(Metadata {
+                mode,
+                links,
+                size,
+                accessed: time,
+                modified: time,
+                changed: time,
+            }),
+        })
+    }
+
+    fn entries(&self) -> &Mutex<BTreeMap<String, Arc<TmpInode>>> {
+        match &self.contents {
+            Contents::Directory(entries) => entries,
+            _ => unreachable!("the VFS only uses directory operations on directories"),
+        }
+    }
+
+    fn memory(&self) -> Result<&Arc<SharedMemory>, Errno> {
+        match &self.contents {
+            Contents::File(memory) => Ok(memory),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        matches!(self.contents, Contents::Directory(_))
+    }
+
+    /// Records that the contents of a directory changed.
+    fn touch(&self) {
+        let mut metadata = self.metadata.lock();
+        metadata.modified = now();
+        metadata.changed = metadata.modified;
+    }
+
+    /// Adds to or takes from the link count.
+    fn adjust_links(&self, delta: i64) {
+        let mut metadata = self.metadata.lock();
+        metadata.links = metadata.links.saturating_add_signed(delta);
+        metadata.changed = now();
+    }
+
+    /// Adds a new entry, which must not exist already.
+    fn add(&self, name: &str, inode: Arc<TmpInode>) -> Result<(), Errno> {
+        let mut entries = self.entries().lock();
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        if inode.is_directory() {
+            // The new directory's `..`.
+            self.adjust_links(1);
+        }
+        entries.insert(name.into(), inode);
+        drop(entries);
+        self.touch();
+        Ok(())
+    }
+
+    /// Removes an entry after checking it, and marks it as having one name fewer.
+    fn remove(
+        &self,
+        name: &str,
+        check: impl FnOnce(&TmpInode) -> Result<(), Errno>,
+    ) -> Result<(), Errno> {
+        let mut entries = self.entries().lock();
+        let inode = entries.get(name).ok_or(Errno::ENOENT)?;
+        check(inode)?;
+        let inode = entries.remove(name).expect("entry was just found");
+        drop(entries);
+        if inode.is_directory() {
+            self.adjust_links(-1);
+            inode.adjust_links(-2);
+        } else {
+            inode.adjust_links(-1);
+        }
+        self.touch();
+        Ok(())
+    }
+}
+
+/// Checks that an entry can be replaced by, or removed as, a directory or not.
+fn check_replaceable(inode: &TmpInode, directory: bool) -> Result<(), Errno> {
+    match (inode.is_directory(), directory) {
+        (true, true) if !inode.entries().lock().is_empty() => Err(Errno::ENOTEMPTY),
+        (true, false) => Err(Errno::EISDIR),
+        (false, true) => Err(Errno::ENOTDIR),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for TmpInode {
+    fn kind(&self) -> FileType {
+        match self.contents {
+            Contents::File(_) => FileType::Regular,
+            Contents::Directory(_) => FileType::Directory,
+            Contents::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        let metadata = self.metadata.lock();
+        let blocks = match &self.contents {
+            Contents::File(memory) => memory.resident_pages() as u64 * (PAGE_SIZE / 512),
+            _ => 0,
+        };
+        Stat {
+            links: metadata.links,
+            blocks,
+            accessed: metadata.accessed,
+            modified: metadata.modified,
+            changed: metadata.changed,
+            ..Stat::new(self.number, self.kind(), metadata.mode, metadata.size)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let memory = self.memory()?;
+        let mut metadata = self.metadata.lock();
+        let len = buf.len().min(metadata.size.saturating_sub(offset) as usize);
+        memory.read(offset, &mut buf[..len]);
+        metadata.accessed = now();
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let memory = self.memory()?;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(Errno::EINVAL)?;
+        memory.write(offset, buf).map_err(|_| Errno::ENOSPC)?;
+        let mut metadata = self.metadata.lock();
+        metadata.size = metadata.size.max(end);
+        metadata.modified = now();
+        metadata.changed = metadata.modified;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        let memory = self.memory()?;
+        if size > MAX_FILE_SIZE {
+            return Err(Errno::EINVAL);
+        }
+        let mut metadata = self.metadata.lock();
+        if size < metadata.size {
+            memory.discard_from(size);
+        }
+        metadata.size = size;
+        metadata.modified = now();
+        metadata.changed = metadata.modified;
+        Ok(())
+    }
+
+    fn shared_memory(&self) -> Result<Arc<SharedMemory>, Errno> {
+        self.memory().cloned()
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let inode = self.entries().lock().get(name).cloned();
+        inode
+            .map(|inode| inode as Arc<dyn Inode>)
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        self.metadata.lock().accessed = now();
+        Ok(self
+            .entries()
+            .lock()
+            .iter()
+            .nth(index)
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                inode: inode.number,
+                kind: inode.kind(),
+            }))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, Errno> {
+        let contents = match kind {
+            FileType::Regular => Contents::File(SharedMemory::new()),
+            FileType::Directory => Contents::Directory(Mutex::default()),
+            _ => return Err(Errno::EINVAL),
+        };
+        let inode = TmpInode::new(contents, mode);
+        self.add(name, inode.clone())?;
+        Ok(inode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let inode = TmpInode::new(Contents::Symlink(target.into()), 0o777);
+        self.add(name, inode.clone())?;
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), Errno> {
+        let inode = downcast(inode)?;
+        self.add(name, inode.clone())?;
+        inode.adjust_links(1);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        self.remove(name, |inode| check_replaceable(inode, false))
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), Errno> {
+        self.remove(name, |inode| check_replaceable(inode, true))
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &Arc<dyn Inode>,
+        new_name: &str,
+    ) -> Result<(), Errno> {
+        let new_parent = downcast(new_parent)?;
+        let inode = self
+            .entries()
+            .lock()
+            .get(old_name)
+            .cloned()
+            .ok_or(Errno::ENOENT)?;
+        let directory = inode.is_directory();
+        let existing = new_parent.entries().lock().get(new_name).cloned();
+        if let Some(existing) = existing {
+            check_replaceable(&existing, directory)?;
+            new_parent.remove(new_name, |_| Ok(()))?;
+        }
+        self.entries().lock().remove(old_name);
+        if directory {
+            self.adjust_links(-1);
+        }
+        self.touch();
+        new_parent.add(new_name, inode.clone())?;
+        inode.metadata.lock().changed = now();
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, Errno> {
+        match &self.contents {
+            Contents::Symlink(target) => {
+                self.metadata.lock().accessed = now();
+                Ok(target.clone())
+            }
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// Gets at a tmpfs inode, which the VFS has checked is in the same filesystem.
+fn downcast(inode: &Arc<dyn Inode>) -> Result<Arc<TmpInode>, Errno> {
+    (inode.clone() as Arc<dyn Any + Send + Sync>)
+        .downcast()
+        .map_err(|_| Errno::EXDEV)
+}
+
+#[test_case]
+fn test_tmpfs_operations() {
+    use super::test_fs::detached_root;
+    use super::{link, lookup, mkdir, open, rename, rmdir, stat, unlink, OpenFlags};
+    use crate::file::File;
+
+    let root = detached_root(TmpFs::new());
+    let flags = OpenFlags::CREATE | OpenFlags::READ_WRITE;
+    let file = open(&root, "a", flags, 0o644).unwrap();
+    assert_eq!(file.write(b"hello, world"), Ok(12));
+    file.truncate(5).unwrap();
+    file.truncate(8).unwrap();
+    let mut buf = [0xff; 16];
+    assert_eq!(file.read_at(0, &mut buf), Ok(8));
+    assert_eq!(&buf[..8], b"hello\0\0\0");
+
+    link(&root, "a", "b").unwrap();
+    assert_eq!(stat(&root, "a", true).unwrap().links, 2);
+    mkdir(&root, "dir", 0o755).unwrap();
+    rename(&root, "b", "dir/c").unwrap();
+    assert!(lookup(&root, "dir/c", true)
+        .unwrap()
+        .same_file(&lookup(&root, "a", true).unwrap()));
+    assert_eq!(rmdir(&root, "dir"), Err(Errno::ENOTEMPTY));
+    assert_eq!(rename(&root, "dir", "dir/d"), Err(Errno::EINVAL));
+    assert_eq!(unlink(&root, "dir"), Err(Errno::EISDIR));
+    unlink(&root, "dir/c").unwrap();
+    assert_eq!(stat(&root, "a", true).unwrap().links, 1);
+    rmdir(&root, "dir").unwrap();
+    assert_eq!(stat(&root, ".", true).unwrap().links, 2);
+}
+
+#[test_case]
+fn test_tmpfs_frees_memory() {
+    use super::test_fs::detached_root;
+    use super::{open, unlink, OpenFlags};
+    use crate::{file::File, memory::frame};
+
+    let root = detached_root(TmpFs::new());
+    let page = [0x5a; PAGE_SIZE as usize];
+    let mut free_frames = 0;
+    for round in 0..50 {
+        // The first round warms up the heap, which keeps the frames it grows into.
+        if round == 1 {
+            free_frames = frame::stats().free();
+        }
+        let name = alloc::format!("file{}", round % 5);
+        let file = open(
+            &root,
+            &name,
+            OpenFlags::CREATE | OpenFlags::WRITE_ONLY | OpenFlags::TRUNCATE,
+            0o644,
+        )
+        .unwrap();
+        for _ in 0..(round % 7 + 1) {
+            file.write(&page).unwrap();
+        }
+        // Open files keep their contents after they are unlinked.
+        if round % 3 == 0 {
+            unlink(&root, &name).unwrap();
+            assert_eq!(file.stat().unwrap().size, (round % 7 + 1) * PAGE_SIZE);
+        }
+    }
+    for name in ["file0", "file1", "file2", "file3", "file4"] {
+        let _ = unlink(&root, name);
+    }
+    assert_eq!(frame::stats().free(), free_frames);
+}
@@ -5,29 +5,25 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-        let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        stack_start + STACK_SIZE as u64
-    };
-    tss
-});
+/// The task state segment.
+/// Its first privilege stack is the kernel stack of the current task,
+/// which the CPU switches to when an interrupt arrives in user mode,
+/// so it is updated on every context switch.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::empty();
     let code_selector = gdt.append(Descriptor::kernel_code_segment());
     gdt.append(Descriptor::kernel_data_segment());
-    gdt.append(Descriptor::user_code_segment());
-    gdt.append(Descriptor::user_data_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS) });
     (
         gdt,
         Selectors {
             code_selector,
+            user_code_selector,
+            user_data_selector,
             tss_selector,
         },
     )
@@ -35,6 +31,8 @@ static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
 
 struct Selectors {
     code_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
@@ -42,9 +40,38 @@ pub fn init() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
+    let tss = &raw mut TSS;
+    unsafe {
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            stack_start + STACK_SIZE as u64
+        };
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// The code segment selector for user mode, with the requested privilege level set to ring 3.
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+/// The data segment selector for user mode, with the requested privilege level set to ring 3.
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// Sets the stack that the CPU switches to when an interrupt arrives in user mode.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    let tss = &raw mut TSS;
+    unsafe {
+        (*tss).privilege_stack_table[0] = stack_top;
+    }
+}
@@ -0,0 +1,103 @@
+//! Starting and supervising the first user process.
+//!
+//! Once the kernel has finished initialising, it runs the init program from the root filesystem.
+//! If init exits with [POWER_OFF_STATUS], the machine is powered off.
+//! Otherwise, init is restarted a few times before the kernel gives up on it.
+//!
+//! Before init, the kernel runs the `ping` and `pong` programs, which check that message passing works.
+
+use crate::{
+    acpi, block,
+    capability::{Capability, CapabilityTable, Rights},
+    file::FileTable,
+    ipc::Endpoint,
+    println,
+    process::Process,
+    qemu::{self, QemuExitCode},
+    scheduler,
+    task::Task,
+};
+
+/// The path of the init program.
+/// This can be changed by setting `FUNCOS_INIT` when building the kernel,
+/// or with the `init` setting in [crate::config].
+pub const INIT_PATH: &str = match option_env!("FUNCOS_INIT") {
+    Some(path) => path,
+    None => "/bin/init",
+};
+
+/// When init exits with this status, the machine is powered off.
+pub const POWER_OFF_STATUS: i32 = 0;
+
+/// The number of times that init is restarted after exiting with some other status.
+const MAX_RESPAWNS: usize = 3;
+
+/// Schedules a kernel thread that starts and supervises init.
+pub fn start() {
+    scheduler::spawn(Task::new_kernel("init supervisor", supervise));
+}
+
+fn supervise() {
+    run_ping_pong();
+
+    let path = crate::config::get("init").unwrap_or_else(|| INIT_PATH.into());
+    for attempt in 0..=MAX_RESPAWNS {
+        if attempt > 0 {
+            println!("Restarting init ({attempt}/{MAX_RESPAWNS}).");
+        }
+        let init = match Process::spawn_path(
+            &path,
+            &[&path],
+            FileTable::with_console(),
+            CapabilityTable::default(),
+        ) {
+            Ok(init) => init,
+            Err(err) => {
+                println!("Could not start init from {path}: {err:?}");
+                return;
+            }
+        };
+        let status = init.wait();
+        if status == POWER_OFF_STATUS {
+            println!("Init exited. Powering off.");
+            power_off();
+        }
+        println!("Init exited with status {status}.");
+    }
+    println!("Init keeps exiting, so it will not be restarted again.");
+}
+
+/// Runs `/bin/ping` and `/bin/pong`, each with a capability to the same endpoint as handle 0,
+/// and waits for them to finish.
+fn run_ping_pong() {
+    let endpoint = Endpoint::new();
+    let spawn = |path: &str, rights| {
+        let mut capabilities = CapabilityTable::default();
+        capabilities
+            .insert(Capability::Endpoint(endpoint.clone(), rights))
+            .expect("capability table is empty");
+        Process::spawn_path(path, &[path], FileTable::with_console(), capabilities)
+    };
+    let (ping, pong) = match (
+        spawn("/bin/ping", Rights::SEND | Rights::GRANT),
+        spawn("/bin/pong", Rights::RECEIVE),
+    ) {
+        (Ok(ping), Ok(pong)) => (ping, pong),
+        (ping, pong) => {
+            println!("Could not start ping and pong: {:?}", ping.and(pong).err());
+            return;
+        }
+    };
+    let statuses = (ping.wait(), pong.wait());
+    println!("Ping and pong exited with statuses {statuses:?}.");
+}
+
+/// Turns the machine off, through ACPI if possible, or otherwise by asking QEMU to exit.
+/// Anything written to disks that is still in the cache is written back first.
+pub fn power_off() -> ! {
+    if let Err(errno) = block::cache::CACHE.sync() {
+        println!("Could not write back the disk cache: {errno:?}");
+    }
+    acpi::power_off();
+    qemu::exit_qemu(QemuExitCode::Success);
+}
@@ -0,0 +1,109 @@
+//! Buffers for bytes that arrive from devices, such as the keyboard and the serial port.
+//!
+//! Interrupt handlers push bytes in, and tasks block until there is something to read,
+//! or wait for any of several files with [file::wait_ready].
+//! The buffer has a fixed size and never allocates, since it is filled by interrupt handlers,
+//! which could have interrupted the heap. Bytes that arrive while it is full are dropped.
+
+use crate::{
+    errno::Errno,
+    file::{self, POLLIN, POLLOUT},
+    scheduler::WaitQueue,
+    sync::IrqMutex,
+};
+
+/// The most bytes that can be waiting in an [InputBuffer].
+pub const INPUT_CAPACITY: usize = 256;
+
+struct Ring {
+    bytes: [u8; INPUT_CAPACITY],
+    start: usize,
+    len: usize,
+}
+
+pub struct InputBuffer {
+    ring: IrqMutex<Ring>,
+    /// Woken when bytes are pushed.
+    readable: WaitQueue,
+}
+
+impl InputBuffer {
+    pub const fn new() -> Self {
+        Self {
+            ring: IrqMutex::new(Ring {
+                bytes: [0; INPUT_CAPACITY],
+                start: 0,
+                len: 0,
+            }),
+            readable: WaitQueue::new(),
+        }
+    }
+
+    /// Adds bytes to the end of the buffer, dropping any that don't fit, and wakes the readers.
+    pub fn push(&self, bytes: &[u8]) {
+        {
+            let mut ring = self.ring.lock();
+            for &byte in bytes {
+                if ring.len == INPUT_CAPACITY {
+                    break;
+                }
+                let end = (ring.start + ring.len) % INPUT_CAPACITY;
+                ring.bytes[end] = byte;
+                ring.len += 1;
+            }
+        }
+        self.readable.wake_all();
+        file::notify_ready();
+    }
+
+    /// See [file::File::poll]. Input can only be read, but writes go elsewhere and never block.
+    pub fn poll(&self) -> u16 {
+        match self.ring.lock().len {
+            0 => POLLOUT,
+            _ => POLLIN | POLLOUT,
+        }
+    }
+
+    /// Blocks until at least one byte is available, and then reads as many as fit into `buf`.
+    /// Fails with [Errno::EINTR] if the process has a signal to handle first.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.readable.wait_interruptible(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == 0 {
+                return None;
+            }
+            let len = buf.len().min(ring.len);
+            for byte in &mut buf[..len] {
+                *byte = ring.bytes[ring.start];
+                ring.start = (ring.start + 1) % INPUT_CAPACITY;
+                ring.len -= 1;
+            }
+            Some(len)
+        })
+    }
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_input_buffer() {
+    let input = InputBuffer::new();
+    input.push(&[1; INPUT_CAPACITY - 1]);
+    input.push(&[2, 3]);
+    let mut buf = [0; INPUT_CAPACITY];
+    assert_eq!(input.poll(), POLLIN | POLLOUT);
+    assert_eq!(input.read(&mut buf), Ok(INPUT_CAPACITY));
+    assert_eq!(input.poll(), POLLOUT);
+    assert_eq!(buf[INPUT_CAPACITY - 1], 2);
+    input.push(b"ab");
+    assert_eq!(input.read(&mut buf[..1]), Ok(1));
+    assert_eq!(input.read(&mut buf), Ok(1));
+    assert_eq!(buf[0], b'b');
+}
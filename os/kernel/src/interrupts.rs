@@ -1,17 +1,17 @@
-use crate::{gdt, serial::COM1_SERIAL, serial_println};
+use crate::{apic, gdt, serial::COM1_SERIAL, serial_println, trap};
 use spin::Lazy;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
     unsafe {
         idt.double_fault
             .set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
+    trap::register_entries(&mut idt);
+    idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
     idt
 });
 
@@ -23,19 +23,8 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    code: u64,
-) {
-    serial_println!("EXCEPTION: GENERAL PROTECTION FAULT {}\n{:#?}", code, stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    code: PageFaultErrorCode,
-) {
-    serial_println!("EXCEPTION: PAGE FAULT {:?}\n{:#?}", code, stack_frame);
-}
+/// Spurious interrupts from the local APIC must not be acknowledged, so there is nothing to do.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
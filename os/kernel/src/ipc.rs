@@ -0,0 +1,160 @@
+//! Synchronous message passing between processes, in the style of a microkernel.
+//!
+//! An [Endpoint] is a rendezvous point: [Endpoint::send] blocks until a receiver takes the message,
+//! and [Endpoint::receive] blocks until a sender arrives. A message carries a few bytes of data,
+//! and can also carry capabilities, which are installed in the receiver's capability table.
+//!
+//! [Endpoint::call] sends a message along with a one-shot [Reply] capability,
+//! and then blocks until the receiver uses it to reply.
+//!
+//! Each of these fails with `EINTR` if the process has a signal to handle while it waits.
+//! A message that was interrupted before it was received is taken back.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{capability::Capability, errno::Errno, scheduler::WaitQueue, sync::IrqMutex};
+
+/// The most bytes of data that a message can carry.
+pub const MAX_DATA: usize = 64;
+/// The most capabilities that a message can carry.
+pub const MAX_CAPABILITIES: usize = 4;
+
+pub struct Message {
+    pub data: Vec<u8>,
+    pub capabilities: Vec<Capability>,
+    /// Set if the sender is waiting for a reply.
+    pub reply: Option<Reply>,
+}
+
+impl Message {
+    pub fn new(data: &[u8], capabilities: Vec<Capability>) -> Result<Self, Errno> {
+        if data.len() > MAX_DATA || capabilities.len() > MAX_CAPABILITIES {
+            return Err(Errno::E2BIG);
+        }
+        Ok(Self {
+            data: data.into(),
+            capabilities,
+            reply: None,
+        })
+    }
+}
+
+/// A message waiting to be received.
+struct Transfer {
+    /// This is taken by the receiver.
+    message: IrqMutex<Option<Message>>,
+    /// Woken when the message has been taken.
+    taken: WaitQueue,
+}
+
+pub struct Endpoint {
+    queue: IrqMutex<VecDeque<Arc<Transfer>>>,
+    /// Woken when a message is queued.
+    receivers: WaitQueue,
+}
+
+impl Endpoint {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            queue: IrqMutex::new(VecDeque::new()),
+            receivers: WaitQueue::new(),
+        })
+    }
+
+    /// Blocks until a receiver has taken the message.
+    pub fn send(&self, message: Message) -> Result<(), Errno> {
+        let transfer = Arc::new(Transfer {
+            message: IrqMutex::new(Some(message)),
+            taken: WaitQueue::new(),
+        });
+        self.queue.lock().push_back(transfer.clone());
+        self.receivers.wake_all();
+        let result = transfer
+            .taken
+            .wait_interruptible(|| transfer.message.lock().is_none().then_some(()));
+        if result.is_err() {
+            self.queue
+                .lock()
+                .retain(|queued| !Arc::ptr_eq(queued, &transfer));
+            let unsent = transfer.message.lock().take();
+            if unsent.is_none() {
+                // A receiver took it just before the signal arrived.
+                return Ok(());
+            }
+        }
+        result
+    }
+
+    /// Blocks until a message arrives, and returns it.
+    pub fn receive(&self) -> Result<Message, Errno> {
+        let transfer = self
+            .receivers
+            .wait_interruptible(|| self.queue.lock().pop_front())?;
+        let message = transfer
+            .message
+            .lock()
+            .take()
+            .expect("message received twice");
+        transfer.taken.wake_all();
+        Ok(message)
+    }
+
+    /// Sends a message, and then blocks until the receiver replies.
+    pub fn call(&self, mut message: Message) -> Result<Message, Errno> {
+        let slot = Arc::new(ReplySlot {
+            reply: IrqMutex::new(None),
+            ready: WaitQueue::new(),
+        });
+        message.reply = Some(Reply(Some(slot.clone())));
+        self.send(message)?;
+        slot.ready.wait_interruptible(|| slot.reply.lock().take())?
+    }
+}
+
+/// Where a reply is left for a caller.
+struct ReplySlot {
+    reply: IrqMutex<Option<Result<Message, Errno>>>,
+    /// Woken when the reply has been left.
+    ready: WaitQueue,
+}
+
+/// The right to reply to a call.
+/// If this is dropped without replying, the call fails with `EPIPE`.
+pub struct Reply(Option<Arc<ReplySlot>>);
+
+impl Reply {
+    pub fn reply(mut self, message: Message) {
+        self.fill(Ok(message));
+    }
+
+    fn fill(&mut self, result: Result<Message, Errno>) {
+        if let Some(slot) = self.0.take() {
+            *slot.reply.lock() = Some(result);
+            slot.ready.wake_all();
+        }
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        self.fill(Err(Errno::EPIPE));
+    }
+}
+
+/// The layout of a message in user memory, as passed to the IPC system calls.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct UserMessage {
+    pub len: u64,
+    pub data: [u8; MAX_DATA],
+    pub handle_count: u64,
+    /// Handles of capabilities to send, or of the capabilities that were received.
+    pub handles: [u64; MAX_CAPABILITIES],
+    /// Set on receipt to the handle of the [Reply] capability if the sender is waiting for a reply,
+    /// or to [NO_HANDLE] otherwise.
+    pub reply: u64,
+}
+
+pub const NO_HANDLE: u64 = u64::MAX;
@@ -0,0 +1,55 @@
+//! The kernel log: a copy of the most recent text that the kernel printed,
+//! which can be read back from `/proc/kmsg`.
+//!
+//! Everything printed with [crate::serial_print] or [crate::print] is recorded here.
+//! Output from user programs is not.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+/// How many bytes of the log are kept. Older text is forgotten.
+pub const LOG_CAPACITY: usize = 64 * 1024;
+
+struct Ring {
+    bytes: [u8; LOG_CAPACITY],
+    /// Where the next byte will be written.
+    end: usize,
+    /// Set once the log has wrapped around, so that all of `bytes` is in use.
+    full: bool,
+}
+
+static LOG: Mutex<Ring> = Mutex::new(Ring {
+    bytes: [0; LOG_CAPACITY],
+    end: 0,
+    full: false,
+});
+
+/// Adds text to the end of the log.
+///
+/// If the log is already locked, for example because we panicked while writing to it,
+/// the text is dropped rather than waiting forever.
+pub fn write(text: &[u8]) {
+    let Some(mut log) = LOG.try_lock() else {
+        return;
+    };
+    // Only the last part of very long text fits.
+    let text = &text[text.len().saturating_sub(LOG_CAPACITY)..];
+    let end = log.end;
+    let first = text.len().min(LOG_CAPACITY - end);
+    log.bytes[end..end + first].copy_from_slice(&text[..first]);
+    log.bytes[..text.len() - first].copy_from_slice(&text[first..]);
+    log.end = (end + text.len()) % LOG_CAPACITY;
+    log.full |= end + text.len() >= LOG_CAPACITY;
+}
+
+/// Returns everything in the log, oldest first.
+pub fn contents() -> Vec<u8> {
+    let log = LOG.lock();
+    let mut contents = Vec::with_capacity(LOG_CAPACITY);
+    if log.full {
+        contents.extend_from_slice(&log.bytes[log.end..]);
+    }
+    contents.extend_from_slice(&log.bytes[..log.end]);
+    contents
+}
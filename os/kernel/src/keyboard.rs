@@ -0,0 +1,138 @@
+//! The PS/2 keyboard.
+//!
+//! The keyboard controller sends a scancode on every key press and release, using scancode set 1
+//! since the controller translates to it by default. Key presses are turned into bytes with a
+//! US layout and pushed into [INPUT], which is what reading `/dev/console` returns.
+//! Arrow keys become the escape sequences that a VT100 would send.
+
+use x86_64::instructions::port::Port;
+
+use crate::{input::InputBuffer, sync::IrqMutex, trap};
+
+pub const KEYBOARD_IRQ: u8 = 1;
+
+const DATA_PORT: u16 = 0x60;
+
+/// Bytes typed on the keyboard, waiting to be read.
+pub static INPUT: InputBuffer = InputBuffer::new();
+
+/// Set in a scancode when the key is released rather than pressed.
+const RELEASED: u8 = 0x80;
+/// Comes before the scancodes of keys that were added after the original keyboard.
+const EXTENDED: u8 = 0xe0;
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CONTROL: u8 = 0x1d;
+const CAPS_LOCK: u8 = 0x3a;
+
+const UP: u8 = 0x48;
+const LEFT: u8 = 0x4b;
+const RIGHT: u8 = 0x4d;
+const DOWN: u8 = 0x50;
+
+/// The byte for each scancode, without and with shift. Zero means the key doesn't type anything.
+const KEYS: [(u8, u8); 0x3a] = {
+    let plain = b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+    let shifted = b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+    let mut keys = [(0, 0); 0x3a];
+    let mut i = 0;
+    while i < keys.len() {
+        keys[i] = (plain[i], shifted[i]);
+        i += 1;
+    }
+    keys
+};
+
+#[derive(Default)]
+struct State {
+    shift: bool,
+    control: bool,
+    caps_lock: bool,
+    /// Set if the last byte was [EXTENDED].
+    extended: bool,
+}
+
+static STATE: IrqMutex<State> = IrqMutex::new(State {
+    shift: false,
+    control: false,
+    caps_lock: false,
+    extended: false,
+});
+
+pub fn init() {
+    // Throw away anything that was typed before we were listening.
+    unsafe { Port::<u8>::new(DATA_PORT).read() };
+    trap::set_irq_handler(KEYBOARD_IRQ, interrupt);
+}
+
+fn interrupt() {
+    let scancode = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    let mut state = STATE.lock();
+    if scancode == EXTENDED {
+        state.extended = true;
+        return;
+    }
+    let extended = core::mem::take(&mut state.extended);
+    let pressed = scancode & RELEASED == 0;
+    let key = scancode & !RELEASED;
+
+    if extended {
+        let arrow = match key {
+            UP => b'A',
+            DOWN => b'B',
+            RIGHT => b'C',
+            LEFT => b'D',
+            // The right control key shares its scancode with the left one.
+            CONTROL => {
+                state.control = pressed;
+                return;
+            }
+            _ => return,
+        };
+        if pressed {
+            INPUT.push(&[0x1b, b'[', arrow]);
+        }
+        return;
+    }
+
+    match key {
+        LEFT_SHIFT | RIGHT_SHIFT => state.shift = pressed,
+        CONTROL => state.control = pressed,
+        CAPS_LOCK if pressed => state.caps_lock = !state.caps_lock,
+        _ if pressed => {
+            if let Some(byte) = translate(&state, key) {
+                INPUT.push(&[byte]);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The byte that a key press types, given the modifier keys that are held down.
+fn translate(state: &State, key: u8) -> Option<u8> {
+    let &(plain, shifted) = KEYS.get(key as usize)?;
+    let mut byte = if state.shift { shifted } else { plain };
+    if state.caps_lock && plain.is_ascii_lowercase() {
+        byte = if state.shift { plain } else { shifted };
+    }
+    if state.control && byte.is_ascii_alphabetic() {
+        // Control characters are the letters with the top three bits cleared, so `^C` is 3.
+        byte &= 0x1f;
+    }
+    (byte != 0).then_some(byte)
+}
+
+#[test_case]
+fn test_translate() {
+    let mut state = State::default();
+    assert_eq!(translate(&state, 0x1e), Some(b'a'));
+    state.shift = true;
+    assert_eq!(translate(&state, 0x02), Some(b'!'));
+    state.caps_lock = true;
+    assert_eq!(translate(&state, 0x1e), Some(b'a'));
+    state.shift = false;
+    state.control = true;
+    assert_eq!(translate(&state, 0x2e), Some(3));
+    assert_eq!(translate(&state, LEFT_SHIFT), None);
+}
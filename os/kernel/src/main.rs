@@ -1,23 +1,63 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks, abi_x86_interrupt)]
+// Only the tests use `#[thread_local]` statics for now.
+#![cfg_attr(test, feature(thread_local))]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod apic;
+pub mod ata;
+pub mod block;
+pub mod capability;
 pub mod colour;
+pub mod config;
+pub mod e1000;
+pub mod elf;
+pub mod errno;
+pub mod file;
+pub mod fpu;
+pub mod fs;
 pub mod gdt;
 pub mod human_units;
+pub mod init;
+pub mod input;
 pub mod interrupts;
+pub mod ipc;
+pub mod kernel_log;
+pub mod keyboard;
 pub mod linalg;
+pub mod memory;
+pub mod net;
 pub mod num_traits;
+pub mod nvme;
+pub mod pci;
+pub mod pic;
+pub mod pipe;
 pub mod print;
+pub mod process;
 pub mod qemu;
+pub mod ramdisk;
+pub mod rtl8139;
+pub mod scheduler;
 pub mod screen_font;
 pub mod serial;
+pub mod signal;
+pub mod sync;
+pub mod syscall;
+pub mod tar;
+pub mod task;
 pub mod terminal_video;
+pub mod timer;
+pub mod tls;
+pub mod trap;
 pub mod video;
+pub mod virtio;
 
-use bootloader_api::info::MemoryRegionKind;
+use bootloader_api::{config::Mapping, info::MemoryRegionKind};
 use colour::Colour;
 use human_units::HumanBytes;
 use terminal_video::TerminalVideoBuffer;
@@ -25,6 +65,10 @@ use terminal_video::TerminalVideoBuffer;
 const CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
     config.kernel_stack_size = 100 * 1024; // 100 KiB
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // Keep the bootloader's mappings out of user space and away from the kernel heap.
+    config.mappings.dynamic_range_start = Some(memory::BOOTLOADER_DYNAMIC_START);
+    config.mappings.dynamic_range_end = Some(memory::BOOTLOADER_DYNAMIC_END);
     config
 };
 
@@ -45,6 +89,23 @@ fn kmain(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
 
     serial_println!("GDT and IDT loaded.");
 
+    let boot_info: &'static bootloader_api::BootInfo = boot_info;
+    memory::init(boot_info);
+    tls::init(boot_info);
+    ramdisk::init(boot_info);
+    fs::init();
+    acpi::init(boot_info);
+    fpu::init();
+
+    serial_println!("Memory initialised.");
+
+    pic::init();
+    apic::init();
+    timer::init();
+    file::init();
+    keyboard::init();
+    serial::init_input();
+
     TerminalVideoBuffer::with_default(|terminal| {
         terminal.set_background(Colour::from_rgb(10, 15, 20));
         terminal.clear_screen();
@@ -83,6 +144,14 @@ fn kmain(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         )
     );
 
+    pci::init();
+    virtio::init();
+    ata::init();
+    nvme::init();
+    e1000::init();
+    rtl8139::init();
+    net::init();
+
     #[cfg(test)]
     {
         test_main();
@@ -93,7 +162,9 @@ fn kmain(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     {
         println!("Hello, world! 0.1 + 0.2 = {}", 0.1 + 0.2);
         println!("Testing enabled: {}", cfg!(test));
-        panic!("Shutting down kernel.");
+
+        init::start();
+        scheduler::run();
     }
 }
 
@@ -0,0 +1,204 @@
+//! Physical and virtual memory management.
+//!
+//! The bootloader maps all of physical memory at a fixed offset,
+//! so the kernel can read and write any frame without creating a new mapping for it.
+//!
+//! The virtual address space is laid out as follows.
+//!
+//! * Level 4 entry `0` holds the kernel image, which is linked at a low address.
+//! * Level 4 entries `1..256` are user space, and are private to each [address_space::AddressSpace].
+//! * Level 4 entries `256..384` are used by the bootloader for its dynamic mappings,
+//!   such as the physical memory map, the boot stack and the framebuffer.
+//! * Level 4 entries `384..512` are used by the kernel for its own dynamic mappings,
+//!   such as the heap and the registers of devices.
+//!
+//! All kernel level 4 entries are populated before any address space is created,
+//! so that kernel mappings are shared between every address space.
+
+pub mod address_space;
+pub mod dma;
+pub mod frame;
+pub mod heap;
+pub mod mmio;
+pub mod shared;
+pub mod vma;
+
+use spin::{Mutex, Once};
+use x86_64::{
+    registers::{
+        control::{Cr3, Cr3Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        page::PageRangeInclusive, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// The lowest address that user programs may map.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+/// One past the highest address that user programs may map.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Dynamic mappings made by the bootloader are placed in this range.
+pub const BOOTLOADER_DYNAMIC_START: u64 = 0xffff_8000_0000_0000;
+pub const BOOTLOADER_DYNAMIC_END: u64 = 0xffff_c000_0000_0000;
+
+/// The kernel heap grows upwards from this address.
+pub const KERNEL_HEAP_START: u64 = 0xffff_c000_0000_0000;
+pub const KERNEL_HEAP_MAX_SIZE: u64 = 0x0000_0080_0000_0000;
+
+/// Device registers are mapped upwards from this address, just after the heap.
+pub const KERNEL_MMIO_START: u64 = KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE;
+pub const KERNEL_MMIO_MAX_SIZE: u64 = 0x0000_0080_0000_0000;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The level 4 page table that the bootloader gave us.
+/// Kernel threads run on this page table, and every address space shares its kernel half.
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+
+/// Used for modifying kernel mappings, which are shared between all address spaces.
+static KERNEL_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Initialises the frame allocator and the kernel heap.
+///
+/// # Panics
+///
+/// Panics if the bootloader did not map physical memory, or if it mapped something in user space.
+pub fn init(boot_info: &'static bootloader_api::BootInfo) {
+    let offset = VirtAddr::new(
+        *boot_info
+            .physical_memory_offset
+            .as_ref()
+            .expect("bootloader did not map physical memory"),
+    );
+    PHYSICAL_MEMORY_OFFSET.call_once(|| offset);
+
+    // Allow pages to be marked as not executable.
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+
+    frame::init(&boot_info.memory_regions);
+
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_PAGE_TABLE.call_once(|| level_4_frame);
+    let level_4_table =
+        unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>() };
+
+    for (index, entry) in level_4_table.iter_mut().enumerate() {
+        let in_user_space = (1..256).contains(&index);
+        if in_user_space {
+            assert!(
+                entry.is_unused(),
+                "bootloader mapped level 4 entry {index}, which is reserved for user space"
+            );
+        } else if index >= 256 && entry.is_unused() {
+            let table = frame::allocate_zeroed_frame().expect("out of memory");
+            entry.set_frame(table, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    *KERNEL_MAPPER.lock() = Some(unsafe { OffsetPageTable::new(level_4_table, offset) });
+
+    heap::init();
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory not yet initialised")
+}
+
+/// Returns the virtual address at which the given physical address is mapped.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Returns the physical address that the given kernel address is mapped to, if it is mapped.
+pub fn kernel_virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    KERNEL_MAPPER
+        .lock()
+        .as_ref()
+        .expect("memory not yet initialised")
+        .translate_addr(addr)
+}
+
+pub fn kernel_page_table() -> PhysFrame {
+    *KERNEL_PAGE_TABLE.get().expect("memory not yet initialised")
+}
+
+/// Switches to the kernel's own page table.
+/// This must be done before freeing the address space that is currently active.
+pub fn activate_kernel_page_table() {
+    let frame = kernel_page_table();
+    if Cr3::read().0 != frame {
+        unsafe {
+            Cr3::write(frame, Cr3Flags::empty());
+        }
+    }
+}
+
+/// Maps fresh zeroed frames to the given pages in the kernel half of the address space.
+///
+/// # Panics
+///
+/// Panics if the pages are not in the kernel half, or if any of them are already mapped.
+pub fn map_kernel_pages(
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+) -> Result<(), OutOfMemory> {
+    assert!(pages.start.start_address().as_u64() >= BOOTLOADER_DYNAMIC_START);
+    let mut mapper = KERNEL_MAPPER.lock();
+    let mapper = mapper.as_mut().expect("memory not yet initialised");
+    for page in pages {
+        let frame = frame::allocate_zeroed_frame().ok_or(OutOfMemory)?;
+        let result = unsafe {
+            mapper.map_to(
+                page,
+                frame,
+                flags | PageTableFlags::PRESENT,
+                &mut frame::GlobalFrameAllocator,
+            )
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                // The frame was never mapped, so nothing else refers to it.
+                unsafe { frame::deallocate_frame(frame) };
+                return Err(OutOfMemory);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the pages that contain any of the addresses in `start..end`.
+///
+/// # Panics
+///
+/// Panics if the range is empty.
+pub fn pages_in(start: u64, end: u64) -> PageRangeInclusive {
+    assert!(start < end, "empty page range");
+    Page::range_inclusive(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    )
+}
+
+/// Rounds the given address up to a multiple of [PAGE_SIZE].
+pub const fn page_align_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Rounds the given address down to a multiple of [PAGE_SIZE].
+pub const fn page_align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
@@ -0,0 +1,306 @@
+//! Page tables for user processes.
+
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::MapToError, page::PageRangeInclusive, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame,
+    },
+    VirtAddr,
+};
+
+use super::{
+    frame::{self, GlobalFrameAllocator},
+    kernel_page_table, pages_in, phys_to_virt, physical_memory_offset, OutOfMemory, PAGE_SIZE,
+    USER_SPACE_END, USER_SPACE_START,
+};
+
+/// The level 4 entries that belong to user space.
+const USER_LEVEL_4_ENTRIES: core::ops::Range<usize> = 1..256;
+
+/// Marks page table entries whose frame belongs to a [super::shared::SharedMemory] or a device
+/// rather than to the address space, so it must not be freed when the page is unmapped.
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// A set of page tables whose kernel half is shared with every other address space,
+/// and whose user half is private.
+///
+/// # Invariants
+///
+/// Every page table in the user half was allocated from the frame allocator
+/// and is owned by this address space. So is every frame mapped in the user half,
+/// except for those whose entries are marked as shared.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in user space.
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let level_4_frame = frame::allocate_zeroed_frame().ok_or(OutOfMemory)?;
+        let kernel_table = unsafe { &*table_ptr(kernel_page_table()) };
+        let table = unsafe { &mut *table_ptr(level_4_frame) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !USER_LEVEL_4_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(Self { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Loads this address space into `CR3`.
+    pub fn activate(&self) {
+        if Cr3::read().0 != self.level_4_frame {
+            unsafe {
+                Cr3::write(self.level_4_frame, Cr3Flags::empty());
+            }
+        }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *table_ptr(self.level_4_frame),
+                physical_memory_offset(),
+            )
+        }
+    }
+
+    /// Maps fresh zeroed frames to the given user pages.
+    /// Pages that are already mapped are left alone, except that `flags` are added to their flags.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the pages are outside user space.
+    pub fn map_zeroed(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), OutOfMemory> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        for page in pages {
+            assert!(is_user_page(page));
+            if let Some((_, old_flags)) = self.translate(page.start_address()) {
+                // The page is executable if either mapping wants it to be.
+                let mut new_flags = old_flags | flags;
+                if !(old_flags & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    new_flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    self.mapper()
+                        .update_flags(page, new_flags)
+                        .expect("page was just translated")
+                        .flush();
+                }
+                continue;
+            }
+
+            let frame = frame::allocate_zeroed_frame().ok_or(OutOfMemory)?;
+            if let Err(error) = unsafe { self.map_frame(page, frame, flags, false) } {
+                unsafe { frame::deallocate_frame(frame) };
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the given frame to an unmapped user page.
+    /// Unlike [AddressSpace::map_zeroed], this does not make the page accessible to user mode
+    /// unless `flags` says so.
+    ///
+    /// If `shared` is false, the address space takes ownership of the frame,
+    /// and frees it when the page is unmapped. Otherwise, the frame is only borrowed.
+    ///
+    /// # Safety
+    ///
+    /// The frame must be owned by the caller. If it is shared, it must outlive the mapping.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page is outside user space or already mapped.
+    pub unsafe fn map_frame(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        shared: bool,
+    ) -> Result<(), OutOfMemory> {
+        assert!(is_user_page(page));
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if shared {
+            flags |= SHARED;
+        }
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        match self.mapper().map_to_with_table_flags(
+            page,
+            frame,
+            flags,
+            table_flags,
+            &mut GlobalFrameAllocator,
+        ) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::PageAlreadyMapped(_)) => panic!("page {page:?} is already mapped"),
+            Err(_) => Err(OutOfMemory),
+        }
+    }
+
+    /// Changes the flags of the given user pages, keeping track of which frames are shared.
+    /// Pages that are not mapped are skipped.
+    pub fn set_flags(&mut self, pages: PageRangeInclusive, flags: PageTableFlags) {
+        for page in pages {
+            assert!(is_user_page(page));
+            let Some((_, old_flags)) = self.translate(page.start_address()) else {
+                continue;
+            };
+            let new_flags = flags | PageTableFlags::PRESENT | (old_flags & SHARED);
+            unsafe {
+                self.mapper()
+                    .update_flags(page, new_flags)
+                    .expect("page was just translated")
+                    .flush();
+            }
+        }
+    }
+
+    /// Unmaps the given user pages, freeing the frames behind them unless they are shared.
+    /// Pages that are not mapped are skipped.
+    pub fn unmap(&mut self, pages: PageRangeInclusive) {
+        for page in pages {
+            assert!(is_user_page(page));
+            let Some((_, flags)) = self.translate(page.start_address()) else {
+                continue;
+            };
+            let (frame, flush) = self.mapper().unmap(page).expect("page was just translated");
+            flush.flush();
+            if !flags.contains(SHARED) {
+                unsafe { frame::deallocate_frame(frame) };
+            }
+        }
+    }
+
+    /// Returns the frame behind the given address, and the flags it is mapped with.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysFrame, PageTableFlags)> {
+        let mut table = unsafe { &*table_ptr(self.level_4_frame) };
+        for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entry = &table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT)
+                || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            {
+                return None;
+            }
+            table = unsafe { &*table_ptr(entry.frame().ok()?) };
+        }
+        let entry = &table[addr.p1_index()];
+        Some((entry.frame().ok()?, entry.flags()))
+    }
+
+    /// Returns true if the given user page is mapped.
+    pub fn is_mapped(&self, page: Page) -> bool {
+        self.translate(page.start_address()).is_some()
+    }
+
+    /// Checks that every byte in `addr..addr + len` is mapped and accessible to user mode,
+    /// and writable if `write` is set.
+    pub fn is_accessible(&self, addr: u64, len: u64, write: bool) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        if addr < USER_SPACE_START || end > USER_SPACE_END {
+            return false;
+        }
+        if len == 0 {
+            return true;
+        }
+
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            required |= PageTableFlags::WRITABLE;
+        }
+        pages_in(addr, end).into_iter().all(|page| {
+            self.translate(page.start_address())
+                .is_some_and(|(_, flags)| flags.contains(required))
+        })
+    }
+
+    /// Copies `data` into this address space at the given address,
+    /// ignoring the page protection flags.
+    /// This works even if the address space is not active.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the destination is not mapped.
+    pub fn write_bytes(&self, addr: u64, data: &[u8]) {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written as u64;
+            let (frame, _) = self
+                .translate(VirtAddr::new(addr))
+                .expect("destination not mapped");
+            let offset = addr % PAGE_SIZE;
+            let len = (PAGE_SIZE - offset).min((data.len() - written) as u64) as usize;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys_to_virt(frame.start_address() + offset).as_mut_ptr(),
+                    len,
+                );
+            }
+            written += len;
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.level_4_frame,
+            "cannot free the active address space"
+        );
+
+        let level_4_table = unsafe { &*table_ptr(self.level_4_frame) };
+        for index in USER_LEVEL_4_ENTRIES {
+            if let Ok(frame) = level_4_table[index].frame() {
+                unsafe { free_table(frame, 3) };
+            }
+        }
+        unsafe { frame::deallocate_frame(self.level_4_frame) };
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+fn is_user_page(page: Page) -> bool {
+    let addr = page.start_address().as_u64();
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr)
+}
+
+/// Frees a page table of the given level, together with everything mapped through it.
+///
+/// # Safety
+///
+/// The table and everything it maps, other than shared frames, must be owned by the caller.
+unsafe fn free_table(table_frame: PhysFrame, level: u8) {
+    let table = &*table_ptr(table_frame);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let frame = entry
+            .frame()
+            .expect("huge pages are not used in user space");
+        if level > 1 {
+            free_table(frame, level - 1);
+        } else if !entry.flags().contains(SHARED) {
+            frame::deallocate_frame(frame);
+        }
+    }
+    frame::deallocate_frame(table_frame);
+}
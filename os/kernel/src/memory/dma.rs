@@ -0,0 +1,102 @@
+//! Memory that devices read and write by themselves.
+//!
+//! Devices see physical addresses, and the heap is only contiguous in virtual memory,
+//! so drivers give their devices [DmaBuffer]s instead, copying data in and out as needed.
+
+use bytemuck::Pod;
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+use super::{frame, page_align_up, phys_to_virt, OutOfMemory, PAGE_SIZE};
+
+/// Zeroed, physically contiguous memory, which is freed when dropped.
+///
+/// The device may change the memory at any time, so it is only accessed through volatile reads
+/// and writes, and never through references.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    size: usize,
+}
+
+impl DmaBuffer {
+    pub fn new(size: usize) -> Result<Self, OutOfMemory> {
+        let pages = page_align_up(size.max(1) as u64) / PAGE_SIZE;
+        let start = frame::allocate_contiguous(pages).ok_or(OutOfMemory)?;
+        let buffer = Self { start, size };
+        unsafe {
+            core::ptr::write_bytes(buffer.ptr::<u8>(0), 0, pages as usize * PAGE_SIZE as usize)
+        };
+        Ok(buffer)
+    }
+
+    /// The physical address of the byte at the given offset, to hand to the device.
+    pub fn phys(&self, offset: usize) -> PhysAddr {
+        assert!(offset <= self.size, "DMA offset out of range");
+        self.start.start_address() + offset as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        phys_to_virt(self.start.start_address() + offset as u64).as_mut_ptr()
+    }
+
+    fn check<T>(&self, offset: usize, len: usize) {
+        assert!(
+            offset + len * size_of::<T>() <= self.size && offset.is_multiple_of(align_of::<T>()),
+            "DMA access at {offset:#x} out of range"
+        );
+    }
+
+    /// Reads the value at the given byte offset.
+    pub fn read<T: Pod>(&self, offset: usize) -> T {
+        self.check::<T>(offset, 1);
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes the value at the given byte offset.
+    pub fn write<T: Pod>(&self, offset: usize, value: T) {
+        self.check::<T>(offset, 1);
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    /// Copies bytes out of the buffer, starting at the given offset.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        self.check::<u8>(offset, buf.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.ptr::<u8>(offset), buf.as_mut_ptr(), buf.len())
+        };
+    }
+
+    /// Copies bytes into the buffer, starting at the given offset.
+    pub fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        self.check::<u8>(offset, buf.len());
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr::<u8>(offset), buf.len()) };
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let pages = page_align_up(self.size.max(1) as u64) / PAGE_SIZE;
+        for page in 0..pages {
+            unsafe { frame::deallocate_frame(self.start + page) };
+        }
+    }
+}
+
+#[test_case]
+fn test_dma_buffer() {
+    let before = frame::stats();
+    let buffer = DmaBuffer::new(3 * PAGE_SIZE as usize).unwrap();
+    assert_eq!(frame::stats().used, before.used + 3);
+    assert_eq!(buffer.read::<u64>(2 * PAGE_SIZE as usize), 0);
+    buffer.write::<u32>(PAGE_SIZE as usize - 4, 0x1234_5678);
+    buffer.write_bytes(PAGE_SIZE as usize, b"DMA");
+    let mut bytes = [0; 7];
+    buffer.read_bytes(PAGE_SIZE as usize - 4, &mut bytes);
+    assert_eq!(bytes, *b"\x78\x56\x34\x12DMA");
+    drop(buffer);
+    assert_eq!(frame::stats(), before);
+}
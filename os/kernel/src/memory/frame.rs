@@ -0,0 +1,236 @@
+//! Allocation of physical frames.
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use super::{page_align_down, page_align_up, phys_to_virt, PAGE_SIZE};
+
+/// We never hand out frames below this address,
+/// since real mode code and firmware data structures like to live there.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Hands out the usable frames described by the bootloader's memory map.
+///
+/// Frames are first taken in order from the usable memory regions.
+/// Frames that are freed are kept in a linked list, where each free frame stores
+/// the physical address of the next one in its first eight bytes.
+struct FrameAllocator {
+    regions: &'static [MemoryRegion],
+    /// The index of the region that we are currently taking fresh frames from.
+    region: usize,
+    /// The next fresh frame in `regions[region]`.
+    next: u64,
+    /// The most recently freed frame.
+    free_list: Option<PhysFrame>,
+    total_frames: usize,
+    used_frames: usize,
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// A handle to the global frame allocator, for use with the `x86_64` paging APIs.
+/// The frames it allocates are always zeroed, so they can be used directly as page tables.
+pub struct GlobalFrameAllocator;
+
+/// Statistics about the usage of physical memory, in units of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+}
+
+impl FrameStats {
+    pub fn free(&self) -> usize {
+        self.total - self.used
+    }
+}
+
+impl FrameAllocator {
+    fn new(regions: &'static [MemoryRegion]) -> Self {
+        let total_frames = regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| {
+                let start = page_align_up(region.start.max(LOW_MEMORY_END));
+                let end = page_align_down(region.end);
+                (end.saturating_sub(start) / PAGE_SIZE) as usize
+            })
+            .sum();
+        Self {
+            regions,
+            region: 0,
+            next: 0,
+            free_list: None,
+            total_frames,
+            used_frames: 0,
+        }
+    }
+
+    fn allocate(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+            self.free_list =
+                (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.used_frames += 1;
+            return Some(frame);
+        }
+
+        while let Some(region) = self.regions.get(self.region) {
+            if region.kind == MemoryRegionKind::Usable {
+                let start = page_align_up(region.start.max(LOW_MEMORY_END)).max(self.next);
+                if start + PAGE_SIZE <= page_align_down(region.end) {
+                    self.next = start + PAGE_SIZE;
+                    self.used_frames += 1;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+            }
+            self.region += 1;
+            self.next = 0;
+        }
+
+        None
+    }
+
+    /// Takes `count` physically consecutive fresh frames.
+    /// Whatever is left of a region that is too small for them is put on the free list.
+    fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrame> {
+        while let Some(region) = self.regions.get(self.region) {
+            if region.kind == MemoryRegionKind::Usable {
+                let start = page_align_up(region.start.max(LOW_MEMORY_END)).max(self.next);
+                let end = page_align_down(region.end);
+                if start + count * PAGE_SIZE <= end {
+                    self.next = start + count * PAGE_SIZE;
+                    self.used_frames += count as usize;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+                for frame in (start..end).step_by(PAGE_SIZE as usize) {
+                    self.used_frames += 1;
+                    unsafe {
+                        self.deallocate(PhysFrame::containing_address(PhysAddr::new(frame)));
+                    }
+                }
+            }
+            self.region += 1;
+            self.next = 0;
+        }
+
+        None
+    }
+
+    /// # Safety
+    ///
+    /// The frame must have been returned by [Self::allocate] and must not be used after this call.
+    unsafe fn deallocate(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(0, |frame| frame.start_address().as_u64());
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+        self.free_list = Some(frame);
+        self.used_frames -= 1;
+    }
+}
+
+pub(super) fn init(regions: &'static [MemoryRegion]) {
+    *FRAME_ALLOCATOR.lock() = Some(FrameAllocator::new(regions));
+}
+
+/// Allocates a frame of physical memory, whose contents are unspecified.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("frame allocator not yet initialised")
+        .allocate()
+}
+
+/// Allocates `count` frames of physical memory that are next to each other, whose contents are unspecified.
+/// The frames are freed one at a time.
+pub fn allocate_contiguous(count: u64) -> Option<PhysFrame> {
+    if count == 1 {
+        return allocate_frame();
+    }
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("frame allocator not yet initialised")
+        .allocate_contiguous(count)
+}
+
+/// Allocates a frame of physical memory and fills it with zeroes.
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = allocate_frame()?;
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            PAGE_SIZE as usize,
+        );
+    }
+    Some(frame)
+}
+
+/// Returns a frame to the allocator.
+///
+/// # Safety
+///
+/// The frame must have been allocated by this module and must not be used after this call.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("frame allocator not yet initialised")
+        .deallocate(frame);
+}
+
+/// The memory map that the bootloader gave us.
+pub fn regions() -> &'static [MemoryRegion] {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .expect("frame allocator not yet initialised")
+        .regions
+}
+
+pub fn stats() -> FrameStats {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator
+        .as_ref()
+        .expect("frame allocator not yet initialised");
+    FrameStats {
+        total: allocator.total_frames,
+        used: allocator.used_frames,
+    }
+}
+
+unsafe impl x86_64::structures::paging::FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_zeroed_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate_frame(frame);
+    }
+}
+
+#[test_case]
+fn test_frames_are_reused() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let before = stats();
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(stats().used, before.used + 1);
+    unsafe {
+        deallocate_frame(frame);
+    }
+    assert_eq!(stats(), before);
+    assert_eq!(allocate_frame(), Some(frame));
+    unsafe {
+        deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,196 @@
+//! The kernel heap.
+//!
+//! This is a first-fit allocator over a sorted linked list of free blocks.
+//! Adjacent free blocks are merged when memory is returned.
+//! When no free block is large enough, the heap maps more pages at its end.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{null_mut, NonNull},
+};
+
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::{map_kernel_pages, page_align_up, pages_in, KERNEL_HEAP_MAX_SIZE, KERNEL_HEAP_START};
+
+/// The amount of memory mapped when the heap is initialised.
+const INITIAL_SIZE: u64 = 1024 * 1024;
+
+/// The least amount of memory mapped each time the heap needs to grow.
+const MIN_GROWTH: u64 = 64 * 1024;
+
+/// Every block is a multiple of this size and aligned to it,
+/// which guarantees that the space left over after an allocation can hold a [FreeBlock].
+const BLOCK_ALIGN: usize = 16;
+
+/// A free block of memory, stored at the start of the memory it describes.
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// # Invariants
+///
+/// The free list is sorted by address, and no two free blocks are adjacent.
+/// Every free block lies in `KERNEL_HEAP_START..end`, which is mapped.
+pub struct Heap {
+    head: Option<NonNull<FreeBlock>>,
+    end: u64,
+}
+
+/// The heap only hands out pointers into memory that it owns.
+unsafe impl Send for Heap {}
+
+pub struct LockedHeap(Mutex<Heap>);
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap(Mutex::new(Heap {
+    head: None,
+    end: KERNEL_HEAP_START,
+}));
+
+pub(super) fn init() {
+    let mut heap = ALLOCATOR.0.lock();
+    heap.grow(INITIAL_SIZE).expect("could not map initial heap");
+}
+
+/// The number of bytes that the heap has mapped, whether or not they are allocated.
+pub fn size() -> u64 {
+    ALLOCATOR.0.lock().end - KERNEL_HEAP_START
+}
+
+/// Rounds the layout up so that blocks are always multiples of [BLOCK_ALIGN].
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(BLOCK_ALIGN);
+    let size = layout.size().max(1).next_multiple_of(BLOCK_ALIGN);
+    (size, align)
+}
+
+impl Heap {
+    /// Maps at least `size` more bytes at the end of the heap, and adds them to the free list.
+    fn grow(&mut self, size: u64) -> Option<()> {
+        let size = page_align_up(size.max(MIN_GROWTH));
+        if self.end + size > KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE {
+            return None;
+        }
+        let start = self.end;
+        map_kernel_pages(
+            pages_in(start, start + size),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .ok()?;
+        self.end += size;
+        unsafe {
+            self.free(start as usize, size as usize);
+        }
+        Some(())
+    }
+
+    /// Tries to carve an allocation out of the free list.
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut current = self.head;
+        while let Some(block) = current {
+            let block_start = block.as_ptr() as usize;
+            let block_end = block_start + block.as_ref().size;
+            let next = block.as_ref().next;
+
+            let alloc_start = block_start.next_multiple_of(align);
+            let alloc_end = alloc_start + size;
+            if alloc_end <= block_end {
+                // Unlink this block, then give back whatever we didn't use.
+                match prev {
+                    Some(mut prev) => prev.as_mut().next = next,
+                    None => self.head = next,
+                }
+                if alloc_start > block_start {
+                    self.free(block_start, alloc_start - block_start);
+                }
+                if block_end > alloc_end {
+                    self.free(alloc_end, block_end - alloc_end);
+                }
+                return NonNull::new(alloc_start as *mut u8);
+            }
+
+            prev = current;
+            current = next;
+        }
+        None
+    }
+
+    /// Adds the given range to the free list, merging it with its neighbours.
+    ///
+    /// # Safety
+    ///
+    /// The range must be unused heap memory, aligned to [BLOCK_ALIGN].
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut next = self.head;
+        while let Some(block) = next {
+            if block.as_ptr() as usize > start {
+                break;
+            }
+            prev = next;
+            next = block.as_ref().next;
+        }
+
+        let mut block = NonNull::new_unchecked(start as *mut FreeBlock);
+        block.write(FreeBlock { size, next });
+
+        if let Some(mut next) = next {
+            if start + size == next.as_ptr() as usize {
+                block.as_mut().size += next.as_ref().size;
+                block.as_mut().next = next.as_mut().next;
+            }
+        }
+
+        match prev {
+            Some(mut prev) => {
+                if prev.as_ptr() as usize + prev.as_ref().size == start {
+                    prev.as_mut().size += block.as_ref().size;
+                    prev.as_mut().next = block.as_ref().next;
+                } else {
+                    prev.as_mut().next = Some(block);
+                }
+            }
+            None => self.head = Some(block),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let mut heap = self.0.lock();
+        loop {
+            if let Some(ptr) = heap.allocate(size, align) {
+                return ptr.as_ptr();
+            }
+            if heap.grow((size + align) as u64).is_none() {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.0.lock().free(ptr as usize, size);
+    }
+}
+
+#[test_case]
+fn test_heap_grows() {
+    use alloc::vec::Vec;
+
+    // Allocating more than the initial heap size forces the heap to grow.
+    let large: Vec<u8> = alloc::vec![7; 3 * INITIAL_SIZE as usize];
+    assert!(large.iter().all(|&byte| byte == 7));
+    drop(large);
+
+    let boxes: Vec<_> = (0..1000u64).map(alloc::boxed::Box::new).collect();
+    assert!(boxes
+        .iter()
+        .enumerate()
+        .all(|(i, value)| **value == i as u64));
+}
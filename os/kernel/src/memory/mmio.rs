@@ -0,0 +1,120 @@
+//! Memory-mapped device registers.
+//!
+//! The bootloader's map of physical memory uses ordinary cached pages, and need not reach
+//! the addresses where devices put their registers, so each [Mmio] region gets its own
+//! uncached mapping. Mappings are never removed, since drivers keep their devices forever.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bytemuck::Pod;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    frame, page_align_down, page_align_up, OutOfMemory, KERNEL_MAPPER, KERNEL_MMIO_MAX_SIZE,
+    KERNEL_MMIO_START, PAGE_SIZE,
+};
+
+/// The next unused address in the MMIO region.
+static NEXT: AtomicU64 = AtomicU64::new(KERNEL_MMIO_START);
+
+/// A range of device registers, mapped into the kernel half of the address space.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    base: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+}
+
+impl Mmio {
+    /// Maps `size` bytes of device registers starting at the given physical address.
+    pub fn map(phys: PhysAddr, size: usize) -> Result<Self, OutOfMemory> {
+        let start = page_align_down(phys.as_u64());
+        let end = page_align_up(phys.as_u64() + size.max(1) as u64);
+        let base = NEXT.fetch_add(end - start, Ordering::Relaxed);
+        if base + (end - start) > KERNEL_MMIO_START + KERNEL_MMIO_MAX_SIZE {
+            return Err(OutOfMemory);
+        }
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE;
+        let mut mapper = KERNEL_MAPPER.lock();
+        let mapper = mapper.as_mut().expect("memory not yet initialised");
+        for offset in (0..end - start).step_by(PAGE_SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + offset));
+            let frame = PhysFrame::containing_address(PhysAddr::new(start + offset));
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut frame::GlobalFrameAllocator)
+                    .map_err(|_| OutOfMemory)?
+                    .flush();
+            }
+        }
+
+        Ok(Self {
+            base: VirtAddr::new(base + phys.as_u64() - start),
+            phys,
+            size,
+        })
+    }
+
+    /// The virtual address of the first register.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// The physical address of the first register.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the registers starting at `offset`, which must lie within this region.
+    pub fn slice(&self, offset: usize, size: usize) -> Self {
+        assert!(offset + size <= self.size, "MMIO slice out of range");
+        Self {
+            base: self.base + offset as u64,
+            phys: self.phys + offset as u64,
+            size,
+        }
+    }
+
+    /// Reads the register at the given byte offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is not entirely within the region.
+    pub fn read<T: Pod>(&self, offset: usize) -> T {
+        self.check::<T>(offset);
+        unsafe { (self.base + offset as u64).as_ptr::<T>().read_volatile() }
+    }
+
+    /// Writes the register at the given byte offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is not entirely within the region.
+    pub fn write<T: Pod>(&self, offset: usize, value: T) {
+        self.check::<T>(offset);
+        unsafe {
+            (self.base + offset as u64)
+                .as_mut_ptr::<T>()
+                .write_volatile(value)
+        }
+    }
+
+    fn check<T>(&self, offset: usize) {
+        assert!(
+            offset + size_of::<T>() <= self.size && offset.is_multiple_of(align_of::<T>()),
+            "MMIO access at {offset:#x} out of range"
+        );
+    }
+}
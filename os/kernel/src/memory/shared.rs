@@ -0,0 +1,129 @@
+//! Memory that can be mapped into several places at once.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use super::{frame, phys_to_virt, OutOfMemory, PAGE_SIZE};
+
+/// A sparse array of pages, each allocated and zeroed when it is first used.
+///
+/// Pages are mapped into address spaces as shared frames, so writes through one mapping
+/// are seen through every other. The frames are freed when the last reference is dropped,
+/// and every [super::vma::Vma] that maps this memory holds a reference.
+#[derive(Default)]
+pub struct SharedMemory {
+    frames: Mutex<BTreeMap<u64, PhysFrame>>,
+}
+
+impl SharedMemory {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Returns the frame that holds the page at the given index.
+    pub fn frame(&self, index: u64) -> Result<PhysFrame, OutOfMemory> {
+        let mut frames = self.frames.lock();
+        if let Some(&frame) = frames.get(&index) {
+            return Ok(frame);
+        }
+        let frame = frame::allocate_zeroed_frame().ok_or(OutOfMemory)?;
+        frames.insert(index, frame);
+        Ok(frame)
+    }
+
+    /// The number of pages that have been used.
+    pub fn resident_pages(&self) -> usize {
+        self.frames.lock().len()
+    }
+
+    /// Copies bytes starting at the given offset into `buf`.
+    /// Pages that have never been used read as zeroes, and are not allocated.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        let frames = self.frames.lock();
+        for (chunk_offset, chunk) in chunks(offset, buf.len()) {
+            let buf = &mut buf[chunk];
+            match frames.get(&(chunk_offset / PAGE_SIZE)) {
+                Some(frame) => unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        frame_bytes(*frame, chunk_offset),
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                    )
+                },
+                None => buf.fill(0),
+            }
+        }
+    }
+
+    /// Copies `buf` into the memory starting at the given offset, allocating pages as needed.
+    /// If memory runs out, the bytes that fit in pages that could be allocated are still written.
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<(), OutOfMemory> {
+        for (chunk_offset, chunk) in chunks(offset, buf.len()) {
+            let frame = self.frame(chunk_offset / PAGE_SIZE)?;
+            let buf = &buf[chunk];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buf.as_ptr(),
+                    frame_bytes(frame, chunk_offset),
+                    buf.len(),
+                )
+            };
+        }
+        Ok(())
+    }
+
+    /// Throws away everything from the given offset on.
+    ///
+    /// If nothing else refers to this memory, whole pages after the offset are freed.
+    /// Otherwise they may still be mapped somewhere, so they are zeroed instead.
+    pub fn discard_from(self: &Arc<Self>, offset: u64) {
+        let mut frames = self.frames.lock();
+        let first_whole_page = offset.div_ceil(PAGE_SIZE);
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            if let Some(&frame) = frames.get(&(offset / PAGE_SIZE)) {
+                let len = PAGE_SIZE - offset % PAGE_SIZE;
+                unsafe { core::ptr::write_bytes(frame_bytes(frame, offset), 0, len as usize) };
+            }
+        }
+        if Arc::strong_count(self) == 1 {
+            for frame in frames.split_off(&first_whole_page).into_values() {
+                unsafe { frame::deallocate_frame(frame) };
+            }
+        } else {
+            for &frame in frames.range(first_whole_page..).map(|(_, frame)| frame) {
+                unsafe { core::ptr::write_bytes(frame_bytes(frame, 0), 0, PAGE_SIZE as usize) };
+            }
+        }
+    }
+}
+
+/// Splits `len` bytes starting at `offset` into pieces that don't cross page boundaries.
+/// Returns each piece's offset in the memory, and its range in the buffer.
+fn chunks(offset: u64, len: usize) -> impl Iterator<Item = (u64, core::ops::Range<usize>)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+        let chunk_offset = offset + done as u64;
+        let chunk_len = ((PAGE_SIZE - chunk_offset % PAGE_SIZE) as usize).min(len - done);
+        let range = done..done + chunk_len;
+        done += chunk_len;
+        Some((chunk_offset, range))
+    })
+}
+
+/// A pointer to the byte at the given offset in memory, within the given frame.
+fn frame_bytes(frame: PhysFrame, offset: u64) -> *mut u8 {
+    (phys_to_virt(frame.start_address()) + offset % PAGE_SIZE).as_mut_ptr()
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in self.frames.get_mut().values() {
+            unsafe { frame::deallocate_frame(frame) };
+        }
+    }
+}
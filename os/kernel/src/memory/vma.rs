@@ -0,0 +1,496 @@
+//! Virtual memory areas: the parts of a process's user space that it is allowed to touch.
+//!
+//! Each process has a [VmaList] that records which ranges of user space are mapped,
+//! how they may be accessed, and what fills them. Most pages are only given a frame when they are
+//! first touched. A page fault in a mapped area asks the list for a [PendingPage],
+//! which is then loaded and mapped into the process's [AddressSpace].
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::ops::Range;
+
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    address_space::AddressSpace, frame, pages_in, phys_to_virt, shared::SharedMemory, OutOfMemory,
+    PAGE_SIZE,
+};
+use crate::{errno::Errno, file::File};
+
+bitflags::bitflags! {
+    /// How an area may be accessed. The values match the `PROT_*` arguments of `mmap`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Protection: u64 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+impl Protection {
+    /// The page table flags for pages with this protection.
+    ///
+    /// Pages cannot be writable or executable without also being readable,
+    /// so any protection other than none allows reading.
+    /// Pages with no access at all are left mapped, but not accessible to user mode.
+    pub fn page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if !self.is_empty() {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.contains(Self::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Self::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// The kind of memory access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// Returns true if a page mapped with the given flags allows this access from user mode.
+    pub fn is_allowed_by(self, flags: PageTableFlags) -> bool {
+        flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && match self {
+                Access::Read => true,
+                Access::Write => flags.contains(PageTableFlags::WRITABLE),
+                Access::Execute => !flags.contains(PageTableFlags::NO_EXECUTE),
+            }
+    }
+}
+
+/// What fills the pages of an area.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zeroed memory, private to the process.
+    Anonymous,
+    /// A private copy of a file, starting at the given offset.
+    /// Changes are not written back to the file.
+    File { file: Arc<dyn File>, offset: u64 },
+    /// Memory that is shared with every other mapping of it, starting at the given offset.
+    Shared {
+        memory: Arc<SharedMemory>,
+        offset: u64,
+    },
+    /// The memory of a device, such as a framebuffer, which is `len` bytes long from `start`.
+    /// Pages past the end can't be loaded.
+    Device { start: PhysAddr, len: u64 },
+}
+
+impl Backing {
+    /// The backing for the part of an area that starts `distance` bytes into this one.
+    fn advance(&self, distance: u64) -> Self {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { file, offset } => Backing::File {
+                file: file.clone(),
+                offset: offset + distance,
+            },
+            Backing::Shared { memory, offset } => Backing::Shared {
+                memory: memory.clone(),
+                offset: offset + distance,
+            },
+            Backing::Device { start, len } => Backing::Device {
+                start: *start + distance,
+                len: len.saturating_sub(distance),
+            },
+        }
+    }
+}
+
+/// A page-aligned range of user space that the process may use.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl Vma {
+    /// # Panics
+    ///
+    /// Panics if the range is empty or not page-aligned.
+    pub fn new(start: u64, end: u64, protection: Protection, backing: Backing) -> Self {
+        assert!(start < end, "empty memory area");
+        assert!(start.is_multiple_of(PAGE_SIZE) && end.is_multiple_of(PAGE_SIZE));
+        Self {
+            start,
+            end,
+            protection,
+            backing,
+        }
+    }
+
+    /// Shrinks this area to end at `addr`, and returns the rest of it.
+    fn split_off(&mut self, addr: u64) -> Vma {
+        let rest = Vma {
+            start: addr,
+            end: self.end,
+            protection: self.protection,
+            backing: self.backing.advance(addr - self.start),
+        };
+        self.end = addr;
+        rest
+    }
+}
+
+/// The memory areas of a process, which never overlap.
+#[derive(Default)]
+pub struct VmaList {
+    /// Keyed by start address.
+    vmas: BTreeMap<u64, Vma>,
+}
+
+impl VmaList {
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Returns the area containing the given address.
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.vmas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| addr < vma.end)
+    }
+
+    /// Returns true if no area overlaps `start..end`.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        self.vmas
+            .range(..end)
+            .next_back()
+            .is_none_or(|(_, vma)| vma.end <= start)
+    }
+
+    /// Returns the lowest address in `within` at which `len` bytes are free.
+    pub fn find_free(&self, len: u64, within: Range<u64>) -> Option<u64> {
+        let mut candidate = within.start;
+        for vma in self.vmas.values() {
+            if vma.end <= candidate {
+                continue;
+            }
+            if vma.start >= candidate + len {
+                break;
+            }
+            candidate = vma.end;
+        }
+        (candidate + len <= within.end).then_some(candidate)
+    }
+
+    /// Adds an area. Its pages are not mapped until they are touched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the area overlaps an existing one.
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(
+            self.is_free(vma.start, vma.end),
+            "memory area {:#x}..{:#x} overlaps another",
+            vma.start,
+            vma.end
+        );
+        self.vmas.insert(vma.start, vma);
+    }
+
+    /// Makes sure that no area crosses the given address.
+    fn split_at(&mut self, addr: u64) {
+        let Some((_, vma)) = self.vmas.range_mut(..addr).next_back() else {
+            return;
+        };
+        if addr < vma.end {
+            let rest = vma.split_off(addr);
+            self.vmas.insert(addr, rest);
+        }
+    }
+
+    /// Splits the areas at the ends of the given range, and returns the start addresses of those inside it.
+    fn isolate(&mut self, start: u64, end: u64) -> Vec<u64> {
+        self.split_at(start);
+        self.split_at(end);
+        self.vmas
+            .range(start..end)
+            .map(|(&start, _)| start)
+            .collect()
+    }
+
+    /// Removes every area, or part of an area, in `start..end`, and unmaps its pages.
+    pub fn remove(&mut self, address_space: &mut AddressSpace, start: u64, end: u64) {
+        for key in self.isolate(start, end) {
+            let vma = self.vmas.remove(&key).expect("area was just found");
+            // Unmap the pages before dropping the area, which may own the memory behind them.
+            address_space.unmap(pages_in(vma.start, vma.end));
+        }
+    }
+
+    /// Changes the protection of `start..end`, which must be entirely covered by areas.
+    /// Pages that are already mapped are updated straight away.
+    pub fn protect(
+        &mut self,
+        address_space: &mut AddressSpace,
+        start: u64,
+        end: u64,
+        protection: Protection,
+    ) -> Result<(), Errno> {
+        let mut addr = start;
+        while addr < end {
+            addr = self.find(addr).ok_or(Errno::ENOMEM)?.end;
+        }
+
+        for key in self.isolate(start, end) {
+            let vma = self.vmas.get_mut(&key).expect("area was just found");
+            vma.protection = protection;
+            address_space.set_flags(pages_in(vma.start, vma.end), protection.page_flags());
+        }
+        Ok(())
+    }
+
+    /// Works out what should be mapped at the page containing `addr` to allow the given access.
+    /// Returns `None` if the access is not allowed.
+    pub fn pending_page(&self, addr: u64, access: Access) -> Option<PendingPage> {
+        let vma = self.find(addr)?;
+        let flags = vma.protection.page_flags();
+        if !access.is_allowed_by(flags) {
+            return None;
+        }
+
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let distance = page.start_address().as_u64() - vma.start;
+        let source = match vma.backing.advance(distance) {
+            Backing::Anonymous => Source::Zeroed,
+            Backing::File { file, offset } => Source::File(file, offset),
+            Backing::Shared { memory, offset } => Source::Shared(memory, offset / PAGE_SIZE),
+            Backing::Device { start, len } => {
+                Source::Device((len > 0).then(|| PhysFrame::containing_address(start)))
+            }
+        };
+        Some(PendingPage {
+            page,
+            flags,
+            source,
+        })
+    }
+}
+
+enum Source {
+    Zeroed,
+    File(Arc<dyn File>, u64),
+    /// A page index in the shared memory.
+    Shared(Arc<SharedMemory>, u64),
+    /// A frame of device memory, or `None` past the end of the device.
+    Device(Option<PhysFrame>),
+}
+
+/// A page that is about to be faulted in.
+pub struct PendingPage {
+    page: Page,
+    flags: PageTableFlags,
+    source: Source,
+}
+
+impl PendingPage {
+    /// Finds or fills the frame that the page should be mapped to.
+    /// This may read from a file, which can block,
+    /// so the caller should not hold any locks.
+    pub fn load(self) -> Result<LoadedPage, Errno> {
+        let (frame, shared) = match self.source {
+            Source::Zeroed => (frame::allocate_zeroed_frame().ok_or(OutOfMemory)?, false),
+            Source::File(file, offset) => (read_page(&*file, offset)?, false),
+            Source::Shared(memory, index) => (memory.frame(index)?, true),
+            Source::Device(frame) => (frame.ok_or(Errno::ENXIO)?, true),
+        };
+        Ok(LoadedPage {
+            page: self.page,
+            flags: self.flags,
+            frame,
+            shared,
+        })
+    }
+}
+
+/// Reads a page of a file into a fresh frame. Anything past the end of the file is zeroed.
+fn read_page(file: &dyn File, offset: u64) -> Result<PhysFrame, Errno> {
+    let frame = frame::allocate_zeroed_frame().ok_or(OutOfMemory)?;
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            PAGE_SIZE as usize,
+        )
+    };
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(offset + filled as u64, &mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(errno) => {
+                unsafe { frame::deallocate_frame(frame) };
+                return Err(errno);
+            }
+        }
+    }
+    Ok(frame)
+}
+
+/// A frame that is ready to be mapped. It is freed if it is dropped without being mapped.
+pub struct LoadedPage {
+    page: Page,
+    flags: PageTableFlags,
+    frame: PhysFrame,
+    /// If set, the frame belongs to a [SharedMemory], which the page's area keeps alive,
+    /// or to a device.
+    shared: bool,
+}
+
+impl LoadedPage {
+    /// Maps the page, unless something has been mapped there in the meantime.
+    pub fn map(self, address_space: &mut AddressSpace) -> Result<(), OutOfMemory> {
+        if address_space.is_mapped(self.page) {
+            return Ok(());
+        }
+        unsafe {
+            address_space.map_frame(self.page, self.frame, self.flags, self.shared)?;
+        }
+        core::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Drop for LoadedPage {
+    fn drop(&mut self) {
+        if !self.shared {
+            unsafe { frame::deallocate_frame(self.frame) };
+        }
+    }
+}
+
+#[test_case]
+fn test_protection_changes() {
+    fn flags(address_space: &AddressSpace, addr: u64) -> PageTableFlags {
+        address_space.translate(VirtAddr::new(addr)).unwrap().1
+    }
+
+    let free_frames = frame::stats().free();
+    let mut address_space = AddressSpace::new().unwrap();
+    let mut vmas = VmaList::default();
+
+    let start = super::USER_SPACE_START;
+    let end = start + 4 * PAGE_SIZE;
+    let read_write = Protection::READ | Protection::WRITE;
+    vmas.insert(Vma::new(start, end, read_write, Backing::Anonymous));
+    for addr in (start..end).step_by(PAGE_SIZE as usize) {
+        let page = vmas.pending_page(addr, Access::Write).unwrap();
+        page.load().unwrap().map(&mut address_space).unwrap();
+    }
+    assert!(vmas.pending_page(start, Access::Execute).is_none());
+    assert!(vmas.pending_page(end, Access::Read).is_none());
+
+    // Making the middle pages read-only splits the area, and updates the mapped pages.
+    vmas.protect(
+        &mut address_space,
+        start + PAGE_SIZE,
+        end - PAGE_SIZE,
+        Protection::READ,
+    )
+    .unwrap();
+    assert_eq!(vmas.iter().count(), 3);
+    assert!(Access::Write.is_allowed_by(flags(&address_space, start)));
+    assert!(!Access::Write.is_allowed_by(flags(&address_space, start + PAGE_SIZE)));
+    assert!(Access::Read.is_allowed_by(flags(&address_space, start + PAGE_SIZE)));
+    assert!(vmas
+        .pending_page(start + PAGE_SIZE, Access::Write)
+        .is_none());
+
+    vmas.protect(&mut address_space, start, end, Protection::empty())
+        .unwrap();
+    assert!(!Access::Read.is_allowed_by(flags(&address_space, start)));
+    assert_eq!(
+        vmas.protect(&mut address_space, start, end + PAGE_SIZE, read_write),
+        Err(Errno::ENOMEM)
+    );
+
+    vmas.remove(&mut address_space, start, end);
+    assert_eq!(vmas.iter().count(), 0);
+    assert!(!address_space.is_mapped(Page::containing_address(VirtAddr::new(start))));
+
+    // Every frame comes back once the page tables are freed too.
+    drop(address_space);
+    assert_eq!(frame::stats().free(), free_frames);
+}
+
+#[test_case]
+fn test_file_and_shared_backing() {
+    struct Counting;
+    impl File for Counting {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+            // A file of 6000 bytes, where each byte is its offset modulo 256.
+            let len = buf.len().min(6000usize.saturating_sub(offset as usize));
+            for (i, byte) in buf[..len].iter_mut().enumerate() {
+                *byte = (offset as usize + i) as u8;
+            }
+            Ok(len)
+        }
+    }
+
+    let mut address_space = AddressSpace::new().unwrap();
+    let mut vmas = VmaList::default();
+    let start = super::USER_SPACE_START;
+    let file: Arc<dyn File> = Arc::new(Counting);
+    vmas.insert(Vma::new(
+        start,
+        start + 2 * PAGE_SIZE,
+        Protection::READ,
+        Backing::File { file, offset: 0 },
+    ));
+    let memory = SharedMemory::new();
+    let shared_start = start + 4 * PAGE_SIZE;
+    for offset in [0, PAGE_SIZE] {
+        vmas.insert(Vma::new(
+            shared_start + offset,
+            shared_start + offset + PAGE_SIZE,
+            Protection::READ | Protection::WRITE,
+            Backing::Shared {
+                memory: memory.clone(),
+                offset: 0,
+            },
+        ));
+    }
+    for addr in [
+        start,
+        start + PAGE_SIZE,
+        shared_start,
+        shared_start + PAGE_SIZE,
+    ] {
+        let page = vmas.pending_page(addr, Access::Read).unwrap();
+        page.load().unwrap().map(&mut address_space).unwrap();
+    }
+
+    let byte_at = |addr: u64| {
+        let (frame, _) = address_space.translate(VirtAddr::new(addr)).unwrap();
+        unsafe { *phys_to_virt(frame.start_address() + addr % PAGE_SIZE).as_ptr::<u8>() }
+    };
+    assert_eq!(byte_at(start + 300), 44);
+    assert_eq!(byte_at(start + PAGE_SIZE + 1000), (PAGE_SIZE + 1000) as u8);
+    // The second page is only partly filled by the file.
+    assert_eq!(byte_at(start + 6000), 0);
+
+    // Both shared areas map the same frame.
+    let frame_at = |addr| address_space.translate(VirtAddr::new(addr)).unwrap().0;
+    assert_eq!(frame_at(shared_start), frame_at(shared_start + PAGE_SIZE));
+    assert_eq!(memory.resident_pages(), 1);
+
+    // Unmapping shared pages leaves their frames alone.
+    vmas.remove(&mut address_space, start, shared_start + 2 * PAGE_SIZE);
+    assert_eq!(memory.resident_pages(), 1);
+}
@@ -0,0 +1,386 @@
+//! Network interfaces, and the protocols that we speak over them.
+//!
+//! Drivers register each network card that they find with [add_interface], which names it
+//! `eth0`, `eth1` and so on, and there is a [loopback] interface called `lo`.
+//! A card's interrupt handler does nothing but [wake] the network task, which then [poll]s every
+//! interface. Polling an interface passes each frame that has arrived to its receive callback,
+//! which hands it up through [ethernet] to [arp] or [ipv4], and from there to [icmp], [udp] or [tcp].
+//! Since this happens in a task rather than an interrupt handler, the protocols may allocate,
+//! take locks and send replies straight away. The network task also wakes several times a second,
+//! for cards that cannot interrupt and for the protocols' timers.
+//!
+//! The rest of the kernel uses the network through the sockets in [udp] and [tcp],
+//! and looks up names with [dns]. Processes use them through the file descriptors in [socket].
+
+pub mod arp;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
+use bytemuck::{Pod, Zeroable};
+use ipv4::Ipv4Config;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    config,
+    errno::Errno,
+    file, println,
+    scheduler::{self, WaitQueue},
+    serial_println,
+    task::Task,
+    timer,
+};
+
+/// The most data that an Ethernet frame carries.
+pub const MTU: usize = 1500;
+/// The size of the destination and source addresses and the EtherType at the start of a frame.
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// The largest frame that a card sends or receives, not counting the frame check sequence,
+/// which the card adds and removes itself.
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + MTU;
+/// Shorter frames must be padded to this size before they are sent.
+pub const MIN_FRAME_SIZE: usize = 60;
+
+/// How often the network task wakes up even if no card interrupts.
+const POLL_INTERVAL_TICKS: u64 = timer::TICKS_PER_SECOND / 10;
+
+/// The hardware address of a network card.
+#[repr(transparent)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const ZERO: Self = Self([0; 6]);
+    pub const BROADCAST: Self = Self([0xff; 6]);
+
+    /// Whether frames sent to this address go to a group of cards rather than just one.
+    /// This includes the broadcast address.
+    pub fn is_multicast(self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// Called with each Ethernet frame that an interface receives.
+pub type ReceiveCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// A network card that sends and receives Ethernet frames.
+pub trait NetworkInterface: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    /// Whether the card is connected to a network.
+    fn link_up(&self) -> bool;
+
+    /// Queues a frame to be sent. The frame starts with the Ethernet header,
+    /// and must be no longer than [MAX_FRAME_SIZE]. Shorter frames are padded as needed.
+    fn send(&self, frame: &[u8]) -> Result<(), Errno>;
+
+    /// Sets the function that [NetworkInterface::poll] passes frames to,
+    /// replacing any that was set before.
+    fn set_receive_callback(&self, callback: ReceiveCallback);
+
+    /// Passes each frame that has arrived since the last poll to the receive callback,
+    /// and returns how many there were. Frames that arrive before there is a callback are dropped.
+    fn poll(&self) -> usize;
+
+    /// Whether frames sent through this interface come straight back,
+    /// so that there are no other machines to find with ARP.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+/// Somewhere for a driver to keep its receive callback.
+pub struct Receiver(Mutex<Option<ReceiveCallback>>);
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    pub fn set(&self, callback: ReceiveCallback) {
+        *self.0.lock() = Some(callback);
+    }
+
+    /// Passes a frame to the callback, if there is one.
+    /// The lock is not held during the call, so the callback may replace itself.
+    pub fn deliver(&self, frame: &[u8]) {
+        let callback = self.0.lock().clone();
+        if let Some(callback) = callback {
+            callback(frame);
+        }
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A network card that has been given a name, and perhaps an address.
+pub struct Interface {
+    pub name: String,
+    pub device: Arc<dyn NetworkInterface>,
+    config: Mutex<Option<Ipv4Config>>,
+    arp: Mutex<arp::Cache>,
+}
+
+impl Interface {
+    /// The interface's address and the network that it is on, if it has been given them.
+    pub fn config(&self) -> Option<Ipv4Config> {
+        *self.config.lock()
+    }
+
+    pub fn set_config(&self, config: Option<Ipv4Config>) {
+        match config {
+            Some(config) => println!("{}: {}", self.name, config),
+            None => println!("{}: no address", self.name),
+        }
+        *self.config.lock() = config;
+    }
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Woken by [wake], and waited on by the network task.
+static ACTIVITY: WaitQueue = WaitQueue::new();
+static PENDING: AtomicBool = AtomicBool::new(false);
+/// Woken whenever the interfaces have been polled,
+/// so that tasks waiting on the network can see whether what they are waiting for has happened.
+static POLLED: WaitQueue = WaitQueue::new();
+
+/// Adds the loopback interface, gives the interfaces the addresses in the settings,
+/// and starts the network task. Interfaces without an address in the settings get one with [dhcp].
+/// Cards found after this have no address until they are given one.
+///
+/// A setting such as `net.eth0.address = 10.0.2.15/24` gives an interface its address and the size
+/// of its network, and one such as `net.eth0.gateway = 10.0.2.2` the router to other networks.
+/// `net.dns = 10.0.2.3` gives the DNS servers, separated by commas, if DHCP doesn't.
+pub fn init() {
+    let lo = add_interface(Arc::new(loopback::Loopback::new()));
+    lo.set_config(Some(Ipv4Config {
+        address: ipv4::LOCALHOST,
+        prefix_len: 8,
+        gateway: None,
+    }));
+    for interface in interfaces() {
+        if interface.device.is_loopback() {
+            continue;
+        }
+        let Some(address) = config::get(&format!("net.{}.address", interface.name)) else {
+            dhcp::start(interface);
+            continue;
+        };
+        let gateway = config::get(&format!("net.{}.gateway", interface.name));
+        match Ipv4Config::parse(&address, gateway.as_deref()) {
+            Some(config) => interface.set_config(Some(config)),
+            None => {
+                serial_println!("{}: bad address {}", interface.name, address);
+            }
+        }
+    }
+
+    if let Some(servers) = config::get("net.dns") {
+        let servers = servers.split(',').map(|server| server.trim().parse());
+        match servers.collect() {
+            Ok(servers) => dns::set_servers(servers),
+            Err(_) => {
+                serial_println!("Bad DNS servers in net.dns");
+            }
+        }
+    }
+
+    timer::add_tick_handler(tick);
+    scheduler::spawn(Task::new_kernel("net", || loop {
+        ACTIVITY.wait_until(|| PENDING.swap(false, Ordering::Acquire).then_some(()));
+        poll();
+    }));
+}
+
+fn tick() {
+    if timer::ticks().is_multiple_of(POLL_INTERVAL_TICKS) {
+        wake();
+    }
+}
+
+/// Makes the network task poll every interface soon. This may be called from interrupt handlers.
+pub fn wake() {
+    PENDING.store(true, Ordering::Release);
+    ACTIVITY.wake_all();
+}
+
+/// Handles the frames that have arrived on every interface, and whatever the protocols' timers
+/// say is due. Returns how many frames there were.
+pub fn poll() -> usize {
+    let mut count = 0;
+    for interface in interfaces() {
+        count += interface.device.poll();
+    }
+    let now = timer::ticks();
+    for interface in interfaces() {
+        arp::expire(&interface, now);
+    }
+    ipv4::expire(now);
+    tcp::expire(now);
+    POLLED.wake_all();
+    file::notify_ready();
+    count
+}
+
+/// Blocks until `condition` returns `Some`, while the network task handles frames.
+/// The condition is checked after every poll, which happens at least several times a second.
+///
+/// Outside of a task, as while testing, this polls the interfaces itself,
+/// letting interrupts in between so that time passes.
+pub fn wait_until<T>(mut condition: impl FnMut() -> Option<T>) -> T {
+    if scheduler::current().is_some() {
+        return POLLED.wait_until(condition);
+    }
+    let interrupts_were_enabled = interrupts::are_enabled();
+    loop {
+        poll();
+        if let Some(result) = condition() {
+            if !interrupts_were_enabled {
+                interrupts::disable();
+            }
+            return result;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Blocks like [wait_until], for a condition that can fail, as the system calls on sockets do.
+/// Fails with [Errno::EINTR] if the process has a signal to handle first.
+pub fn wait_interruptible<T>(
+    condition: impl FnMut() -> Option<Result<T, Errno>>,
+) -> Result<T, Errno> {
+    if scheduler::current().is_some() {
+        return POLLED.wait_interruptible(condition)?;
+    }
+    wait_until(condition)
+}
+
+/// The ports that [udp] and [tcp] give sockets that are bound to port 0.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=u16::MAX;
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(*EPHEMERAL_PORTS.start());
+
+/// Finds a port for a socket that didn't ask for a particular one, trying each in turn
+/// from where the last search left off.
+fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Result<u16, Errno> {
+    let count = EPHEMERAL_PORTS.len() as u16;
+    for _ in 0..count {
+        let port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+        let port = EPHEMERAL_PORTS.start() + port.wrapping_sub(*EPHEMERAL_PORTS.start()) % count;
+        if !in_use(port) {
+            return Ok(port);
+        }
+    }
+    Err(Errno::EADDRINUSE)
+}
+
+/// Gives a network card the next free name, and arranges for the frames that it receives to be
+/// handled. Cards have no address until they are given one.
+pub fn add_interface(device: Arc<dyn NetworkInterface>) -> Arc<Interface> {
+    let mut interfaces = INTERFACES.lock();
+    let name = if device.is_loopback() {
+        "lo".into()
+    } else {
+        let cards = interfaces
+            .iter()
+            .filter(|interface| !interface.device.is_loopback())
+            .count();
+        format!("eth{cards}")
+    };
+    println!(
+        "{name}: {}, link {}",
+        device.mac_address(),
+        if device.link_up() { "up" } else { "down" }
+    );
+    let interface = Arc::new(Interface {
+        name,
+        device: device.clone(),
+        config: Mutex::new(None),
+        arp: Mutex::new(arp::Cache::new()),
+    });
+    // Interfaces are never removed, so the cycle that this makes doesn't matter.
+    let receiving = interface.clone();
+    device.set_receive_callback(Arc::new(move |frame: &[u8]| {
+        ethernet::receive(&receiving, frame);
+    }));
+    interfaces.push(interface.clone());
+    interface
+}
+
+/// The network interfaces, in the order that they were added.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .cloned()
+}
+
+/// Checks that a card can reach the router on QEMU's user-mode network, where the host is
+/// 10.0.2.2 and we are 10.0.2.15, by asking for the router's address with ARP.
+#[cfg(test)]
+pub fn test_card(card: &dyn NetworkInterface) {
+    use core::net::Ipv4Addr;
+
+    assert!(card.link_up());
+    let mac = card.mac_address();
+    assert!(mac != MacAddress::ZERO && !mac.is_multicast());
+    assert_eq!(card.send(&[0; MAX_FRAME_SIZE + 1]), Err(Errno::EMSGSIZE));
+
+    let interface = interfaces()
+        .into_iter()
+        .find(|interface| core::ptr::addr_eq(Arc::as_ptr(&interface.device), card))
+        .expect("card has no interface");
+    if interface.config().is_none() {
+        interface.set_config(Ipv4Config::parse("10.0.2.15/24", Some("10.0.2.2")));
+    }
+    let router = Ipv4Addr::new(10, 0, 2, 2);
+    // Enough to go round any card's transmit ring more than once.
+    for _ in 0..300 {
+        arp::request(&interface, router).unwrap();
+    }
+    let deadline = timer::ticks() + 5 * timer::TICKS_PER_SECOND;
+    let found = wait_until(|| match arp::lookup(&interface, router) {
+        Some(mac) => Some(Some(mac)),
+        None => (timer::ticks() > deadline).then_some(None),
+    });
+    assert!(found.is_some(), "no ARP reply from the router");
+}
+
+#[test_case]
+fn test_mac_address() {
+    use alloc::string::ToString;
+
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(mac.to_string(), "52:54:00:12:34:56");
+    assert!(!mac.is_multicast());
+    assert!(MacAddress::BROADCAST.is_multicast());
+}
@@ -0,0 +1,308 @@
+//! The Address Resolution Protocol, which finds the card that has a given IPv4 address.
+//!
+//! Each interface has a cache of the addresses that it has found. Packets for an address that is
+//! not in the cache wait in the cache while we ask for it, and are sent when the answer comes,
+//! or dropped if it never does. Entries expire after a while, in case the address moves.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+use core::net::Ipv4Addr;
+
+use super::{ethernet, Interface, MacAddress};
+use crate::{errno::Errno, timer};
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+/// The size of a packet that maps IPv4 addresses to Ethernet addresses.
+const PACKET_SIZE: usize = 28;
+
+/// How long an address stays in the cache.
+const LIFETIME: u64 = 60 * timer::TICKS_PER_SECOND;
+/// How long to wait for an answer before asking again, and how many times to ask.
+const RETRY_INTERVAL: u64 = timer::TICKS_PER_SECOND;
+const MAX_REQUESTS: u32 = 3;
+/// The most packets that can wait for one address. Older ones are dropped to make room.
+const MAX_WAITING: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    operation: u16,
+    sender_mac: MacAddress,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddress,
+    target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// Parses a packet, if it maps IPv4 addresses to Ethernet addresses.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < PACKET_SIZE
+            || u16::from_be_bytes([bytes[0], bytes[1]]) != HARDWARE_ETHERNET
+            || u16::from_be_bytes([bytes[2], bytes[3]]) != ethernet::ETHERTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return None;
+        }
+        Some(Self {
+            operation: u16::from_be_bytes([bytes[6], bytes[7]]),
+            sender_mac: MacAddress(bytes[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[14..18]).unwrap()),
+            target_mac: MacAddress(bytes[18..24].try_into().unwrap()),
+            target_ip: Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[24..28]).unwrap()),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_SIZE);
+        bytes.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes.extend_from_slice(&ethernet::ETHERTYPE_IPV4.to_be_bytes());
+        bytes.extend_from_slice(&[6, 4]);
+        bytes.extend_from_slice(&self.operation.to_be_bytes());
+        bytes.extend_from_slice(&self.sender_mac.0);
+        bytes.extend_from_slice(&self.sender_ip.octets());
+        bytes.extend_from_slice(&self.target_mac.0);
+        bytes.extend_from_slice(&self.target_ip.octets());
+        bytes
+    }
+}
+
+enum Entry {
+    Resolved {
+        mac: MacAddress,
+        expires: u64,
+    },
+    /// We have asked for the address, and these IPv4 packets are waiting for it.
+    Pending {
+        waiting: Vec<Vec<u8>>,
+        requests: u32,
+        retry_at: u64,
+    },
+}
+
+/// The addresses that an interface has found, and the ones that it is looking for.
+pub struct Cache {
+    entries: BTreeMap<Ipv4Addr, Entry>,
+}
+
+impl Cache {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn lookup(&self, ip: Ipv4Addr, now: u64) -> Option<MacAddress> {
+        match self.entries.get(&ip)? {
+            &Entry::Resolved { mac, expires } if now < expires => Some(mac),
+            _ => None,
+        }
+    }
+
+    /// Records an address, and returns the packets that were waiting for it.
+    fn insert(&mut self, ip: Ipv4Addr, mac: MacAddress, now: u64) -> Vec<Vec<u8>> {
+        let entry = Entry::Resolved {
+            mac,
+            expires: now + LIFETIME,
+        };
+        match self.entries.insert(ip, entry) {
+            Some(Entry::Pending { waiting, .. }) => waiting,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Makes a packet wait for an address. Returns whether a request for it should be sent,
+    /// because nobody has asked for it yet.
+    fn enqueue(&mut self, ip: Ipv4Addr, packet: Vec<u8>, now: u64) -> bool {
+        match self.entries.get_mut(&ip) {
+            Some(Entry::Pending { waiting, .. }) => {
+                if waiting.len() == MAX_WAITING {
+                    waiting.remove(0);
+                }
+                waiting.push(packet);
+                false
+            }
+            _ => {
+                let entry = Entry::Pending {
+                    waiting: alloc::vec![packet],
+                    requests: 1,
+                    retry_at: now + RETRY_INTERVAL,
+                };
+                self.entries.insert(ip, entry);
+                true
+            }
+        }
+    }
+
+    /// Forgets addresses that have expired, and gives up on those that nobody has answered for.
+    /// Returns the addresses that should be asked for again.
+    fn expire(&mut self, now: u64) -> Vec<Ipv4Addr> {
+        let mut retry = Vec::new();
+        self.entries.retain(|&ip, entry| match entry {
+            Entry::Resolved { expires, .. } => now < *expires,
+            Entry::Pending {
+                requests, retry_at, ..
+            } => {
+                if now < *retry_at {
+                    true
+                } else if *requests < MAX_REQUESTS {
+                    *requests += 1;
+                    *retry_at = now + RETRY_INTERVAL;
+                    retry.push(ip);
+                    true
+                } else {
+                    false
+                }
+            }
+        });
+        retry
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles an ARP packet that arrived on an interface.
+pub fn receive(interface: &Interface, bytes: &[u8]) {
+    let (Some(packet), Some(config)) = (Packet::parse(bytes), interface.config()) else {
+        return;
+    };
+    let now = timer::ticks();
+    let for_us = packet.target_ip == config.address;
+    // Learn the sender's address if it is talking to us, or if we already knew it,
+    // so that a machine that changes its address is heard.
+    let waiting = {
+        let mut cache = interface.arp.lock();
+        if for_us || cache.entries.contains_key(&packet.sender_ip) {
+            cache.insert(packet.sender_ip, packet.sender_mac, now)
+        } else {
+            Vec::new()
+        }
+    };
+    for ip_packet in waiting {
+        let _ = ethernet::send(
+            interface,
+            packet.sender_mac,
+            ethernet::ETHERTYPE_IPV4,
+            &ip_packet,
+        );
+    }
+
+    if for_us && packet.operation == OPERATION_REQUEST {
+        let reply = Packet {
+            operation: OPERATION_REPLY,
+            sender_mac: interface.device.mac_address(),
+            sender_ip: config.address,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = ethernet::send(
+            interface,
+            packet.sender_mac,
+            ethernet::ETHERTYPE_ARP,
+            &reply.to_bytes(),
+        );
+    }
+}
+
+/// Broadcasts a request for the card with the given address.
+pub fn request(interface: &Interface, ip: Ipv4Addr) -> Result<(), Errno> {
+    let sender_ip = interface
+        .config()
+        .map_or(Ipv4Addr::UNSPECIFIED, |config| config.address);
+    let request = Packet {
+        operation: OPERATION_REQUEST,
+        sender_mac: interface.device.mac_address(),
+        sender_ip,
+        target_mac: MacAddress::ZERO,
+        target_ip: ip,
+    };
+    ethernet::send(
+        interface,
+        MacAddress::BROADCAST,
+        ethernet::ETHERTYPE_ARP,
+        &request.to_bytes(),
+    )
+}
+
+/// The card with the given address, if it is in the interface's cache.
+pub fn lookup(interface: &Interface, ip: Ipv4Addr) -> Option<MacAddress> {
+    interface.arp.lock().lookup(ip, timer::ticks())
+}
+
+/// Sends an IPv4 packet to the card with the given address on the interface's network,
+/// first asking for the card if we don't know it.
+pub fn send(interface: &Interface, ip: Ipv4Addr, packet: Vec<u8>) -> Result<(), Errno> {
+    let now = timer::ticks();
+    let ask = {
+        let mut cache = interface.arp.lock();
+        if let Some(mac) = cache.lookup(ip, now) {
+            drop(cache);
+            return ethernet::send(interface, mac, ethernet::ETHERTYPE_IPV4, &packet);
+        }
+        cache.enqueue(ip, packet, now)
+    };
+    if ask {
+        request(interface, ip)?;
+    }
+    Ok(())
+}
+
+/// Expires the interface's cache, and asks again for the addresses that are still unanswered.
+pub fn expire(interface: &Interface, now: u64) {
+    let retry = interface.arp.lock().expire(now);
+    for ip in retry {
+        let _ = request(interface, ip);
+    }
+}
+
+/// The addresses in the interface's cache, and the cards that have them.
+pub fn entries(interface: &Interface) -> Vec<(Ipv4Addr, MacAddress)> {
+    let now = timer::ticks();
+    let cache = interface.arp.lock();
+    cache
+        .entries
+        .keys()
+        .filter_map(|&ip| Some((ip, cache.lookup(ip, now)?)))
+        .collect()
+}
+
+#[test_case]
+fn test_cache() {
+    let packet = Packet {
+        operation: OPERATION_REQUEST,
+        sender_mac: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+        sender_ip: Ipv4Addr::new(10, 0, 2, 15),
+        target_mac: MacAddress::ZERO,
+        target_ip: Ipv4Addr::new(10, 0, 2, 2),
+    };
+    assert_eq!(Packet::parse(&packet.to_bytes()), Some(packet));
+
+    let mut cache = Cache::new();
+    let ip = Ipv4Addr::new(10, 0, 2, 2);
+    let mac = MacAddress([0x52, 0x55, 10, 0, 2, 2]);
+    // The first packet for an address asks for it, and later ones wait with it.
+    assert!(cache.enqueue(ip, alloc::vec![1], 0));
+    assert!(!cache.enqueue(ip, alloc::vec![2], 0));
+    assert_eq!(cache.lookup(ip, 0), None);
+    assert_eq!(cache.expire(RETRY_INTERVAL), [ip]);
+    assert_eq!(cache.insert(ip, mac, 10), [[1], [2]]);
+    assert_eq!(cache.lookup(ip, 10), Some(mac));
+    assert!(cache.expire(10 + LIFETIME).is_empty());
+    assert_eq!(cache.lookup(ip, 10 + LIFETIME), None);
+
+    // Nobody answers for this one.
+    let nobody = Ipv4Addr::new(10, 0, 2, 99);
+    cache.enqueue(nobody, alloc::vec![3], 0);
+    for retry in 1..MAX_REQUESTS as u64 {
+        assert_eq!(cache.expire(retry * RETRY_INTERVAL), [nobody]);
+    }
+    assert!(cache
+        .expire(MAX_REQUESTS as u64 * RETRY_INTERVAL)
+        .is_empty());
+    assert!(cache.entries.is_empty());
+}
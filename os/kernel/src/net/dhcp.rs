@@ -0,0 +1,349 @@
+//! A DHCP client, which asks the network for an interface's address, its router and its DNS servers.
+//!
+//! The client broadcasts a DISCOVER, takes the first OFFER, asks for the offered address with
+//! a REQUEST, and configures the interface once the server confirms it with an ACK. The address is
+//! leased for a while, so the client asks again when half of the lease has gone, and gives the
+//! address up if the lease runs out without being renewed.
+
+use alloc::{collections::btree_map::BTreeMap, format, sync::Arc, vec, vec::Vec};
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use super::{dns, ipv4::Ipv4Config, udp::UdpSocket, wait_until, Interface, MacAddress};
+use crate::{errno::Errno, println, scheduler, task::Task, timer};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies, since we can't receive anything else without an address.
+const FLAG_BROADCAST: u16 = 0x8000;
+/// Comes between the fixed part of a message and its options.
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// The size of the fixed part of a message, up to and including the magic cookie.
+const FIXED_SIZE: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_HOST_NAME: u8 = 12;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// How long to wait for the first reply. This doubles with each attempt.
+const REPLY_TIMEOUT: u64 = 2 * timer::TICKS_PER_SECOND;
+const ATTEMPTS: u32 = 4;
+/// How long to wait before trying again after failing to get a lease.
+const RETRY_INTERVAL: u64 = 30 * timer::TICKS_PER_SECOND;
+/// How long a lease lasts if the server doesn't say.
+const DEFAULT_LEASE_TIME: u64 = 24 * 60 * 60;
+
+/// What a DHCP server gave us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub config: Ipv4Config,
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// The server that gave out the lease.
+    pub server: Ipv4Addr,
+    /// How long the lease lasts, in seconds.
+    pub lease_time: u64,
+}
+
+/// A message from a server.
+struct Message {
+    op: u8,
+    xid: u32,
+    /// The address that the server is offering us.
+    yiaddr: Ipv4Addr,
+    chaddr: MacAddress,
+    options: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Message {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < FIXED_SIZE || bytes[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut options = BTreeMap::new();
+        let mut rest = &bytes[FIXED_SIZE..];
+        while let [code, tail @ ..] = rest {
+            match *code {
+                OPTION_PAD => rest = tail,
+                OPTION_END => break,
+                code => {
+                    let (&len, tail) = tail.split_first()?;
+                    let value = tail.get(..len as usize)?;
+                    options.insert(code, value.to_vec());
+                    rest = &tail[len as usize..];
+                }
+            }
+        }
+        Some(Self {
+            op: bytes[0],
+            xid: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            yiaddr: Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[16..20]).unwrap()),
+            chaddr: MacAddress(bytes[28..34].try_into().unwrap()),
+            options,
+        })
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        match self.options.get(&OPTION_MESSAGE_TYPE)?[..] {
+            [kind] => Some(kind),
+            _ => None,
+        }
+    }
+
+    /// The addresses in an option, which holds a list of them.
+    fn addresses(&self, code: u8) -> Vec<Ipv4Addr> {
+        self.options.get(&code).map_or(Vec::new(), |value| {
+            value
+                .chunks_exact(4)
+                .map(|octets| Ipv4Addr::from(<[u8; 4]>::try_from(octets).unwrap()))
+                .collect()
+        })
+    }
+
+    /// The lease that an ACK gives.
+    fn lease(&self) -> Option<Lease> {
+        let server = *self.addresses(OPTION_SERVER_ID).first()?;
+        let netmask = self.addresses(OPTION_SUBNET_MASK).first().copied();
+        let lease_time = match self.options.get(&OPTION_LEASE_TIME).map(|value| &value[..]) {
+            Some(&[a, b, c, d]) => u32::from_be_bytes([a, b, c, d]) as u64,
+            _ => DEFAULT_LEASE_TIME,
+        };
+        Some(Lease {
+            config: Ipv4Config {
+                address: self.yiaddr,
+                prefix_len: netmask.map_or(24, |mask| mask.to_bits().leading_ones() as u8),
+                gateway: self.addresses(OPTION_ROUTER).first().copied(),
+            },
+            dns_servers: self.addresses(OPTION_DNS_SERVERS),
+            server,
+            lease_time,
+        })
+    }
+}
+
+/// Makes a message from the client, with the options that every message has.
+fn message(xid: u32, mac: MacAddress, kind: u8, options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut message = vec![0; FIXED_SIZE];
+    message[..4].copy_from_slice(&[OP_REQUEST, HARDWARE_ETHERNET, 6, 0]);
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&mac.0);
+    message[236..240].copy_from_slice(&MAGIC_COOKIE);
+    let kind = [kind];
+    let parameters = [
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS_SERVERS,
+        OPTION_LEASE_TIME,
+    ];
+    let options = [
+        (OPTION_MESSAGE_TYPE, &kind[..]),
+        (OPTION_HOST_NAME, b"funcos"),
+        (OPTION_PARAMETERS, &parameters),
+    ]
+    .into_iter()
+    .chain(options.iter().copied());
+    for (code, value) in options {
+        message.extend_from_slice(&[code, value.len() as u8]);
+        message.extend_from_slice(value);
+    }
+    message.push(OPTION_END);
+    message
+}
+
+/// Waits for a reply to our transaction of one of the given types, until the deadline.
+fn receive(
+    socket: &UdpSocket,
+    xid: u32,
+    mac: MacAddress,
+    types: &[u8],
+    deadline: u64,
+) -> Option<Message> {
+    let mut buffer = vec![0; 1500];
+    wait_until(|| {
+        while let Ok((len, _)) = socket.try_recv_from(&mut buffer) {
+            let Some(message) = Message::parse(&buffer[..len]) else {
+                continue;
+            };
+            if message.op == OP_REPLY
+                && message.xid == xid
+                && message.chaddr == mac
+                && message
+                    .message_type()
+                    .is_some_and(|kind| types.contains(&kind))
+            {
+                return Some(Some(message));
+            }
+        }
+        (timer::ticks() >= deadline).then_some(None)
+    })
+}
+
+/// Asks for a lease for an interface, without configuring the interface.
+/// If the interface has an address already, asks to keep it.
+pub fn acquire(interface: &Arc<Interface>) -> Result<Lease, Errno> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT))?;
+    let servers = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    let mac = interface.device.mac_address();
+    let mut requested = interface.config().map(|config| config.address);
+
+    for attempt in 0..ATTEMPTS {
+        let timeout = REPLY_TIMEOUT << attempt;
+        // Every exchange needs its own transaction ID, so that late replies to an earlier one
+        // aren't taken for replies to this one.
+        let xid = (unsafe { core::arch::x86_64::_rdtsc() } as u32)
+            ^ u32::from_be_bytes(mac.0[2..].try_into().unwrap());
+
+        let octets = requested.map(|address| address.octets());
+        let discover = match &octets {
+            Some(octets) => message(
+                xid,
+                mac,
+                DHCPDISCOVER,
+                &[(OPTION_REQUESTED_ADDRESS, octets)],
+            ),
+            None => message(xid, mac, DHCPDISCOVER, &[]),
+        };
+        socket.send_on(interface, &discover, servers)?;
+        let Some(offer) = receive(&socket, xid, mac, &[DHCPOFFER], timer::ticks() + timeout) else {
+            continue;
+        };
+        let Some(server) = offer.addresses(OPTION_SERVER_ID).first().copied() else {
+            continue;
+        };
+
+        let request = message(
+            xid,
+            mac,
+            DHCPREQUEST,
+            &[
+                (OPTION_REQUESTED_ADDRESS, &offer.yiaddr.octets()),
+                (OPTION_SERVER_ID, &server.octets()),
+            ],
+        );
+        socket.send_on(interface, &request, servers)?;
+        let reply = receive(
+            &socket,
+            xid,
+            mac,
+            &[DHCPACK, DHCPNAK],
+            timer::ticks() + timeout,
+        );
+        match reply {
+            Some(ack) if ack.message_type() == Some(DHCPACK) => {
+                if let Some(lease) = ack.lease() {
+                    return Ok(lease);
+                }
+            }
+            // The server won't let us have that address, so ask for any.
+            Some(_) => requested = None,
+            None => {}
+        }
+    }
+    Err(Errno::ETIMEDOUT)
+}
+
+/// Gets a lease for an interface, and gives the interface its address, router and DNS servers.
+pub fn configure(interface: &Arc<Interface>) -> Result<Lease, Errno> {
+    let lease = acquire(interface)?;
+    println!(
+        "{}: leased {} from {} for {}s",
+        interface.name, lease.config.address, lease.server, lease.lease_time
+    );
+    interface.set_config(Some(lease.config));
+    if !lease.dns_servers.is_empty() {
+        dns::set_servers(lease.dns_servers.clone());
+    }
+    Ok(lease)
+}
+
+/// Starts a task that keeps an interface configured, renewing its lease before it runs out.
+pub fn start(interface: Arc<Interface>) {
+    let name = format!("dhcp {}", interface.name);
+    scheduler::spawn(Task::new_kernel(name, move || {
+        let mut expires = None;
+        loop {
+            let now = timer::ticks();
+            let retry_at = match configure(&interface) {
+                Ok(lease) => {
+                    let lease_time = lease.lease_time * timer::TICKS_PER_SECOND;
+                    expires = Some(now + lease_time);
+                    now + lease_time / 2
+                }
+                Err(_) => {
+                    if expires.is_some_and(|expires| timer::ticks() >= expires) {
+                        println!("{}: lease expired", interface.name);
+                        interface.set_config(None);
+                        expires = None;
+                    }
+                    timer::ticks() + RETRY_INTERVAL
+                }
+            };
+            wait_until(|| (timer::ticks() >= retry_at).then_some(()));
+        }
+    }));
+}
+
+#[test_case]
+fn test_message() {
+    let mac = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    let mut ack = message(
+        0x1234_5678,
+        mac,
+        DHCPACK,
+        &[
+            (OPTION_SUBNET_MASK, &[255, 255, 255, 0]),
+            (OPTION_ROUTER, &[10, 0, 2, 2]),
+            (OPTION_DNS_SERVERS, &[10, 0, 2, 3, 1, 1, 1, 1]),
+            (OPTION_SERVER_ID, &[10, 0, 2, 2]),
+            (OPTION_LEASE_TIME, &86400u32.to_be_bytes()),
+        ],
+    );
+    ack[0] = OP_REPLY;
+    ack[16..20].copy_from_slice(&[10, 0, 2, 15]);
+    let ack = Message::parse(&ack).unwrap();
+    assert_eq!((ack.op, ack.xid, ack.chaddr), (OP_REPLY, 0x1234_5678, mac));
+    assert_eq!(ack.message_type(), Some(DHCPACK));
+    assert_eq!(
+        ack.lease(),
+        Some(Lease {
+            config: Ipv4Config::parse("10.0.2.15/24", Some("10.0.2.2")).unwrap(),
+            dns_servers: vec![Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)],
+            server: Ipv4Addr::new(10, 0, 2, 2),
+            lease_time: 86400,
+        })
+    );
+}
+
+#[test_case]
+fn test_dhcp() {
+    // QEMU's user-mode network has a DHCP server, which gives out addresses from 10.0.2.15.
+    for interface in super::interfaces() {
+        if interface.device.is_loopback() {
+            continue;
+        }
+        let lease = configure(&interface).unwrap();
+        assert_eq!(
+            lease.config,
+            Ipv4Config::parse("10.0.2.15/24", Some("10.0.2.2")).unwrap()
+        );
+        assert_eq!(lease.dns_servers, [Ipv4Addr::new(10, 0, 2, 3)]);
+        assert_eq!(interface.config(), Some(lease.config));
+    }
+}
@@ -0,0 +1,246 @@
+//! A stub resolver, which asks a DNS server for the addresses of a name and remembers the answers.
+//!
+//! The servers come from DHCP, or from the `net.dns` setting, which lists them separated by commas.
+//! Answers are cached for as long as the server says that they stay good, and names that don't
+//! exist are remembered for a short while, so that asking again doesn't go back to the server.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, vec, vec::Vec};
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use spin::Mutex;
+
+use super::{ipv4, udp::UdpSocket, wait_interruptible};
+use crate::{errno::Errno, timer};
+
+pub const PORT: u16 = 53;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const HEADER_SIZE: usize = 12;
+
+/// The longest name, and the longest label in one.
+pub const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// How long to wait for each server to answer, and how many times to ask them all.
+const QUERY_TIMEOUT: u64 = 2 * timer::TICKS_PER_SECOND;
+const ATTEMPTS: u32 = 2;
+/// How long to remember answers, whatever the server says, and how long to remember that a name
+/// doesn't exist.
+const MAX_TTL: u64 = 24 * 60 * 60;
+const NEGATIVE_TTL: u64 = 60;
+const MAX_CACHED: usize = 256;
+
+static SERVERS: Mutex<Vec<Ipv4Addr>> = Mutex::new(Vec::new());
+
+struct CacheEntry {
+    addresses: Result<Vec<Ipv4Addr>, Errno>,
+    expires: u64,
+}
+
+static CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+
+/// Replaces the servers that names are looked up with.
+pub fn set_servers(servers: Vec<Ipv4Addr>) {
+    *SERVERS.lock() = servers;
+}
+
+pub fn servers() -> Vec<Ipv4Addr> {
+    SERVERS.lock().clone()
+}
+
+/// Finds the addresses of a name. Addresses written as numbers are returned as they are,
+/// and `localhost` is ours. Fails with `ENOENT` if the name has no addresses, `ETIMEDOUT` if no
+/// server answers, and `ENETUNREACH` if there are no servers to ask.
+pub fn resolve(name: &str) -> Result<Vec<Ipv4Addr>, Errno> {
+    if let Ok(address) = name.parse() {
+        return Ok(vec![address]);
+    }
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    if name == "localhost" {
+        return Ok(vec![ipv4::LOCALHOST]);
+    }
+    let query = query(0, &name)?;
+
+    let now = timer::ticks();
+    if let Some(entry) = CACHE.lock().get(&name) {
+        if now < entry.expires {
+            return entry.addresses.clone();
+        }
+    }
+
+    let (addresses, ttl) = ask_servers(query)?;
+    let mut cache = CACHE.lock();
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, entry| now < entry.expires);
+        if cache.len() >= MAX_CACHED {
+            cache.pop_first();
+        }
+    }
+    let entry = CacheEntry {
+        addresses: addresses.clone(),
+        expires: now + ttl.min(MAX_TTL) * timer::TICKS_PER_SECOND,
+    };
+    cache.insert(name, entry);
+    addresses
+}
+
+/// Asks each server in turn, until one answers. Returns the answer, and how many seconds it stays good.
+fn ask_servers(mut query: Vec<u8>) -> Result<(Result<Vec<Ipv4Addr>, Errno>, u64), Errno> {
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(Errno::ENETUNREACH);
+    }
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    let mut buffer = vec![0; 512];
+    for _ in 0..ATTEMPTS {
+        for &server in &servers {
+            let server = SocketAddrV4::new(server, PORT);
+            let id = unsafe { core::arch::x86_64::_rdtsc() } as u16;
+            query[..2].copy_from_slice(&id.to_be_bytes());
+            if socket.send_to(&query, server).is_err() {
+                continue;
+            }
+            let deadline = timer::ticks() + QUERY_TIMEOUT;
+            let answer = wait_interruptible(|| {
+                while let Ok((len, from)) = socket.try_recv_from(&mut buffer) {
+                    if from != server {
+                        continue;
+                    }
+                    if let Some(answer) = parse_response(&buffer[..len], id) {
+                        return Some(Ok(Some(answer)));
+                    }
+                }
+                (timer::ticks() >= deadline).then_some(Ok(None))
+            })?;
+            if let Some(answer) = answer {
+                return Ok(answer);
+            }
+        }
+    }
+    Err(Errno::ETIMEDOUT)
+}
+
+/// Makes a query for the A records of a name.
+fn query(id: u16, name: &str) -> Result<Vec<u8>, Errno> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Errno::EINVAL);
+    }
+    let mut query = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question, and no answers or other records.
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(Errno::EINVAL);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Skips over a name that starts at `offset`, and returns where it ends.
+/// Names may end with a pointer to the rest of the name somewhere else in the message.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => {
+                return (offset + 2 <= message.len()).then_some(offset + 2)
+            }
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        message.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Parses the response to a query with the given ID. Returns the addresses, or `ENOENT` if the
+/// name has none, and how many seconds the answer stays good. Returns `None` if the message isn't
+/// a response to the query, or the server couldn't answer it.
+///
+/// The server may answer with aliases before the addresses, so every address in the answer counts.
+fn parse_response(message: &[u8], id: u16) -> Option<(Result<Vec<Ipv4Addr>, Errno>, u64)> {
+    let flags = read_u16(message, 2)?;
+    if read_u16(message, 0)? != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Some((Err(Errno::ENOENT), NEGATIVE_TTL)),
+        _ => return None,
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        // Each question has a type and class after the name.
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = MAX_TTL;
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let kind = read_u16(message, offset)?;
+        let class = read_u16(message, offset + 2)?;
+        let record_ttl =
+            u32::from_be_bytes(message.get(offset + 4..offset + 8)?.try_into().unwrap());
+        let len = read_u16(message, offset + 8)? as usize;
+        let data = message.get(offset + 10..offset + 10 + len)?;
+        offset += 10 + len;
+        ttl = ttl.min(record_ttl as u64);
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            addresses.push(Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap()));
+        }
+    }
+    if addresses.is_empty() {
+        return Some((Err(Errno::ENOENT), NEGATIVE_TTL));
+    }
+    Some((Ok(addresses), ttl))
+}
+
+#[test_case]
+fn test_parse_response() {
+    let mut response = query(0x4242, "www.example.com").unwrap();
+    let question_end = response.len();
+    response[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_RECURSION_DESIRED).to_be_bytes());
+    response[6..8].copy_from_slice(&2u16.to_be_bytes());
+    // An alias, pointing back at the name in the question, and then an address for it.
+    response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 120, 0, 4]);
+    response.extend_from_slice(&[1, b'e', 0xc0, 16]);
+    response.extend_from_slice(&[0xc0, question_end as u8, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    response.extend_from_slice(&[93, 184, 215, 14]);
+    assert_eq!(
+        parse_response(&response, 0x4242),
+        Some((Ok(vec![Ipv4Addr::new(93, 184, 215, 14)]), 60))
+    );
+    assert_eq!(parse_response(&response, 0x4243), None);
+
+    response[3] |= RCODE_NAME_ERROR as u8;
+    assert_eq!(
+        parse_response(&response, 0x4242),
+        Some((Err(Errno::ENOENT), NEGATIVE_TTL))
+    );
+}
+
+#[test_case]
+fn test_resolve() {
+    assert_eq!(resolve("10.0.2.2"), Ok(vec![Ipv4Addr::new(10, 0, 2, 2)]));
+    assert_eq!(resolve("LocalHost."), Ok(vec![ipv4::LOCALHOST]));
+    assert_eq!(resolve("bad..name"), Err(Errno::EINVAL));
+}
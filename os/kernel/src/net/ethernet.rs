@@ -0,0 +1,43 @@
+//! Ethernet frames: a destination and source address, a type, and a payload.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{arp, ipv4, Interface, MacAddress, ETHERNET_HEADER_SIZE};
+use crate::errno::Errno;
+
+/// The kinds of payload that we understand.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Hands a frame that arrived on an interface to the protocol that it is for.
+pub fn receive(interface: &Arc<Interface>, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let destination = MacAddress(frame[0..6].try_into().unwrap());
+    // Cards may pass on frames for other machines' addresses.
+    if destination != interface.device.mac_address() && !destination.is_multicast() {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::receive(interface, payload),
+        ETHERTYPE_IPV4 => ipv4::receive(interface, payload),
+        _ => {}
+    }
+}
+
+/// Sends a payload of the given type to another card on the same network.
+pub fn send(
+    interface: &Interface,
+    destination: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<(), Errno> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&interface.device.mac_address().0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    interface.device.send(&frame)
+}
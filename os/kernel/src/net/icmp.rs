@@ -0,0 +1,124 @@
+//! ICMP, which carries echo requests and replies for `ping`, and reports errors such as a packet
+//! arriving for a port that nobody is listening on.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use super::{
+    ipv4::{self, Checksum, Header},
+    wait_until, Interface,
+};
+use crate::{errno::Errno, timer};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// The size of the type, code, checksum and the four bytes that depend on the type.
+const HEADER_SIZE: usize = 8;
+
+/// Makes a message, filling in its checksum.
+fn message(kind: u8, code: u8, rest: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(payload);
+    let checksum = Checksum::new().add(&message).finish();
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+fn send(destination: Ipv4Addr, message: &[u8]) -> Result<(), Errno> {
+    let route = ipv4::route(destination)?;
+    ipv4::send(&route, destination, ipv4::PROTOCOL_ICMP, message)
+}
+
+/// The echo requests that we are waiting for replies to, by identifier and sequence number,
+/// and when the replies came.
+static PINGS: Mutex<BTreeMap<(u16, u16), Option<u64>>> = Mutex::new(BTreeMap::new());
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+/// The identifier of our echo requests.
+const PING_ID: u16 = 0xf0c5;
+
+/// Handles an ICMP message that arrived on an interface.
+pub fn receive(_interface: &Arc<Interface>, header: &Header, message: &[u8]) {
+    if message.len() < HEADER_SIZE || Checksum::new().add(message).finish() != 0 {
+        return;
+    }
+    let rest: [u8; 4] = message[4..8].try_into().unwrap();
+    match message[0] {
+        TYPE_ECHO_REQUEST if !header.destination.is_broadcast() => {
+            let reply = self::message(TYPE_ECHO_REPLY, 0, rest, &message[HEADER_SIZE..]);
+            let _ = send(header.source, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let id = u16::from_be_bytes([rest[0], rest[1]]);
+            let sequence = u16::from_be_bytes([rest[2], rest[3]]);
+            if let Some(received) = PINGS.lock().get_mut(&(id, sequence)) {
+                received.get_or_insert(timer::ticks());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Tells the sender of a packet that nobody is listening on the port that it was sent to.
+pub fn port_unreachable(header: &Header, payload: &[u8]) {
+    if header.destination.is_broadcast() || header.destination.is_multicast() {
+        return;
+    }
+    // The error carries the start of the packet, so that the sender can tell which one it was.
+    let original = header.to_packet(&payload[..payload.len().min(8)]);
+    let error = message(
+        TYPE_DESTINATION_UNREACHABLE,
+        CODE_PORT_UNREACHABLE,
+        [0; 4],
+        &original,
+    );
+    let _ = send(header.source, &error);
+}
+
+/// Sends an echo request, and waits for the reply. Returns how long it took to come.
+pub fn ping(destination: Ipv4Addr, timeout: Duration) -> Result<Duration, Errno> {
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let key = (PING_ID, sequence);
+    PINGS.lock().insert(key, None);
+    let mut rest = [0; 4];
+    rest[..2].copy_from_slice(&PING_ID.to_be_bytes());
+    rest[2..].copy_from_slice(&sequence.to_be_bytes());
+    let payload: Vec<u8> = (0..56).collect();
+
+    let sent = timer::ticks();
+    let deadline = sent + timeout.as_millis() as u64 * timer::TICKS_PER_SECOND / 1000;
+    let result = send(destination, &message(TYPE_ECHO_REQUEST, 0, rest, &payload));
+    let result = result.map(|()| {
+        wait_until(|| match PINGS.lock()[&key] {
+            Some(received) => Some(Ok(received)),
+            None => (timer::ticks() > deadline).then_some(Err(Errno::ETIMEDOUT)),
+        })
+    });
+    PINGS.lock().remove(&key);
+    let received = result??;
+    Ok(Duration::from_millis(
+        (received - sent) * 1000 / timer::TICKS_PER_SECOND,
+    ))
+}
+
+#[test_case]
+fn test_ping() {
+    ping(ipv4::LOCALHOST, Duration::from_secs(1)).unwrap();
+    // QEMU's user-mode network answers pings to the router, if there is a card on it.
+    if let Some(gateway) = super::interfaces()
+        .iter()
+        .find_map(|interface| interface.config()?.gateway)
+    {
+        ping(gateway, Duration::from_secs(5)).unwrap();
+    }
+}
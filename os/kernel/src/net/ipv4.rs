@@ -0,0 +1,504 @@
+//! IPv4, which carries packets between machines, through routers if they are on other networks.
+//!
+//! Packets that are too big for a network are split into fragments, which only the destination puts
+//! back together. We split packets that are too big for our cards, and put fragments back together
+//! by remembering which parts of each packet have arrived, giving up on packets that stay incomplete.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt::Display,
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use spin::Mutex;
+
+use super::{arp, ethernet, icmp, interfaces, tcp, udp, Interface, MacAddress, MTU};
+use crate::{errno::Errno, timer};
+
+pub const LOCALHOST: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+
+/// The protocols that packets carry.
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The size of a header without options, which is all that we send.
+pub const HEADER_SIZE: usize = 20;
+/// The most that a packet can carry without being split into fragments.
+pub const MAX_PAYLOAD: usize = MTU - HEADER_SIZE;
+/// The most that any packet can carry.
+pub const MAX_PACKET_PAYLOAD: usize = u16::MAX as usize - HEADER_SIZE;
+
+const VERSION_4_NO_OPTIONS: u8 = 0x45;
+const DEFAULT_TTL: u8 = 64;
+/// Bits of the flags and fragment offset: more fragments follow, and where this one goes.
+const MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+/// How long to wait for the rest of a packet, and how many packets can be incomplete at once.
+const REASSEMBLY_TIMEOUT: u64 = 30 * timer::TICKS_PER_SECOND;
+const MAX_REASSEMBLIES: usize = 16;
+
+/// The address of an interface, and the network that it is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    /// The number of leading bits of the address that give the network.
+    pub prefix_len: u8,
+    /// The router that reaches other networks.
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    /// Parses an address and prefix length such as `10.0.2.15/24`, and perhaps a gateway.
+    pub fn parse(address: &str, gateway: Option<&str>) -> Option<Self> {
+        let (address, prefix_len) = address.split_once('/')?;
+        let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+        let gateway = match gateway {
+            Some(gateway) => Some(gateway.parse().ok()?),
+            None => None,
+        };
+        Some(Self {
+            address: address.parse().ok()?,
+            prefix_len,
+            gateway,
+        })
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - self.prefix_len as u32)
+                .unwrap_or(0),
+        )
+    }
+
+    /// Whether an address is on this network.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask().to_bits();
+        ip.to_bits() & mask == self.address.to_bits() & mask
+    }
+
+    /// The address that reaches every machine on this network.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.address.to_bits() | !self.netmask().to_bits())
+    }
+}
+
+impl Display for Ipv4Config {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The Internet checksum: the ones' complement of the ones' complement sum of 16-bit words.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Checksum(u32);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    /// Adds bytes to the sum. Only the last slice that is added may have an odd length.
+    pub fn add(&mut self, bytes: &[u8]) -> &mut Self {
+        for chunk in bytes.chunks(2) {
+            let word = match *chunk {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => unreachable!(),
+            };
+            self.0 += word as u32;
+        }
+        self
+    }
+
+    /// Adds the pseudo-header that TCP and UDP include in their checksums.
+    pub fn add_pseudo_header(
+        &mut self,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        protocol: u8,
+        len: usize,
+    ) -> &mut Self {
+        self.add(&source.octets())
+            .add(&destination.octets())
+            .add(&[0, protocol])
+            .add(&(len as u16).to_be_bytes())
+    }
+
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.0;
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+/// The parts of a packet's header that we look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub id: u16,
+    /// Where this fragment's payload starts in the whole packet's payload.
+    pub fragment_offset: usize,
+    pub more_fragments: bool,
+}
+
+impl Header {
+    /// Parses a packet, and returns its header and payload.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if Checksum::new().add(&packet[..header_len]).finish() != 0 {
+            return None;
+        }
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        let header = Self {
+            source: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+            destination: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+            protocol: packet[9],
+            ttl: packet[8],
+            id: u16::from_be_bytes([packet[4], packet[5]]),
+            fragment_offset: (fragment & FRAGMENT_OFFSET) as usize * 8,
+            more_fragments: fragment & MORE_FRAGMENTS != 0,
+        };
+        // Cards may pad short frames, so the packet can be shorter than what we were given.
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Makes a packet with this header and the given payload.
+    pub fn to_packet(&self, payload: &[u8]) -> Vec<u8> {
+        let mut fragment = (self.fragment_offset / 8) as u16;
+        if self.more_fragments {
+            fragment |= MORE_FRAGMENTS;
+        }
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.extend_from_slice(&[VERSION_4_NO_OPTIONS, 0]);
+        packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&fragment.to_be_bytes());
+        packet.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        packet.extend_from_slice(&self.source.octets());
+        packet.extend_from_slice(&self.destination.octets());
+        let checksum = Checksum::new().add(&packet).finish();
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// How to send a packet to some address.
+#[derive(Clone)]
+pub struct Route {
+    pub interface: Arc<Interface>,
+    /// The address that packets should come from.
+    pub source: Ipv4Addr,
+    /// The machine on the interface's network that the packet goes to next:
+    /// either the destination itself, or a router.
+    pub next_hop: Ipv4Addr,
+}
+
+/// Decides how to send packets to an address. Packets for our own addresses go round through
+/// the loopback interface, and packets for other networks go to the first interface with a gateway.
+pub fn route(destination: Ipv4Addr) -> Result<Route, Errno> {
+    let interfaces = interfaces();
+    let configured = || {
+        interfaces
+            .iter()
+            .filter_map(|interface| Some((interface, interface.config()?)))
+    };
+    let ours = configured().any(|(_, config)| config.address == destination);
+    if ours || destination.is_loopback() {
+        let (interface, _) = configured()
+            .find(|(interface, _)| interface.device.is_loopback())
+            .ok_or(Errno::ENETUNREACH)?;
+        return Ok(Route {
+            interface: interface.clone(),
+            source: if ours { destination } else { LOCALHOST },
+            next_hop: destination,
+        });
+    }
+
+    let cards = || configured().filter(|(interface, _)| !interface.device.is_loopback());
+    let direct = cards()
+        .find(|(_, config)| destination.is_broadcast() || config.contains(destination))
+        .map(|(interface, config)| (interface, config, destination));
+    let (interface, config, next_hop) = direct
+        .or_else(|| {
+            cards().find_map(|(interface, config)| Some((interface, config, config.gateway?)))
+        })
+        .ok_or(Errno::ENETUNREACH)?;
+    Ok(Route {
+        interface: interface.clone(),
+        source: config.address,
+        next_hop,
+    })
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Sends a payload to an address, splitting it into fragments if it is too big for the interface.
+pub fn send(
+    route: &Route,
+    destination: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), Errno> {
+    if payload.len() > MAX_PACKET_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+    let mut header = Header {
+        source: route.source,
+        destination,
+        protocol,
+        ttl: DEFAULT_TTL,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        fragment_offset: 0,
+        more_fragments: false,
+    };
+    // Every fragment but the last carries a multiple of eight bytes.
+    let fragment_size = MAX_PAYLOAD & !7;
+    let mut chunks = payload.chunks(fragment_size).peekable();
+    if payload.is_empty() {
+        return send_packet(route, header.to_packet(&[]));
+    }
+    while let Some(chunk) = chunks.next() {
+        header.more_fragments = chunks.peek().is_some();
+        send_packet(route, header.to_packet(chunk))?;
+        header.fragment_offset += chunk.len();
+    }
+    Ok(())
+}
+
+fn send_packet(route: &Route, packet: Vec<u8>) -> Result<(), Errno> {
+    let interface = &route.interface;
+    let broadcast = route.next_hop.is_broadcast()
+        || interface
+            .config()
+            .is_some_and(|config| config.prefix_len < 31 && config.broadcast() == route.next_hop);
+    if interface.device.is_loopback() {
+        ethernet::send(
+            interface,
+            MacAddress::ZERO,
+            ethernet::ETHERTYPE_IPV4,
+            &packet,
+        )
+    } else if broadcast {
+        ethernet::send(
+            interface,
+            MacAddress::BROADCAST,
+            ethernet::ETHERTYPE_IPV4,
+            &packet,
+        )
+    } else {
+        arp::send(interface, route.next_hop, packet)
+    }
+}
+
+/// Handles a packet that arrived on an interface, once all of its fragments have arrived.
+pub fn receive(interface: &Arc<Interface>, packet: &[u8]) {
+    let Some((header, payload)) = Header::parse(packet) else {
+        return;
+    };
+    // An interface that has no address yet takes everything, since it may be finding one out.
+    let for_us = interface.config().is_none_or(|config| {
+        header.destination == config.address
+            || header.destination == config.broadcast()
+            || header.destination.is_broadcast()
+    });
+    if !for_us {
+        return;
+    }
+
+    if header.fragment_offset == 0 && !header.more_fragments {
+        deliver(interface, &header, payload);
+    } else if let Some(whole) = reassemble(&header, payload) {
+        deliver(interface, &header, &whole);
+    }
+}
+
+fn deliver(interface: &Arc<Interface>, header: &Header, payload: &[u8]) {
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::receive(interface, header, payload),
+        PROTOCOL_UDP => udp::receive(interface, header, payload),
+        PROTOCOL_TCP => tcp::receive(header, payload),
+        _ => {}
+    }
+}
+
+/// A packet that has arrived in fragments, some of which are missing.
+struct Reassembly {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+    payload: Vec<u8>,
+    /// The parts of the payload that have arrived, in order and not touching.
+    received: Vec<(usize, usize)>,
+    /// The length of the payload, once the last fragment has arrived.
+    len: Option<usize>,
+    expires: u64,
+}
+
+impl Reassembly {
+    fn new(header: &Header, now: u64) -> Self {
+        Self {
+            source: header.source,
+            destination: header.destination,
+            protocol: header.protocol,
+            id: header.id,
+            payload: Vec::new(),
+            received: Vec::new(),
+            len: None,
+            expires: now + REASSEMBLY_TIMEOUT,
+        }
+    }
+
+    fn matches(&self, header: &Header) -> bool {
+        (self.source, self.destination, self.protocol, self.id)
+            == (
+                header.source,
+                header.destination,
+                header.protocol,
+                header.id,
+            )
+    }
+
+    /// Adds a fragment. Returns false if it doesn't fit with the ones that came before.
+    fn add(&mut self, header: &Header, fragment: &[u8]) -> bool {
+        let start = header.fragment_offset;
+        let end = start + fragment.len();
+        if end > MAX_PACKET_PAYLOAD || self.len.is_some_and(|len| end > len) {
+            return false;
+        }
+        if !header.more_fragments {
+            if self.len.is_some_and(|len| len != end) || self.payload.len() > end {
+                return false;
+            }
+            self.len = Some(end);
+        }
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[start..end].copy_from_slice(fragment);
+
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.len
+            .is_some_and(|len| self.received == [(0, len)] || len == 0)
+    }
+}
+
+static REASSEMBLIES: Mutex<Vec<Reassembly>> = Mutex::new(Vec::new());
+
+/// Adds a fragment to the packet that it is part of, and returns the whole payload once it is complete.
+fn reassemble(header: &Header, fragment: &[u8]) -> Option<Vec<u8>> {
+    let mut reassemblies = REASSEMBLIES.lock();
+    let index = match reassemblies.iter().position(|r| r.matches(header)) {
+        Some(index) => index,
+        None => {
+            if reassemblies.len() == MAX_REASSEMBLIES {
+                // Make room by giving up on the oldest.
+                reassemblies.remove(0);
+            }
+            reassemblies.push(Reassembly::new(header, timer::ticks()));
+            reassemblies.len() - 1
+        }
+    };
+    if !reassemblies[index].add(header, fragment) {
+        reassemblies.remove(index);
+        return None;
+    }
+    if reassemblies[index].is_complete() {
+        return Some(reassemblies.remove(index).payload);
+    }
+    None
+}
+
+/// Gives up on packets whose fragments have been incomplete for too long.
+pub fn expire(now: u64) {
+    REASSEMBLIES
+        .lock()
+        .retain(|reassembly| now < reassembly.expires);
+}
+
+#[test_case]
+fn test_header() {
+    let header = Header {
+        source: Ipv4Addr::new(10, 0, 2, 15),
+        destination: Ipv4Addr::new(10, 0, 2, 2),
+        protocol: PROTOCOL_UDP,
+        ttl: DEFAULT_TTL,
+        id: 0x1234,
+        fragment_offset: 1480,
+        more_fragments: true,
+    };
+    let packet = header.to_packet(&[1, 2, 3]);
+    assert_eq!(Header::parse(&packet), Some((header, &[1, 2, 3][..])));
+    let mut corrupt = packet.clone();
+    corrupt[8] -= 1;
+    assert_eq!(Header::parse(&corrupt), None);
+
+    let config = Ipv4Config::parse("10.0.2.15/24", Some("10.0.2.2")).unwrap();
+    assert_eq!(config.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(config.broadcast(), Ipv4Addr::new(10, 0, 2, 255));
+    assert!(config.contains(Ipv4Addr::new(10, 0, 2, 3)));
+    assert!(!config.contains(Ipv4Addr::new(10, 0, 3, 3)));
+    assert_eq!(Ipv4Config::parse("10.0.2.15/33", None), None);
+}
+
+#[test_case]
+fn test_reassembly() {
+    let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let header = |offset: usize, more: bool| Header {
+        source: Ipv4Addr::new(10, 0, 2, 2),
+        destination: Ipv4Addr::new(10, 0, 2, 15),
+        protocol: PROTOCOL_UDP,
+        ttl: DEFAULT_TTL,
+        id: 7,
+        fragment_offset: offset,
+        more_fragments: more,
+    };
+    // The fragments arrive out of order, and one of them twice.
+    let mut reassembly = Reassembly::new(&header(0, true), 0);
+    assert!(reassembly.add(&header(2960, false), &payload[2960..]));
+    assert!(reassembly.add(&header(0, true), &payload[..1480]));
+    assert!(!reassembly.is_complete());
+    assert!(reassembly.add(&header(0, true), &payload[..1480]));
+    assert!(reassembly.add(&header(1480, true), &payload[1480..2960]));
+    assert!(reassembly.is_complete());
+    assert_eq!(reassembly.payload, payload);
+
+    // A fragment past the end of the packet.
+    let mut reassembly = Reassembly::new(&header(0, true), 0);
+    assert!(reassembly.add(&header(8, false), &payload[..8]));
+    assert!(!reassembly.add(&header(16, true), &payload[..8]));
+}
@@ -0,0 +1,363 @@
+//! Sockets that processes open as file descriptors, on top of [tcp] and [udp].
+//!
+//! These follow the BSD socket calls. A stream socket starts out neither connected nor
+//! listening. Binding it only records the address, and listening claims the port, so that is
+//! where `EADDRINUSE` is reported. Connecting ignores the bound address and picks a port of its own.
+//! A datagram socket claims its port as soon as it is bound. If it hasn't been bound, the first
+//! send, receive or connect binds it to a free port.
+//!
+//! Sockets block unless they were created with [SOCK_NONBLOCK], in which case anything that
+//! would block fails with `EAGAIN`, and connecting fails with `EINPROGRESS` and carries on in
+//! the background until the socket polls as writable.
+
+use alloc::sync::Arc;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Mutex;
+
+use super::{
+    interfaces,
+    tcp::{self, TcpListener, TcpStream},
+    udp::UdpSocket,
+    wait_interruptible,
+};
+use crate::{
+    errno::Errno,
+    file::{File, POLLERR, POLLHUP, POLLIN, POLLOUT},
+    fs::{FileType, Stat},
+};
+
+/// The only address family, IPv4.
+pub const AF_INET: u64 = 2;
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+/// Added to the type of a socket so that it never blocks.
+pub const SOCK_NONBLOCK: u64 = 0o4000;
+const IPPROTO_TCP: u64 = 6;
+const IPPROTO_UDP: u64 = 17;
+
+/// An IPv4 address and port, laid out as a `struct sockaddr_in`.
+/// The port and address are in network byte order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: [u8; 2],
+    pub address: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn to_socket_addr(&self) -> Result<SocketAddrV4, Errno> {
+        if self.family as u64 != AF_INET {
+            return Err(Errno::EAFNOSUPPORT);
+        }
+        Ok(SocketAddrV4::new(
+            Ipv4Addr::from(self.address),
+            u16::from_be_bytes(self.port),
+        ))
+    }
+}
+
+impl From<SocketAddrV4> for SockAddrIn {
+    fn from(address: SocketAddrV4) -> Self {
+        Self {
+            family: AF_INET as u16,
+            port: address.port().to_be_bytes(),
+            address: address.ip().octets(),
+            zero: [0; 8],
+        }
+    }
+}
+
+enum State {
+    /// A stream socket that is neither listening nor connected, and the address it was bound to.
+    Stream(Option<SocketAddrV4>),
+    Listener(Arc<TcpListener>),
+    Connected(Arc<TcpStream>),
+    Datagram(Option<Arc<UdpSocket>>),
+}
+
+pub struct Socket {
+    state: Mutex<State>,
+    nonblocking: bool,
+}
+
+impl Socket {
+    /// Creates a socket of the given type, which may include [SOCK_NONBLOCK].
+    /// The protocol may be 0, to pick the one that goes with the type.
+    pub fn new(domain: u64, kind: u64, protocol: u64) -> Result<Self, Errno> {
+        if domain != AF_INET {
+            return Err(Errno::EAFNOSUPPORT);
+        }
+        let state = match (kind & !SOCK_NONBLOCK, protocol) {
+            (SOCK_STREAM, 0 | IPPROTO_TCP) => State::Stream(None),
+            (SOCK_DGRAM, 0 | IPPROTO_UDP) => State::Datagram(None),
+            (SOCK_STREAM | SOCK_DGRAM, _) => return Err(Errno::EPROTONOSUPPORT),
+            _ => return Err(Errno::EINVAL),
+        };
+        Ok(Self {
+            state: Mutex::new(state),
+            nonblocking: kind & SOCK_NONBLOCK != 0,
+        })
+    }
+
+    /// Gives the socket a local address. The address must be one of ours, or unspecified.
+    pub fn bind(&self, address: SocketAddrV4) -> Result<(), Errno> {
+        let ip = *address.ip();
+        if !ip.is_unspecified()
+            && !interfaces().iter().any(|interface| {
+                interface
+                    .config()
+                    .is_some_and(|config| config.address == ip)
+            })
+        {
+            return Err(Errno::EADDRNOTAVAIL);
+        }
+        match &mut *self.state.lock() {
+            State::Stream(local @ None) => *local = Some(address),
+            State::Datagram(socket @ None) => *socket = Some(Arc::new(UdpSocket::bind(address)?)),
+            _ => return Err(Errno::EINVAL),
+        }
+        Ok(())
+    }
+
+    /// Starts listening for connections on the address that the socket was bound to,
+    /// or a free port if it wasn't.
+    pub fn listen(&self, backlog: usize) -> Result<(), Errno> {
+        let mut state = self.state.lock();
+        match &*state {
+            State::Stream(local) => {
+                let local = local.unwrap_or(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+                *state = State::Listener(Arc::new(TcpListener::bind(local, backlog)?));
+                Ok(())
+            }
+            State::Listener(_) => Ok(()),
+            State::Connected(_) => Err(Errno::EINVAL),
+            State::Datagram(_) => Err(Errno::EOPNOTSUPP),
+        }
+    }
+
+    /// Accepts a connection on a listening socket. Returns a new socket for it, which blocks
+    /// whether or not this one does, and the address of the other side.
+    pub fn accept(&self) -> Result<(Socket, SocketAddrV4), Errno> {
+        let listener = match &*self.state.lock() {
+            State::Listener(listener) => listener.clone(),
+            _ => return Err(Errno::EINVAL),
+        };
+        let stream = if self.nonblocking {
+            listener.try_accept()?
+        } else {
+            listener.accept()?
+        };
+        let peer = stream.peer_addr();
+        let socket = Socket {
+            state: Mutex::new(State::Connected(Arc::new(stream))),
+            nonblocking: false,
+        };
+        Ok((socket, peer))
+    }
+
+    /// Connects a stream socket to an address, or sets the address that a datagram socket
+    /// sends to by default and receives from.
+    pub fn connect(&self, remote: SocketAddrV4) -> Result<(), Errno> {
+        let mut state = self.state.lock();
+        let stream = match &*state {
+            State::Stream(_) => {
+                let stream = Arc::new(TcpStream::connect_nonblocking(remote)?);
+                *state = State::Connected(stream.clone());
+                if self.nonblocking {
+                    return Err(Errno::EINPROGRESS);
+                }
+                stream
+            }
+            State::Connected(stream) => {
+                return match stream.connect_result() {
+                    None => Err(Errno::EALREADY),
+                    Some(Ok(())) => Err(Errno::EISCONN),
+                    Some(Err(errno)) => Err(errno),
+                }
+            }
+            State::Listener(_) => return Err(Errno::EINVAL),
+            State::Datagram(_) => {
+                drop(state);
+                self.datagram()?.connect(remote);
+                return Ok(());
+            }
+        };
+        drop(state);
+        wait_interruptible(|| stream.connect_result())
+    }
+
+    /// Sends bytes over a connected stream socket, or a datagram to `destination`,
+    /// or to the address that the datagram socket is connected to.
+    /// Streams ignore the destination. Blocking streams send everything before returning,
+    /// unless they are interrupted or the connection fails after some of it was sent.
+    pub fn send(&self, buffer: &[u8], destination: Option<SocketAddrV4>) -> Result<usize, Errno> {
+        if let Some(stream) = self.stream()? {
+            if self.nonblocking {
+                return stream.try_write(buffer);
+            }
+            let mut sent = 0;
+            while sent < buffer.len() {
+                match stream.write(&buffer[sent..]) {
+                    Ok(len) => sent += len,
+                    Err(_) if sent > 0 => break,
+                    Err(errno) => return Err(errno),
+                }
+            }
+            return Ok(sent);
+        }
+        let socket = self.datagram()?;
+        let destination = match destination {
+            Some(destination) => destination,
+            None => socket.peer_addr().map_err(|_| Errno::EDESTADDRREQ)?,
+        };
+        socket.send_to(buffer, destination)
+    }
+
+    /// Receives bytes from a connected stream socket, or a datagram.
+    /// Returns how many bytes there were, and for datagrams, where they came from.
+    /// The part of a datagram that doesn't fit in the buffer is dropped.
+    pub fn recv(&self, buffer: &mut [u8]) -> Result<(usize, Option<SocketAddrV4>), Errno> {
+        if let Some(stream) = self.stream()? {
+            let len = if self.nonblocking {
+                stream.try_read(buffer)?
+            } else {
+                stream.read(buffer)?
+            };
+            return Ok((len, None));
+        }
+        let socket = self.datagram()?;
+        let (len, from) = if self.nonblocking {
+            socket.try_recv_from(buffer)?
+        } else {
+            socket.recv_from(buffer)?
+        };
+        Ok((len, Some(from)))
+    }
+
+    /// The connection, for a connected stream socket, or `None` for a datagram socket.
+    fn stream(&self) -> Result<Option<Arc<TcpStream>>, Errno> {
+        match &*self.state.lock() {
+            State::Connected(stream) => Ok(Some(stream.clone())),
+            State::Stream(_) | State::Listener(_) => Err(Errno::ENOTCONN),
+            State::Datagram(_) => Ok(None),
+        }
+    }
+
+    /// The UDP socket of a datagram socket, bound to a free port if it wasn't bound already.
+    fn datagram(&self) -> Result<Arc<UdpSocket>, Errno> {
+        let mut state = self.state.lock();
+        let State::Datagram(socket) = &mut *state else {
+            unreachable!("not a datagram socket");
+        };
+        if let Some(socket) = socket {
+            return Ok(socket.clone());
+        }
+        let bound = Arc::new(UdpSocket::bind(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            0,
+        ))?);
+        *socket = Some(bound.clone());
+        Ok(bound)
+    }
+}
+
+impl File for Socket {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(self.recv(buf)?.0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        self.send(buf, None)
+    }
+
+    fn stat(&self) -> Result<Stat, Errno> {
+        Ok(Stat::new(0, FileType::Socket, 0o777, 0))
+    }
+
+    fn poll(&self) -> u16 {
+        match &*self.state.lock() {
+            // There is nothing to wait for until it connects or listens.
+            State::Stream(_) => POLLOUT | POLLHUP,
+            State::Listener(listener) => {
+                if listener.readable() {
+                    POLLIN
+                } else {
+                    0
+                }
+            }
+            State::Connected(stream) => {
+                let mut events = 0;
+                if stream.readable() {
+                    events |= POLLIN;
+                }
+                if stream.writable() {
+                    events |= POLLOUT;
+                }
+                if matches!(stream.connect_result(), Some(Err(_))) {
+                    events |= POLLERR;
+                }
+                if matches!(stream.state(), tcp::State::TimeWait | tcp::State::Closed) {
+                    events |= POLLHUP;
+                }
+                events
+            }
+            State::Datagram(socket) => {
+                if socket.as_ref().is_some_and(|socket| socket.readable()) {
+                    POLLIN | POLLOUT
+                } else {
+                    POLLOUT
+                }
+            }
+        }
+    }
+
+    fn as_socket(&self) -> Option<&Socket> {
+        Some(self)
+    }
+}
+
+#[test_case]
+fn test_socket() {
+    use super::{ipv4::LOCALHOST, wait_until};
+
+    let address = SocketAddrV4::new(LOCALHOST, 8000);
+    let listener = Socket::new(AF_INET, SOCK_STREAM, 0).unwrap();
+    listener.bind(address).unwrap();
+    listener.listen(4).unwrap();
+    assert_eq!(listener.poll(), 0);
+
+    let client = Socket::new(AF_INET, SOCK_STREAM | SOCK_NONBLOCK, 0).unwrap();
+    assert_eq!(client.send(b"hello", None), Err(Errno::ENOTCONN));
+    assert_eq!(client.connect(address), Err(Errno::EINPROGRESS));
+    wait_until(|| (client.poll() & POLLOUT != 0).then_some(()));
+    assert_eq!(client.connect(address), Err(Errno::EISCONN));
+    wait_until(|| (listener.poll() == POLLIN).then_some(()));
+    let (server, _) = listener.accept().unwrap();
+
+    let mut buffer = [0; 16];
+    assert_eq!(client.recv(&mut buffer), Err(Errno::EAGAIN));
+    assert_eq!(client.send(b"hello", None), Ok(5));
+    assert_eq!(server.recv(&mut buffer), Ok((5, None)));
+    assert_eq!(&buffer[..5], b"hello");
+    drop(server);
+    wait_until(|| (client.poll() & POLLIN != 0).then_some(()));
+    assert_eq!(client.recv(&mut buffer), Ok((0, None)));
+
+    let server = Socket::new(AF_INET, SOCK_DGRAM, 0).unwrap();
+    server.bind(address).unwrap();
+    let client = Socket::new(AF_INET, SOCK_DGRAM, 0).unwrap();
+    assert_eq!(client.send(b"ping", None), Err(Errno::EDESTADDRREQ));
+    assert_eq!(client.send(b"ping", Some(address)), Ok(4));
+    let (len, from) = server.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"ping");
+    server.send(b"pong", from).unwrap();
+    assert_eq!(client.recv(&mut buffer), Ok((4, Some(address))));
+    assert_eq!(
+        Socket::new(AF_INET, SOCK_DGRAM, IPPROTO_TCP).err(),
+        Some(Errno::EPROTONOSUPPORT)
+    );
+}
@@ -0,0 +1,1077 @@
+//! TCP, which carries reliable streams of bytes between ports.
+//!
+//! Each connection keeps the bytes that it has sent until they are acknowledged, and sends them
+//! again from the first unacknowledged one if no acknowledgement comes in time, waiting longer
+//! each time. How long to wait at first is worked out from how long acknowledgements take to come.
+//! Bytes that arrive out of order are dropped, and come again. Each side says how much room it has
+//! for more bytes, and the other sends no more than that; when there is no room, the sender probes
+//! now and then to find out when there is.
+//!
+//! Connections are kept in a table by their local and remote addresses, and stay there after their
+//! [TcpStream] is dropped until they have finished closing.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::net::SocketAddrV4;
+
+use spin::Mutex;
+
+use super::{
+    ephemeral_port,
+    ipv4::{self, Checksum, Header},
+    wait_interruptible, MTU,
+};
+use crate::{errno::Errno, timer};
+
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+
+/// The size of a header without options.
+const HEADER_SIZE: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The most that we want in one segment, so that segments need not be split into fragments.
+const MSS: usize = MTU - ipv4::HEADER_SIZE - HEADER_SIZE;
+/// The most that the other side may send in one segment, if it doesn't tell us.
+const DEFAULT_MSS: usize = 536;
+
+/// How many bytes each connection keeps for sending and for receiving.
+/// We don't scale windows, so the receive buffer is no bigger than the largest window.
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+const RECEIVE_BUFFER_SIZE: usize = u16::MAX as usize;
+
+const INITIAL_RTO: u64 = timer::TICKS_PER_SECOND;
+const MIN_RTO: u64 = timer::TICKS_PER_SECOND / 5;
+const MAX_RTO: u64 = 60 * timer::TICKS_PER_SECOND;
+/// How many times to send something again before giving up on the connection.
+const MAX_RETRIES: u32 = 8;
+/// How long a closed connection keeps its addresses, so that stray segments from it die out.
+const TIME_WAIT: u64 = 10 * timer::TICKS_PER_SECOND;
+/// How long to wait for the other side to close after we have.
+const FIN_WAIT_2_TIMEOUT: u64 = 60 * timer::TICKS_PER_SECOND;
+
+/// Whether sequence number `a` comes before `b`, allowing for wrapping.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    /// Whether we may still send bytes.
+    fn can_send(self) -> bool {
+        matches!(self, State::Established | State::CloseWait)
+    }
+
+    /// Whether the other side may still send bytes.
+    fn can_receive(self) -> bool {
+        matches!(self, State::Established | State::FinWait1 | State::FinWait2)
+    }
+}
+
+/// A segment that has arrived.
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(header: &Header, bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let data_offset = (bytes[12] >> 4) as usize * 4;
+        if data_offset < HEADER_SIZE || data_offset > bytes.len() {
+            return None;
+        }
+        let checksum = Checksum::new()
+            .add_pseudo_header(
+                header.source,
+                header.destination,
+                ipv4::PROTOCOL_TCP,
+                bytes.len(),
+            )
+            .add(bytes)
+            .finish();
+        if checksum != 0 {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &bytes[HEADER_SIZE..data_offset];
+        while let [kind, rest @ ..] = options {
+            match (*kind, rest) {
+                (OPTION_END, _) => break,
+                (OPTION_NOP, _) => options = rest,
+                (_, [len, ..]) if *len >= 2 && *len as usize <= options.len() => {
+                    if *kind == OPTION_MSS && *len == 4 {
+                        mss = Some(u16::from_be_bytes([rest[1], rest[2]]));
+                    }
+                    options = &options[*len as usize..];
+                }
+                _ => break,
+            }
+        }
+
+        Some(Self {
+            source_port: u16::from_be_bytes([bytes[0], bytes[1]]),
+            destination_port: u16::from_be_bytes([bytes[2], bytes[3]]),
+            seq: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            ack: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            flags: bytes[13],
+            window: u16::from_be_bytes([bytes[14], bytes[15]]),
+            mss,
+            payload: &bytes[data_offset..],
+        })
+    }
+
+    /// How much sequence space the segment takes up. SYN and FIN take one each.
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+/// Sends a segment. Errors are ignored, since lost segments are sent again.
+#[allow(clippy::too_many_arguments)]
+fn send_segment(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+) {
+    let Ok(mut route) = ipv4::route(*remote.ip()) else {
+        return;
+    };
+    if !local.ip().is_unspecified() {
+        route.source = *local.ip();
+    }
+    let options_len = if mss.is_some() { 4 } else { 0 };
+    let len = HEADER_SIZE + options_len + payload.len();
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&local.port().to_be_bytes());
+    segment.extend_from_slice(&remote.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[(((HEADER_SIZE + options_len) / 4) as u8) << 4, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[OPTION_MSS, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+    let checksum = Checksum::new()
+        .add_pseudo_header(route.source, *remote.ip(), ipv4::PROTOCOL_TCP, len)
+        .add(&segment)
+        .finish();
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    let _ = ipv4::send(&route, *remote.ip(), ipv4::PROTOCOL_TCP, &segment);
+}
+
+/// Answers a segment that belongs to no connection with a reset.
+fn send_reset(header: &Header, segment: &Segment) {
+    if segment.flags & RST != 0 {
+        return;
+    }
+    let local = SocketAddrV4::new(header.destination, segment.destination_port);
+    let remote = SocketAddrV4::new(header.source, segment.source_port);
+    if segment.flags & ACK != 0 {
+        send_segment(local, remote, segment.ack, 0, RST, 0, None, &[]);
+    } else {
+        let ack = segment.seq.wrapping_add(segment.len());
+        send_segment(local, remote, 0, ack, RST | ACK, 0, None, &[]);
+    }
+}
+
+/// The transmission control block: everything that we know about a connection.
+struct Tcb {
+    state: State,
+    /// Why the connection failed, if it did.
+    error: Option<Errno>,
+
+    /// Our initial sequence number, which the SYN takes.
+    iss: u32,
+    /// The oldest sequence number that hasn't been acknowledged.
+    snd_una: u32,
+    /// The next sequence number to send. This goes back to `snd_una` when we send again
+    /// whatever hasn't been acknowledged.
+    snd_nxt: u32,
+    /// The sequence number after the last one that has been sent.
+    snd_max: u32,
+    /// How much the other side has room for, from `snd_una`.
+    snd_wnd: u32,
+    /// The sequence and acknowledgement numbers of the segment that last updated `snd_wnd`.
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// The most that the other side wants in one segment.
+    mss: usize,
+    /// Bytes that haven't been acknowledged, starting at `snd_una` once the SYN has been.
+    send_buffer: VecDeque<u8>,
+    /// Whether to send a FIN once the send buffer has been sent, whether it has been, and whether
+    /// it has been acknowledged.
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    /// The sequence number of our FIN, once it has been sent.
+    fin_seq: Option<u32>,
+
+    /// The next sequence number that we expect.
+    rcv_nxt: u32,
+    receive_buffer: VecDeque<u8>,
+    /// The window that we last told the other side about.
+    rcv_wnd_advertised: u32,
+    fin_received: bool,
+
+    /// When to send again whatever hasn't been acknowledged, if anything.
+    retransmit_at: Option<u64>,
+    rto: u64,
+    /// The smoothed round-trip time and its variation, once there has been a sample.
+    srtt: Option<u64>,
+    rttvar: u64,
+    /// A sequence number being timed, and when it was sent.
+    rtt_sample: Option<(u32, u64)>,
+    retries: u32,
+    /// When to forget the connection, in `TimeWait` and `FinWait2`.
+    close_at: Option<u64>,
+}
+
+impl Tcb {
+    fn new(state: State, now: u64) -> Self {
+        // Initial sequence numbers come from a clock, so that a new connection between the same
+        // ports doesn't mistake segments from an old one for its own.
+        let iss = (unsafe { core::arch::x86_64::_rdtsc() } >> 4) as u32;
+        Self {
+            state,
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            mss: DEFAULT_MSS,
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            fin_seq: None,
+            rcv_nxt: 0,
+            receive_buffer: VecDeque::new(),
+            rcv_wnd_advertised: 0,
+            fin_received: false,
+            retransmit_at: Some(now + INITIAL_RTO),
+            rto: INITIAL_RTO,
+            srtt: None,
+            rttvar: 0,
+            rtt_sample: None,
+            retries: 0,
+            close_at: None,
+        }
+    }
+
+    fn rcv_wnd(&self) -> u32 {
+        (RECEIVE_BUFFER_SIZE - self.receive_buffer.len()) as u32
+    }
+
+    /// Whether the SYN that we sent has been acknowledged, so that sequence numbers from
+    /// `snd_una` on are for bytes in the send buffer.
+    fn synchronised(&self) -> bool {
+        !matches!(self.state, State::SynSent | State::SynReceived)
+    }
+
+    /// Updates the round-trip time estimate with a sample, as in RFC 6298.
+    fn sample_rtt(&mut self, rtt: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + (4 * self.rttvar).max(1)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn fail(&mut self, error: Errno) {
+        self.error.get_or_insert(error);
+        self.state = State::Closed;
+    }
+}
+
+struct Connection {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    tcb: Mutex<Tcb>,
+    /// The listener that the connection arrived on, until it is accepted.
+    listener: Weak<Listener>,
+}
+
+impl Connection {
+    /// Sends a segment from this connection, acknowledging what we have received.
+    fn send(&self, tcb: &mut Tcb, seq: u32, flags: u8, payload: &[u8]) {
+        let window = tcb.rcv_wnd();
+        tcb.rcv_wnd_advertised = window;
+        let mss = (flags & SYN != 0).then_some(MSS as u16);
+        let (ack, flags) = if tcb.state == State::SynSent {
+            (0, flags)
+        } else {
+            (tcb.rcv_nxt, flags | ACK)
+        };
+        send_segment(
+            self.local,
+            self.remote,
+            seq,
+            ack,
+            flags,
+            window as u16,
+            mss,
+            payload,
+        );
+    }
+
+    fn send_ack(&self, tcb: &mut Tcb) {
+        let seq = tcb.snd_nxt;
+        self.send(tcb, seq, 0, &[]);
+    }
+
+    /// Sends as much as the other side has room for, and a FIN after it if we have closed.
+    /// A probe sends a byte even if there is no room, to find out when there is.
+    fn transmit(&self, tcb: &mut Tcb, now: u64, probe: bool) {
+        match tcb.state {
+            State::SynSent | State::SynReceived => {
+                let iss = tcb.iss;
+                return self.send(tcb, iss, SYN, &[]);
+            }
+            State::Established
+            | State::CloseWait
+            | State::FinWait1
+            | State::Closing
+            | State::LastAck => {}
+            _ => return,
+        }
+        let mut probe = probe;
+        loop {
+            let offset = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+            let window_end = tcb.snd_una.wrapping_add(tcb.snd_wnd.max(probe as u32));
+            let usable = window_end.wrapping_sub(tcb.snd_nxt) as i32;
+            if offset < tcb.send_buffer.len() && usable > 0 {
+                let len = (tcb.send_buffer.len() - offset)
+                    .min(tcb.mss)
+                    .min(usable as usize);
+                let (front, back) = tcb.send_buffer.as_slices();
+                let payload: Vec<u8> = front
+                    .iter()
+                    .chain(back)
+                    .skip(offset)
+                    .take(len)
+                    .copied()
+                    .collect();
+                let seq = tcb.snd_nxt;
+                self.send(tcb, seq, PSH, &payload);
+                tcb.rtt_sample.get_or_insert((seq, now));
+                tcb.snd_nxt = seq.wrapping_add(len as u32);
+            } else if offset == tcb.send_buffer.len() && tcb.fin_queued && !tcb.fin_sent {
+                let seq = tcb.snd_nxt;
+                self.send(tcb, seq, FIN, &[]);
+                tcb.snd_nxt = seq.wrapping_add(1);
+                tcb.fin_sent = true;
+                tcb.fin_seq = Some(seq);
+                tcb.state = match tcb.state {
+                    State::Established => State::FinWait1,
+                    State::CloseWait => State::LastAck,
+                    state => state,
+                };
+            } else {
+                break;
+            }
+            if before(tcb.snd_max, tcb.snd_nxt) {
+                tcb.snd_max = tcb.snd_nxt;
+            }
+            probe = false;
+            tcb.retransmit_at.get_or_insert(now + tcb.rto);
+        }
+        // Keep probing while the other side has no room for what is waiting.
+        if tcb.snd_una == tcb.snd_max && !tcb.send_buffer.is_empty() {
+            tcb.retransmit_at.get_or_insert(now + tcb.rto);
+        }
+    }
+
+    /// Handles the retransmission timer going off.
+    fn timeout(&self, tcb: &mut Tcb, now: u64) {
+        tcb.retransmit_at = None;
+        let in_flight = tcb.snd_una != tcb.snd_max;
+        if in_flight {
+            tcb.retries += 1;
+            if tcb.retries > MAX_RETRIES {
+                return tcb.fail(Errno::ETIMEDOUT);
+            }
+            // Send everything again from the first unacknowledged byte.
+            if tcb.synchronised() {
+                tcb.snd_nxt = tcb.snd_una;
+                if !tcb.fin_acked {
+                    tcb.fin_sent = false;
+                }
+            }
+            tcb.rtt_sample = None;
+        }
+        tcb.rto = (tcb.rto * 2).min(MAX_RTO);
+        self.transmit(tcb, now, !in_flight);
+        if tcb.state == State::SynSent || tcb.state == State::SynReceived {
+            tcb.retransmit_at = Some(now + tcb.rto);
+        }
+    }
+
+    /// Handles an acceptable acknowledgement number. Returns false if it acknowledges something
+    /// that we haven't sent.
+    fn acknowledge(&self, tcb: &mut Tcb, segment: &Segment, now: u64) -> bool {
+        if before(tcb.snd_max, segment.ack) {
+            return false;
+        }
+        // Something came back, so the other side is still there.
+        tcb.retries = 0;
+        if before(tcb.snd_una, segment.ack) {
+            let acked = segment.ack.wrapping_sub(tcb.snd_una) as usize;
+            tcb.send_buffer.drain(..acked.min(tcb.send_buffer.len()));
+            tcb.snd_una = segment.ack;
+            // After going back, this may acknowledge what was sent before.
+            if before(tcb.snd_nxt, segment.ack) {
+                tcb.snd_nxt = segment.ack;
+            }
+            if tcb
+                .fin_seq
+                .is_some_and(|fin| !before(segment.ack, fin.wrapping_add(1)))
+            {
+                tcb.fin_sent = true;
+                tcb.fin_acked = true;
+            }
+            if let Some((seq, sent)) = tcb.rtt_sample {
+                if !before(segment.ack, seq.wrapping_add(1)) {
+                    tcb.rtt_sample = None;
+                    tcb.sample_rtt(now - sent);
+                }
+            }
+            tcb.retransmit_at = (tcb.snd_una != tcb.snd_max).then_some(now + tcb.rto);
+        }
+        // Only take the window from segments newer than the one that last gave it.
+        if before(tcb.snd_wl1, segment.seq)
+            || (tcb.snd_wl1 == segment.seq && !before(segment.ack, tcb.snd_wl2))
+        {
+            tcb.snd_wnd = segment.window as u32;
+            tcb.snd_wl1 = segment.seq;
+            tcb.snd_wl2 = segment.ack;
+        }
+        true
+    }
+
+    /// Handles a segment for this connection. Returns whether the connection is finished with.
+    fn receive(self: &Arc<Self>, segment: &Segment, now: u64) -> bool {
+        let tcb = &mut *self.tcb.lock();
+        if tcb.state == State::SynSent {
+            return self.receive_syn_sent(tcb, segment, now);
+        }
+        if segment.flags & SYN != 0 {
+            // The other side didn't get our SYN-ACK, or has got muddled.
+            if tcb.state == State::SynReceived {
+                self.transmit(tcb, now, false);
+            } else {
+                self.send_ack(tcb);
+            }
+            return false;
+        }
+
+        // Only take segments that start where we expect, trimming any part that we already have.
+        let mut payload = segment.payload;
+        let mut seq = segment.seq;
+        if before(seq, tcb.rcv_nxt) {
+            let old = (tcb.rcv_nxt.wrapping_sub(seq) as usize).min(payload.len());
+            payload = &payload[old..];
+            seq = seq.wrapping_add(old as u32);
+        }
+        let fin = segment.flags & FIN != 0;
+        if seq != tcb.rcv_nxt {
+            if segment.flags & RST == 0 {
+                self.send_ack(tcb);
+            }
+            return false;
+        }
+
+        if segment.flags & RST != 0 {
+            match tcb.state {
+                State::SynReceived if tcb.error.is_none() => tcb.state = State::Closed,
+                State::Closing | State::LastAck | State::TimeWait => tcb.state = State::Closed,
+                _ => tcb.fail(Errno::ECONNRESET),
+            }
+            return true;
+        }
+        if segment.flags & ACK == 0 {
+            return false;
+        }
+
+        if tcb.state == State::SynReceived {
+            if segment.ack != tcb.iss.wrapping_add(1) {
+                send_segment(self.local, self.remote, segment.ack, 0, RST, 0, None, &[]);
+                return false;
+            }
+            tcb.state = State::Established;
+            tcb.snd_una = segment.ack;
+            tcb.snd_wnd = segment.window as u32;
+            tcb.snd_wl1 = segment.seq;
+            tcb.snd_wl2 = segment.ack;
+            tcb.retransmit_at = None;
+            tcb.retries = 0;
+            let Some(listener) = self.listener.upgrade() else {
+                // The listener was closed while the handshake finished, so nobody can accept this.
+                send_segment(self.local, self.remote, tcb.snd_nxt, 0, RST, 0, None, &[]);
+                tcb.state = State::Closed;
+                return true;
+            };
+            listener.ready.lock().push_back(self.clone());
+        }
+
+        if !self.acknowledge(tcb, segment, now) {
+            self.send_ack(tcb);
+            return false;
+        }
+        if tcb.fin_acked {
+            match tcb.state {
+                State::FinWait1 => {
+                    tcb.state = State::FinWait2;
+                    tcb.close_at = Some(now + FIN_WAIT_2_TIMEOUT);
+                }
+                State::Closing => {
+                    tcb.state = State::TimeWait;
+                    tcb.close_at = Some(now + TIME_WAIT);
+                }
+                State::LastAck => {
+                    tcb.state = State::Closed;
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        let mut reply = false;
+        if !payload.is_empty() && tcb.state.can_receive() {
+            let len = payload.len().min(tcb.rcv_wnd() as usize);
+            tcb.receive_buffer.extend(&payload[..len]);
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(len as u32);
+            reply = true;
+            // The FIN only counts if everything before it fitted.
+            if len < payload.len() {
+                self.send_ack(tcb);
+                return false;
+            }
+        }
+        if fin && !tcb.fin_received {
+            tcb.fin_received = true;
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+            reply = true;
+            match tcb.state {
+                State::Established => tcb.state = State::CloseWait,
+                State::FinWait1 => tcb.state = State::Closing,
+                State::FinWait2 => {
+                    tcb.state = State::TimeWait;
+                    tcb.close_at = Some(now + TIME_WAIT);
+                }
+                _ => {}
+            }
+        }
+
+        let sent_before = tcb.snd_nxt;
+        self.transmit(tcb, now, false);
+        if reply && tcb.snd_nxt == sent_before {
+            self.send_ack(tcb);
+        }
+        false
+    }
+
+    fn receive_syn_sent(&self, tcb: &mut Tcb, segment: &Segment, now: u64) -> bool {
+        let acceptable = segment.ack == tcb.iss.wrapping_add(1);
+        if segment.flags & ACK != 0 && !acceptable {
+            if segment.flags & RST == 0 {
+                send_segment(self.local, self.remote, segment.ack, 0, RST, 0, None, &[]);
+            }
+            return false;
+        }
+        if segment.flags & RST != 0 {
+            if segment.flags & ACK != 0 {
+                tcb.fail(Errno::ECONNREFUSED);
+                return true;
+            }
+            return false;
+        }
+        if segment.flags & SYN == 0 {
+            return false;
+        }
+        tcb.rcv_nxt = segment.seq.wrapping_add(1);
+        tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+        tcb.snd_wnd = segment.window as u32;
+        tcb.snd_wl1 = segment.seq;
+        tcb.snd_wl2 = segment.ack;
+        tcb.retries = 0;
+        if segment.flags & ACK != 0 {
+            tcb.snd_una = segment.ack;
+            tcb.retransmit_at = None;
+            tcb.state = State::Established;
+            self.send_ack(tcb);
+            self.transmit(tcb, now, false);
+        } else {
+            // Both sides opened at once.
+            tcb.state = State::SynReceived;
+            self.transmit(tcb, now, false);
+        }
+        false
+    }
+}
+
+type Key = (SocketAddrV4, SocketAddrV4);
+
+/// Connections by their local and remote addresses.
+/// Never lock this while holding a connection's [Tcb], which is locked in the other order.
+static CONNECTIONS: Mutex<BTreeMap<Key, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+
+struct Listener {
+    local: SocketAddrV4,
+    backlog: usize,
+    /// Connections that have been established, waiting to be accepted.
+    ready: Mutex<VecDeque<Arc<Connection>>>,
+}
+
+static LISTENERS: Mutex<BTreeMap<u16, Weak<Listener>>> = Mutex::new(BTreeMap::new());
+
+fn remove(connection: &Connection) {
+    CONNECTIONS
+        .lock()
+        .remove(&(connection.local, connection.remote));
+}
+
+/// Handles a segment that arrived.
+pub fn receive(header: &Header, bytes: &[u8]) {
+    let Some(segment) = Segment::parse(header, bytes) else {
+        return;
+    };
+    if header.destination.is_broadcast() || header.destination.is_multicast() {
+        return;
+    }
+    let local = SocketAddrV4::new(header.destination, segment.destination_port);
+    let remote = SocketAddrV4::new(header.source, segment.source_port);
+    let now = timer::ticks();
+
+    let connection = CONNECTIONS.lock().get(&(local, remote)).cloned();
+    if let Some(connection) = connection {
+        if connection.receive(&segment, now) {
+            remove(&connection);
+        }
+        return;
+    }
+
+    let listener = LISTENERS
+        .lock()
+        .get(&local.port())
+        .and_then(Weak::upgrade)
+        .filter(|listener| {
+            listener.local.ip().is_unspecified() || *listener.local.ip() == *local.ip()
+        });
+    match listener {
+        Some(listener) if segment.flags & (SYN | ACK | RST) == SYN => {
+            accept_syn(&listener, local, remote, &segment, now)
+        }
+        _ => send_reset(header, &segment),
+    }
+}
+
+/// Starts a connection that arrived on a listener, if it has room for one.
+fn accept_syn(
+    listener: &Arc<Listener>,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    segment: &Segment,
+    now: u64,
+) {
+    let mut connections = CONNECTIONS.lock();
+    let pending = connections
+        .values()
+        .filter(|connection| {
+            core::ptr::eq(connection.listener.as_ptr(), Arc::as_ptr(listener))
+                && connection.tcb.lock().state == State::SynReceived
+        })
+        .count();
+    if pending + listener.ready.lock().len() >= listener.backlog {
+        return;
+    }
+    let mut tcb = Tcb::new(State::SynReceived, now);
+    tcb.rcv_nxt = segment.seq.wrapping_add(1);
+    tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+    let connection = Arc::new(Connection {
+        local,
+        remote,
+        tcb: Mutex::new(tcb),
+        listener: Arc::downgrade(listener),
+    });
+    connections.insert((local, remote), connection.clone());
+    drop(connections);
+    let tcb = &mut *connection.tcb.lock();
+    connection.transmit(tcb, now, false);
+}
+
+/// Sends again whatever hasn't been acknowledged in time, and forgets connections that have
+/// finished closing.
+pub fn expire(now: u64) {
+    let connections: Vec<_> = CONNECTIONS.lock().values().cloned().collect();
+    for connection in connections {
+        let finished = {
+            let tcb = &mut *connection.tcb.lock();
+            if tcb.close_at.is_some_and(|close_at| now >= close_at) {
+                tcb.state = State::Closed;
+            } else if tcb.retransmit_at.is_some_and(|at| now >= at) {
+                connection.timeout(tcb, now);
+            }
+            tcb.state == State::Closed
+        };
+        if finished {
+            remove(&connection);
+        }
+    }
+}
+
+/// A socket that waits for connections on a port.
+pub struct TcpListener(Arc<Listener>);
+
+impl TcpListener {
+    /// Listens on an address, keeping up to `backlog` connections waiting to be accepted.
+    /// Port 0 picks a free port.
+    pub fn bind(address: SocketAddrV4, backlog: usize) -> Result<Self, Errno> {
+        let mut listeners = LISTENERS.lock();
+        let in_use = |port| {
+            listeners
+                .get(&port)
+                .is_some_and(|listener: &Weak<Listener>| listener.strong_count() > 0)
+        };
+        let port = match address.port() {
+            0 => ephemeral_port(in_use)?,
+            port if in_use(port) => return Err(Errno::EADDRINUSE),
+            port => port,
+        };
+        let listener = Arc::new(Listener {
+            local: SocketAddrV4::new(*address.ip(), port),
+            backlog: backlog.max(1),
+            ready: Mutex::new(VecDeque::new()),
+        });
+        listeners.insert(port, Arc::downgrade(&listener));
+        Ok(Self(listener))
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.0.local
+    }
+
+    /// Whether a connection is waiting to be accepted.
+    pub fn readable(&self) -> bool {
+        !self.0.ready.lock().is_empty()
+    }
+
+    /// Accepts a connection if one is waiting, or fails with `EAGAIN`.
+    pub fn try_accept(&self) -> Result<TcpStream, Errno> {
+        let connection = self.0.ready.lock().pop_front().ok_or(Errno::EAGAIN)?;
+        Ok(TcpStream(connection))
+    }
+
+    /// Waits for a connection, and accepts it.
+    pub fn accept(&self) -> Result<TcpStream, Errno> {
+        wait_interruptible(|| match self.try_accept() {
+            Err(Errno::EAGAIN) => None,
+            result => Some(result),
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let port = self.0.local.port();
+        let mut listeners = LISTENERS.lock();
+        if listeners
+            .get(&port)
+            .is_some_and(|listener| core::ptr::eq(listener.as_ptr(), Arc::as_ptr(&self.0)))
+        {
+            listeners.remove(&port);
+        }
+        drop(listeners);
+        // Nobody will accept these now.
+        for connection in core::mem::take(&mut *self.0.ready.lock()) {
+            TcpStream(connection).abort();
+        }
+    }
+}
+
+/// A TCP connection. Dropping it closes the connection, which finishes in the background.
+pub struct TcpStream(Arc<Connection>);
+
+impl TcpStream {
+    /// Starts connecting to an address, without waiting for the other side to answer.
+    /// Reading and writing fail with `EAGAIN` until it does.
+    pub fn connect_nonblocking(remote: SocketAddrV4) -> Result<Self, Errno> {
+        let route = ipv4::route(*remote.ip())?;
+        let now = timer::ticks();
+        let mut connections = CONNECTIONS.lock();
+        let listeners = LISTENERS.lock();
+        let port = ephemeral_port(|port| {
+            listeners.contains_key(&port)
+                || connections.keys().any(|(local, _)| local.port() == port)
+        })?;
+        drop(listeners);
+        let local = SocketAddrV4::new(route.source, port);
+        let connection = Arc::new(Connection {
+            local,
+            remote,
+            tcb: Mutex::new(Tcb::new(State::SynSent, now)),
+            listener: Weak::new(),
+        });
+        connections.insert((local, remote), connection.clone());
+        drop(connections);
+        connection.transmit(&mut connection.tcb.lock(), now, false);
+        Ok(Self(connection))
+    }
+
+    /// Connects to an address, and waits until the other side answers.
+    pub fn connect(remote: SocketAddrV4) -> Result<Self, Errno> {
+        let stream = Self::connect_nonblocking(remote)?;
+        wait_interruptible(|| stream.connect_result())?;
+        Ok(stream)
+    }
+
+    /// Whether a connection has been made, once the other side has answered.
+    pub fn connect_result(&self) -> Option<Result<(), Errno>> {
+        let tcb = self.0.tcb.lock();
+        match tcb.state {
+            State::SynSent | State::SynReceived => None,
+            _ => Some(tcb.error.map_or(Ok(()), Err)),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.0.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.0.remote
+    }
+
+    pub fn state(&self) -> State {
+        self.0.tcb.lock().state
+    }
+
+    /// Reads whatever has arrived, or fails with `EAGAIN` if nothing has.
+    /// Returns 0 once the other side has closed and everything that it sent has been read.
+    pub fn try_read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        let tcb = &mut *self.0.tcb.lock();
+        if tcb.receive_buffer.is_empty() {
+            return match tcb.error {
+                Some(error) => Err(error),
+                None if tcb.fin_received || tcb.state == State::Closed => Ok(0),
+                None => Err(Errno::EAGAIN),
+            };
+        }
+        let len = buffer.len().min(tcb.receive_buffer.len());
+        for (byte, received) in buffer.iter_mut().zip(tcb.receive_buffer.drain(..len)) {
+            *byte = received;
+        }
+        // Tell the other side once there is a useful amount of room again,
+        // in case it is waiting for it.
+        if tcb.state.can_receive()
+            && tcb.rcv_wnd().saturating_sub(tcb.rcv_wnd_advertised) >= MSS as u32
+        {
+            self.0.send_ack(tcb);
+        }
+        Ok(len)
+    }
+
+    /// Waits for something to arrive, and reads it as [TcpStream::try_read] does.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        wait_interruptible(|| match self.try_read(buffer) {
+            Err(Errno::EAGAIN) => None,
+            result => Some(result),
+        })
+    }
+
+    /// Queues as much of the buffer as there is room for, and returns how much that was,
+    /// or fails with `EAGAIN` if there is none.
+    pub fn try_write(&self, buffer: &[u8]) -> Result<usize, Errno> {
+        let tcb = &mut *self.0.tcb.lock();
+        if let Some(error) = tcb.error {
+            return Err(error);
+        }
+        match tcb.state {
+            State::SynSent | State::SynReceived => return Err(Errno::EAGAIN),
+            state if !state.can_send() || tcb.fin_queued => return Err(Errno::EPIPE),
+            _ => {}
+        }
+        let len = buffer.len().min(SEND_BUFFER_SIZE - tcb.send_buffer.len());
+        if len == 0 {
+            return Err(Errno::EAGAIN);
+        }
+        tcb.send_buffer.extend(&buffer[..len]);
+        self.0.transmit(tcb, timer::ticks(), false);
+        Ok(len)
+    }
+
+    /// Waits for room, and writes as [TcpStream::try_write] does.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, Errno> {
+        wait_interruptible(|| match self.try_write(buffer) {
+            Err(Errno::EAGAIN) => None,
+            result => Some(result),
+        })
+    }
+
+    pub fn write_all(&self, mut buffer: &[u8]) -> Result<(), Errno> {
+        while !buffer.is_empty() {
+            let len = self.write(buffer)?;
+            buffer = &buffer[len..];
+        }
+        Ok(())
+    }
+
+    /// Whether reading would not fail with `EAGAIN`.
+    pub fn readable(&self) -> bool {
+        let tcb = self.0.tcb.lock();
+        !tcb.receive_buffer.is_empty()
+            || tcb.fin_received
+            || tcb.error.is_some()
+            || tcb.state == State::Closed
+    }
+
+    /// Whether writing would not fail with `EAGAIN`.
+    pub fn writable(&self) -> bool {
+        let tcb = self.0.tcb.lock();
+        match tcb.state {
+            State::SynSent | State::SynReceived => false,
+            state if state.can_send() && !tcb.fin_queued => {
+                tcb.send_buffer.len() < SEND_BUFFER_SIZE
+            }
+            _ => true,
+        }
+    }
+
+    /// Stops sending. The other side reads the end of the stream once it has read everything
+    /// that was sent before.
+    pub fn shutdown(&self) {
+        let tcb = &mut *self.0.tcb.lock();
+        if tcb.state == State::SynSent {
+            tcb.state = State::Closed;
+        } else if !tcb.fin_queued {
+            tcb.fin_queued = true;
+            self.0.transmit(tcb, timer::ticks(), false);
+        }
+    }
+
+    /// Resets the connection, dropping whatever hasn't been sent.
+    fn abort(self) {
+        {
+            let tcb = &mut *self.0.tcb.lock();
+            if !matches!(tcb.state, State::SynSent | State::TimeWait | State::Closed) {
+                send_segment(
+                    self.0.local,
+                    self.0.remote,
+                    tcb.snd_nxt,
+                    0,
+                    RST,
+                    0,
+                    None,
+                    &[],
+                );
+            }
+            tcb.state = State::Closed;
+        }
+        remove(&self.0);
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.shutdown();
+        if self.0.tcb.lock().state == State::Closed {
+            remove(&self.0);
+        }
+    }
+}
+
+#[test_case]
+fn test_tcp() {
+    use core::net::Ipv4Addr;
+
+    use super::wait_until;
+
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), 4).unwrap();
+    let address = SocketAddrV4::new(ipv4::LOCALHOST, listener.local_addr().port());
+    let client = TcpStream::connect(address).unwrap();
+    let server = listener.accept().unwrap();
+    assert_eq!(server.peer_addr(), client.local_addr());
+    assert_eq!(server.state(), State::Established);
+
+    // More than fits in either side's buffers, so that both windows fill up.
+    let message: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut received = Vec::new();
+    let mut sent = 0;
+    let mut buffer = [0; 4096];
+    while received.len() < message.len() {
+        if sent < message.len() {
+            sent += match client.try_write(&message[sent..]) {
+                Err(Errno::EAGAIN) => 0,
+                result => result.unwrap(),
+            };
+        }
+        let len = wait_until(|| match server.try_read(&mut buffer) {
+            Err(Errno::EAGAIN) if sent < message.len() && client.writable() => Some(0),
+            Err(Errno::EAGAIN) => None,
+            result => Some(result.unwrap()),
+        });
+        received.extend_from_slice(&buffer[..len]);
+    }
+    assert!(received == message);
+
+    // Closing one side ends the other's stream, and it can still reply.
+    client.shutdown();
+    assert_eq!(server.read(&mut buffer), Ok(0));
+    server.write_all(b"bye").unwrap();
+    assert_eq!(client.read(&mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"bye");
+    drop(server);
+    assert_eq!(client.read(&mut buffer), Ok(0));
+    drop(client);
+
+    // Nobody listens on the port once the listener is dropped.
+    drop(listener);
+    assert_eq!(TcpStream::connect(address).err(), Some(Errno::ECONNREFUSED));
+}
@@ -0,0 +1,265 @@
+//! UDP, which sends datagrams between ports, with no promise that they arrive.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use spin::Mutex;
+
+use super::{
+    ephemeral_port, icmp,
+    ipv4::{self, Checksum, Header},
+    wait_interruptible, Interface,
+};
+use crate::errno::Errno;
+
+/// The size of the ports, length and checksum at the start of a datagram.
+const HEADER_SIZE: usize = 8;
+/// The most datagram payload that one packet can carry.
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PACKET_PAYLOAD - HEADER_SIZE;
+/// The most datagrams that can wait to be received. More are dropped.
+const MAX_QUEUED: usize = 64;
+
+struct Socket {
+    local: SocketAddrV4,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Where datagrams go by default, and the only place that they are accepted from.
+    peer: Option<SocketAddrV4>,
+    received: VecDeque<(SocketAddrV4, Vec<u8>)>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Weak<Socket>>> = Mutex::new(BTreeMap::new());
+
+/// A UDP socket, bound to a port until it is dropped.
+pub struct UdpSocket(Arc<Socket>);
+
+impl UdpSocket {
+    /// Binds a socket to an address. Port 0 picks a free port, and the unspecified address
+    /// receives datagrams sent to any of our addresses.
+    pub fn bind(address: SocketAddrV4) -> Result<Self, Errno> {
+        let mut sockets = SOCKETS.lock();
+        let in_use = |port| {
+            sockets
+                .get(&port)
+                .is_some_and(|socket: &Weak<Socket>| socket.strong_count() > 0)
+        };
+        let port = match address.port() {
+            0 => ephemeral_port(in_use)?,
+            port if in_use(port) => return Err(Errno::EADDRINUSE),
+            port => port,
+        };
+        let socket = Arc::new(Socket {
+            local: SocketAddrV4::new(*address.ip(), port),
+            state: Mutex::new(State {
+                peer: None,
+                received: VecDeque::new(),
+            }),
+        });
+        sockets.insert(port, Arc::downgrade(&socket));
+        Ok(Self(socket))
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.0.local
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddrV4, Errno> {
+        self.0.state.lock().peer.ok_or(Errno::ENOTCONN)
+    }
+
+    /// Sends datagrams to an address by default, and receives only from it.
+    /// Datagrams from elsewhere that are already waiting are dropped.
+    pub fn connect(&self, peer: SocketAddrV4) {
+        let mut state = self.0.state.lock();
+        state.peer = Some(peer);
+        state.received.retain(|&(from, _)| from == peer);
+    }
+
+    pub fn send_to(&self, payload: &[u8], destination: SocketAddrV4) -> Result<usize, Errno> {
+        let route = ipv4::route(*destination.ip())?;
+        self.send_via(&route, payload, destination)
+    }
+
+    /// Sends a datagram out of a particular interface, even one that has no address yet,
+    /// as when broadcasting to find one.
+    pub fn send_on(
+        &self,
+        interface: &Arc<Interface>,
+        payload: &[u8],
+        destination: SocketAddrV4,
+    ) -> Result<usize, Errno> {
+        let route = ipv4::Route {
+            interface: interface.clone(),
+            source: interface
+                .config()
+                .map_or(Ipv4Addr::UNSPECIFIED, |config| config.address),
+            next_hop: *destination.ip(),
+        };
+        self.send_via(&route, payload, destination)
+    }
+
+    fn send_via(
+        &self,
+        route: &ipv4::Route,
+        payload: &[u8],
+        destination: SocketAddrV4,
+    ) -> Result<usize, Errno> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Errno::EMSGSIZE);
+        }
+        let mut route = route.clone();
+        if !self.0.local.ip().is_unspecified() {
+            route.source = *self.0.local.ip();
+        }
+        let datagram = datagram(
+            SocketAddrV4::new(route.source, self.0.local.port()),
+            destination,
+            payload,
+        );
+        ipv4::send(&route, *destination.ip(), ipv4::PROTOCOL_UDP, &datagram)?;
+        Ok(payload.len())
+    }
+
+    /// Sends a datagram to the address that the socket is connected to.
+    pub fn send(&self, payload: &[u8]) -> Result<usize, Errno> {
+        self.send_to(payload, self.peer_addr()?)
+    }
+
+    /// Whether a datagram is waiting to be received.
+    pub fn readable(&self) -> bool {
+        !self.0.state.lock().received.is_empty()
+    }
+
+    /// Receives a datagram if one is waiting, or fails with `EAGAIN`.
+    /// Returns how much of it fitted in the buffer, and where it came from.
+    /// The rest of a datagram that doesn't fit is dropped.
+    pub fn try_recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddrV4), Errno> {
+        let (from, payload) = self
+            .0
+            .state
+            .lock()
+            .received
+            .pop_front()
+            .ok_or(Errno::EAGAIN)?;
+        let len = payload.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&payload[..len]);
+        Ok((len, from))
+    }
+
+    /// Waits for a datagram, and receives it as [UdpSocket::try_recv_from] does.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddrV4), Errno> {
+        wait_interruptible(|| match self.try_recv_from(buffer) {
+            Err(Errno::EAGAIN) => None,
+            result => Some(result),
+        })
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut sockets = SOCKETS.lock();
+        let port = self.0.local.port();
+        if sockets
+            .get(&port)
+            .is_some_and(|socket| core::ptr::eq(socket.as_ptr(), Arc::as_ptr(&self.0)))
+        {
+            sockets.remove(&port);
+        }
+    }
+}
+
+/// Makes a datagram, with its checksum.
+fn datagram(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&source.port().to_be_bytes());
+    datagram.extend_from_slice(&destination.port().to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum = Checksum::new()
+        .add_pseudo_header(*source.ip(), *destination.ip(), ipv4::PROTOCOL_UDP, len)
+        .add(&datagram)
+        .finish();
+    // A checksum of zero means that there is no checksum, so zero is sent as all ones instead.
+    let checksum = if checksum == 0 { 0xffff } else { checksum };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// Handles a datagram that arrived on an interface.
+pub fn receive(_interface: &Arc<Interface>, header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if checksum != 0
+        && Checksum::new()
+            .add_pseudo_header(header.source, header.destination, ipv4::PROTOCOL_UDP, len)
+            .add(datagram)
+            .finish()
+            != 0
+    {
+        return;
+    }
+    let from = SocketAddrV4::new(
+        header.source,
+        u16::from_be_bytes([datagram[0], datagram[1]]),
+    );
+    let port = u16::from_be_bytes([datagram[2], datagram[3]]);
+
+    let socket = SOCKETS.lock().get(&port).and_then(Weak::upgrade);
+    let Some(socket) = socket.filter(|socket| {
+        let ip = *socket.local.ip();
+        ip.is_unspecified() || ip == header.destination || header.destination.is_broadcast()
+    }) else {
+        icmp::port_unreachable(header, datagram);
+        return;
+    };
+    let mut state = socket.state.lock();
+    if state.peer.is_some_and(|peer| peer != from) || state.received.len() == MAX_QUEUED {
+        return;
+    }
+    state
+        .received
+        .push_back((from, datagram[HEADER_SIZE..].into()));
+}
+
+#[test_case]
+fn test_udp() {
+    let server = UdpSocket::bind(SocketAddrV4::new(ipv4::LOCALHOST, 0)).unwrap();
+    let client = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    assert_eq!(
+        UdpSocket::bind(server.local_addr()).err(),
+        Some(Errno::EADDRINUSE)
+    );
+    client.connect(server.local_addr());
+    // Big enough to be split into fragments.
+    let message: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+    client.send(&message).unwrap();
+
+    let mut buffer = [0; 5000];
+    let (len, from) = server.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], &message[..]);
+    assert_eq!(from.port(), client.local_addr().port());
+    server.send_to(b"pong", from).unwrap();
+    let (len, _) = client.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"pong");
+    assert_eq!(client.try_recv_from(&mut buffer), Err(Errno::EAGAIN));
+
+    // The port is free again once the socket is dropped.
+    let address = server.local_addr();
+    drop(server);
+    UdpSocket::bind(address).unwrap();
+}
@@ -0,0 +1,484 @@
+//! NVMe controllers, which QEMU attaches with `-device nvme`.
+//!
+//! The driver and the controller talk through pairs of queues in memory: the driver writes
+//! commands into a submission queue and rings its doorbell, and the controller writes an entry
+//! into the matching completion queue when it has finished each one. The admin queue pair sets
+//! up the controller; we then make one I/O queue pair, shared by all of its namespaces, each of
+//! which is a disk. Commands are made one at a time, through a bounce buffer.
+
+use alloc::{format, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Mutex;
+
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+    memory::{dma::DmaBuffer, mmio::Mmio, PAGE_SIZE},
+    pci::{self, msi, PciDevice},
+    serial_println, sync,
+};
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "nvme",
+    matches: &[pci::Match::Interface(0x01, 0x08, 0x02)],
+    probe,
+};
+
+/// Offsets of the controller's registers.
+const CAPABILITIES: usize = 0x00;
+const VERSION: usize = 0x08;
+const CONFIGURATION: usize = 0x14;
+const STATUS: usize = 0x1c;
+const ADMIN_QUEUE_ATTRIBUTES: usize = 0x24;
+const ADMIN_SUBMISSION_QUEUE: usize = 0x28;
+const ADMIN_COMPLETION_QUEUE: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CONFIGURATION_ENABLE: u32 = 1 << 0;
+/// Entries are 64 bytes in submission queues and 16 in completion queues, as powers of two.
+const CONFIGURATION_QUEUE_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+const STATUS_READY: u32 = 1 << 0;
+const STATUS_FATAL: u32 = 1 << 1;
+
+/// Admin commands.
+const CREATE_IO_SUBMISSION_QUEUE: u8 = 0x01;
+const CREATE_IO_COMPLETION_QUEUE: u8 = 0x05;
+const IDENTIFY: u8 = 0x06;
+const SET_FEATURES: u8 = 0x09;
+
+/// What [IDENTIFY] describes.
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 2;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// I/O commands.
+const FLUSH: u8 = 0x00;
+const WRITE: u8 = 0x01;
+const READ: u8 = 0x02;
+
+/// Bits of the command-specific words when creating queues.
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+const ADMIN_QUEUE_SIZE: u16 = 16;
+const IO_QUEUE_SIZE: u16 = 64;
+
+/// The most that a single command will move.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// How many times to check the controller before giving up on it.
+const TIMEOUT_POLLS: usize = 10_000_000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+struct Command {
+    /// The opcode, and the command identifier in the top half.
+    opcode: u32,
+    namespace: u32,
+    reserved: u64,
+    metadata: u64,
+    /// Physical region pages: the data, described as in [Controller::io].
+    prp: [u64; 2],
+    dwords: [u32; 6],
+}
+
+impl Command {
+    fn new(opcode: u8) -> Self {
+        Self {
+            opcode: opcode as u32,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    submission_head: u16,
+    submission_queue: u16,
+    command_id: u16,
+    /// The phase bit, which flips each time the controller wraps around the queue, then the status.
+    status: u16,
+}
+
+/// A submission queue and the completion queue that its commands finish on.
+struct QueuePair {
+    id: u16,
+    size: u16,
+    submissions: DmaBuffer,
+    completions: DmaBuffer,
+    tail: u16,
+    head: u16,
+    /// The phase bit that new completions will have.
+    phase: bool,
+    next_command_id: u16,
+    /// Offsets of the doorbell registers.
+    submission_doorbell: usize,
+    completion_doorbell: usize,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16, doorbell_stride: usize) -> Result<Self, Errno> {
+        let buffer = |entry_size: usize| {
+            DmaBuffer::new(size as usize * entry_size).map_err(|_| Errno::ENOMEM)
+        };
+        Ok(Self {
+            id,
+            size,
+            submissions: buffer(size_of::<Command>())?,
+            completions: buffer(size_of::<Completion>())?,
+            tail: 0,
+            head: 0,
+            phase: true,
+            next_command_id: 0,
+            submission_doorbell: DOORBELLS + 2 * id as usize * doorbell_stride,
+            completion_doorbell: DOORBELLS + (2 * id as usize + 1) * doorbell_stride,
+        })
+    }
+
+    /// Takes the next completion, if the controller has written it.
+    fn poll(&mut self, registers: &Mmio) -> Option<Completion> {
+        let offset = self.head as usize * size_of::<Completion>();
+        let completion = self.completions.read::<Completion>(offset);
+        if (completion.status & 1 != 0) != self.phase {
+            return None;
+        }
+        self.head += 1;
+        if self.head == self.size {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+        registers.write::<u32>(self.completion_doorbell, self.head as u32);
+        Some(completion)
+    }
+
+    /// Submits a command and waits for it to finish, returning its command-specific result.
+    fn submit(&mut self, registers: &Mmio, mut command: Command) -> Result<u32, Errno> {
+        let id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        command.opcode |= (id as u32) << 16;
+        self.submissions
+            .write(self.tail as usize * size_of::<Command>(), command);
+        self.tail = (self.tail + 1) % self.size;
+        registers.write::<u32>(self.submission_doorbell, self.tail as u32);
+
+        loop {
+            let completion = sync::wait_for_device(|| self.poll(registers));
+            if completion.command_id != id {
+                continue;
+            }
+            return match completion.status >> 1 {
+                0 => Ok(completion.result),
+                status => {
+                    serial_println!(
+                        "NVMe queue {}: command {:#x} failed with status {:#x}",
+                        self.id,
+                        command.opcode as u8,
+                        status
+                    );
+                    Err(Errno::EIO)
+                }
+            };
+        }
+    }
+}
+
+struct Io {
+    queue: QueuePair,
+    /// The bounce buffer, and a list of the physical addresses of all but its first page.
+    buffer: DmaBuffer,
+    page_list: DmaBuffer,
+}
+
+impl Io {
+    /// Points a command at the first `len` bytes of the bounce buffer.
+    ///
+    /// The first page is given directly. If there are two, so is the second. Otherwise, the second
+    /// entry points at a list of the rest.
+    fn describe(&self, command: &mut Command, len: usize) {
+        let pages = len.div_ceil(PAGE_SIZE as usize);
+        command.prp[0] = self.buffer.phys(0).as_u64();
+        command.prp[1] = match pages {
+            0 | 1 => 0,
+            2 => self.buffer.phys(PAGE_SIZE as usize).as_u64(),
+            _ => self.page_list.phys(0).as_u64(),
+        };
+    }
+}
+
+struct Controller {
+    registers: Mmio,
+    admin: Mutex<QueuePair>,
+    io: Mutex<Io>,
+    /// The largest transfer that the controller accepts, up to [MAX_TRANSFER].
+    max_transfer: usize,
+}
+
+impl Controller {
+    fn admin(&self, command: Command) -> Result<u32, Errno> {
+        self.admin.lock().submit(&self.registers, command)
+    }
+
+    /// Runs an admin command that fills in a page, and returns the page.
+    fn identify(&self, kind: u32, namespace: u32) -> Result<[u8; 4096], Errno> {
+        let page = DmaBuffer::new(PAGE_SIZE as usize).map_err(|_| Errno::ENOMEM)?;
+        let mut command = Command::new(IDENTIFY);
+        command.namespace = namespace;
+        command.prp[0] = page.phys(0).as_u64();
+        command.dwords[0] = kind;
+        self.admin(command)?;
+        let mut data = [0; 4096];
+        page.read_bytes(0, &mut data);
+        Ok(data)
+    }
+}
+
+fn wait(registers: &Mmio, ready: bool) -> Result<(), Errno> {
+    for _ in 0..TIMEOUT_POLLS {
+        let status = registers.read::<u32>(STATUS);
+        if status & STATUS_FATAL != 0 {
+            return Err(Errno::EIO);
+        }
+        if (status & STATUS_READY != 0) == ready {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Errno::EIO)
+}
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+/// The number of controllers found so far, which gives each its name.
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The disks found so far.
+static DISKS: Mutex<Vec<Arc<NvmeDisk>>> = Mutex::new(Vec::new());
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    pci.enable_bus_master();
+    let registers = pci.map_bar(0)?;
+    let capabilities = registers.read::<u32>(CAPABILITIES) as u64
+        | (registers.read::<u32>(CAPABILITIES + 4) as u64) << 32;
+    let max_queue_size = (capabilities & 0xffff) as u16 + 1;
+    let doorbell_stride = 4 << ((capabilities >> 32) & 0xf);
+    let version = registers.read::<u32>(VERSION);
+
+    // Reset the controller, then tell it where the admin queues are.
+    registers.write::<u32>(CONFIGURATION, 0);
+    wait(&registers, false)?;
+    let admin = QueuePair::new(0, ADMIN_QUEUE_SIZE.min(max_queue_size), doorbell_stride)?;
+    let size = admin.size as u32 - 1;
+    registers.write::<u32>(ADMIN_QUEUE_ATTRIBUTES, size << 16 | size);
+    for (register, addr) in [
+        (ADMIN_SUBMISSION_QUEUE, admin.submissions.phys(0)),
+        (ADMIN_COMPLETION_QUEUE, admin.completions.phys(0)),
+    ] {
+        registers.write::<u32>(register, addr.as_u64() as u32);
+        registers.write::<u32>(register + 4, (addr.as_u64() >> 32) as u32);
+    }
+    registers.write::<u32>(
+        CONFIGURATION,
+        CONFIGURATION_ENABLE | CONFIGURATION_QUEUE_ENTRY_SIZES,
+    );
+    wait(&registers, true)?;
+
+    // The admin queue always raises MSI-X entry 0, and we give the I/O queue entry 1.
+    // The controller interrupting is enough to wake up a driver waiting for it.
+    let msix = pci.enable_msix().ok().filter(|msix| msix.len() >= 2);
+    let io_interrupts = match &msix {
+        Some(msix) => {
+            for entry in 0..2 {
+                msix.route(entry, msi::allocate_vector(|| {})?);
+            }
+            QUEUE_INTERRUPTS_ENABLED | 1 << 16
+        }
+        None => 0,
+    };
+
+    let io_queue = QueuePair::new(1, IO_QUEUE_SIZE.min(max_queue_size), doorbell_stride)?;
+    let buffer = DmaBuffer::new(MAX_TRANSFER).map_err(|_| Errno::ENOMEM)?;
+    let page_list = DmaBuffer::new(PAGE_SIZE as usize).map_err(|_| Errno::ENOMEM)?;
+    for page in 1..MAX_TRANSFER / PAGE_SIZE as usize {
+        page_list.write::<u64>(
+            (page - 1) * 8,
+            buffer.phys(page * PAGE_SIZE as usize).as_u64(),
+        );
+    }
+    let io_size = io_queue.size as u32 - 1;
+    let (completions, submissions) = (
+        io_queue.completions.phys(0).as_u64(),
+        io_queue.submissions.phys(0).as_u64(),
+    );
+    let mut controller = Controller {
+        registers,
+        admin: Mutex::new(admin),
+        io: Mutex::new(Io {
+            queue: io_queue,
+            buffer,
+            page_list,
+        }),
+        max_transfer: MAX_TRANSFER,
+    };
+
+    let identity = controller.identify(IDENTIFY_CONTROLLER, 0)?;
+    let model = core::str::from_utf8(&identity[24..64]).unwrap_or("").trim();
+    let serial = core::str::from_utf8(&identity[4..24]).unwrap_or("").trim();
+    // The largest transfer is given as a power of two number of the smallest pages.
+    let max_transfer_pages = identity[77];
+    if max_transfer_pages != 0 {
+        let min_page_size = 1 << (12 + ((capabilities >> 48) & 0xf));
+        controller.max_transfer = MAX_TRANSFER.min(min_page_size << max_transfer_pages);
+    }
+
+    let mut set_queues = Command::new(SET_FEATURES);
+    set_queues.dwords[0] = FEATURE_NUMBER_OF_QUEUES;
+    // Both counts are one less than the number of queues.
+    set_queues.dwords[1] = 0;
+    controller.admin(set_queues)?;
+    let mut create = Command::new(CREATE_IO_COMPLETION_QUEUE);
+    create.prp[0] = completions;
+    create.dwords[0] = io_size << 16 | 1;
+    create.dwords[1] = QUEUE_PHYSICALLY_CONTIGUOUS | io_interrupts;
+    controller.admin(create)?;
+    let mut create = Command::new(CREATE_IO_SUBMISSION_QUEUE);
+    create.prp[0] = submissions;
+    create.dwords[0] = io_size << 16 | 1;
+    // The completion queue's identifier goes in the top half.
+    create.dwords[1] = QUEUE_PHYSICALLY_CONTIGUOUS | 1 << 16;
+    controller.admin(create)?;
+
+    let index = CONTROLLERS.fetch_add(1, Ordering::Relaxed);
+    serial_println!(
+        "NVMe nvme{}: {} (serial {}), version {}.{}, {}",
+        index,
+        model,
+        serial,
+        version >> 16,
+        (version >> 8) & 0xff,
+        if msix.is_some() { "MSI-X" } else { "polling" }
+    );
+
+    let controller = Arc::new(controller);
+    let namespaces = controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0)?;
+    let namespaces: Vec<u32> = bytemuck::cast_slice::<u8, u32>(&namespaces)
+        .iter()
+        .copied()
+        .take_while(|&namespace| namespace != 0)
+        .collect();
+    for namespace in namespaces {
+        let identity = controller.identify(IDENTIFY_NAMESPACE, namespace)?;
+        let sector_count = u64::from_le_bytes(identity[0..8].try_into().unwrap());
+        let format = (identity[26] & 0xf) as usize;
+        let sector_size = 1 << identity[128 + 4 * format + 2];
+        if sector_count == 0 || !(512..=PAGE_SIZE as usize).contains(&sector_size) {
+            continue;
+        }
+        let disk = Arc::new(NvmeDisk {
+            controller: controller.clone(),
+            namespace,
+            sector_size,
+            sector_count,
+        });
+        DISKS.lock().push(disk.clone());
+        block::add_disk(&format!("nvme{index}n{namespace}"), disk);
+    }
+    Ok(())
+}
+
+/// A namespace of a controller.
+pub struct NvmeDisk {
+    controller: Arc<Controller>,
+    namespace: u32,
+    sector_size: usize,
+    sector_count: u64,
+}
+
+impl NvmeDisk {
+    /// Reads or writes up to the controller's largest transfer, through the bounce buffer.
+    fn command(&self, io: &mut Io, opcode: u8, sector: u64, len: usize) -> Result<(), Errno> {
+        let mut command = Command::new(opcode);
+        command.namespace = self.namespace;
+        io.describe(&mut command, len);
+        command.dwords[0] = sector as u32;
+        command.dwords[1] = (sector >> 32) as u32;
+        // The number of sectors, less one.
+        command.dwords[2] = (len / self.sector_size) as u32 - 1;
+        io.queue.submit(&self.controller.registers, command)?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for NvmeDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let max_transfer = self.controller.max_transfer;
+        let mut io = self.controller.io.lock();
+        for (i, chunk) in buf.chunks_mut(max_transfer).enumerate() {
+            let start = sector + (i * max_transfer / self.sector_size) as u64;
+            self.command(&mut io, READ, start, chunk.len())?;
+            io.buffer.read_bytes(0, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let max_transfer = self.controller.max_transfer;
+        let mut io = self.controller.io.lock();
+        for (i, chunk) in buf.chunks(max_transfer).enumerate() {
+            let start = sector + (i * max_transfer / self.sector_size) as u64;
+            io.buffer.write_bytes(0, chunk);
+            self.command(&mut io, WRITE, start, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        let mut command = Command::new(FLUSH);
+        command.namespace = self.namespace;
+        let mut io = self.controller.io.lock();
+        io.queue.submit(&self.controller.registers, command)?;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_nvme() {
+    use alloc::vec;
+
+    // The test runner attaches the ext2 image as an NVMe namespace, if it could build one.
+    let Some(disk) = DISKS.lock().first().cloned() else {
+        return;
+    };
+    let sectors = (3 * MAX_TRANSFER / 2) / disk.sector_size;
+    let mut saved = vec![0; sectors * disk.sector_size];
+    disk.read_sectors(0, &mut saved).unwrap();
+    assert_eq!(saved[1024 + 56..1024 + 58], 0xef53u16.to_le_bytes());
+
+    // Nothing lives in the first kilobyte of an ext2 filesystem, and QEMU throws the change away.
+    let data: Vec<u8> = (0..disk.sector_size).map(|i| (i / 5) as u8).collect();
+    disk.write_sectors(0, &data).unwrap();
+    disk.flush().unwrap();
+    let mut back = vec![0; saved.len()];
+    disk.read_sectors(0, &mut back).unwrap();
+    assert_eq!(back[..data.len()], data[..]);
+    assert_eq!(back[data.len()..], saved[data.len()..]);
+    disk.write_sectors(0, &saved[..data.len()]).unwrap();
+}
@@ -0,0 +1,534 @@
+//! The PCI bus, where most devices other than the legacy PC ones live.
+//!
+//! At boot, [init] walks every bus that the firmware set up, following bridges to the buses
+//! behind them, and prints a listing of what it finds. Each BAR (base address register) is sized
+//! then, so drivers can map a device's registers with [PciDevice::map_bar].
+//!
+//! Drivers describe the devices that they handle with a [Driver], and hand it to
+//! [register_driver]. Its probe function is called for each matching device that no other
+//! driver has claimed.
+
+pub mod config;
+pub mod msi;
+pub mod names;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt::{self, Display};
+
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+
+use crate::{errno::Errno, memory::mmio::Mmio, println, serial_println, trap};
+
+/// Offsets of registers in the configuration space header.
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0e;
+pub const BAR_0: u16 = 0x10;
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+pub const SUBSYSTEM_ID: u16 = 0x2e;
+pub const CAPABILITIES: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3c;
+pub const INTERRUPT_PIN: u16 = 0x3d;
+
+/// Bits of the command register.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Identifiers of capabilities.
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// Bits of a BAR.
+const BAR_IO: u32 = 1 << 0;
+const BAR_64_BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Where a function lives: its segment (or domain), bus, device and function numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    /// Formats the address in the same way as `lspci`, leaving out segment 0.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.segment != 0 {
+            write!(f, "{:04x}:", self.segment)?;
+        }
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A range of addresses that a device decodes, as described by one of its BARs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+/// A function on the bus, which is what drivers think of as a device.
+#[derive(Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    /// The legacy interrupt controller line that the firmware routed the device's interrupt to.
+    pub interrupt_line: u8,
+    /// Which of the four interrupt pins the device uses, counting from 1, or 0 if none.
+    pub interrupt_pin: u8,
+    /// A 64-bit BAR takes up two slots, the second of which is `None`.
+    pub bars: [Option<Bar>; 6],
+    /// The name of the driver that claimed this device.
+    driver: Once<&'static str>,
+}
+
+impl PciDevice {
+    /// Reads the function's header, sizing its BARs.
+    /// Returns `None` if there is no such function.
+    fn new(address: PciAddress) -> Option<Self> {
+        let id = config::read_u32(address, VENDOR_ID);
+        if id as u16 == 0xffff {
+            return None;
+        }
+        let class = config::read_u32(address, REVISION);
+        let header_type = (config::read_u32(address, HEADER_TYPE & !3) >> 16) as u8;
+        let subsystem = config::read_u32(address, SUBSYSTEM_VENDOR_ID);
+        let interrupt = config::read_u32(address, INTERRUPT_LINE);
+        let mut device = Self {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: [None; 6],
+            driver: Once::new(),
+        };
+        let bar_count = match header_type & HEADER_TYPE_MASK {
+            0 => {
+                device.subsystem_vendor_id = subsystem as u16;
+                device.subsystem_id = (subsystem >> 16) as u16;
+                6
+            }
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        device.size_bars(bar_count);
+        Some(device)
+    }
+
+    /// Finds the size of each BAR by writing all ones to it and seeing which bits stick.
+    /// Decoding is turned off meanwhile, so that the device does not claim strange addresses.
+    fn size_bars(&mut self, count: usize) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let mut index = 0;
+        while index < count {
+            let offset = BAR_0 + index as u16 * 4;
+            let value = self.read_u32(offset);
+            self.write_u32(offset, u32::MAX);
+            let mask = self.read_u32(offset);
+            self.write_u32(offset, value);
+
+            if value & BAR_IO != 0 {
+                let size = (!(mask & !0b11) as u16).wrapping_add(1);
+                if mask != 0 && size != 0 {
+                    self.bars[index] = Some(Bar::Io {
+                        port: (value & !0b11) as u16,
+                        size,
+                    });
+                }
+                index += 1;
+                continue;
+            }
+
+            let mut address = (value & !0b1111) as u64;
+            let mut mask = (mask & !0b1111) as u64;
+            let wide = value & BAR_64_BIT != 0 && index + 1 < count;
+            if wide {
+                let upper_offset = offset + 4;
+                let upper = self.read_u32(upper_offset);
+                self.write_u32(upper_offset, u32::MAX);
+                let upper_mask = self.read_u32(upper_offset);
+                self.write_u32(upper_offset, upper);
+                address |= (upper as u64) << 32;
+                mask |= (upper_mask as u64) << 32;
+            } else {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+            if mask != 0 && mask != 0xffff_ffff_0000_0000 {
+                self.bars[index] = Some(Bar::Memory {
+                    address,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: value & BAR_PREFETCHABLE != 0,
+                });
+            }
+            index += if wide { 2 } else { 1 };
+        }
+
+        self.write_u16(COMMAND, command);
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value);
+    }
+
+    /// Writes half of a 32-bit register, leaving the other half as it was.
+    ///
+    /// Be careful with registers where writing a one clears a bit, such as the status register:
+    /// the other half is written back with the value that was read.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset & !3) & !(0xffff << shift);
+        self.write_u32(offset & !3, old | (value as u32) << shift);
+    }
+
+    /// Writes one byte of a 32-bit register, leaving the rest as it was.
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        let old = self.read_u32(offset & !3) & !(0xff << shift);
+        self.write_u32(offset & !3, old | (value as u32) << shift);
+    }
+
+    fn is_bridge(&self) -> bool {
+        self.header_type & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE
+    }
+
+    /// Iterates over the identifier and offset of each of the function's capabilities.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut next = if self.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_u8(CAPABILITIES) & !3
+        } else {
+            0
+        };
+        // A broken device could make a loop, but there is only room for so many capabilities.
+        let mut remaining = 48;
+        core::iter::from_fn(move || {
+            if next == 0 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let offset = next as u16;
+            let header = self.read_u16(offset);
+            next = (header >> 8) as u8 & !3;
+            Some((header as u8, offset))
+        })
+    }
+
+    /// The offset of the first capability with the given identifier.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|&(found, _)| found == id)
+            .map(|(_, offset)| offset)
+    }
+
+    pub fn enable_memory_space(&self) {
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_MEMORY_SPACE);
+    }
+
+    /// Lets the device read and write memory by itself, which it needs for DMA.
+    pub fn enable_bus_master(&self) {
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_BUS_MASTER);
+    }
+
+    /// Calls `handler` whenever the device raises its legacy interrupt line,
+    /// which other devices may share.
+    pub fn add_interrupt_handler(&self, handler: fn()) -> Result<(), Errno> {
+        // The firmware gives the line to the interrupt controller, unless the device has no line.
+        if self.interrupt_pin == 0 || self.interrupt_line >= 16 {
+            return Err(Errno::ENODEV);
+        }
+        if !trap::add_irq_handler(self.interrupt_line, handler) {
+            return Err(Errno::EBUSY);
+        }
+        self.write_u16(COMMAND, self.read_u16(COMMAND) & !COMMAND_INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// Maps the registers described by the given memory BAR, and lets the device respond to them.
+    pub fn map_bar(&self, index: usize) -> Result<Mmio, Errno> {
+        let Some(Bar::Memory { address, size, .. }) = self.bars.get(index).copied().flatten()
+        else {
+            return Err(Errno::ENODEV);
+        };
+        // The firmware did not give the BAR an address.
+        if address == 0 {
+            return Err(Errno::ENODEV);
+        }
+        let mmio = Mmio::map(PhysAddr::new(address), size as usize).map_err(|_| Errno::ENOMEM)?;
+        self.enable_memory_space();
+        Ok(mmio)
+    }
+
+    /// The first port described by the given I/O BAR. This lets the device respond to its ports.
+    pub fn io_bar(&self, index: usize) -> Result<u16, Errno> {
+        let Some(Bar::Io { port, .. }) = self.bars.get(index).copied().flatten() else {
+            return Err(Errno::ENODEV);
+        };
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_IO_SPACE);
+        Ok(port)
+    }
+
+    /// The name of the driver that claimed this device, if any.
+    pub fn driver(&self) -> Option<&'static str> {
+        self.driver.get().copied()
+    }
+}
+
+impl Display for PciDevice {
+    /// Formats the device like a line of `lspci -nn`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {} [{:04x}:{:04x}]",
+            self.address,
+            names::class(self.class, self.subclass, self.prog_if),
+            self.class,
+            self.subclass,
+            names::vendor(self.vendor_id).unwrap_or("Device"),
+            self.vendor_id,
+            self.device_id
+        )?;
+        if self.revision != 0 {
+            write!(f, " (rev {:02x})", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes some of the devices that a driver handles.
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    /// A particular vendor and device identifier.
+    Device(u16, u16),
+    /// Any device from the given vendor.
+    Vendor(u16),
+    /// Any device with the given class and subclass.
+    Class(u8, u8),
+    /// Any device with the given class, subclass and programming interface.
+    Interface(u8, u8, u8),
+}
+
+impl Match {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            Self::Device(vendor, id) => device.vendor_id == vendor && device.device_id == id,
+            Self::Vendor(vendor) => device.vendor_id == vendor,
+            Self::Class(class, subclass) => device.class == class && device.subclass == subclass,
+            Self::Interface(class, subclass, prog_if) => {
+                device.class == class && device.subclass == subclass && device.prog_if == prog_if
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    /// The driver is offered any device that one of these matches.
+    pub matches: &'static [Match],
+    /// Sets up the device. An error means that the driver does not want it after all,
+    /// and it is offered to the next driver.
+    pub probe: fn(&Arc<PciDevice>) -> Result<(), Errno>,
+}
+
+static DEVICES: Once<Vec<Arc<PciDevice>>> = Once::new();
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+
+/// Finds every device, and prints a listing of them.
+pub fn init() {
+    config::init();
+    let devices = DEVICES.call_once(enumerate);
+    println!(
+        "PCI: {} devices, through {}",
+        devices.len(),
+        config::description()
+    );
+    for device in devices {
+        println!("{device}");
+    }
+}
+
+/// Every device that was found at boot, in order of address.
+pub fn devices() -> &'static [Arc<PciDevice>] {
+    DEVICES.get().expect("PCI not yet initialised")
+}
+
+/// Offers the driver every unclaimed device that it matches,
+/// and keeps it so that it can be listed later.
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        if device.driver().is_some()
+            || !driver.matches.iter().any(|pattern| pattern.matches(device))
+        {
+            continue;
+        }
+        match (driver.probe)(device) {
+            Ok(()) => {
+                device.driver.call_once(|| driver.name);
+                serial_println!("PCI {}: claimed by {}", device.address, driver.name);
+            }
+            Err(errno) => {
+                serial_println!(
+                    "PCI {}: {} failed with {:?}",
+                    device.address,
+                    driver.name,
+                    errno
+                );
+            }
+        }
+    }
+}
+
+/// The names of the drivers that have been registered.
+pub fn drivers() -> Vec<&'static str> {
+    DRIVERS.lock().iter().map(|driver| driver.name).collect()
+}
+
+fn enumerate() -> Vec<Arc<PciDevice>> {
+    let mut devices = Vec::new();
+    let mut scanned = Vec::new();
+    for (segment, bus) in config::roots() {
+        scan_bus(segment, bus, &mut devices, &mut scanned);
+        // If the host bridge has several functions, each is in charge of its own bus.
+        let host = PciAddress {
+            segment,
+            bus,
+            device: 0,
+            function: 0,
+        };
+        let header_type = (config::read_u32(host, HEADER_TYPE & !3) >> 16) as u8;
+        if config::read_u32(host, VENDOR_ID) as u16 != 0xffff
+            && header_type & HEADER_MULTI_FUNCTION != 0
+        {
+            for function in 1..8 {
+                scan_bus(
+                    segment,
+                    bus.saturating_add(function),
+                    &mut devices,
+                    &mut scanned,
+                );
+            }
+        }
+    }
+    devices.sort_by_key(|device: &Arc<PciDevice>| device.address);
+    devices
+}
+
+fn scan_bus(
+    segment: u16,
+    bus: u8,
+    devices: &mut Vec<Arc<PciDevice>>,
+    scanned: &mut Vec<(u16, u8)>,
+) {
+    if scanned.contains(&(segment, bus)) {
+        return;
+    }
+    scanned.push((segment, bus));
+
+    for device in 0..32 {
+        for function in 0..8 {
+            let address = PciAddress {
+                segment,
+                bus,
+                device,
+                function,
+            };
+            let Some(found) = PciDevice::new(address) else {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            };
+            let multi_function = found.header_type & HEADER_MULTI_FUNCTION != 0;
+            if found.is_bridge() {
+                let secondary = found.read_u8(SECONDARY_BUS);
+                // A bridge that the firmware did not configure has a secondary bus of zero.
+                if secondary > bus {
+                    scan_bus(segment, secondary, devices, scanned);
+                }
+            }
+            devices.push(Arc::new(found));
+            if function == 0 && !multi_function {
+                break;
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_enumerate() {
+    let devices = devices();
+    let host = devices
+        .iter()
+        .find(|device| Match::Class(0x06, 0x00).matches(device))
+        .expect("no host bridge");
+    assert_eq!(host.address.device, 0);
+    assert_eq!(host.read_u16(VENDOR_ID), host.vendor_id);
+    assert_eq!(host.read_u8(REVISION), host.revision);
+    assert!(devices.is_sorted_by_key(|device| device.address));
+
+    // QEMU's display adapter has its framebuffer in a memory BAR.
+    if let Some(display) = devices
+        .iter()
+        .find(|device| Match::Device(0x1234, 0x1111).matches(device))
+    {
+        let Some(Bar::Memory { size, .. }) = display.bars[0] else {
+            panic!("bad BAR {:?}", display.bars[0]);
+        };
+        assert!(size.is_power_of_two() && size >= 0x10_0000);
+    }
+}
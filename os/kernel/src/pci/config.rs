@@ -0,0 +1,158 @@
+//! Reading and writing the configuration space of each function.
+//!
+//! Every PC can reach the first 256 bytes of each function on segment 0 through a pair of I/O
+//! ports. Newer machines also map the whole 4 KiB of every function into memory (ECAM), at
+//! addresses listed in the ACPI `MCFG` table, which reaches the extended capabilities and any
+//! other segments as well. We use ECAM whenever the firmware describes it.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use spin::{Mutex, Once};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use super::PciAddress;
+use crate::{acpi, memory::mmio::Mmio, sync::IrqMutex};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// The size of the configuration space of a function through ECAM, and the size of a bus's worth.
+const FUNCTION_SIZE: usize = 0x1000;
+const BUS_SIZE: usize = FUNCTION_SIZE * 8 * 32;
+
+/// Offsets in the `MCFG` table.
+const MCFG_ENTRIES: usize = 44;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// A range of buses on one segment whose configuration space is mapped into memory.
+struct EcamRegion {
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /// Each bus is mapped the first time that it is used.
+    buses: Mutex<BTreeMap<u8, Mmio>>,
+}
+
+impl EcamRegion {
+    fn contains(&self, address: PciAddress) -> bool {
+        address.segment == self.segment && (self.start_bus..=self.end_bus).contains(&address.bus)
+    }
+
+    fn function(&self, address: PciAddress) -> Mmio {
+        let mut buses = self.buses.lock();
+        let bus = buses.entry(address.bus).or_insert_with(|| {
+            let offset = (address.bus - self.start_bus) as u64 * BUS_SIZE as u64;
+            Mmio::map(PhysAddr::new(self.base + offset), BUS_SIZE).expect("out of memory")
+        });
+        let function = address.device as usize * 8 + address.function as usize;
+        bus.slice(function * FUNCTION_SIZE, FUNCTION_SIZE)
+    }
+}
+
+enum Access {
+    Ports(IrqMutex<()>),
+    Ecam(Vec<EcamRegion>),
+}
+
+static ACCESS: Once<Access> = Once::new();
+
+pub(super) fn init() {
+    ACCESS.call_once(|| {
+        let regions = acpi::find_table(b"MCFG")
+            .map(|mcfg| {
+                mcfg.get(MCFG_ENTRIES..)
+                    .unwrap_or_default()
+                    .chunks_exact(MCFG_ENTRY_SIZE)
+                    .map(|entry| EcamRegion {
+                        base: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                        segment: u16::from_le_bytes(entry[8..10].try_into().unwrap()),
+                        start_bus: entry[10],
+                        end_bus: entry[11],
+                        buses: Mutex::new(BTreeMap::new()),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if regions.is_empty() {
+            Access::Ports(IrqMutex::new(()))
+        } else {
+            Access::Ecam(regions)
+        }
+    });
+}
+
+fn access() -> &'static Access {
+    ACCESS.get().expect("PCI not yet initialised")
+}
+
+/// Describes how configuration space is being reached, for the boot log.
+pub(super) fn description() -> &'static str {
+    match access() {
+        Access::Ports(_) => "I/O ports",
+        Access::Ecam(_) => "ECAM",
+    }
+}
+
+/// The segments that can be reached, and the first bus number on each.
+pub(super) fn roots() -> Vec<(u16, u8)> {
+    match access() {
+        Access::Ports(_) => alloc::vec![(0, 0)],
+        Access::Ecam(regions) => regions
+            .iter()
+            .map(|region| (region.segment, region.start_bus))
+            .collect(),
+    }
+}
+
+/// Reads the aligned 32-bit register at the given offset.
+/// Functions that do not exist read as all ones.
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    match access() {
+        Access::Ports(lock) => {
+            if address.segment != 0 || offset >= 0x100 {
+                return u32::MAX;
+            }
+            let _guard = lock.lock();
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                Port::new(CONFIG_DATA).read()
+            }
+        }
+        Access::Ecam(regions) => match regions.iter().find(|region| region.contains(address)) {
+            Some(region) => region.function(address).read(offset as usize & !3),
+            None => u32::MAX,
+        },
+    }
+}
+
+/// Writes the aligned 32-bit register at the given offset.
+/// Writes to functions that do not exist are ignored.
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    match access() {
+        Access::Ports(lock) => {
+            if address.segment != 0 || offset >= 0x100 {
+                return;
+            }
+            let _guard = lock.lock();
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                Port::new(CONFIG_DATA).write(value);
+            }
+        }
+        Access::Ecam(regions) => {
+            if let Some(region) = regions.iter().find(|region| region.contains(address)) {
+                region.function(address).write(offset as usize & !3, value);
+            }
+        }
+    }
+}
+
+fn port_address(address: PciAddress, offset: u16) -> u32 {
+    CONFIG_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
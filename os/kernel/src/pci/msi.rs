@@ -0,0 +1,128 @@
+//! Message signalled interrupts.
+//!
+//! Rather than sharing one of a handful of interrupt lines, a device that supports MSI raises an
+//! interrupt by writing to the [crate::apic]'s message address, so every device can have vectors
+//! of its own. Plain MSI gives a function one vector, configured in its capability structure.
+//! MSI-X gives it a table of them in one of its BARs, so that a driver can have a separate vector
+//! for each queue.
+
+use alloc::sync::Arc;
+
+use x86_64::PhysAddr;
+
+use super::{Bar, PciDevice, CAPABILITY_MSI, CAPABILITY_MSIX, COMMAND, COMMAND_INTERRUPT_DISABLE};
+use crate::{apic, errno::Errno, memory::mmio::Mmio, trap};
+
+/// Bits of the message control register, for MSI.
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+/// Bits of the message control register, for MSI-X.
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// Finds an unused vector, and calls `handler` whenever it is raised.
+pub fn allocate_vector(handler: impl Fn() + Send + Sync + 'static) -> Result<u8, Errno> {
+    trap::allocate_msi_vector(Arc::new(handler)).ok_or(Errno::EBUSY)
+}
+
+impl PciDevice {
+    /// Has the device raise the given vector using MSI, instead of its interrupt line.
+    pub fn enable_msi(&self, vector: u8) -> Result<(), Errno> {
+        let capability = self.find_capability(CAPABILITY_MSI).ok_or(Errno::ENODEV)?;
+        let control = self.read_u16(capability + 2);
+        let (address, data) = apic::message(vector);
+
+        self.write_u32(capability + 4, address as u32);
+        let data_offset = if control & MSI_64_BIT != 0 {
+            self.write_u32(capability + 8, (address >> 32) as u32);
+            capability + 12
+        } else {
+            capability + 8
+        };
+        self.write_u16(data_offset, data as u16);
+        if control & MSI_PER_VECTOR_MASKING != 0 {
+            self.write_u32(data_offset + 4, 0);
+        }
+
+        // Ask for a single vector.
+        self.write_u16(
+            capability + 2,
+            control & !MSI_MULTIPLE_MESSAGE_ENABLE | MSI_ENABLE,
+        );
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// Switches the device over to MSI-X, with every entry of its table masked.
+    /// Use [MsiX::route] to choose the vector for each entry that the driver needs.
+    pub fn enable_msix(&self) -> Result<MsiX, Errno> {
+        let capability = self.find_capability(CAPABILITY_MSIX).ok_or(Errno::ENODEV)?;
+        let control = self.read_u16(capability + 2);
+        let len = (control & MSIX_TABLE_SIZE) + 1;
+        let table = self.read_u32(capability + 4);
+        let Some(Bar::Memory { address, .. }) = self.bars[(table & 0b111) as usize] else {
+            return Err(Errno::ENODEV);
+        };
+        self.enable_memory_space();
+        let table = Mmio::map(
+            PhysAddr::new(address + (table & !0b111) as u64),
+            len as usize * MSIX_ENTRY_SIZE,
+        )
+        .map_err(|_| Errno::ENOMEM)?;
+
+        // Mask everything while the table is set up.
+        self.write_u16(capability + 2, control | MSIX_FUNCTION_MASK | MSIX_ENABLE);
+        let msix = MsiX { table, len };
+        for entry in 0..len {
+            msix.mask(entry);
+        }
+        self.write_u16(
+            capability + 2,
+            (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+        );
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_INTERRUPT_DISABLE);
+        Ok(msix)
+    }
+}
+
+/// The MSI-X table of a device.
+pub struct MsiX {
+    table: Mmio,
+    len: u16,
+}
+
+impl MsiX {
+    /// The number of entries in the table.
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Has the given entry raise the given vector, and unmasks it.
+    pub fn route(&self, entry: u16, vector: u8) {
+        assert!(entry < self.len, "MSI-X entry out of range");
+        let (address, data) = apic::message(vector);
+        let offset = entry as usize * MSIX_ENTRY_SIZE;
+        self.table.write::<u32>(offset, address as u32);
+        self.table.write::<u32>(offset + 4, (address >> 32) as u32);
+        self.table.write::<u32>(offset + 8, data);
+        self.table.write::<u32>(offset + 12, 0);
+    }
+
+    /// Stops the given entry from raising interrupts.
+    pub fn mask(&self, entry: u16) {
+        assert!(entry < self.len, "MSI-X entry out of range");
+        let offset = entry as usize * MSIX_ENTRY_SIZE + 12;
+        self.table.write::<u32>(offset, MSIX_VECTOR_MASKED);
+    }
+}
@@ -0,0 +1,55 @@
+//! Human-readable names for the devices that we are likely to meet, for the boot listing.
+//! Anything else is shown by its numbers alone.
+
+/// The name of a vendor, given its identifier.
+pub fn vendor(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x1022 => "AMD",
+        0x10de => "NVIDIA",
+        0x10ec => "Realtek",
+        0x1234 => "QEMU",
+        0x144d => "Samsung",
+        0x15ad => "VMware",
+        0x1af4 | 0x1b36 => "Red Hat",
+        0x8086 => "Intel",
+        _ => return None,
+    })
+}
+
+/// The name of a kind of device, given its class, subclass and programming interface.
+pub fn class(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x00, 0x01, _) => "VGA compatible unclassified device",
+        (0x00, _, _) => "Non-VGA unclassified device",
+        (0x01, 0x00, _) => "SCSI storage controller",
+        (0x01, 0x01, _) => "IDE interface",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x08, _) => "Non-Volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+        (0x03, 0x00, _) => "VGA compatible controller",
+        (0x03, _, _) => "Display controller",
+        (0x04, 0x01, _) => "Multimedia audio controller",
+        (0x04, 0x03, _) => "Audio device",
+        (0x04, _, _) => "Multimedia controller",
+        (0x05, _, _) => "Memory controller",
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, _, _) => "Bridge",
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, _, _) => "Communication controller",
+        (0x08, _, _) => "System peripheral",
+        (0x09, _, _) => "Input device controller",
+        (0x0c, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0c, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0c, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0c, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0c, 0x03, _) => "USB controller",
+        (0x0c, 0x05, _) => "SMBus",
+        (0x0c, _, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
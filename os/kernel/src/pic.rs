@@ -0,0 +1,111 @@
+//! The legacy pair of 8259 programmable interrupt controllers.
+//!
+//! By default, these deliver hardware interrupts on vectors that collide with CPU exceptions,
+//! so we remap them to start at [PIC_1_OFFSET].
+
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqMutex;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// One past the last vector used by the interrupt controllers.
+pub const PIC_2_END: u8 = PIC_2_OFFSET + 8;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const COMMAND_EOI: u8 = 0x20;
+const COMMAND_READ_ISR: u8 = 0x0b;
+
+/// The secondary controller is attached to this line of the primary one.
+const CASCADE_IRQ: u8 = 2;
+
+/// The interrupt lines that are currently unmasked, as a bitmask.
+static ENABLED: IrqMutex<u16> = IrqMutex::new(0);
+
+/// Remaps both controllers and masks every interrupt line.
+pub fn init() {
+    unsafe {
+        let mut command_1 = Port::<u8>::new(PIC_1_COMMAND);
+        let mut data_1 = Port::<u8>::new(PIC_1_DATA);
+        let mut command_2 = Port::<u8>::new(PIC_2_COMMAND);
+        let mut data_2 = Port::<u8>::new(PIC_2_DATA);
+
+        command_1.write(ICW1_INIT);
+        io_wait();
+        command_2.write(ICW1_INIT);
+        io_wait();
+        data_1.write(PIC_1_OFFSET);
+        io_wait();
+        data_2.write(PIC_2_OFFSET);
+        io_wait();
+        data_1.write(1 << CASCADE_IRQ);
+        io_wait();
+        data_2.write(CASCADE_IRQ);
+        io_wait();
+        data_1.write(ICW4_8086);
+        io_wait();
+        data_2.write(ICW4_8086);
+        io_wait();
+    }
+
+    *ENABLED.lock() = 1 << CASCADE_IRQ;
+    write_masks(1 << CASCADE_IRQ);
+}
+
+/// Allows the given interrupt line to raise interrupts.
+pub fn unmask(irq: u8) {
+    let mut enabled = ENABLED.lock();
+    *enabled |= 1 << irq;
+    write_masks(*enabled);
+}
+
+/// Signals that the interrupt on the given line has been handled.
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            Port::<u8>::new(PIC_2_COMMAND).write(COMMAND_EOI);
+        }
+        Port::<u8>::new(PIC_1_COMMAND).write(COMMAND_EOI);
+    }
+}
+
+/// The controllers raise a spurious interrupt on the lowest priority line of each chip
+/// if an interrupt disappears before it is acknowledged.
+/// These must not be acknowledged with [end_of_interrupt],
+/// except that the primary controller needs to hear about spurious interrupts from the secondary one.
+pub fn is_spurious(irq: u8) -> bool {
+    let (command, line) = match irq {
+        7 => (PIC_1_COMMAND, 7),
+        15 => (PIC_2_COMMAND, 7),
+        _ => return false,
+    };
+    let in_service = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(COMMAND_READ_ISR);
+        port.read()
+    };
+    let spurious = in_service & (1 << line) == 0;
+    if spurious && irq == 15 {
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(COMMAND_EOI) };
+    }
+    spurious
+}
+
+fn write_masks(enabled: u16) {
+    let masks = !enabled;
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA).write(masks as u8);
+        Port::<u8>::new(PIC_2_DATA).write((masks >> 8) as u8);
+    }
+}
+
+/// Gives the controllers some time to process a command, by writing to an unused port.
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
@@ -0,0 +1,179 @@
+//! Pipes: one-way channels of bytes between processes.
+//!
+//! A pipe has a bounded buffer. Reading from an empty pipe blocks until something is written,
+//! and writing to a full pipe blocks until something is read. Once every write end has been closed,
+//! reads return end of file. Once every read end has been closed, writes fail with `EPIPE`,
+//! and the writer is sent `SIGPIPE`.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+
+use crate::{
+    errno::Errno,
+    file::{self, File, POLLERR, POLLHUP, POLLIN, POLLOUT},
+    scheduler,
+    scheduler::WaitQueue,
+    signal::SIGPIPE,
+    sync::IrqMutex,
+};
+
+/// The most bytes that can be waiting in a pipe.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    state: IrqMutex<PipeState>,
+    /// Woken when data is written, or the last writer closes.
+    readable: WaitQueue,
+    /// Woken when data is read, or the last reader closes.
+    writable: WaitQueue,
+}
+
+pub struct PipeReader(Arc<Pipe>);
+
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Creates a pipe, and returns its read and write ends.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: IrqMutex::new(PipeState {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl File for PipeReader {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let read = pipe.readable.wait_interruptible(|| {
+            let mut state = pipe.state.lock();
+            if state.buffer.is_empty() {
+                // End of file once there is nobody left to write.
+                return (state.writers == 0).then_some(0);
+            }
+            let len = buf.len().min(state.buffer.len());
+            for (byte, value) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+                *byte = value;
+            }
+            Some(len)
+        })?;
+        pipe.writable.wake_all();
+        file::notify_ready();
+        Ok(read)
+    }
+
+    fn poll(&self) -> u16 {
+        let state = self.0.state.lock();
+        match (state.buffer.is_empty(), state.writers == 0) {
+            (false, false) => POLLIN,
+            (true, false) => 0,
+            (_, true) => POLLIN | POLLHUP,
+        }
+    }
+}
+
+impl File for PipeWriter {
+    /// Blocks until all of `buf` has been written, or the last reader closes.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let result = pipe.writable.wait_interruptible(|| {
+                let mut state = pipe.state.lock();
+                if state.readers == 0 {
+                    return Some(Err(Errno::EPIPE));
+                }
+                let len = (PIPE_CAPACITY - state.buffer.len()).min(buf.len() - written);
+                if len == 0 {
+                    return None;
+                }
+                state.buffer.extend(&buf[written..written + len]);
+                Some(Ok(len))
+            });
+            let errno = match result {
+                Ok(Ok(len)) => {
+                    written += len;
+                    pipe.readable.wake_all();
+                    file::notify_ready();
+                    continue;
+                }
+                Ok(Err(errno)) => {
+                    if let Some(process) = scheduler::current_process() {
+                        process.send_signal(SIGPIPE);
+                    }
+                    errno
+                }
+                // Interrupted by a signal.
+                Err(errno) => errno,
+            };
+            // Report the bytes that did make it into the pipe, if there were any.
+            return if written > 0 { Ok(written) } else { Err(errno) };
+        }
+        Ok(written)
+    }
+
+    fn poll(&self) -> u16 {
+        let state = self.0.state.lock();
+        if state.readers == 0 {
+            POLLERR
+        } else if state.buffer.len() < PIPE_CAPACITY {
+            POLLOUT
+        } else {
+            0
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.state.lock().readers -= 1;
+        self.0.writable.wake_all();
+        file::notify_ready();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.state.lock().writers -= 1;
+        self.0.readable.wake_all();
+        file::notify_ready();
+    }
+}
+
+#[test_case]
+fn test_pipe() {
+    let (reader, writer) = pipe();
+    assert_eq!(reader.poll(), 0);
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    assert_eq!(writer.write(b", world"), Ok(7));
+
+    let mut buf = [0; 8];
+    assert_eq!(reader.read(&mut buf), Ok(8));
+    assert_eq!(&buf, b"hello, w");
+    assert_eq!(reader.poll(), POLLIN);
+
+    // The rest is still there after the writer is closed, followed by end of file.
+    drop(writer);
+    assert_eq!(reader.read(&mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"orld");
+    assert_eq!(reader.read(&mut buf), Ok(0));
+    assert_eq!(reader.poll(), POLLIN | POLLHUP);
+
+    // Writing with no readers fails.
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.poll(), POLLERR);
+    assert_eq!(writer.write(b"hello"), Err(Errno::EPIPE));
+}
@@ -17,3 +17,14 @@ pub fn _print(args: core::fmt::Arguments) {
     crate::serial::_print(args);
     TerminalVideoBuffer::with_default(|terminal| terminal.write_fmt(args).unwrap());
 }
+
+/// Prints raw bytes, which need not be valid UTF-8, to both the serial port and the default terminal.
+pub fn print_bytes(bytes: &[u8]) {
+    {
+        let mut serial = crate::serial::COM1_SERIAL.lock();
+        for &byte in bytes {
+            serial.send(byte);
+        }
+    }
+    TerminalVideoBuffer::with_default(|terminal| terminal.put_string(bytes));
+}
@@ -0,0 +1,621 @@
+//! User processes.
+//!
+//! Each process has its own address space, and is run by a single [Task].
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt::Display,
+    ops::Range,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::VirtAddr;
+
+use crate::{
+    capability::CapabilityTable,
+    elf::{
+        Elf, ElfError, SEGMENT_EXECUTABLE, SEGMENT_LOAD, SEGMENT_TLS, SEGMENT_WRITABLE,
+        TYPE_EXECUTABLE,
+    },
+    errno::Errno,
+    file::FileTable,
+    fs::{self, Dentry},
+    memory::{
+        self,
+        address_space::AddressSpace,
+        page_align_down, page_align_up, pages_in,
+        vma::{Access, Backing, Protection, Vma, VmaList},
+        OutOfMemory, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START,
+    },
+    scheduler::{self, WaitQueue},
+    serial_println,
+    signal::{self, Delivery, SignalState, SIGCONT, SIGKILL},
+    task::Task,
+    tls,
+    trap::TrapFrame,
+};
+
+/// The initial stack pointer of a process, just below a guard page at the very top of user space.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+pub const USER_STACK_SIZE: u64 = 128 * 1024;
+
+/// The program break may not be moved more than this far past the end of the program's data.
+const MAX_HEAP_SIZE: u64 = 1 << 32;
+
+/// Memory mapped without a fixed address is placed in this range,
+/// well above the heap and below the stack and its guard page.
+const MMAP_AREA: Range<u64> = 0x0000_4000_0000_0000..USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u32);
+
+impl Display for Pid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    inner: Mutex<ProcessInner>,
+    /// Woken when the process has finished exiting.
+    exited: WaitQueue,
+    /// Woken when the process is sent a signal that could end a stop.
+    continued: WaitQueue,
+    /// The task that runs the process.
+    task: Once<Weak<Task>>,
+}
+
+/// Every process that has not yet exited.
+static PROCESSES: Mutex<BTreeMap<Pid, Weak<Process>>> = Mutex::new(BTreeMap::new());
+
+/// Finds the process with the given ID, if it has not exited.
+pub fn find(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).and_then(Weak::upgrade)
+}
+
+/// Every process that has not exited, in order of process ID.
+pub fn all() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().filter_map(Weak::upgrade).collect()
+}
+
+pub struct ProcessInner {
+    /// This is `None` once the process has exited.
+    pub address_space: Option<AddressSpace>,
+    /// The parts of user space that the process may use, whose pages are mapped on demand.
+    pub vmas: VmaList,
+    /// The lowest address that the program break can be set to.
+    pub heap_start: u64,
+    /// The end of the process's heap, as set by the `brk` system call.
+    pub brk: u64,
+    /// Set when the process asks to exit.
+    /// The process is torn down the next time it would return to user mode.
+    pub exit_status: Option<i32>,
+    /// The processes started by this one that it has not yet waited for.
+    pub children: Vec<Arc<Process>>,
+    pub signals: SignalState,
+    pub files: FileTable,
+    pub capabilities: CapabilityTable,
+    /// The directory that relative paths start from.
+    /// This is `None` if the process started before anything was mounted at `/`.
+    pub cwd: Option<Arc<Dentry>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// The file could not be opened or read.
+    Read(Errno),
+    Elf(ElfError),
+    /// The file was a valid ELF file, but not one that we can run.
+    NotExecutable,
+    /// The arguments don't fit on the stack.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl From<ElfError> for ExecError {
+    fn from(error: ElfError) -> Self {
+        ExecError::Elf(error)
+    }
+}
+
+impl From<OutOfMemory> for ExecError {
+    fn from(_: OutOfMemory) -> Self {
+        ExecError::OutOfMemory
+    }
+}
+
+impl From<ExecError> for Errno {
+    fn from(error: ExecError) -> Self {
+        match error {
+            ExecError::Read(errno) => errno,
+            ExecError::Elf(_) | ExecError::NotExecutable => Errno::ENOEXEC,
+            ExecError::ArgumentsTooLong => Errno::E2BIG,
+            ExecError::OutOfMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+impl Process {
+    /// Creates a process that runs the given executable, and schedules it to run.
+    /// The arguments are passed to the program on its stack,
+    /// and it starts with the given open files and capabilities.
+    pub fn spawn(
+        name: &str,
+        executable: &[u8],
+        args: &[&str],
+        files: FileTable,
+        capabilities: CapabilityTable,
+    ) -> Result<Arc<Self>, ExecError> {
+        let elf = Elf::parse(executable)?;
+        let mut address_space = AddressSpace::new()?;
+        let mut vmas = VmaList::default();
+        let image_end = load_segments(&mut address_space, &mut vmas, &elf)?;
+        let stack_pointer = set_up_stack(&mut address_space, &mut vmas, args)?;
+        let thread_pointer = set_up_tls(&mut address_space, &mut vmas, &elf)?;
+
+        static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+        let process = Arc::new(Self {
+            pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            name: name.into(),
+            inner: Mutex::new(ProcessInner {
+                address_space: Some(address_space),
+                vmas,
+                heap_start: image_end,
+                brk: image_end,
+                exit_status: None,
+                children: Vec::new(),
+                signals: SignalState::default(),
+                files,
+                capabilities,
+                cwd: fs::root().ok(),
+            }),
+            exited: WaitQueue::new(),
+            continued: WaitQueue::new(),
+            task: Once::new(),
+        });
+        PROCESSES
+            .lock()
+            .insert(process.pid, Arc::downgrade(&process));
+
+        let frame = TrapFrame::new_user(elf.header().entry, stack_pointer);
+        let task = Task::new_user(name, process.clone(), frame, thread_pointer);
+        process.task.call_once(|| Arc::downgrade(&task));
+        scheduler::spawn(task);
+        Ok(process)
+    }
+
+    /// Spawns a process that runs the executable at the given absolute path.
+    /// The process is named after the last component of the path.
+    pub fn spawn_path(
+        path: &str,
+        args: &[&str],
+        files: FileTable,
+        capabilities: CapabilityTable,
+    ) -> Result<Arc<Self>, ExecError> {
+        let executable = fs::root()
+            .and_then(|root| fs::read(&root, path))
+            .map_err(ExecError::Read)?;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        Self::spawn(name, &executable, args, files, capabilities)
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, ProcessInner> {
+        self.inner.lock()
+    }
+
+    /// Switches to this process's address space, if it still has one.
+    pub fn activate(&self) {
+        if let Some(address_space) = &self.lock().address_space {
+            address_space.activate();
+        }
+    }
+
+    /// Asks the process to exit with the given status.
+    /// If the process has already been asked to exit, this does nothing.
+    pub fn exit(&self, status: i32) {
+        self.lock().exit_status.get_or_insert(status);
+    }
+
+    /// Sends a signal to the process. It takes effect the next time the process returns to user mode,
+    /// and interrupts the system call that the process is blocked in, if any.
+    pub fn send_signal(&self, signal: u32) {
+        let deliverable = {
+            let mut inner = self.lock();
+            inner.signals.raise(signal);
+            inner.signals.has_deliverable()
+        };
+        if signal == SIGCONT || signal == SIGKILL {
+            self.continued.wake_all();
+        }
+        if deliverable {
+            if let Some(task) = self.task.get().and_then(Weak::upgrade) {
+                scheduler::wake(&task);
+            }
+        }
+    }
+
+    /// Whether the process has a signal to handle, which should interrupt any system call that
+    /// is waiting. See [WaitQueue::wait_interruptible].
+    pub fn has_signal_to_handle(&self) -> bool {
+        self.lock().signals.has_deliverable()
+    }
+
+    /// Sends a signal caused by the process itself, such as a fault, which it cannot block or ignore.
+    pub fn force_signal(&self, signal: u32) {
+        self.lock().signals.force(signal);
+    }
+
+    /// Blocks until the process is continued or killed.
+    fn wait_while_stopped(&self) {
+        self.lock().signals.stopped = true;
+        self.continued.wait_until(|| {
+            let inner = self.lock();
+            let signals = &inner.signals;
+            (!signals.stopped || signals.pending.contains(SIGKILL)).then_some(())
+        });
+    }
+
+    /// Blocks until the process has exited, and returns its exit status.
+    pub fn wait(&self) -> i32 {
+        self.exited.wait_until(|| self.exit_status_once_exited())
+    }
+
+    /// Blocks until the process has exited, and returns its exit status,
+    /// unless the calling process is interrupted by a signal first.
+    pub fn wait_interruptible(&self) -> Result<i32, Errno> {
+        self.exited
+            .wait_interruptible(|| self.exit_status_once_exited())
+    }
+
+    fn exit_status_once_exited(&self) -> Option<i32> {
+        let inner = self.lock();
+        inner
+            .address_space
+            .is_none()
+            .then(|| inner.exit_status.unwrap())
+    }
+
+    /// Moves the program break to `addr`, and returns the new program break.
+    /// If the break cannot be moved there, it stays where it is.
+    /// New heap pages are mapped when they are first touched.
+    pub fn set_brk(&self, addr: u64) -> u64 {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        if addr < inner.heap_start || addr > inner.heap_start + MAX_HEAP_SIZE {
+            return inner.brk;
+        }
+        let address_space = inner.address_space.as_mut().expect("process has exited");
+
+        let old_end = page_align_up(inner.brk);
+        let new_end = page_align_up(addr);
+        if new_end > old_end {
+            if !inner.vmas.is_free(old_end, new_end) {
+                return inner.brk;
+            }
+            inner.vmas.insert(Vma::new(
+                old_end,
+                new_end,
+                Protection::READ | Protection::WRITE,
+                Backing::Anonymous,
+            ));
+        } else if new_end < old_end {
+            inner.vmas.remove(address_space, new_end, old_end);
+        }
+        inner.brk = addr;
+        addr
+    }
+
+    /// Maps `len` bytes of memory, and returns the address of the new mapping.
+    ///
+    /// If `fixed` is set, the mapping is placed at `addr`, replacing anything mapped there.
+    /// Otherwise, `addr` is only a hint, which is used if that part of user space is free.
+    pub fn map(
+        &self,
+        addr: u64,
+        len: u64,
+        fixed: bool,
+        protection: Protection,
+        backing: Backing,
+    ) -> Result<u64, Errno> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let address_space = inner.address_space.as_mut().ok_or(Errno::EFAULT)?;
+        let len = page_align_up(len);
+        let in_user_space = |start: u64| {
+            start.is_multiple_of(PAGE_SIZE)
+                && start >= USER_SPACE_START
+                && start
+                    .checked_add(len)
+                    .is_some_and(|end| end <= USER_SPACE_END)
+        };
+
+        let start = if fixed {
+            if !in_user_space(addr) {
+                return Err(Errno::EINVAL);
+            }
+            inner.vmas.remove(address_space, addr, addr + len);
+            addr
+        } else if in_user_space(addr) && inner.vmas.is_free(addr, addr + len) {
+            addr
+        } else {
+            inner.vmas.find_free(len, MMAP_AREA).ok_or(Errno::ENOMEM)?
+        };
+        inner
+            .vmas
+            .insert(Vma::new(start, start + len, protection, backing));
+        Ok(start)
+    }
+
+    /// Unmaps every page in `start..end`, which must be page-aligned.
+    pub fn unmap(&self, start: u64, end: u64) -> Result<(), Errno> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let address_space = inner.address_space.as_mut().ok_or(Errno::EFAULT)?;
+        inner.vmas.remove(address_space, start, end);
+        Ok(())
+    }
+
+    /// Changes the protection of every page in `start..end`, which must be page-aligned and mapped.
+    pub fn protect(&self, start: u64, end: u64, protection: Protection) -> Result<(), Errno> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let address_space = inner.address_space.as_mut().ok_or(Errno::EFAULT)?;
+        inner.vmas.protect(address_space, start, end, protection)
+    }
+
+    /// Makes sure that the page containing `addr` is mapped in a way that allows the given access,
+    /// mapping it if its memory area allows that. Fails with `EFAULT` if it does not.
+    pub fn fault_in(&self, addr: u64, access: Access) -> Result<(), Errno> {
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&addr) {
+            return Err(Errno::EFAULT);
+        }
+        let pending = {
+            let inner = self.lock();
+            let address_space = inner.address_space.as_ref().ok_or(Errno::EFAULT)?;
+            let page = VirtAddr::new(page_align_down(addr));
+            if let Some((_, flags)) = address_space.translate(page) {
+                return match access.is_allowed_by(flags) {
+                    true => Ok(()),
+                    false => Err(Errno::EFAULT),
+                };
+            }
+            inner.vmas.pending_page(addr, access).ok_or(Errno::EFAULT)?
+        };
+        // Filling the page may read a file, which can block, so this is done without the lock.
+        let page = pending.load()?;
+        let mut inner = self.lock();
+        let address_space = inner.address_space.as_mut().ok_or(Errno::EFAULT)?;
+        page.map(address_space)?;
+        Ok(())
+    }
+
+    /// Faults in every page that contains part of `addr..addr + len`.
+    pub fn fault_in_range(&self, addr: u64, len: u64, access: Access) -> Result<(), Errno> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+        if addr < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(Errno::EFAULT);
+        }
+        for page in pages_in(addr, end) {
+            self.fault_in(page.start_address().as_u64(), access)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps the loadable segments of the executable into the address space,
+/// and returns the page-aligned end of the highest one.
+fn load_segments(
+    address_space: &mut AddressSpace,
+    vmas: &mut VmaList,
+    elf: &Elf,
+) -> Result<u64, ExecError> {
+    if elf.header().kind != TYPE_EXECUTABLE {
+        return Err(ExecError::NotExecutable);
+    }
+
+    let mut image_end = USER_SPACE_START;
+    for segment in elf
+        .program_headers()
+        .filter(|segment| segment.kind == SEGMENT_LOAD && segment.memory_size > 0)
+    {
+        let start = segment.virtual_address;
+        let end = start
+            .checked_add(segment.memory_size)
+            .ok_or(ExecError::NotExecutable)?;
+        if start < USER_SPACE_START || end > USER_STACK_TOP - USER_STACK_SIZE {
+            return Err(ExecError::NotExecutable);
+        }
+
+        let mut protection = Protection::READ;
+        if segment.flags & SEGMENT_WRITABLE != 0 {
+            protection |= Protection::WRITE;
+        }
+        if segment.flags & SEGMENT_EXECUTABLE != 0 {
+            protection |= Protection::EXECUTE;
+        }
+        address_space.map_zeroed(pages_in(start, end), protection.page_flags())?;
+        address_space.write_bytes(start, elf.segment_data(&segment));
+
+        // Segments are sorted by address, but neighbouring segments may share a page.
+        // That page belongs to the area of the first segment that uses it.
+        let area_start = page_align_down(start).max(page_align_up(image_end));
+        let area_end = page_align_up(end);
+        if area_start < area_end {
+            vmas.insert(Vma::new(
+                area_start,
+                area_end,
+                protection,
+                Backing::Anonymous,
+            ));
+        }
+        image_end = image_end.max(end);
+    }
+    Ok(page_align_up(image_end))
+}
+
+/// Maps a TLS block for the program's `PT_TLS` segment, if it has one,
+/// and returns its thread pointer. Returns zero if there is no segment.
+fn set_up_tls(
+    address_space: &mut AddressSpace,
+    vmas: &mut VmaList,
+    elf: &Elf,
+) -> Result<u64, ExecError> {
+    let Some(segment) = elf
+        .program_headers()
+        .find(|segment| segment.kind == SEGMENT_TLS)
+    else {
+        return Ok(0);
+    };
+    let template = tls::Template::new(
+        elf.segment_data(&segment),
+        segment.memory_size,
+        segment.align,
+    )
+    .filter(|template| template.align() <= PAGE_SIZE)
+    .ok_or(ExecError::NotExecutable)?;
+
+    // The block starts on a page boundary, so the thread pointer is suitably aligned.
+    let size = page_align_up(template.block_size());
+    let start = vmas
+        .find_free(size, MMAP_AREA)
+        .ok_or(ExecError::OutOfMemory)?;
+    let protection = Protection::READ | Protection::WRITE;
+    vmas.insert(Vma::new(
+        start,
+        start + size,
+        protection,
+        Backing::Anonymous,
+    ));
+    address_space.map_zeroed(pages_in(start, start + size), protection.page_flags())?;
+
+    let thread_pointer = start + template.thread_pointer_offset();
+    address_space.write_bytes(start, template.data());
+    address_space.write_bytes(thread_pointer, &thread_pointer.to_ne_bytes());
+    Ok(thread_pointer)
+}
+
+/// Sets up the user stack, and pushes the arguments onto it.
+/// Returns the initial stack pointer.
+/// Only the pages holding the arguments are mapped straight away; the rest are mapped on demand.
+///
+/// The stack pointer points at `argc`, followed by `argc` pointers to null-terminated strings,
+/// a null pointer, and then an empty list of environment variables ending with a null pointer.
+fn set_up_stack(
+    address_space: &mut AddressSpace,
+    vmas: &mut VmaList,
+    args: &[&str],
+) -> Result<u64, ExecError> {
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let pointers_size = (args.len() + 3) * size_of::<u64>();
+    if (strings_size + pointers_size) as u64 > USER_STACK_SIZE / 2 {
+        return Err(ExecError::ArgumentsTooLong);
+    }
+
+    let protection = Protection::READ | Protection::WRITE;
+    vmas.insert(Vma::new(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_TOP,
+        protection,
+        Backing::Anonymous,
+    ));
+    // Leave room for aligning the stack pointer.
+    let used = (strings_size + pointers_size + 16) as u64;
+    address_space.map_zeroed(
+        pages_in(USER_STACK_TOP - used, USER_STACK_TOP),
+        protection.page_flags(),
+    )?;
+
+    // The stack is zeroed, so the strings are already null-terminated.
+    let mut stack_pointer = USER_STACK_TOP;
+    let mut words = Vec::with_capacity(args.len() + 3);
+    words.push(args.len() as u64);
+    for arg in args {
+        stack_pointer -= arg.len() as u64 + 1;
+        address_space.write_bytes(stack_pointer, arg.as_bytes());
+        words.push(stack_pointer);
+    }
+    words.push(0);
+    words.push(0);
+
+    stack_pointer = (stack_pointer - (words.len() * size_of::<u64>()) as u64) & !0xf;
+    address_space.write_bytes(stack_pointer, bytemuck::cast_slice(&words));
+    Ok(stack_pointer)
+}
+
+/// Called with interrupts disabled just before the current task returns to user mode.
+/// This delivers any pending signals, and exits the process if it has been asked to.
+pub fn before_return_to_user(frame: &mut TrapFrame) {
+    let exiting = scheduler::current_process().is_some_and(|process| {
+        handle_signals(&process, frame);
+        process.lock().exit_status.is_some()
+    });
+    if exiting {
+        exit_current();
+    }
+}
+
+fn handle_signals(process: &Process, frame: &mut TrapFrame) {
+    while process.lock().exit_status.is_none() {
+        match signal::deliver(process, frame) {
+            None => return,
+            Some(Delivery::Resume) => {}
+            Some(Delivery::Exit(status)) => process.exit(status),
+            Some(Delivery::Stop) => process.wait_while_stopped(),
+        }
+    }
+}
+
+/// Tears down the current process, and then stops the current task.
+fn exit_current() -> ! {
+    {
+        let process = scheduler::current_process().expect("not running a process");
+        PROCESSES.lock().remove(&process.pid);
+        memory::activate_kernel_page_table();
+        let (address_space, vmas, children, files, capabilities) = {
+            let mut inner = process.lock();
+            (
+                inner.address_space.take(),
+                core::mem::take(&mut inner.vmas),
+                core::mem::take(&mut inner.children),
+                core::mem::take(&mut inner.files),
+                core::mem::take(&mut inner.capabilities),
+            )
+        };
+        drop(address_space);
+        // This must come after the address space, since it may own memory that was mapped there.
+        drop(vmas);
+        // Nobody can wait for these any more, but they keep running.
+        drop(children);
+        // This closes the ends of any pipes that the process had open.
+        drop(files);
+        // This fails any calls that the process was due to reply to.
+        drop(capabilities);
+        serial_println!(
+            "Process {} ({}) exited with status {}.",
+            process.pid,
+            process.name,
+            process.lock().exit_status.unwrap()
+        );
+        process.exited.wake_all();
+    }
+    scheduler::exit_current();
+}
@@ -0,0 +1,35 @@
+//! The ramdisk that the bootloader loads into memory alongside the kernel.
+//!
+//! The ramdisk is a `tar` archive, built by the runner from the `rootfs` directory and the user programs.
+//! It is mounted at `/` as a read-only filesystem.
+
+use spin::Once;
+
+use crate::{
+    fs::{self, tarfs::TarFs},
+    serial_println,
+};
+
+static RAMDISK: Once<&'static [u8]> = Once::new();
+
+/// Finds the ramdisk, and mounts it at `/`. The heap must have been initialised.
+pub fn init(boot_info: &'static bootloader_api::BootInfo) {
+    let Some(&addr) = boot_info.ramdisk_addr.as_ref() else {
+        serial_println!("No ramdisk, so nothing is mounted at /.");
+        return;
+    };
+    let data =
+        unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+    RAMDISK.call_once(|| data);
+    match TarFs::new(data) {
+        Ok(tarfs) => fs::mount("/", tarfs).expect("something is already mounted at /"),
+        Err(error) => {
+            serial_println!("The ramdisk is not a valid archive: {:?}", error);
+        }
+    }
+}
+
+/// Returns the contents of the ramdisk, if the bootloader loaded one.
+pub fn data() -> Option<&'static [u8]> {
+    RAMDISK.get().copied()
+}
@@ -0,0 +1,409 @@
+//! Realtek RTL8139 Fast Ethernet controllers, which QEMU emulates with `-device rtl8139`.
+//!
+//! Unlike most cards, the RTL8139 has no receive descriptors: it copies each frame that arrives
+//! into one ring buffer, after a header giving its status and length, and we follow behind it.
+//! It sends frames from four buffers, which it uses in turn.
+//! Its registers are reached through I/O ports, and its MAC address through a serial EEPROM.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    errno::Errno,
+    memory::dma::DmaBuffer,
+    net::{self, MacAddress, NetworkInterface, ReceiveCallback, Receiver},
+    pci::{self, PciDevice},
+    sync::{self, IrqMutex},
+};
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "rtl8139",
+    matches: &[pci::Match::Device(0x10ec, 0x8139)],
+    probe,
+};
+
+/// Offsets of the card's registers.
+const ID: u16 = 0x00;
+const TRANSMIT_STATUS: u16 = 0x10;
+const TRANSMIT_ADDRESS: u16 = 0x20;
+const RECEIVE_BUFFER_START: u16 = 0x30;
+const COMMAND: u16 = 0x37;
+const CURRENT_READ_ADDRESS: u16 = 0x38;
+const INTERRUPT_MASK: u16 = 0x3c;
+const INTERRUPT_STATUS: u16 = 0x3e;
+const TRANSMIT_CONFIGURATION: u16 = 0x40;
+const RECEIVE_CONFIGURATION: u16 = 0x44;
+const EEPROM_CONTROL: u16 = 0x50;
+const CONFIG_1: u16 = 0x52;
+const BASIC_MODE_STATUS: u16 = 0x64;
+
+const COMMAND_RECEIVE_BUFFER_EMPTY: u8 = 1 << 0;
+const COMMAND_TRANSMIT_ENABLE: u8 = 1 << 2;
+const COMMAND_RECEIVE_ENABLE: u8 = 1 << 3;
+const COMMAND_RESET: u8 = 1 << 4;
+
+/// Causes of interrupts: a frame arrived or was sent, or something went wrong with either,
+/// or the link changed.
+const INTERRUPTS: u16 = 0x7f;
+
+/// Accept frames to our address, multicast and broadcast frames, into an 8 KiB ring,
+/// letting frames that go past the end of the ring carry on after it.
+const RECEIVE_PHYSICAL_MATCH: u32 = 1 << 1;
+const RECEIVE_MULTICAST: u32 = 1 << 2;
+const RECEIVE_BROADCAST: u32 = 1 << 3;
+const RECEIVE_WRAP: u32 = 1 << 7;
+const RECEIVE_UNLIMITED_DMA: u32 = 7 << 8;
+
+const TRANSMIT_UNLIMITED_DMA: u32 = 7 << 8;
+
+/// Bits of a transmit status register: the card has finished with the buffer,
+/// and whether the frame was sent or given up on.
+const TRANSMIT_OWN: u32 = 1 << 13;
+const TRANSMIT_OK: u32 = 1 << 15;
+const TRANSMIT_ABORTED: u32 = 1 << 30;
+
+/// Bits of the status at the start of each received frame.
+const RECEIVE_OK: u16 = 1 << 0;
+
+const BASIC_MODE_LINK_UP: u16 = 1 << 2;
+
+/// The ring buffer's size, not counting the room that frames can overflow into.
+const RING_SIZE: usize = 8192;
+const RING_PADDING: usize = 16 + net::MAX_FRAME_SIZE + 4;
+/// The card never shows us the last 16 bytes before its write pointer.
+const READ_ADDRESS_OFFSET: usize = 16;
+/// Each received frame is preceded by its status and length, and followed by its frame check sequence.
+const RECEIVE_HEADER_SIZE: usize = 4;
+const FCS_SIZE: usize = 4;
+
+const TRANSMIT_BUFFERS: usize = 4;
+const TRANSMIT_BUFFER_SIZE: usize = 2048;
+
+/// Bits of the EEPROM control register, which connects us to the EEPROM's pins.
+const EEPROM_PROGRAM: u8 = 0x80;
+const EEPROM_CHIP_SELECT: u8 = 0x08;
+const EEPROM_CLOCK: u8 = 0x04;
+const EEPROM_DATA_IN: u8 = 0x02;
+const EEPROM_DATA_OUT: u8 = 0x01;
+/// The start bit and the read opcode.
+const EEPROM_READ: u32 = 0b110;
+/// The first word of a 93C56 EEPROM, read as though it had the larger addresses.
+const EEPROM_93C56_SIGNATURE: u16 = 0x8129;
+/// The EEPROM words that hold the MAC address.
+const EEPROM_MAC: u32 = 7;
+
+/// How many times to check the card before giving up on it.
+const TIMEOUT_POLLS: usize = 1_000_000;
+
+/// The cards found so far.
+static CARDS: Mutex<Vec<Arc<Rtl8139>>> = Mutex::new(Vec::new());
+
+/// The ports of the cards, which the interrupt handler must quieten.
+static INTERRUPTING: IrqMutex<Vec<u16>> = IrqMutex::new(Vec::new());
+
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    pci.enable_bus_master();
+    let port = pci.io_bar(0)?;
+    let registers = Registers(port);
+
+    // Wake the card up, then reset it.
+    registers.write_u8(CONFIG_1, 0);
+    registers.write_u8(COMMAND, COMMAND_RESET);
+    let mut polls = 0;
+    while registers.read_u8(COMMAND) & COMMAND_RESET != 0 {
+        polls += 1;
+        if polls == TIMEOUT_POLLS {
+            return Err(Errno::EIO);
+        }
+        core::hint::spin_loop();
+    }
+
+    // The card loads its ID registers from the EEPROM when it resets,
+    // so use those if the EEPROM doesn't make sense.
+    let from_eeprom = registers.read_mac_from_eeprom();
+    let mac = if from_eeprom != MacAddress::ZERO && !from_eeprom.is_multicast() {
+        from_eeprom
+    } else {
+        MacAddress(core::array::from_fn(|i| registers.read_u8(ID + i as u16)))
+    };
+
+    // The card only takes 32-bit addresses.
+    let receive = DmaBuffer::new(RING_SIZE + RING_PADDING).map_err(|_| Errno::ENOMEM)?;
+    let transmit =
+        DmaBuffer::new(TRANSMIT_BUFFERS * TRANSMIT_BUFFER_SIZE).map_err(|_| Errno::ENOMEM)?;
+    if receive.phys(0).as_u64() >> 32 != 0 || transmit.phys(0).as_u64() >> 32 != 0 {
+        return Err(Errno::ENOMEM);
+    }
+    registers.write_u32(RECEIVE_BUFFER_START, receive.phys(0).as_u64() as u32);
+    for i in 0..TRANSMIT_BUFFERS {
+        registers.write_u32(
+            TRANSMIT_ADDRESS + 4 * i as u16,
+            transmit.phys(i * TRANSMIT_BUFFER_SIZE).as_u64() as u32,
+        );
+    }
+    registers.write_u8(COMMAND, COMMAND_RECEIVE_ENABLE | COMMAND_TRANSMIT_ENABLE);
+    registers.write_u32(
+        RECEIVE_CONFIGURATION,
+        RECEIVE_PHYSICAL_MATCH
+            | RECEIVE_MULTICAST
+            | RECEIVE_BROADCAST
+            | RECEIVE_WRAP
+            | RECEIVE_UNLIMITED_DMA,
+    );
+    registers.write_u32(TRANSMIT_CONFIGURATION, TRANSMIT_UNLIMITED_DMA);
+    registers.write_u16(
+        CURRENT_READ_ADDRESS,
+        (RING_SIZE - READ_ADDRESS_OFFSET) as u16,
+    );
+
+    INTERRUPTING.lock().push(port);
+    if pci.add_interrupt_handler(interrupt).is_ok() {
+        registers.write_u16(INTERRUPT_STATUS, u16::MAX);
+        registers.write_u16(INTERRUPT_MASK, INTERRUPTS);
+    }
+
+    let card = Arc::new(Rtl8139 {
+        registers,
+        mac,
+        receive: Mutex::new(ReceiveRing {
+            memory: receive,
+            offset: 0,
+        }),
+        transmit: Mutex::new(TransmitBuffers {
+            memory: transmit,
+            next: 0,
+            used: [false; TRANSMIT_BUFFERS],
+        }),
+        receiver: Receiver::new(),
+    });
+    CARDS.lock().push(card.clone());
+    net::add_interface(card);
+    Ok(())
+}
+
+/// The handler for the card's legacy interrupt line, which other devices may share.
+fn interrupt() {
+    for &port in INTERRUPTING.lock().iter() {
+        let registers = Registers(port);
+        let status = registers.read_u16(INTERRUPT_STATUS);
+        if status != 0 {
+            // Writing back the bits that are set clears them.
+            registers.write_u16(INTERRUPT_STATUS, status);
+            net::wake();
+        }
+    }
+}
+
+/// The card's I/O ports.
+struct Registers(u16);
+
+impl Registers {
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.0 + register).read() }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.0 + register).read() }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.0 + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.0 + register).write(value) }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.0 + register).write(value) }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.0 + register).write(value) }
+    }
+
+    fn read_mac_from_eeprom(&self) -> MacAddress {
+        // 93C46 EEPROMs have 6-bit word addresses, and 93C56 ones 8-bit.
+        let address_bits = if self.read_eeprom(0, 8) == EEPROM_93C56_SIGNATURE {
+            8
+        } else {
+            6
+        };
+        let mut mac = [0; 6];
+        for (word, bytes) in mac.chunks_mut(2).enumerate() {
+            let value = self.read_eeprom(EEPROM_MAC + word as u32, address_bits);
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        MacAddress(mac)
+    }
+
+    /// Reads a 16-bit word from the EEPROM by driving its pins one bit at a time.
+    /// Reading the control register in between gives the EEPROM time to respond.
+    fn read_eeprom(&self, word: u32, address_bits: u32) -> u16 {
+        let selected = EEPROM_PROGRAM | EEPROM_CHIP_SELECT;
+        self.write_u8(EEPROM_CONTROL, EEPROM_PROGRAM);
+        self.write_u8(EEPROM_CONTROL, selected);
+        self.read_u8(EEPROM_CONTROL);
+
+        // Send the command, most significant bit first.
+        let command = EEPROM_READ << address_bits | word;
+        for bit in (0..3 + address_bits).rev() {
+            let data = if command & 1 << bit != 0 {
+                EEPROM_DATA_IN
+            } else {
+                0
+            };
+            self.write_u8(EEPROM_CONTROL, selected | data);
+            self.read_u8(EEPROM_CONTROL);
+            self.write_u8(EEPROM_CONTROL, selected | data | EEPROM_CLOCK);
+            self.read_u8(EEPROM_CONTROL);
+        }
+        self.write_u8(EEPROM_CONTROL, selected);
+        self.read_u8(EEPROM_CONTROL);
+
+        let mut value = 0;
+        for _ in 0..16 {
+            self.write_u8(EEPROM_CONTROL, selected | EEPROM_CLOCK);
+            self.read_u8(EEPROM_CONTROL);
+            let bit = self.read_u8(EEPROM_CONTROL) & EEPROM_DATA_OUT;
+            value = value << 1 | bit as u16;
+            self.write_u8(EEPROM_CONTROL, selected);
+            self.read_u8(EEPROM_CONTROL);
+        }
+        // Leave programming mode.
+        self.write_u8(EEPROM_CONTROL, 0);
+        value
+    }
+}
+
+pub struct Rtl8139 {
+    registers: Registers,
+    mac: MacAddress,
+    receive: Mutex<ReceiveRing>,
+    transmit: Mutex<TransmitBuffers>,
+    receiver: Receiver,
+}
+
+struct ReceiveRing {
+    memory: DmaBuffer,
+    /// Where the next frame's header is.
+    offset: usize,
+}
+
+struct TransmitBuffers {
+    memory: DmaBuffer,
+    /// The buffer to use next.
+    next: usize,
+    /// Which buffers have been given to the card since it was reset.
+    used: [bool; TRANSMIT_BUFFERS],
+}
+
+impl Rtl8139 {
+    /// Takes the next frame that has arrived, and lets the card reuse its space in the ring.
+    /// Frames with errors are dropped and returned empty.
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut receive = self.receive.lock();
+        if self.registers.read_u8(COMMAND) & COMMAND_RECEIVE_BUFFER_EMPTY != 0 {
+            return None;
+        }
+
+        let offset = receive.offset;
+        let status = receive.memory.read::<u16>(offset);
+        let len = receive.memory.read::<u16>(offset + 2) as usize;
+        let frame = if status & RECEIVE_OK != 0 && (FCS_SIZE..=RING_PADDING).contains(&len) {
+            let mut frame = vec![0; len - FCS_SIZE];
+            receive
+                .memory
+                .read_bytes(offset + RECEIVE_HEADER_SIZE, &mut frame);
+            frame
+        } else {
+            Vec::new()
+        };
+
+        // Frames start on four-byte boundaries.
+        let next = (offset + RECEIVE_HEADER_SIZE + len + 3) & !3;
+        receive.offset = next % RING_SIZE;
+        self.registers.write_u16(
+            CURRENT_READ_ADDRESS,
+            ((receive.offset + RING_SIZE - READ_ADDRESS_OFFSET) % RING_SIZE) as u16,
+        );
+        Some(frame)
+    }
+}
+
+impl NetworkInterface for Rtl8139 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read_u16(BASIC_MODE_STATUS) & BASIC_MODE_LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Errno> {
+        if frame.len() > net::MAX_FRAME_SIZE {
+            return Err(Errno::EMSGSIZE);
+        }
+        if !self.link_up() {
+            return Err(Errno::ENETDOWN);
+        }
+
+        let mut transmit = self.transmit.lock();
+        let index = transmit.next;
+        let status_register = TRANSMIT_STATUS + 4 * index as u16;
+        if transmit.used[index] {
+            // The card is still sending the frame that was in this buffer last time round.
+            sync::wait_for_device(|| {
+                let status = self.registers.read_u32(status_register);
+                (status & (TRANSMIT_OK | TRANSMIT_ABORTED) != 0 && status & TRANSMIT_OWN != 0)
+                    .then_some(())
+            });
+        }
+
+        let offset = index * TRANSMIT_BUFFER_SIZE;
+        transmit.memory.write_bytes(offset, frame);
+        // The card doesn't pad short frames by itself.
+        let len = frame.len().max(net::MIN_FRAME_SIZE);
+        if frame.len() < len {
+            transmit.memory.write_bytes(
+                offset + frame.len(),
+                &[0; net::MIN_FRAME_SIZE][frame.len()..],
+            );
+        }
+        // Writing the length clears the own bit, which starts sending.
+        self.registers.write_u32(status_register, len as u32);
+        transmit.used[index] = true;
+        transmit.next = (index + 1) % TRANSMIT_BUFFERS;
+        Ok(())
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        self.receiver.set(callback);
+    }
+
+    fn poll(&self) -> usize {
+        let mut count = 0;
+        while let Some(frame) = self.receive() {
+            if !frame.is_empty() {
+                self.receiver.deliver(&frame);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[test_case]
+fn test_rtl8139() {
+    // The test runner gives QEMU's user-mode network an RTL8139.
+    let Some(card) = CARDS.lock().first().cloned() else {
+        return;
+    };
+    net::test_card(&*card);
+}
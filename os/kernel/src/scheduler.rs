@@ -0,0 +1,221 @@
+//! A round-robin scheduler for a single CPU.
+//!
+//! The scheduler runs in a loop on the boot stack. Tasks switch back to this loop whenever they
+//! yield, block or exit, and the loop then picks the next task that is ready to run.
+//! Since the kernel is not preemptible, tasks only switch away from kernel mode at well-defined
+//! points, and user code is preempted by the timer interrupt.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    errno::Errno,
+    gdt,
+    process::Process,
+    sync::IrqMutex,
+    task::{switch_context, Task, TaskState},
+    tls,
+};
+
+struct Scheduler {
+    /// This always has room for every task that hasn't exited,
+    /// so that interrupt handlers can wake tasks without allocating.
+    ready: VecDeque<Arc<Task>>,
+    current: Option<Arc<Task>>,
+    /// The number of tasks that have been spawned and haven't exited.
+    tasks: usize,
+}
+
+static SCHEDULER: IrqMutex<Scheduler> = IrqMutex::new(Scheduler {
+    ready: VecDeque::new(),
+    current: None,
+    tasks: 0,
+});
+
+/// The stack pointer of the scheduler loop, saved while a task is running.
+static SCHEDULER_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// Adds a new task to the back of the queue.
+pub fn spawn(task: Arc<Task>) {
+    task.set_state(TaskState::Ready);
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.tasks += 1;
+    let spare = scheduler.tasks - scheduler.ready.len();
+    scheduler.ready.reserve(spare);
+    scheduler.ready.push_back(task);
+}
+
+/// Returns the task that is currently running, or `None` if called from the scheduler loop itself.
+pub fn current() -> Option<Arc<Task>> {
+    SCHEDULER.lock().current.clone()
+}
+
+/// Returns the process that the current task is running, if any.
+pub fn current_process() -> Option<Arc<Process>> {
+    current().and_then(|task| task.process().cloned())
+}
+
+/// Runs tasks forever. This must be called exactly once, at the end of kernel initialisation.
+pub fn run() -> ! {
+    loop {
+        interrupts::disable();
+        let next = SCHEDULER.lock().ready.pop_front();
+        let Some(task) = next else {
+            // Nothing to do until an interrupt makes some task ready.
+            interrupts::enable_and_hlt();
+            continue;
+        };
+
+        task.set_state(TaskState::Running);
+        gdt::set_kernel_stack(task.stack_top());
+        if let Some(process) = task.process() {
+            process.activate();
+        }
+        let rsp = unsafe { *task.saved_rsp() };
+        unsafe {
+            task.restore_fpu();
+        }
+        tls::load(task.kernel_thread_pointer());
+        SCHEDULER.lock().current = Some(task);
+        unsafe {
+            switch_context(SCHEDULER_RSP.as_ptr(), rsp);
+        }
+
+        let task = SCHEDULER
+            .lock()
+            .current
+            .take()
+            .expect("current task disappeared");
+        unsafe {
+            task.save_fpu();
+        }
+        tls::load(tls::boot_thread_pointer());
+        if task.state() == TaskState::Running {
+            task.set_state(TaskState::Ready);
+            SCHEDULER.lock().ready.push_back(task);
+        }
+    }
+}
+
+/// Saves the current task and returns to the scheduler loop.
+/// This must be called with interrupts disabled.
+fn switch_to_scheduler() {
+    debug_assert!(!interrupts::are_enabled());
+    let saved_rsp = current()
+        .expect("cannot switch tasks outside of a task")
+        .saved_rsp();
+    // The scheduler keeps the task alive while it is running, so `saved_rsp` remains valid.
+    unsafe {
+        switch_context(saved_rsp, SCHEDULER_RSP.load(Ordering::Relaxed));
+    }
+}
+
+/// Lets other tasks run before continuing with this one.
+pub fn yield_now() {
+    interrupts::without_interrupts(switch_to_scheduler);
+}
+
+/// Stops running the current task forever.
+///
+/// Nothing on the current stack will be dropped,
+/// so the caller must make sure that it is not holding any resources.
+pub fn exit_current() -> ! {
+    interrupts::disable();
+    current()
+        .expect("cannot exit outside of a task")
+        .set_state(TaskState::Exited);
+    SCHEDULER.lock().tasks -= 1;
+    switch_to_scheduler();
+    unreachable!("exited task was scheduled again");
+}
+
+/// Makes a blocked task ready to run again. Tasks that are not blocked are unaffected.
+pub fn wake(task: &Arc<Task>) {
+    let mut scheduler = SCHEDULER.lock();
+    if task.state() == TaskState::Blocked {
+        task.set_state(TaskState::Ready);
+        scheduler.ready.push_back(task.clone());
+    }
+}
+
+/// A list of tasks waiting for some condition to become true.
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task until `condition` returns `Some`.
+    ///
+    /// The condition is checked with interrupts disabled,
+    /// so an interrupt handler cannot make it true between checking it and going to sleep.
+    /// It is checked again whenever this queue is woken.
+    pub fn wait_until<T>(&self, mut condition: impl FnMut() -> Option<T>) -> T {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let result = loop {
+            if let Some(result) = condition() {
+                break result;
+            }
+            let task = current().expect("cannot block outside of a task");
+            task.set_state(TaskState::Blocked);
+            self.waiters.lock().push_back(task.clone());
+            switch_to_scheduler();
+            // Tasks can also be woken directly, as when they are sent a signal,
+            // so this one may still be in the queue.
+            self.waiters
+                .lock()
+                .retain(|waiter| !Arc::ptr_eq(waiter, &task));
+        };
+        if interrupts_were_enabled {
+            interrupts::enable();
+        }
+        result
+    }
+
+    /// Blocks like [WaitQueue::wait_until], but gives up with [Errno::EINTR] once the current
+    /// process has a signal to handle, so that the system call it is making can return and the
+    /// signal can be delivered. Kernel tasks are never interrupted.
+    pub fn wait_interruptible<T>(
+        &self,
+        mut condition: impl FnMut() -> Option<T>,
+    ) -> Result<T, Errno> {
+        let process = current_process();
+        self.wait_until(|| {
+            if let Some(result) = condition() {
+                return Some(Ok(result));
+            }
+            let interrupted = process
+                .as_ref()
+                .is_some_and(|process| process.has_signal_to_handle());
+            interrupted.then_some(Err(Errno::EINTR))
+        })
+    }
+
+    /// Wakes every task waiting on this queue.
+    ///
+    /// This is called from interrupt handlers, which may have interrupted the heap's owner,
+    /// so it must not allocate or free. The tasks are taken off the queue one at a time,
+    /// leaving its buffer in place, and the ready queue always has room for them.
+    pub fn wake_all(&self) {
+        loop {
+            let Some(task) = self.waiters.lock().pop_front() else {
+                break;
+            };
+            wake(&task);
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
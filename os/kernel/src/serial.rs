@@ -1,18 +1,63 @@
 use spin::{Lazy, Mutex};
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+use crate::{input::InputBuffer, kernel_log, trap};
+
+const COM1_PORT: u16 = 0x3F8;
+/// The interrupt line that `COM1` raises when it receives data.
+pub const COM1_IRQ: u8 = 4;
+
+/// The line status register, and its bit that says there is a received byte to read.
+const LINE_STATUS: u16 = COM1_PORT + 5;
+const DATA_READY: u8 = 1;
 
 /// The serial port `COM1`.
+/// It is set up to raise an interrupt whenever it receives a byte.
 pub static COM1_SERIAL: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
-    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    let mut serial_port = unsafe { SerialPort::new(COM1_PORT) };
     serial_port.init();
     Mutex::new(serial_port)
 });
 
+/// Bytes received on `COM1`, waiting to be read.
+pub static COM1_INPUT: InputBuffer = InputBuffer::new();
+
+/// Starts collecting the bytes received on `COM1` into [COM1_INPUT].
+pub fn init_input() {
+    Lazy::force(&COM1_SERIAL);
+    trap::set_irq_handler(COM1_IRQ, receive);
+}
+
+/// Reads the received bytes straight from the port,
+/// since the interrupt may have arrived while [COM1_SERIAL] was locked.
+fn receive() {
+    let mut status = Port::<u8>::new(LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1_PORT);
+    while unsafe { status.read() } & DATA_READY != 0 {
+        // Terminals send a carriage return for the enter key.
+        let byte = match unsafe { data.read() } {
+            b'\r' => b'\n',
+            byte => byte,
+        };
+        COM1_INPUT.push(&[byte]);
+    }
+}
+
+/// Writes to the serial port, keeping a copy in the kernel log.
+struct Output<'a>(&'a mut SerialPort);
+
+impl core::fmt::Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        kernel_log::write(s.as_bytes());
+        self.0.write_str(s)
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    COM1_SERIAL
-        .lock()
+    Output(&mut COM1_SERIAL.lock())
         .write_fmt(args)
         .expect("Printing to serial failed");
 }
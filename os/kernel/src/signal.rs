@@ -0,0 +1,390 @@
+//! POSIX-style signals.
+//!
+//! Each process has a set of pending signals, a set of blocked signals, and an action for each signal.
+//! Signals are sent by [Process::send_signal], and delivered just before the process next returns to
+//! user mode. A signal with a handler is delivered by pushing a [SignalFrame] onto the user stack
+//! and jumping to the handler. When the handler returns, it returns into the restorer function that it
+//! registered, which makes the `sigreturn` system call to restore the state saved in the frame.
+//! The frame includes the x87 and SSE registers, but not the upper halves of the AVX registers,
+//! so handlers that use AVX must leave those as they found them.
+//!
+//! The signal numbers and default actions match Linux.
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    errno::Errno,
+    fpu::FxsaveArea,
+    gdt,
+    memory::{USER_SPACE_END, USER_SPACE_START},
+    process::Process,
+    syscall::{read_user, write_user},
+    trap::TrapFrame,
+};
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+/// Signals are numbered from 1 up to, but not including, this number.
+pub const NSIG: u32 = 64;
+
+/// [SigAction::handler] values with special meanings.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Don't block the signal while its handler runs.
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// Reset the action to the default once the handler has been called.
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+/// The `restorer` field is set. This flag is required when setting a handler.
+pub const SA_RESTORER: u64 = 0x0400_0000;
+
+/// Values for the `how` argument of `sigprocmask`.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// A process that is killed by a signal exits with this plus the signal number, as shells report it.
+pub const KILLED_STATUS_BASE: i32 = 128;
+
+/// The user stack below the stack pointer may be in use by leaf functions, so signal frames skip it.
+const RED_ZONE_SIZE: u64 = 128;
+
+/// The flags in `RFLAGS` that user code may change with `sigreturn`:
+/// the arithmetic flags, the trap flag and the direction flag.
+/// A trap flag that is set makes the process single-step, raising `SIGTRAP` after each instruction.
+const USER_RFLAGS: u64 = 0b1100_1101_0101;
+
+/// The trap flag in `RFLAGS`.
+const RFLAGS_TRAP: u64 = 1 << 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignalSet(pub u64);
+
+impl SignalSet {
+    /// The signals that cannot be caught, blocked or ignored.
+    pub const UNBLOCKABLE: Self = Self(1 << SIGKILL | 1 << SIGSTOP);
+
+    pub fn contains(self, signal: u32) -> bool {
+        self.0 & (1 << signal) != 0
+    }
+
+    pub fn insert(&mut self, signal: u32) {
+        self.0 |= 1 << signal;
+    }
+
+    pub fn remove(&mut self, signal: u32) {
+        self.0 &= !(1 << signal);
+    }
+
+    /// Returns the lowest-numbered signal in this set.
+    pub fn first(self) -> Option<u32> {
+        (self.0 != 0).then(|| self.0.trailing_zeros())
+    }
+}
+
+/// The layout of the argument to `sigaction`, matching the Linux kernel's.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Pod, Zeroable)]
+pub struct SigAction {
+    /// The address of the handler, or [SIG_DFL] or [SIG_IGN].
+    pub handler: u64,
+    pub flags: u64,
+    /// The function that handlers return into, which must make the `sigreturn` system call.
+    pub restorer: u64,
+    /// Signals to block while the handler runs, in addition to the signal itself.
+    pub mask: u64,
+}
+
+/// What happens to a process when it receives a signal whose action is [SIG_DFL].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub fn default_action(signal: u32) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// The signal state of a process.
+pub struct SignalState {
+    pub pending: SignalSet,
+    pub blocked: SignalSet,
+    actions: [SigAction; NSIG as usize],
+    /// Set while the process is stopped by a signal such as [SIGSTOP].
+    pub stopped: bool,
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self {
+            pending: SignalSet::default(),
+            blocked: SignalSet::default(),
+            actions: [SigAction::default(); NSIG as usize],
+            stopped: false,
+        }
+    }
+}
+
+pub fn is_valid(signal: u32) -> bool {
+    (1..NSIG).contains(&signal)
+}
+
+impl SignalState {
+    pub fn action(&self, signal: u32) -> SigAction {
+        self.actions[signal as usize]
+    }
+
+    /// Changes the action for a signal, and returns the old one.
+    pub fn set_action(&mut self, signal: u32, action: SigAction) -> Result<SigAction, Errno> {
+        if !is_valid(signal) || SignalSet::UNBLOCKABLE.contains(signal) {
+            return Err(Errno::EINVAL);
+        }
+        if action.handler > SIG_IGN && action.flags & SA_RESTORER == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let old = core::mem::replace(&mut self.actions[signal as usize], action);
+        // Setting a signal to be ignored discards any pending instances of it.
+        if self.is_ignored(signal) {
+            self.pending.remove(signal);
+        }
+        Ok(old)
+    }
+
+    pub fn set_blocked(&mut self, blocked: SignalSet) {
+        self.blocked = SignalSet(blocked.0 & !SignalSet::UNBLOCKABLE.0 & !1);
+    }
+
+    fn is_ignored(&self, signal: u32) -> bool {
+        match self.actions[signal as usize].handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(signal) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+
+    /// Marks a signal as pending, unless it would be ignored.
+    pub fn raise(&mut self, signal: u32) {
+        if signal == SIGCONT {
+            self.stopped = false;
+            for stop in [SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU] {
+                self.pending.remove(stop);
+            }
+        } else if default_action(signal) == DefaultAction::Stop {
+            self.pending.remove(SIGCONT);
+        }
+        if !self.is_ignored(signal) {
+            self.pending.insert(signal);
+        }
+    }
+
+    /// Raises a signal caused by the process itself, such as a fault.
+    /// The signal cannot be blocked or ignored: if it is, the action is reset to the default.
+    pub fn force(&mut self, signal: u32) {
+        if self.blocked.contains(signal) || self.actions[signal as usize].handler == SIG_IGN {
+            self.blocked.remove(signal);
+            self.actions[signal as usize] = SigAction::default();
+        }
+        self.pending.insert(signal);
+    }
+
+    /// Whether any pending signal is not blocked.
+    pub fn has_deliverable(&self) -> bool {
+        self.pending.0 & !self.blocked.0 != 0
+    }
+
+    /// Removes and returns the lowest-numbered pending signal that is not blocked.
+    pub fn take_deliverable(&mut self) -> Option<u32> {
+        let signal = SignalSet(self.pending.0 & !self.blocked.0).first()?;
+        self.pending.remove(signal);
+        Some(signal)
+    }
+}
+
+/// The state saved on the user stack while a signal handler runs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct SignalFrame {
+    /// The handler returns here.
+    restorer: u64,
+    signal: u64,
+    /// The blocked signals before the handler was called.
+    blocked: u64,
+    registers: TrapFrame,
+    fpu: FxsaveArea,
+}
+
+// The frame starts 8 bytes past a multiple of 16, like a function's stack frame,
+// and this lines the floating point registers up to 16 bytes, as `FXSAVE` would store them.
+const _: () = assert!(core::mem::offset_of!(SignalFrame, fpu) % 16 == 8);
+
+/// What the process should do after [deliver] has run.
+pub enum Delivery {
+    /// Continue running in user mode, with whatever changes were made to the trap frame.
+    Resume,
+    /// Exit with the given status.
+    Exit(i32),
+    /// Stop until the process is continued.
+    Stop,
+}
+
+/// Delivers the next deliverable signal, if there is one.
+/// Signals with handlers are delivered by modifying the trap frame.
+pub fn deliver(process: &Process, frame: &mut TrapFrame) -> Option<Delivery> {
+    let (signal, action, blocked) = {
+        let mut inner = process.lock();
+        let signals = &mut inner.signals;
+        let signal = signals.take_deliverable()?;
+        let action = signals.action(signal);
+        let blocked = signals.blocked;
+        if action.handler > SIG_IGN {
+            let mut mask = SignalSet(blocked.0 | action.mask);
+            if action.flags & SA_NODEFER == 0 {
+                mask.insert(signal);
+            }
+            signals.set_blocked(mask);
+            if action.flags & SA_RESETHAND != 0 {
+                signals.actions[signal as usize] = SigAction::default();
+            }
+        }
+        (signal, action, blocked)
+    };
+
+    Some(match action.handler {
+        SIG_IGN => Delivery::Resume,
+        SIG_DFL => match default_action(signal) {
+            DefaultAction::Terminate => Delivery::Exit(KILLED_STATUS_BASE + signal as i32),
+            DefaultAction::Stop => Delivery::Stop,
+            DefaultAction::Ignore | DefaultAction::Continue => Delivery::Resume,
+        },
+        handler => {
+            let signal_frame = SignalFrame {
+                restorer: action.restorer,
+                signal: signal as u64,
+                blocked: blocked.0,
+                registers: *frame,
+                // The kernel never uses these registers, so they still hold the process's values.
+                fpu: unsafe { FxsaveArea::save() },
+            };
+            let frame_addr = frame
+                .rsp
+                .checked_sub(RED_ZONE_SIZE + size_of::<SignalFrame>() as u64)
+                .and_then(|addr| (addr & !0xf).checked_sub(8));
+            let Some(frame_addr) =
+                frame_addr.filter(|&addr| write_user(addr, &signal_frame).is_ok())
+            else {
+                // There is nowhere to run the handler.
+                return Some(Delivery::Exit(KILLED_STATUS_BASE + SIGSEGV as i32));
+            };
+            // The handler starts as if it had been called by the restorer, with the signal as its argument.
+            frame.rip = handler;
+            frame.rsp = frame_addr;
+            frame.rdi = signal as u64;
+            // Otherwise a process that is single-stepping would trap again in its `SIGTRAP` handler.
+            frame.rflags &= !RFLAGS_TRAP;
+            Delivery::Resume
+        }
+    })
+}
+
+/// Restores the state saved by [deliver] once a signal handler has returned.
+/// The handler's return popped the restorer address, so the frame starts just below the stack pointer.
+pub fn sigreturn(process: &Process, frame: &mut TrapFrame) -> Result<(), Errno> {
+    let signal_frame: SignalFrame = read_user(frame.rsp.checked_sub(8).ok_or(Errno::EFAULT)?)?;
+    let saved = signal_frame.registers;
+    // Returning to an address outside user space would fault in the kernel.
+    if !is_user_address(saved.rip) || !is_user_address(saved.rsp) || !signal_frame.fpu.is_valid() {
+        return Err(Errno::EFAULT);
+    }
+    unsafe {
+        signal_frame.fpu.restore();
+    }
+    process
+        .lock()
+        .signals
+        .set_blocked(SignalSet(signal_frame.blocked));
+
+    let rflags = frame.rflags & !USER_RFLAGS | saved.rflags & USER_RFLAGS;
+    *frame = TrapFrame {
+        rflags,
+        // Never let user code choose its own segments.
+        cs: gdt::user_code_selector().0 as u64,
+        ss: gdt::user_data_selector().0 as u64,
+        vector: frame.vector,
+        error_code: frame.error_code,
+        ..saved
+    };
+    Ok(())
+}
+
+/// Whether user code may run or keep its stack at an address.
+/// Everything in user space is canonical, so this also rules out non-canonical addresses.
+fn is_user_address(addr: u64) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr)
+}
+
+#[test_case]
+fn test_signal_state() {
+    let mut signals = SignalState::default();
+
+    // Ignored signals never become pending.
+    signals.raise(SIGCHLD);
+    assert_eq!(signals.take_deliverable(), None);
+
+    // Blocked signals stay pending until they are unblocked, and the lowest is delivered first.
+    signals.set_blocked(SignalSet(1 << SIGUSR1 | 1 << SIGKILL));
+    signals.raise(SIGUSR1);
+    signals.raise(SIGTERM);
+    assert!(signals.has_deliverable());
+    assert_eq!(signals.take_deliverable(), Some(SIGTERM));
+    assert!(!signals.has_deliverable());
+    assert_eq!(signals.take_deliverable(), None);
+    signals.set_blocked(SignalSet::default());
+    assert_eq!(signals.take_deliverable(), Some(SIGUSR1));
+
+    // SIGKILL can't be blocked or caught.
+    assert!(!signals.blocked.contains(SIGKILL));
+    assert_eq!(
+        signals.set_action(SIGKILL, SigAction::default()),
+        Err(Errno::EINVAL)
+    );
+
+    // Faults can't be ignored.
+    let ignore = SigAction {
+        handler: SIG_IGN,
+        ..Default::default()
+    };
+    signals.set_action(SIGSEGV, ignore).unwrap();
+    signals.force(SIGSEGV);
+    assert_eq!(signals.take_deliverable(), Some(SIGSEGV));
+    assert_eq!(signals.action(SIGSEGV).handler, SIG_DFL);
+}
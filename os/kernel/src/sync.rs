@@ -0,0 +1,96 @@
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// The kernel is not preemptible, so an ordinary [Mutex] is enough for data that is only touched by tasks.
+/// Anything that an interrupt handler might lock must use an [IrqMutex] instead,
+/// otherwise the handler could spin forever on a lock held by the code it interrupted.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// See [Mutex::force_unlock].
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before we can be interrupted again.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Waits for a device to finish something, without letting other tasks run.
+///
+/// Drivers are called with spin locks held, since the buffer cache and the filesystems keep
+/// theirs across disk I/O, so they cannot block on a [crate::scheduler::WaitQueue].
+/// If interrupts are enabled, the processor sleeps until the next one arrives,
+/// which is usually the device saying that it is done. Otherwise, this polls.
+pub fn wait_for_device<T>(mut condition: impl FnMut() -> Option<T>) -> T {
+    let interrupts_were_enabled = interrupts::are_enabled();
+    loop {
+        interrupts::disable();
+        if let Some(result) = condition() {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            return result;
+        }
+        if interrupts_were_enabled {
+            // An interrupt that arrives after the check still wakes us up.
+            interrupts::enable_and_hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,1058 @@
+//! The system call interface.
+//!
+//! User programs make system calls with `int 0x80`.
+//! The system call number is passed in `rax`, and up to six arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, in that order.
+//! The result is returned in `rax`: a negative value `-e` means that the call failed with [Errno] `e`.
+
+use x86_64::instructions::interrupts;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::net::SocketAddrV4;
+
+use bytemuck::Pod;
+
+use crate::{
+    capability::{Capability, CapabilityTable, Rights},
+    errno::Errno,
+    file::{self, File, PollFd, MAX_FILES, POLLERR, POLLHUP, POLLIN, POLLNVAL, POLLOUT},
+    fs::{self, path::MAX_PATH, Dentry, OpenFlags, Whence},
+    ipc::{Endpoint, Message, UserMessage, MAX_CAPABILITIES, MAX_DATA, NO_HANDLE},
+    memory::{
+        page_align_up,
+        shared::SharedMemory,
+        vma::{Access, Backing, Protection},
+        PAGE_SIZE, USER_SPACE_END, USER_SPACE_START,
+    },
+    net::{
+        dns,
+        socket::{SockAddrIn, Socket},
+    },
+    pipe,
+    process::{self, Pid, Process},
+    scheduler,
+    signal::{self, SigAction, SignalSet, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK},
+    timer,
+    trap::TrapFrame,
+};
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// System call numbers.
+/// These must be kept in sync with the user runtime.
+pub mod number {
+    pub const EXIT: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const BRK: u64 = 2;
+    pub const SPAWN: u64 = 3;
+    pub const WAIT: u64 = 4;
+    pub const SIGACTION: u64 = 5;
+    pub const SIGPROCMASK: u64 = 6;
+    pub const SIGRETURN: u64 = 7;
+    pub const KILL: u64 = 8;
+    pub const GETPID: u64 = 9;
+    pub const READ: u64 = 10;
+    pub const PIPE: u64 = 11;
+    pub const DUP2: u64 = 12;
+    pub const CLOSE: u64 = 13;
+    pub const ENDPOINT_CREATE: u64 = 14;
+    pub const HANDLE_DUPLICATE: u64 = 15;
+    pub const HANDLE_CLOSE: u64 = 16;
+    pub const IPC_SEND: u64 = 17;
+    pub const IPC_RECEIVE: u64 = 18;
+    pub const IPC_CALL: u64 = 19;
+    pub const IPC_REPLY: u64 = 20;
+    pub const MMAP: u64 = 21;
+    pub const MUNMAP: u64 = 22;
+    pub const MPROTECT: u64 = 23;
+    pub const OPEN: u64 = 24;
+    pub const SEEK: u64 = 25;
+    pub const STAT: u64 = 26;
+    pub const FSTAT: u64 = 27;
+    pub const GETDENTS: u64 = 28;
+    pub const MKDIR: u64 = 29;
+    pub const UNLINK: u64 = 30;
+    pub const RMDIR: u64 = 31;
+    pub const CHDIR: u64 = 32;
+    pub const GETCWD: u64 = 33;
+    pub const SYMLINK: u64 = 34;
+    pub const READLINK: u64 = 35;
+    pub const LINK: u64 = 36;
+    pub const RENAME: u64 = 37;
+    pub const FTRUNCATE: u64 = 38;
+    pub const IOCTL: u64 = 39;
+    pub const RESOLVE: u64 = 40;
+    pub const SOCKET: u64 = 41;
+    pub const BIND: u64 = 42;
+    pub const LISTEN: u64 = 43;
+    pub const ACCEPT: u64 = 44;
+    pub const CONNECT: u64 = 45;
+    pub const SEND: u64 = 46;
+    pub const RECV: u64 = 47;
+    pub const POLL: u64 = 48;
+    pub const SELECT: u64 = 49;
+}
+
+/// Flags for the `mmap` system call, with the same values as Linux.
+pub mod map_flags {
+    pub const SHARED: u64 = 0x01;
+    pub const PRIVATE: u64 = 0x02;
+    pub const FIXED: u64 = 0x10;
+    pub const ANONYMOUS: u64 = 0x20;
+}
+
+/// A flag for the `stat` system call, to describe a symbolic link rather than what it points to.
+pub const STAT_NO_FOLLOW: u64 = 0x100;
+
+/// The most arguments that can be passed to a new process.
+const MAX_ARGS: u64 = 256;
+
+/// The longest string, such as an argument, that can be passed to a system call.
+const MAX_STRING: u64 = 4096;
+
+type SyscallResult = Result<u64, Errno>;
+
+pub fn dispatch(frame: &mut TrapFrame) {
+    // System calls can take a while, so let hardware interrupts in.
+    interrupts::enable();
+
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let result = match frame.rax {
+        number::EXIT => sys_exit(args[0] as i32),
+        number::WRITE => sys_write(args[0], args[1], args[2]),
+        number::BRK => sys_brk(args[0]),
+        number::SPAWN => sys_spawn(args[0], args[1], args[2], args[3]),
+        number::WAIT => sys_wait(args[0]),
+        number::SIGACTION => sys_sigaction(args[0], args[1], args[2]),
+        number::SIGPROCMASK => sys_sigprocmask(args[0], args[1]),
+        number::SIGRETURN => sys_sigreturn(frame),
+        number::KILL => sys_kill(args[0], args[1]),
+        number::GETPID => sys_getpid(),
+        number::READ => sys_read(args[0], args[1], args[2]),
+        number::PIPE => sys_pipe(args[0]),
+        number::DUP2 => sys_dup2(args[0], args[1]),
+        number::CLOSE => sys_close(args[0]),
+        number::ENDPOINT_CREATE => sys_endpoint_create(),
+        number::HANDLE_DUPLICATE => sys_handle_duplicate(args[0], args[1]),
+        number::HANDLE_CLOSE => sys_handle_close(args[0]),
+        number::IPC_SEND => sys_ipc_send(args[0], args[1]),
+        number::IPC_RECEIVE => sys_ipc_receive(args[0], args[1]),
+        number::IPC_CALL => sys_ipc_call(args[0], args[1]),
+        number::IPC_REPLY => sys_ipc_reply(args[0], args[1]),
+        number::MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        number::MUNMAP => sys_munmap(args[0], args[1]),
+        number::MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        number::OPEN => sys_open(args[0], args[1], args[2], args[3]),
+        number::SEEK => sys_seek(args[0], args[1], args[2]),
+        number::STAT => sys_stat(args[0], args[1], args[2], args[3]),
+        number::FSTAT => sys_fstat(args[0], args[1]),
+        number::GETDENTS => sys_getdents(args[0], args[1], args[2]),
+        number::MKDIR => sys_mkdir(args[0], args[1], args[2]),
+        number::UNLINK => sys_unlink(args[0], args[1]),
+        number::RMDIR => sys_rmdir(args[0], args[1]),
+        number::CHDIR => sys_chdir(args[0], args[1]),
+        number::GETCWD => sys_getcwd(args[0], args[1]),
+        number::SYMLINK => sys_symlink(args[0], args[1], args[2], args[3]),
+        number::READLINK => sys_readlink(args[0], args[1], args[2], args[3]),
+        number::LINK => sys_link(args[0], args[1], args[2], args[3]),
+        number::RENAME => sys_rename(args[0], args[1], args[2], args[3]),
+        number::FTRUNCATE => sys_ftruncate(args[0], args[1]),
+        number::IOCTL => sys_ioctl(args[0], args[1], args[2]),
+        number::RESOLVE => sys_resolve(args[0], args[1], args[2], args[3]),
+        number::SOCKET => sys_socket(args[0], args[1], args[2]),
+        number::BIND => sys_bind(args[0], args[1], args[2]),
+        number::LISTEN => sys_listen(args[0], args[1]),
+        number::ACCEPT => sys_accept(args[0], args[1], args[2]),
+        number::CONNECT => sys_connect(args[0], args[1], args[2]),
+        number::SEND => sys_send(args[0], args[1], args[2], args[3], args[4], args[5]),
+        number::RECV => sys_recv(args[0], args[1], args[2], args[3], args[4], args[5]),
+        number::POLL => sys_poll(args[0], args[1], args[2]),
+        number::SELECT => sys_select(args[0], args[1], args[2], args[3], args[4]),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => errno.to_return_value(),
+    };
+}
+
+/// Borrows `len` bytes of the current process's memory, starting at `addr`.
+///
+/// The slice is only valid until the process's memory mappings next change,
+/// so it must not be held across anything that could unmap memory.
+fn user_bytes<'a>(addr: u64, len: u64) -> Result<&'a [u8], Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.fault_in_range(addr, len, Access::Read)?;
+    let inner = process.lock();
+    let address_space = inner.address_space.as_ref().ok_or(Errno::EFAULT)?;
+    if !address_space.is_accessible(addr, len, false) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+/// Mutably borrows `len` bytes of the current process's memory, starting at `addr`.
+/// The same caveats apply as for [user_bytes].
+fn user_bytes_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.fault_in_range(addr, len, Access::Write)?;
+    let inner = process.lock();
+    let address_space = inner.address_space.as_ref().ok_or(Errno::EFAULT)?;
+    if !address_space.is_accessible(addr, len, true) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+/// Reads a value out of the current process's memory.
+pub fn read_user<T: Pod>(addr: u64) -> Result<T, Errno> {
+    let bytes = user_bytes(addr, size_of::<T>() as u64)?;
+    Ok(bytemuck::pod_read_unaligned(bytes))
+}
+
+/// Writes a value into the current process's memory, which must be writable by the process.
+pub fn write_user<T: Pod>(addr: u64, value: &T) -> Result<(), Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let bytes = bytemuck::bytes_of(value);
+    process.fault_in_range(addr, bytes.len() as u64, Access::Write)?;
+    let inner = process.lock();
+    let address_space = inner.address_space.as_ref().ok_or(Errno::EFAULT)?;
+    if !address_space.is_accessible(addr, bytes.len() as u64, true) {
+        return Err(Errno::EFAULT);
+    }
+    address_space.write_bytes(addr, bytes);
+    Ok(())
+}
+
+/// Copies a string out of the current process's memory.
+fn user_string(addr: u64, len: u64) -> Result<String, Errno> {
+    if len > MAX_STRING {
+        return Err(Errno::E2BIG);
+    }
+    let bytes = user_bytes(addr, len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| Errno::EINVAL)
+}
+
+fn sys_exit(status: i32) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.exit(status);
+    Ok(0)
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let file = current_file(fd)?;
+    let bytes = user_bytes(buf, len)?;
+    Ok(file.write(bytes)? as u64)
+}
+
+fn sys_brk(addr: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    Ok(process.set_brk(addr))
+}
+
+/// Starts the program at the given path, and returns its process ID.
+/// The arguments are given as an array of `argc` (pointer, length) pairs.
+fn sys_spawn(path: u64, path_len: u64, argv: u64, argc: u64) -> SyscallResult {
+    if argc > MAX_ARGS {
+        return Err(Errno::E2BIG);
+    }
+    let path = user_path(path, path_len)?;
+    let pairs: Vec<[u64; 2]> = user_bytes(argv, argc * 16)?
+        .chunks_exact(16)
+        .map(bytemuck::pod_read_unaligned)
+        .collect();
+    let args = pairs
+        .into_iter()
+        .map(|[addr, len]| user_string(addr, len))
+        .collect::<Result<Vec<_>, _>>()?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    // The child inherits all of its parent's open files.
+    let process = scheduler::current_process().expect("system call outside of a process");
+    // Capabilities are not inherited: they must be passed explicitly in messages.
+    let files = process.lock().files.clone();
+    let child = Process::spawn_path(&path, &args, files, CapabilityTable::default())?;
+    // The child can't run until this system call returns, so it starts in our current directory.
+    child.lock().cwd = process.lock().cwd.clone();
+    let pid = child.pid();
+    process.lock().children.push(child);
+    Ok(pid.0 as u64)
+}
+
+/// Waits for the child process with the given ID to exit, and returns its exit status.
+fn sys_wait(pid: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let child = process
+        .lock()
+        .children
+        .iter()
+        .find(|child| child.pid().0 as u64 == pid)
+        .cloned()
+        .ok_or(Errno::ECHILD)?;
+    let status = child.wait_interruptible()?;
+    // The child can only be waited for once.
+    process
+        .lock()
+        .children
+        .retain(|other| !Arc::ptr_eq(other, &child));
+    Ok(status as u32 as u64)
+}
+
+/// Sets the action for a signal from `act`, unless it is null,
+/// and stores the previous action in `old_act`, unless it is null.
+fn sys_sigaction(signal: u64, act: u64, old_act: u64) -> SyscallResult {
+    let signal = u32::try_from(signal).map_err(|_| Errno::EINVAL)?;
+    if !signal::is_valid(signal) {
+        return Err(Errno::EINVAL);
+    }
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let old = if act != 0 {
+        let action: SigAction = read_user(act)?;
+        process.lock().signals.set_action(signal, action)?
+    } else {
+        process.lock().signals.action(signal)
+    };
+    if old_act != 0 {
+        write_user(old_act, &old)?;
+    }
+    Ok(0)
+}
+
+/// Changes the set of blocked signals, and returns the previous set.
+fn sys_sigprocmask(how: u64, set: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let mut inner = process.lock();
+    let old = inner.signals.blocked;
+    let new = match how {
+        SIG_BLOCK => old.0 | set,
+        SIG_UNBLOCK => old.0 & !set,
+        SIG_SETMASK => set,
+        _ => return Err(Errno::EINVAL),
+    };
+    inner.signals.set_blocked(SignalSet(new));
+    Ok(old.0)
+}
+
+fn sys_sigreturn(frame: &mut TrapFrame) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    if signal::sigreturn(&process, frame).is_err() {
+        // The saved state is gone, so there is nothing sensible to return to.
+        process.force_signal(signal::SIGSEGV);
+    }
+    // Leave the restored `rax` as it was.
+    Ok(frame.rax)
+}
+
+/// Sends a signal to a process. Signal 0 only checks that the process exists.
+fn sys_kill(pid: u64, signal: u64) -> SyscallResult {
+    let signal = u32::try_from(signal).map_err(|_| Errno::EINVAL)?;
+    if signal != 0 && !signal::is_valid(signal) {
+        return Err(Errno::EINVAL);
+    }
+    let pid = u32::try_from(pid).map_err(|_| Errno::ESRCH)?;
+    let target = process::find(Pid(pid)).ok_or(Errno::ESRCH)?;
+    if signal != 0 {
+        target.send_signal(signal);
+    }
+    Ok(0)
+}
+
+fn sys_getpid() -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    Ok(process.pid().0 as u64)
+}
+
+/// Looks up a file descriptor of the current process.
+/// The process's lock is not held while the file is in use, since using it may block.
+fn current_file(fd: u64) -> Result<Arc<dyn File>, Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let file = process.lock().files.get(fd);
+    file
+}
+
+fn sys_read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let file = current_file(fd)?;
+    let bytes = user_bytes_mut(buf, len)?;
+    Ok(file.read(bytes)? as u64)
+}
+
+/// Creates a pipe, and stores the file descriptors of its read and write ends
+/// as two 32-bit integers at `fds`.
+fn sys_pipe(fds: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let (reader, writer) = pipe::pipe();
+    let pair = {
+        let mut inner = process.lock();
+        let reader = inner.files.insert(Arc::new(reader))?;
+        match inner.files.insert(Arc::new(writer)) {
+            Ok(writer) => [reader as u32, writer as u32],
+            Err(errno) => {
+                inner.files.close(reader)?;
+                return Err(errno);
+            }
+        }
+    };
+    if let Err(errno) = write_user(fds, &pair) {
+        let mut inner = process.lock();
+        inner.files.close(pair[0] as u64)?;
+        inner.files.close(pair[1] as u64)?;
+        return Err(errno);
+    }
+    Ok(0)
+}
+
+fn sys_dup2(old_fd: u64, new_fd: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    // Closing the file that was there may wake other tasks, so don't do it while holding the lock.
+    let replaced = process.lock().files.duplicate_to(old_fd, new_fd)?;
+    drop(replaced);
+    Ok(new_fd)
+}
+
+fn sys_close(fd: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    // Closing the file may wake other tasks, so don't do it while holding the lock.
+    let file = process.lock().files.get(fd)?;
+    process.lock().files.close(fd)?;
+    drop(file);
+    Ok(0)
+}
+
+/// Creates an endpoint, and returns a handle to it with every right.
+fn sys_endpoint_create() -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let handle = process
+        .lock()
+        .capabilities
+        .insert(Capability::Endpoint(Endpoint::new(), Rights::all()))?;
+    Ok(handle)
+}
+
+/// Copies a capability with fewer rights, and returns the new handle.
+fn sys_handle_duplicate(handle: u64, rights: u64) -> SyscallResult {
+    let rights = Rights::from_bits(rights).ok_or(Errno::EINVAL)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let mut inner = process.lock();
+    let copy = inner.capabilities.get(handle)?.duplicate(rights)?;
+    inner.capabilities.insert(copy)
+}
+
+fn sys_handle_close(handle: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    // Dropping a reply capability wakes its caller, so don't do it while holding the lock.
+    let capability = process.lock().capabilities.remove(handle)?;
+    drop(capability);
+    Ok(0)
+}
+
+/// Reads a message from user memory, copying the capabilities that it refers to.
+/// `may_grant` says whether capabilities may be sent.
+fn message_from_user(addr: u64, may_grant: bool) -> Result<Message, Errno> {
+    let message: UserMessage = read_user(addr)?;
+    if message.len > MAX_DATA as u64 || message.handle_count > MAX_CAPABILITIES as u64 {
+        return Err(Errno::E2BIG);
+    }
+    if message.handle_count > 0 && !may_grant {
+        return Err(Errno::EPERM);
+    }
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let inner = process.lock();
+    let capabilities = message.handles[..message.handle_count as usize]
+        .iter()
+        .map(|&handle| {
+            let capability = inner.capabilities.get(handle)?;
+            match capability {
+                Capability::Endpoint(_, rights) => capability.duplicate(*rights),
+                // Reply capabilities can only be used by the process that received them.
+                Capability::Reply(_) => Err(Errno::EINVAL),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Message::new(&message.data[..message.len as usize], capabilities)
+}
+
+/// Installs the capabilities in a message in the current process, and writes the message to user memory.
+fn message_to_user(addr: u64, message: Message) -> Result<(), Errno> {
+    let mut user_message = UserMessage {
+        len: message.data.len() as u64,
+        data: [0; MAX_DATA],
+        handle_count: message.capabilities.len() as u64,
+        handles: [NO_HANDLE; MAX_CAPABILITIES],
+        reply: NO_HANDLE,
+    };
+    user_message.data[..message.data.len()].copy_from_slice(&message.data);
+
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let installed =
+        install_capabilities(&mut process.lock().capabilities, &mut user_message, message);
+    let result = installed.and_then(|()| write_user(addr, &user_message));
+    if result.is_err() {
+        // The process never finds out about the handles, so take back the ones that were installed.
+        // Dropping a reply capability wakes its caller, so don't do it while holding the lock.
+        let removed: Vec<_> = {
+            let mut inner = process.lock();
+            user_message
+                .handles
+                .iter()
+                .chain([&user_message.reply])
+                .filter(|&&handle| handle != NO_HANDLE)
+                .filter_map(|&handle| inner.capabilities.remove(handle).ok())
+                .collect()
+        };
+        drop(removed);
+    }
+    result
+}
+
+/// Stores the capabilities in a message in a table, and fills in their handles.
+/// Handles are left as [NO_HANDLE] for any capabilities that didn't fit.
+fn install_capabilities(
+    table: &mut CapabilityTable,
+    user_message: &mut UserMessage,
+    message: Message,
+) -> Result<(), Errno> {
+    for (handle, capability) in user_message.handles.iter_mut().zip(message.capabilities) {
+        *handle = table.insert(capability)?;
+    }
+    if let Some(reply) = message.reply {
+        user_message.reply = table.insert(Capability::Reply(reply))?;
+    }
+    Ok(())
+}
+
+/// Looks up an endpoint capability of the current process that has the given rights.
+fn current_endpoint(handle: u64, rights: Rights) -> Result<(Arc<Endpoint>, Rights), Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let inner = process.lock();
+    let capability = inner.capabilities.get(handle)?;
+    let endpoint = capability.endpoint(rights)?.clone();
+    let Capability::Endpoint(_, rights) = capability else {
+        unreachable!("capability refers to an endpoint");
+    };
+    Ok((endpoint, *rights))
+}
+
+/// Sends a message, blocking until it is received.
+fn sys_ipc_send(handle: u64, message: u64) -> SyscallResult {
+    let (endpoint, rights) = current_endpoint(handle, Rights::SEND)?;
+    let message = message_from_user(message, rights.contains(Rights::GRANT))?;
+    endpoint.send(message)?;
+    Ok(0)
+}
+
+/// Blocks until a message arrives, and writes it over the message at `message`.
+fn sys_ipc_receive(handle: u64, message: u64) -> SyscallResult {
+    let (endpoint, _) = current_endpoint(handle, Rights::RECEIVE)?;
+    message_to_user(message, endpoint.receive()?)?;
+    Ok(0)
+}
+
+/// Sends a message, blocks until the receiver replies, and writes the reply over the message.
+fn sys_ipc_call(handle: u64, message: u64) -> SyscallResult {
+    let (endpoint, rights) = current_endpoint(handle, Rights::SEND)?;
+    let request = message_from_user(message, rights.contains(Rights::GRANT))?;
+    message_to_user(message, endpoint.call(request)?)?;
+    Ok(0)
+}
+
+/// Replies to a call using a reply capability, which is used up.
+fn sys_ipc_reply(handle: u64, message: u64) -> SyscallResult {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    if !matches!(
+        process.lock().capabilities.get(handle)?,
+        Capability::Reply(_)
+    ) {
+        return Err(Errno::EINVAL);
+    }
+    // Check the message before using up the reply capability.
+    let message = message_from_user(message, true)?;
+    let Capability::Reply(reply) = process.lock().capabilities.remove(handle)? else {
+        unreachable!("checked above");
+    };
+    reply.reply(message);
+    Ok(0)
+}
+
+/// Maps memory into the current process, and returns its address.
+/// The memory is either zeroed, or read from the file `fd` starting at `offset`.
+fn sys_mmap(
+    addr: u64,
+    len: u64,
+    protection: u64,
+    flags: u64,
+    fd: u64,
+    offset: u64,
+) -> SyscallResult {
+    use map_flags::{ANONYMOUS, FIXED, PRIVATE, SHARED};
+
+    let protection = Protection::from_bits(protection).ok_or(Errno::EINVAL)?;
+    let shared = match flags & (SHARED | PRIVATE) {
+        SHARED => true,
+        PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    if len == 0
+        || flags & !(SHARED | PRIVATE | FIXED | ANONYMOUS) != 0
+        || len > USER_SPACE_END - USER_SPACE_START
+        || !offset.is_multiple_of(PAGE_SIZE)
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    let backing = if flags & ANONYMOUS != 0 {
+        match shared {
+            true => Backing::Shared {
+                memory: SharedMemory::new(),
+                offset: 0,
+            },
+            false => Backing::Anonymous,
+        }
+    } else {
+        let file = current_file(fd)?;
+        if let (true, Ok((start, size))) = (shared, file.device_memory()) {
+            if offset >= size {
+                return Err(Errno::ENXIO);
+            }
+            Backing::Device {
+                start: start + offset,
+                len: size - offset,
+            }
+        } else if shared {
+            Backing::Shared {
+                memory: file.shared_memory()?,
+                offset,
+            }
+        } else {
+            // Check that the file can be read from anywhere.
+            file.read_at(offset, &mut []).map_err(|_| Errno::ENODEV)?;
+            Backing::File { file, offset }
+        }
+    };
+
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.map(addr, len, flags & FIXED != 0, protection, backing)
+}
+
+/// Checks that `addr..addr + len` is a page-aligned range of user space, and returns its end.
+fn user_page_range(addr: u64, len: u64) -> Result<u64, Errno> {
+    if len == 0 || len > USER_SPACE_END - USER_SPACE_START || !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let end = addr + page_align_up(len);
+    if addr < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(Errno::EINVAL);
+    }
+    Ok(end)
+}
+
+fn sys_munmap(addr: u64, len: u64) -> SyscallResult {
+    let end = user_page_range(addr, len)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.unmap(addr, end)?;
+    Ok(0)
+}
+
+fn sys_mprotect(addr: u64, len: u64, protection: u64) -> SyscallResult {
+    let end = user_page_range(addr, len)?;
+    let protection = Protection::from_bits(protection).ok_or(Errno::EINVAL)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.protect(addr, end, protection)?;
+    Ok(0)
+}
+
+/// The current process's current directory, which relative paths start from.
+fn current_dir() -> Result<Arc<Dentry>, Errno> {
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let cwd = process.lock().cwd.clone();
+    cwd.map_or_else(fs::root, Ok)
+}
+
+/// Copies a path out of the current process's memory.
+fn user_path(addr: u64, len: u64) -> Result<String, Errno> {
+    if len > MAX_PATH as u64 {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    user_string(addr, len)
+}
+
+/// Opens the file at the given path, and returns its file descriptor.
+fn sys_open(path: u64, path_len: u64, flags: u64, mode: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let flags = u32::try_from(flags)
+        .ok()
+        .and_then(OpenFlags::from_bits)
+        .ok_or(Errno::EINVAL)?;
+    let file = fs::open(&current_dir()?, &path, flags, mode as u32 & 0o7777)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let fd = process.lock().files.insert(file)?;
+    Ok(fd)
+}
+
+/// Moves the position of an open file, and returns the new position.
+fn sys_seek(fd: u64, offset: u64, whence: u64) -> SyscallResult {
+    current_file(fd)?.seek(offset as i64, Whence::from_raw(whence)?)
+}
+
+/// Writes information about the file at the given path to `stat`.
+fn sys_stat(path: u64, path_len: u64, stat: u64, flags: u64) -> SyscallResult {
+    if flags & !STAT_NO_FOLLOW != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let path = user_path(path, path_len)?;
+    let info = fs::stat(&current_dir()?, &path, flags & STAT_NO_FOLLOW == 0)?;
+    write_user(stat, &info)?;
+    Ok(0)
+}
+
+fn sys_fstat(fd: u64, stat: u64) -> SyscallResult {
+    let info = current_file(fd)?.stat()?;
+    write_user(stat, &info)?;
+    Ok(0)
+}
+
+/// Lists entries from an open directory into `buf`, in the same format as Linux's `getdents64`,
+/// and returns how many bytes were written. Returns zero at the end of the directory.
+fn sys_getdents(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let file = current_file(fd)?;
+    let mut records = Vec::new();
+    let mut too_small = false;
+    file.read_dir(&mut |entry| {
+        // The inode number, the offset of the next entry (which we don't use), the record's length,
+        // the file type, and then the name with a null terminator, padded to eight bytes.
+        let start = records.len();
+        let record_len = (19 + entry.name.len() + 1).next_multiple_of(8);
+        if (start + record_len) as u64 > len {
+            too_small = start == 0;
+            return false;
+        }
+        records.extend_from_slice(&entry.inode.to_ne_bytes());
+        records.extend_from_slice(&0i64.to_ne_bytes());
+        records.extend_from_slice(&(record_len as u16).to_ne_bytes());
+        records.push(entry.kind as u8);
+        records.extend_from_slice(entry.name.as_bytes());
+        records.resize(start + record_len, 0);
+        true
+    })?;
+    if too_small {
+        return Err(Errno::EINVAL);
+    }
+    user_bytes_mut(buf, records.len() as u64)?.copy_from_slice(&records);
+    Ok(records.len() as u64)
+}
+
+fn sys_mkdir(path: u64, path_len: u64, mode: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    fs::mkdir(&current_dir()?, &path, mode as u32 & 0o7777)?;
+    Ok(0)
+}
+
+fn sys_unlink(path: u64, path_len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    fs::unlink(&current_dir()?, &path)?;
+    Ok(0)
+}
+
+fn sys_rmdir(path: u64, path_len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    fs::rmdir(&current_dir()?, &path)?;
+    Ok(0)
+}
+
+fn sys_chdir(path: u64, path_len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let dir = fs::lookup(&current_dir()?, &path, true)?;
+    if dir.kind() != fs::FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    let process = scheduler::current_process().expect("system call outside of a process");
+    process.lock().cwd = Some(dir);
+    Ok(0)
+}
+
+/// Writes the absolute path of the current directory to `buf`, without a null terminator,
+/// and returns its length.
+fn sys_getcwd(buf: u64, len: u64) -> SyscallResult {
+    let path = current_dir()?.path();
+    if path.len() as u64 > len {
+        return Err(Errno::ERANGE);
+    }
+    user_bytes_mut(buf, path.len() as u64)?.copy_from_slice(path.as_bytes());
+    Ok(path.len() as u64)
+}
+
+/// Creates a symbolic link at `path` that points to `target`.
+fn sys_symlink(target: u64, target_len: u64, path: u64, path_len: u64) -> SyscallResult {
+    let target = user_path(target, target_len)?;
+    let path = user_path(path, path_len)?;
+    fs::symlink(&current_dir()?, &target, &path)?;
+    Ok(0)
+}
+
+/// Writes the target of a symbolic link to `buf`, cutting it short if it doesn't fit,
+/// and returns how many bytes were written.
+fn sys_readlink(path: u64, path_len: u64, buf: u64, len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let target = fs::read_link(&current_dir()?, &path)?;
+    let target = &target.as_bytes()[..target.len().min(len as usize)];
+    user_bytes_mut(buf, target.len() as u64)?.copy_from_slice(target);
+    Ok(target.len() as u64)
+}
+
+/// Creates a hard link at `new_path` to the file at `old_path`.
+fn sys_link(old_path: u64, old_len: u64, new_path: u64, new_len: u64) -> SyscallResult {
+    let old_path = user_path(old_path, old_len)?;
+    let new_path = user_path(new_path, new_len)?;
+    fs::link(&current_dir()?, &old_path, &new_path)?;
+    Ok(0)
+}
+
+fn sys_rename(old_path: u64, old_len: u64, new_path: u64, new_len: u64) -> SyscallResult {
+    let old_path = user_path(old_path, old_len)?;
+    let new_path = user_path(new_path, new_len)?;
+    fs::rename(&current_dir()?, &old_path, &new_path)?;
+    Ok(0)
+}
+
+fn sys_ftruncate(fd: u64, size: u64) -> SyscallResult {
+    current_file(fd)?.truncate(size)?;
+    Ok(0)
+}
+
+fn sys_ioctl(fd: u64, request: u64, arg: u64) -> SyscallResult {
+    current_file(fd)?.ioctl(request, arg)
+}
+
+/// Looks up the IPv4 addresses of a name, and writes up to `max` of them to `addrs`,
+/// four bytes each in network order. Returns how many were written.
+fn sys_resolve(name: u64, name_len: u64, addrs: u64, max: u64) -> SyscallResult {
+    // `dns::resolve` would refuse a longer name anyway, so don't copy it in.
+    if name_len > dns::MAX_NAME_LEN as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let name = user_string(name, name_len)?;
+    let addresses = dns::resolve(&name)?;
+    let octets: Vec<u8> = addresses
+        .iter()
+        .take(max as usize)
+        .flat_map(|address| address.octets())
+        .collect();
+    user_bytes_mut(addrs, octets.len() as u64)?.copy_from_slice(&octets);
+    Ok(octets.len() as u64 / 4)
+}
+
+/// Calls `f` with the socket that a file descriptor refers to.
+fn with_socket<T>(fd: u64, f: impl FnOnce(&Socket) -> Result<T, Errno>) -> Result<T, Errno> {
+    let file = current_file(fd)?;
+    f(file.as_socket().ok_or(Errno::ENOTSOCK)?)
+}
+
+/// Reads a `struct sockaddr_in` of `len` bytes.
+fn read_sockaddr(addr: u64, len: u64) -> Result<SocketAddrV4, Errno> {
+    if len < size_of::<SockAddrIn>() as u64 {
+        return Err(Errno::EINVAL);
+    }
+    read_user::<SockAddrIn>(addr)?.to_socket_addr()
+}
+
+/// Writes an address as a `struct sockaddr_in`, unless `addr` is null. The 32-bit length at
+/// `len_ptr` says how much room there is, and is replaced by the length of the whole address.
+fn write_sockaddr(addr: u64, len_ptr: u64, address: SocketAddrV4) -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(());
+    }
+    let room = read_user::<u32>(len_ptr)? as usize;
+    let sockaddr = SockAddrIn::from(address);
+    let bytes = bytemuck::bytes_of(&sockaddr);
+    let len = room.min(bytes.len());
+    user_bytes_mut(addr, len as u64)?.copy_from_slice(&bytes[..len]);
+    write_user(len_ptr, &(bytes.len() as u32))
+}
+
+/// Creates a socket, and returns its file descriptor.
+fn sys_socket(domain: u64, kind: u64, protocol: u64) -> SyscallResult {
+    let socket = Socket::new(domain, kind, protocol)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let fd = process.lock().files.insert(Arc::new(socket))?;
+    Ok(fd)
+}
+
+fn sys_bind(fd: u64, addr: u64, len: u64) -> SyscallResult {
+    let address = read_sockaddr(addr, len)?;
+    with_socket(fd, |socket| socket.bind(address))?;
+    Ok(0)
+}
+
+fn sys_listen(fd: u64, backlog: u64) -> SyscallResult {
+    with_socket(fd, |socket| socket.listen(backlog as usize))?;
+    Ok(0)
+}
+
+/// Accepts a connection on a listening socket, and returns a new file descriptor for it.
+/// The address of the other side is written as [write_sockaddr] does.
+fn sys_accept(fd: u64, addr: u64, len_ptr: u64) -> SyscallResult {
+    let (socket, peer) = with_socket(fd, Socket::accept)?;
+    write_sockaddr(addr, len_ptr, peer)?;
+    let process = scheduler::current_process().expect("system call outside of a process");
+    let fd = process.lock().files.insert(Arc::new(socket))?;
+    Ok(fd)
+}
+
+fn sys_connect(fd: u64, addr: u64, len: u64) -> SyscallResult {
+    let address = read_sockaddr(addr, len)?;
+    with_socket(fd, |socket| socket.connect(address))?;
+    Ok(0)
+}
+
+/// Sends bytes on a socket, to the address at `addr` or, if that is null,
+/// the address that the socket is connected to. There are no flags yet, so `flags` must be 0.
+fn sys_send(fd: u64, buf: u64, len: u64, flags: u64, addr: u64, addr_len: u64) -> SyscallResult {
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let destination = match addr {
+        0 => None,
+        addr => Some(read_sockaddr(addr, addr_len)?),
+    };
+    let bytes = user_bytes(buf, len)?;
+    Ok(with_socket(fd, |socket| socket.send(bytes, destination))? as u64)
+}
+
+/// Receives bytes from a socket. For datagrams, where they came from is written to `addr`
+/// as [write_sockaddr] does. There are no flags yet, so `flags` must be 0.
+fn sys_recv(fd: u64, buf: u64, len: u64, flags: u64, addr: u64, len_ptr: u64) -> SyscallResult {
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let bytes = user_bytes_mut(buf, len)?;
+    let (len, from) = with_socket(fd, |socket| socket.recv(bytes))?;
+    if let Some(from) = from {
+        write_sockaddr(addr, len_ptr, from)?;
+    }
+    Ok(len as u64)
+}
+
+/// The tick at which a timeout in milliseconds runs out, or `None` if it is negative,
+/// which means waiting for as long as it takes.
+fn deadline(timeout_ms: i64) -> Option<u64> {
+    let ticks = (timeout_ms as u64)
+        .saturating_mul(timer::TICKS_PER_SECOND)
+        .div_ceil(1000);
+    (timeout_ms >= 0).then(|| timer::ticks().saturating_add(ticks))
+}
+
+/// Waits until any of `nfds` file descriptors, described by the [PollFd]s at `fds`, is ready for
+/// one of the events that it asks for, or the timeout in milliseconds runs out.
+/// Errors and hang-ups are always reported, and file descriptors that are negative are skipped.
+/// Returns how many file descriptors have events, which is zero if the timeout ran out.
+fn sys_poll(fds: u64, nfds: u64, timeout_ms: u64) -> SyscallResult {
+    if nfds > MAX_FILES as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let len = nfds * size_of::<PollFd>() as u64;
+    // With no file descriptors, this just sleeps, and the list may be null.
+    let bytes = if nfds == 0 {
+        &[]
+    } else {
+        user_bytes(fds, len)?
+    };
+    let mut pollfds: Vec<PollFd> = bytes
+        .chunks_exact(size_of::<PollFd>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect();
+    let files: Vec<_> = {
+        let process = scheduler::current_process().expect("system call outside of a process");
+        let inner = process.lock();
+        pollfds
+            .iter()
+            .map(|pollfd| (pollfd.fd >= 0).then(|| inner.files.get(pollfd.fd as u64)))
+            .collect()
+    };
+    let ready = file::wait_ready(deadline(timeout_ms as i64), || {
+        let mut ready = 0;
+        for (pollfd, file) in pollfds.iter_mut().zip(&files) {
+            pollfd.revents = match file {
+                None => 0,
+                Some(Err(_)) => POLLNVAL,
+                Some(Ok(file)) => file.poll() & (pollfd.events | POLLERR | POLLHUP),
+            };
+            if pollfd.revents != 0 {
+                ready += 1;
+            }
+        }
+        (ready > 0).then_some(ready)
+    })?;
+    if nfds > 0 {
+        user_bytes_mut(fds, len)?.copy_from_slice(bytemuck::cast_slice(&pollfds));
+    }
+    Ok(ready.unwrap_or(0))
+}
+
+/// Waits until any of the first `nfds` file descriptors in the sets at `readfds`, `writefds` and
+/// `exceptfds` is ready, or the timeout in milliseconds runs out. Each set is a bitmap in 64-bit
+/// words, and may be null. Errors are the only exceptional condition.
+/// The sets are replaced by the file descriptors in them that are ready,
+/// and the number of those is returned, counting a file descriptor once for each set it is in.
+fn sys_select(
+    nfds: u64,
+    readfds: u64,
+    writefds: u64,
+    exceptfds: u64,
+    timeout_ms: u64,
+) -> SyscallResult {
+    /// The events that put a file descriptor in each set.
+    const EVENTS: [u16; 3] = [POLLIN | POLLHUP | POLLERR, POLLOUT | POLLERR, POLLERR];
+
+    if nfds > MAX_FILES as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let words = nfds.div_ceil(64) as usize;
+    let pointers = [readfds, writefds, exceptfds];
+    let mut sets = Vec::new();
+    for pointer in pointers {
+        sets.push(match pointer {
+            0 => vec![0u64; words],
+            pointer => user_bytes(pointer, words as u64 * 8)?
+                .chunks_exact(8)
+                .map(bytemuck::pod_read_unaligned)
+                .collect(),
+        });
+    }
+    let files = {
+        let process = scheduler::current_process().expect("system call outside of a process");
+        let inner = process.lock();
+        (0..nfds as usize)
+            .map(|fd| {
+                let wanted = sets.iter().any(|set| set[fd / 64] & 1 << (fd % 64) != 0);
+                wanted.then(|| inner.files.get(fd as u64)).transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let mut ready = sets.clone();
+    let count = file::wait_ready(deadline(timeout_ms as i64), || {
+        let mut count = 0;
+        for ready in &mut ready {
+            ready.fill(0);
+        }
+        for (fd, file) in files.iter().enumerate() {
+            let Some(file) = file else { continue };
+            let (word, bit) = (fd / 64, 1 << (fd % 64));
+            let events = file.poll();
+            for ((set, ready), wanted) in sets.iter().zip(&mut ready).zip(EVENTS) {
+                if set[word] & bit != 0 && events & wanted != 0 {
+                    ready[word] |= bit;
+                    count += 1;
+                }
+            }
+        }
+        (count > 0).then_some(count)
+    })?;
+    for (pointer, ready) in pointers.into_iter().zip(&ready) {
+        if pointer != 0 {
+            user_bytes_mut(pointer, words as u64 * 8)?.copy_from_slice(bytemuck::cast_slice(ready));
+        }
+    }
+    Ok(count.unwrap_or(0))
+}
@@ -0,0 +1,174 @@
+//! Reading of `ustar` archives, as produced by `tar`.
+//!
+//! The archive is a sequence of 512-byte blocks. Each file starts with a header block,
+//! followed by its contents padded to a whole number of blocks.
+//! The archive ends with two blocks of zeroes.
+
+use alloc::string::String;
+use core::str;
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const SIZE: core::ops::Range<usize> = 124..136;
+const KIND: usize = 156;
+const LINK_NAME: core::ops::Range<usize> = 157..257;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Another name for an earlier entry, whose path is the link target.
+    HardLink,
+    /// Devices, and anything else that we don't understand.
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// A header was not a `ustar` header, or its fields could not be parsed.
+    BadHeader,
+    /// A file's contents extend past the end of the archive.
+    Truncated,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    /// The path of this entry, without any leading `./` or `/`, or trailing `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    /// The permission bits.
+    pub mode: u32,
+    pub data: &'a [u8],
+    /// The target of a link, or empty for other kinds of entry.
+    pub link_target: &'a str,
+}
+
+/// An iterator over the entries of an archive.
+/// It stops at the end of the archive, or after returning the first error.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+/// Lists the entries of the given archive.
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries { data, offset: 0 }
+}
+
+/// Returns the contents of the given field, up to the first null byte.
+fn field(header: &[u8], range: core::ops::Range<usize>) -> Result<&str, TarError> {
+    let bytes = &header[range];
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).map_err(|_| TarError::BadHeader)
+}
+
+/// Numeric fields are octal, padded with spaces or null bytes.
+fn octal(header: &[u8], range: core::ops::Range<usize>) -> Result<usize, TarError> {
+    let text = field(header, range)?.trim_matches(' ');
+    usize::from_str_radix(text, 8).map_err(|_| TarError::BadHeader)
+}
+
+impl<'a> Entries<'a> {
+    fn parse_entry(&mut self) -> Result<Option<Entry<'a>>, TarError> {
+        let Some(header) = self.data.get(self.offset..self.offset + BLOCK_SIZE) else {
+            // Some writers leave out the final zero blocks.
+            return Ok(None);
+        };
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        if header[MAGIC] != *b"ustar" {
+            return Err(TarError::BadHeader);
+        }
+
+        let size = octal(header, SIZE)?;
+        let kind = match header[KIND] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'1' => EntryKind::HardLink,
+            b'2' => EntryKind::Symlink,
+            other => EntryKind::Other(other),
+        };
+        let prefix = field(header, PREFIX)?;
+        let name = field(header, NAME)?;
+        let mut path = String::from(prefix);
+        if !prefix.is_empty() {
+            path.push('/');
+        }
+        path.push_str(name);
+        let path = path
+            .trim_start_matches("./")
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .into();
+
+        let data_start = self.offset + BLOCK_SIZE;
+        let data = self
+            .data
+            .get(data_start..data_start + size)
+            .ok_or(TarError::Truncated)?;
+        self.offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+
+        Ok(Some(Entry {
+            path,
+            kind,
+            // Some writers leave the mode empty.
+            mode: octal(header, MODE).unwrap_or(0o644) as u32 & 0o7777,
+            data,
+            link_target: field(header, LINK_NAME)?,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.parse_entry().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.offset = self.data.len();
+        }
+        result
+    }
+}
+
+#[test_case]
+fn test_entries() {
+    use alloc::vec::Vec;
+
+    fn header(name: &str, kind: u8, size: usize) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = alloc::format!("{size:011o}");
+        header[SIZE.start..SIZE.start + size.len()].copy_from_slice(size.as_bytes());
+        header[KIND] = kind;
+        header[MAGIC].copy_from_slice(b"ustar");
+        header
+    }
+
+    let mut archive = Vec::new();
+    archive.extend_from_slice(&header("./bin/", b'5', 0));
+    archive.extend_from_slice(&header("./bin/hello", b'0', 5));
+    archive.extend_from_slice(b"hello");
+    archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+    archive.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+
+    let list: Vec<_> = entries(&archive).map(Result::unwrap).collect();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].path, "bin");
+    assert_eq!(list[0].kind, EntryKind::Directory);
+    assert_eq!(list[1].path, "bin/hello");
+    assert_eq!(list[1].kind, EntryKind::File);
+    assert_eq!(list[1].data, b"hello");
+
+    // Truncating the archive in the middle of a file is an error.
+    assert!(matches!(
+        entries(&archive[..BLOCK_SIZE * 2 + 2]).nth(1),
+        Some(Err(TarError::Truncated))
+    ));
+}
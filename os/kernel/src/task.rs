@@ -0,0 +1,266 @@
+//! Threads of execution that the scheduler can switch between.
+//!
+//! Every task has its own kernel stack. A task's registers are saved on that stack when it switches
+//! away, and its stack pointer is saved in the task itself. User processes are run by tasks too:
+//! such a task spends most of its life in user mode, and only runs on its kernel stack while handling
+//! an interrupt or system call.
+
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::{
+    arch::global_asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::{
+    fpu::FpuState,
+    process::Process,
+    scheduler,
+    sync::IrqMutex,
+    tls::{self, TlsBlock},
+    trap::{trap_return, TrapFrame},
+};
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting in the scheduler's queue.
+    Ready,
+    Running,
+    /// Waiting for something to call [scheduler::wake].
+    Blocked,
+    /// Finished, and will never run again.
+    Exited,
+}
+
+pub struct Task {
+    id: TaskId,
+    name: String,
+    state: IrqMutex<TaskState>,
+    /// The stack pointer of this task, saved while it is not running.
+    saved_rsp: UnsafeCell<u64>,
+    stack: Box<KernelStack>,
+    /// The function that a kernel thread runs, taken when it first starts.
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    /// The process that this task runs, or `None` for a kernel thread.
+    process: Option<Arc<Process>>,
+    /// The kernel's thread-local storage for this task.
+    tls: TlsBlock,
+    /// The thread pointer of the process, saved while the task is in the kernel.
+    user_thread_pointer: AtomicU64,
+    /// The floating point registers of the process, saved while the task is not running.
+    /// Kernel threads don't use floating point registers.
+    fpu: Option<UnsafeCell<FpuState>>,
+}
+
+/// The task whose floating point registers are loaded.
+static FPU_OWNER: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// `saved_rsp` and `fpu` are only touched while switching tasks, which happens with interrupts disabled.
+unsafe impl Sync for Task {}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    /// Saves the callee-saved registers on the current stack, stores the stack pointer in `old_rsp`,
+    /// and then restores the registers saved on the stack at `new_rsp`.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// The number of callee-saved registers pushed by [switch_context].
+const SAVED_REGISTERS: usize = 6;
+
+impl Task {
+    fn new(name: String, process: Option<Arc<Process>>, user_thread_pointer: u64) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            state: IrqMutex::new(TaskState::Ready),
+            saved_rsp: UnsafeCell::new(0),
+            stack: unsafe { Box::new_zeroed().assume_init() },
+            entry: Mutex::new(None),
+            tls: TlsBlock::new(),
+            user_thread_pointer: AtomicU64::new(user_thread_pointer),
+            fpu: process
+                .is_some()
+                .then(|| UnsafeCell::new(FpuState::initial())),
+            process,
+        }
+    }
+
+    /// Creates a task that runs `entry` in kernel mode.
+    /// The task must be given to [scheduler::spawn] to start running.
+    pub fn new_kernel(name: impl Into<String>, entry: impl FnOnce() + Send + 'static) -> Arc<Self> {
+        let task = Self::new(name.into(), None, 0);
+        *task.entry.lock() = Some(Box::new(entry));
+        // Pretend that `kernel_thread_entry` was called, and that it then called `switch_context`.
+        unsafe {
+            task.prepare_stack(&[0, kernel_thread_entry as *const () as u64]);
+        }
+        Arc::new(task)
+    }
+
+    /// Creates a task that runs the given process in user mode, starting from the state in `frame`
+    /// with the given thread pointer.
+    /// The task must be given to [scheduler::spawn] to start running.
+    pub fn new_user(
+        name: impl Into<String>,
+        process: Arc<Process>,
+        frame: TrapFrame,
+        thread_pointer: u64,
+    ) -> Arc<Self> {
+        let task = Self::new(name.into(), Some(process), thread_pointer);
+        // Pretend that `user_task_entry` was called by `trap_return`, and that it then called `switch_context`.
+        unsafe {
+            task.trap_frame().write(frame);
+            task.prepare_stack(&[
+                trap_return as *const () as u64,
+                user_task_entry as *const () as u64,
+            ]);
+        }
+        Arc::new(task)
+    }
+
+    /// Pushes the given words onto the kernel stack, below the trap frame area,
+    /// followed by zeroes for the registers that [switch_context] restores.
+    ///
+    /// # Safety
+    ///
+    /// The task must not have started running.
+    unsafe fn prepare_stack(&self, words: &[u64]) {
+        let mut rsp = self.trap_frame() as *mut u64;
+        for &word in words {
+            rsp = rsp.sub(1);
+            rsp.write(word);
+        }
+        for _ in 0..SAVED_REGISTERS {
+            rsp = rsp.sub(1);
+            rsp.write(0);
+        }
+        *self.saved_rsp.get() = rsp as u64;
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> TaskState {
+        *self.state.lock()
+    }
+
+    pub fn set_state(&self, state: TaskState) {
+        *self.state.lock() = state;
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    /// The address just past the end of this task's kernel stack.
+    pub fn stack_top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.stack.0.as_ptr_range().end)
+    }
+
+    /// The user state of this task is saved here whenever it enters the kernel from user mode.
+    /// Kernel threads don't use this area.
+    pub fn trap_frame(&self) -> *mut TrapFrame {
+        (self.stack_top().as_u64() as usize - size_of::<TrapFrame>()) as *mut TrapFrame
+    }
+
+    pub fn saved_rsp(&self) -> *mut u64 {
+        self.saved_rsp.get()
+    }
+
+    /// The thread pointer of this task's kernel thread-local storage.
+    pub fn kernel_thread_pointer(&self) -> u64 {
+        self.tls.thread_pointer()
+    }
+
+    /// Switches from the process's thread pointer to the kernel's, on entry to the kernel from user mode.
+    pub fn enter_kernel(&self) {
+        self.user_thread_pointer
+            .store(tls::current_thread_pointer(), Ordering::Relaxed);
+        tls::load(self.kernel_thread_pointer());
+    }
+
+    /// Switches back to the process's thread pointer, just before returning to user mode.
+    pub fn leave_kernel(&self) {
+        tls::load(self.user_thread_pointer.load(Ordering::Relaxed));
+    }
+
+    /// Loads this task's floating point registers, unless they are loaded already.
+    ///
+    /// # Safety
+    ///
+    /// This may only be called by the scheduler, with interrupts disabled, just before switching to this task.
+    pub unsafe fn restore_fpu(&self) {
+        if let Some(fpu) = &self.fpu {
+            if FPU_OWNER.swap(self.id.0, Ordering::Relaxed) != self.id.0 {
+                (*fpu.get()).restore();
+            }
+        }
+    }
+
+    /// Saves this task's floating point registers.
+    ///
+    /// # Safety
+    ///
+    /// This may only be called by the scheduler, with interrupts disabled, just after switching away from this task.
+    pub unsafe fn save_fpu(&self) {
+        if let Some(fpu) = &self.fpu {
+            (*fpu.get()).save();
+        }
+    }
+}
+
+/// User tasks start here, and then return into [trap_return] to enter user mode.
+extern "C" fn user_task_entry() {
+    scheduler::current()
+        .expect("user task started outside of the scheduler")
+        .leave_kernel();
+}
+
+extern "C" fn kernel_thread_entry() -> ! {
+    let entry = scheduler::current()
+        .expect("kernel thread started outside of the scheduler")
+        .entry
+        .lock()
+        .take()
+        .expect("kernel thread started twice");
+    interrupts::enable();
+    entry();
+    scheduler::exit_current();
+}
@@ -72,6 +72,11 @@ impl TerminalVideoBuffer {
         f(TERMINAL.lock().as_mut().unwrap())
     }
 
+    /// Like [Self::with_default], but returns `None` if a default terminal has not been assigned.
+    pub fn try_with_default<T>(f: impl FnOnce(&mut TerminalVideoBuffer) -> T) -> Option<T> {
+        TERMINAL.lock().as_mut().map(f)
+    }
+
     /// # Safety
     ///
     /// This function is extremely unsafe and only use it in very special circumstances!
@@ -86,6 +91,10 @@ impl TerminalVideoBuffer {
         }
     }
 
+    pub fn video_buffer(&self) -> &VideoBuffer {
+        &self.video_buffer
+    }
+
     /// Returns the width of this terminal in characters.
     pub fn width(&self) -> usize {
         self.video_buffer.width() / 8
@@ -0,0 +1,63 @@
+//! The programmable interval timer, which drives preemption and the kernel's sense of time.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+
+use x86_64::instructions::port::Port;
+
+use crate::{sync::IrqMutex, trap};
+
+pub const TIMER_IRQ: u8 = 0;
+pub const TICKS_PER_SECOND: u64 = 100;
+
+/// The frequency of the oscillator that drives the timer, in hertz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, access the low then high byte of the divisor, square wave mode.
+const PIT_SQUARE_WAVE: u8 = 0b0011_0110;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Called on every tick, from the interrupt handler.
+static TICK_HANDLERS: IrqMutex<Vec<fn()>> = IrqMutex::new(Vec::new());
+
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_SQUARE_WAVE);
+        let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    trap::set_irq_handler(TIMER_IRQ, tick);
+}
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    for handler in TICK_HANDLERS.lock().iter() {
+        handler();
+    }
+}
+
+/// Calls `handler` on every tick, from the timer's interrupt handler.
+/// Like any interrupt handler, it must not allocate or take locks that are not [IrqMutex]es.
+pub fn add_tick_handler(handler: fn()) {
+    TICK_HANDLERS.lock().push(handler);
+}
+
+/// The number of timer interrupts since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The time since the timer was started.
+pub fn uptime() -> Duration {
+    let ticks = ticks();
+    Duration::from_secs(ticks / TICKS_PER_SECOND)
+        + Duration::from_nanos((ticks % TICKS_PER_SECOND) * (1_000_000_000 / TICKS_PER_SECOND))
+}
@@ -0,0 +1,209 @@
+//! Thread-local storage, with the x86-64 ELF layout.
+//!
+//! Each thread has a TLS block holding its own copy of the `.tdata` and `.tbss` sections,
+//! which is made from a template in the executable. The thread pointer, which is kept in the
+//! FS base register, points just past the end of the block, and thread-local variables are accessed
+//! at negative offsets from it. The eight bytes at the thread pointer hold the thread pointer itself.
+//!
+//! The kernel's own template comes from the bootloader, and is empty unless the kernel has
+//! `#[thread_local]` statics, which for now only its tests do.
+//! Every task has a kernel TLS block, and the scheduler loads the block of each task that it switches to.
+//! While a task runs user code, the FS base holds the user program's thread pointer instead,
+//! so it is swapped whenever the task enters or leaves the kernel.
+//! User programs get a TLS block from their `PT_TLS` segment when they are loaded.
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+
+use spin::Once;
+use x86_64::{registers::model_specific::FsBase, PhysAddr, VirtAddr};
+
+use crate::{
+    elf::{Elf, SEGMENT_TLS},
+    memory::phys_to_virt,
+};
+
+/// The size of the thread control block at the thread pointer, which only holds the thread pointer.
+const TCB_SIZE: u64 = 8;
+
+/// The initial contents of each thread's TLS block.
+pub struct Template<'a> {
+    /// The initialised part of the block. The rest is zeroed.
+    data: &'a [u8],
+    memory_size: u64,
+    align: u64,
+}
+
+impl<'a> Template<'a> {
+    /// Returns `None` if the alignment is not a power of two, or the size is absurd.
+    pub fn new(data: &'a [u8], memory_size: u64, align: u64) -> Option<Self> {
+        let align = align.max(1);
+        if !align.is_power_of_two() || data.len() as u64 > memory_size {
+            return None;
+        }
+        memory_size
+            .checked_next_multiple_of(align)?
+            .checked_add(TCB_SIZE)?;
+        Some(Self {
+            data,
+            memory_size,
+            align,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn align(&self) -> u64 {
+        self.align
+    }
+
+    /// The distance from the start of a block to its thread pointer.
+    pub fn thread_pointer_offset(&self) -> u64 {
+        self.memory_size.next_multiple_of(self.align)
+    }
+
+    /// The size of a block, including the thread control block.
+    pub fn block_size(&self) -> u64 {
+        self.thread_pointer_offset() + TCB_SIZE
+    }
+}
+
+static KERNEL_TEMPLATE: Once<Template<'static>> = Once::new();
+
+/// The TLS block of the boot thread, which runs the scheduler loop.
+static BOOT_BLOCK: Once<TlsBlock> = Once::new();
+
+/// Reads the kernel's TLS template, and gives the boot thread a TLS block.
+/// This must be called after memory is initialised, and before any `#[thread_local]` statics are used.
+pub fn init(boot_info: &'static bootloader_api::BootInfo) {
+    let template = boot_info
+        .tls_template
+        .as_ref()
+        .map(|template| {
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    template.start_addr as *const u8,
+                    template.file_size as usize,
+                )
+            };
+            Template::new(data, template.mem_size, kernel_tls_align(boot_info))
+                .expect("bad kernel TLS template")
+        })
+        .unwrap_or(Template {
+            data: &[],
+            memory_size: 0,
+            align: 1,
+        });
+    KERNEL_TEMPLATE.call_once(|| template);
+    load(BOOT_BLOCK.call_once(TlsBlock::new).thread_pointer());
+}
+
+/// The bootloader doesn't tell us how the TLS block must be aligned,
+/// but the kernel's offsets into the block depend on it, so read it from the kernel's `PT_TLS` segment.
+fn kernel_tls_align(boot_info: &bootloader_api::BootInfo) -> u64 {
+    let kernel = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(PhysAddr::new(boot_info.kernel_addr)).as_ptr::<u8>(),
+            boot_info.kernel_len as usize,
+        )
+    };
+    Elf::parse(kernel)
+        .ok()
+        .and_then(|elf| {
+            elf.program_headers()
+                .find(|segment| segment.kind == SEGMENT_TLS)
+        })
+        .expect("kernel has a TLS template but no TLS segment")
+        .align
+}
+
+/// Sets the thread pointer.
+pub fn load(thread_pointer: u64) {
+    FsBase::write(VirtAddr::new(thread_pointer));
+}
+
+/// Returns the thread pointer.
+pub fn current_thread_pointer() -> u64 {
+    FsBase::read().as_u64()
+}
+
+/// The thread pointer of the boot thread.
+pub fn boot_thread_pointer() -> u64 {
+    BOOT_BLOCK
+        .get()
+        .expect("TLS not yet initialised")
+        .thread_pointer()
+}
+
+/// A kernel TLS block, made from the kernel's template.
+pub struct TlsBlock {
+    memory: *mut u8,
+    layout: Layout,
+}
+
+/// The block is only used by the task that owns it.
+unsafe impl Send for TlsBlock {}
+unsafe impl Sync for TlsBlock {}
+
+impl TlsBlock {
+    pub fn new() -> Self {
+        let template = KERNEL_TEMPLATE.get().expect("TLS not yet initialised");
+        let layout = Layout::from_size_align(
+            template.block_size() as usize,
+            template.align.max(TCB_SIZE) as usize,
+        )
+        .expect("bad kernel TLS template");
+        let memory = unsafe { alloc_zeroed(layout) };
+        if memory.is_null() {
+            handle_alloc_error(layout);
+        }
+        let block = Self { memory, layout };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                template.data.as_ptr(),
+                block.memory,
+                template.data.len(),
+            );
+            (block.thread_pointer() as *mut u64).write(block.thread_pointer());
+        }
+        block
+    }
+
+    pub fn thread_pointer(&self) -> u64 {
+        let template = KERNEL_TEMPLATE.get().expect("TLS not yet initialised");
+        self.memory as u64 + template.thread_pointer_offset()
+    }
+}
+
+impl Default for TlsBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, self.layout) };
+    }
+}
+
+#[test_case]
+fn test_thread_locals_are_per_block() {
+    use core::cell::Cell;
+
+    #[thread_local]
+    static COUNTER: Cell<u32> = Cell::new(7);
+
+    COUNTER.set(COUNTER.get() + 1);
+    let boot_value = COUNTER.get();
+
+    // A fresh block starts from the template.
+    let block = TlsBlock::new();
+    let boot = current_thread_pointer();
+    load(block.thread_pointer());
+    assert_eq!(COUNTER.get(), 7);
+    COUNTER.set(100);
+    load(boot);
+    assert_eq!(COUNTER.get(), boot_value);
+}
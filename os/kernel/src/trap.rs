@@ -0,0 +1,482 @@
+//! Entry points for interrupts that can arrive while user code is running.
+//!
+//! CPU exceptions that only the kernel can cause are handled by the `x86-interrupt` handlers in
+//! [crate::interrupts]. Everything else, including faults that user code can cause,
+//! enters through a small assembly stub that saves every register
+//! into a [TrapFrame] on the current task's kernel stack. The Rust handler can then inspect and modify
+//! the interrupted state, or switch to another task entirely.
+
+use alloc::sync::Arc;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytemuck::{Pod, Zeroable};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+    apic,
+    errno::Errno,
+    gdt,
+    memory::vma::Access,
+    pic, process, scheduler, serial_println,
+    signal::{SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP},
+    sync::IrqMutex,
+    syscall,
+    task::Task,
+    timer,
+};
+
+/// Exception vectors that user code can cause.
+const DIVIDE_ERROR: u8 = 0;
+const DEBUG: u8 = 1;
+const INVALID_OPCODE: u8 = 6;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT: u8 = 12;
+const GENERAL_PROTECTION: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+const X87_FLOATING_POINT: u8 = 16;
+const ALIGNMENT_CHECK: u8 = 17;
+const SIMD_FLOATING_POINT: u8 = 19;
+
+/// The name of an exception vector that user code can cause.
+pub fn exception_name(vector: u8) -> Option<&'static str> {
+    match vector {
+        DIVIDE_ERROR => Some("divide error"),
+        DEBUG => Some("debug"),
+        INVALID_OPCODE => Some("invalid opcode"),
+        SEGMENT_NOT_PRESENT => Some("segment not present"),
+        STACK_SEGMENT => Some("stack segment fault"),
+        GENERAL_PROTECTION => Some("general protection fault"),
+        PAGE_FAULT => Some("page fault"),
+        X87_FLOATING_POINT => Some("x87 floating point exception"),
+        ALIGNMENT_CHECK => Some("alignment check"),
+        SIMD_FLOATING_POINT => Some("SIMD floating point exception"),
+        _ => None,
+    }
+}
+
+/// The registers of an interrupted thread of execution, in the order that they are pushed by the entry stubs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero if the CPU does not push an error code for this vector.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// The interrupt flag in `RFLAGS`.
+const RFLAGS_IF: u64 = 1 << 9;
+/// This bit of `RFLAGS` is reserved and always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+impl TrapFrame {
+    /// A trap frame that starts executing user code at `entry` with the stack pointer `stack`.
+    pub fn new_user(entry: u64, stack: u64) -> Self {
+        Self {
+            rip: entry,
+            cs: gdt::user_code_selector().0 as u64,
+            rflags: RFLAGS_IF | RFLAGS_RESERVED,
+            rsp: stack,
+            ss: gdt::user_data_selector().0 as u64,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the interrupted code was running in ring 3.
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 3 == PrivilegeLevel::Ring3 as u64
+    }
+}
+
+/// Declares an entry stub for the given vector.
+/// Vectors for which the CPU does not push an error code push a zero instead,
+/// so that every [TrapFrame] has the same layout.
+macro_rules! trap_stub {
+    ($name:ident, $vector:literal) => {
+        trap_stub!($name, $vector, "push 0");
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        trap_stub!($name, $vector, "");
+    };
+    ($name:ident, $vector:literal, $push_error_code:literal) => {
+        global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            $push_error_code,
+            concat!("push ", stringify!($vector)),
+            "jmp trap_common",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+global_asm!(
+    "trap_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    // New tasks start here, with their stack pointer pointing at a trap frame.
+    ".global trap_return",
+    "trap_return:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // Skip the vector and error code.
+    "add rsp, 16",
+    "iretq",
+    dispatch = sym trap_dispatch,
+);
+
+extern "C" {
+    /// Restores the [TrapFrame] at the stack pointer.
+    /// This is not a real function, and may only be used as a return address.
+    pub fn trap_return();
+}
+
+trap_stub!(trap_divide_error, 0);
+trap_stub!(trap_debug, 1);
+trap_stub!(trap_invalid_opcode, 6);
+trap_stub!(trap_segment_not_present, 11, error_code);
+trap_stub!(trap_stack_segment, 12, error_code);
+trap_stub!(trap_general_protection, 13, error_code);
+trap_stub!(trap_page_fault, 14, error_code);
+trap_stub!(trap_x87_floating_point, 16);
+trap_stub!(trap_alignment_check, 17, error_code);
+trap_stub!(trap_simd_floating_point, 19);
+trap_stub!(trap_irq_0, 32);
+trap_stub!(trap_irq_1, 33);
+trap_stub!(trap_irq_2, 34);
+trap_stub!(trap_irq_3, 35);
+trap_stub!(trap_irq_4, 36);
+trap_stub!(trap_irq_5, 37);
+trap_stub!(trap_irq_6, 38);
+trap_stub!(trap_irq_7, 39);
+trap_stub!(trap_irq_8, 40);
+trap_stub!(trap_irq_9, 41);
+trap_stub!(trap_irq_10, 42);
+trap_stub!(trap_irq_11, 43);
+trap_stub!(trap_irq_12, 44);
+trap_stub!(trap_irq_13, 45);
+trap_stub!(trap_irq_14, 46);
+trap_stub!(trap_irq_15, 47);
+trap_stub!(trap_msi_0, 48);
+trap_stub!(trap_msi_1, 49);
+trap_stub!(trap_msi_2, 50);
+trap_stub!(trap_msi_3, 51);
+trap_stub!(trap_msi_4, 52);
+trap_stub!(trap_msi_5, 53);
+trap_stub!(trap_msi_6, 54);
+trap_stub!(trap_msi_7, 55);
+trap_stub!(trap_msi_8, 56);
+trap_stub!(trap_msi_9, 57);
+trap_stub!(trap_msi_10, 58);
+trap_stub!(trap_msi_11, 59);
+trap_stub!(trap_msi_12, 60);
+trap_stub!(trap_msi_13, 61);
+trap_stub!(trap_msi_14, 62);
+trap_stub!(trap_msi_15, 63);
+trap_stub!(trap_syscall, 0x80);
+
+/// The most handlers that can share a legacy interrupt line.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+type IrqHandlers = [Option<fn()>; MAX_SHARED_HANDLERS];
+
+/// Handlers for the legacy hardware interrupt lines.
+static IRQ_HANDLERS: IrqMutex<[IrqHandlers; 16]> = IrqMutex::new([[None; MAX_SHARED_HANDLERS]; 16]);
+
+/// Vectors that devices can raise by sending a message to the [apic], rather than on an interrupt line.
+pub const MSI_VECTOR_START: u8 = pic::PIC_2_END;
+pub const MSI_VECTOR_END: u8 = MSI_VECTOR_START + 16;
+
+type MsiHandler = Option<Arc<dyn Fn() + Send + Sync>>;
+
+/// Handlers for the message signalled interrupt vectors, which are handed out to drivers as they need them.
+static MSI_HANDLERS: IrqMutex<[MsiHandler; 16]> = IrqMutex::new([const { None }; 16]);
+
+/// Points the IDT entries for faults, hardware interrupts and system calls at the entry stubs.
+pub fn register_entries(idt: &mut InterruptDescriptorTable) {
+    let irq_stubs: [unsafe extern "C" fn(); 16] = [
+        trap_irq_0,
+        trap_irq_1,
+        trap_irq_2,
+        trap_irq_3,
+        trap_irq_4,
+        trap_irq_5,
+        trap_irq_6,
+        trap_irq_7,
+        trap_irq_8,
+        trap_irq_9,
+        trap_irq_10,
+        trap_irq_11,
+        trap_irq_12,
+        trap_irq_13,
+        trap_irq_14,
+        trap_irq_15,
+    ];
+    let msi_stubs: [unsafe extern "C" fn(); 16] = [
+        trap_msi_0,
+        trap_msi_1,
+        trap_msi_2,
+        trap_msi_3,
+        trap_msi_4,
+        trap_msi_5,
+        trap_msi_6,
+        trap_msi_7,
+        trap_msi_8,
+        trap_msi_9,
+        trap_msi_10,
+        trap_msi_11,
+        trap_msi_12,
+        trap_msi_13,
+        trap_msi_14,
+        trap_msi_15,
+    ];
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(VirtAddr::new(trap_divide_error as *const () as u64));
+        idt.debug
+            .set_handler_addr(VirtAddr::new(trap_debug as *const () as u64));
+        idt.invalid_opcode
+            .set_handler_addr(VirtAddr::new(trap_invalid_opcode as *const () as u64));
+        idt.segment_not_present
+            .set_handler_addr(VirtAddr::new(trap_segment_not_present as *const () as u64));
+        idt.stack_segment_fault
+            .set_handler_addr(VirtAddr::new(trap_stack_segment as *const () as u64));
+        idt.general_protection_fault
+            .set_handler_addr(VirtAddr::new(trap_general_protection as *const () as u64));
+        idt.page_fault
+            .set_handler_addr(VirtAddr::new(trap_page_fault as *const () as u64));
+        idt.x87_floating_point
+            .set_handler_addr(VirtAddr::new(trap_x87_floating_point as *const () as u64));
+        idt.alignment_check
+            .set_handler_addr(VirtAddr::new(trap_alignment_check as *const () as u64));
+        idt.simd_floating_point
+            .set_handler_addr(VirtAddr::new(trap_simd_floating_point as *const () as u64));
+        for (irq, stub) in irq_stubs.into_iter().enumerate() {
+            idt[pic::PIC_1_OFFSET + irq as u8]
+                .set_handler_addr(VirtAddr::new(stub as *const () as u64));
+        }
+        for (index, stub) in msi_stubs.into_iter().enumerate() {
+            idt[MSI_VECTOR_START + index as u8]
+                .set_handler_addr(VirtAddr::new(stub as *const () as u64));
+        }
+        idt[syscall::SYSCALL_VECTOR]
+            .set_handler_addr(VirtAddr::new(trap_syscall as *const () as u64))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+}
+
+/// How many times each vector has been raised, counting only the vectors handled here.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// The number of times that the given vector has been raised since boot.
+/// This is always zero for exceptions that only the kernel can cause.
+pub fn interrupt_count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Calls `handler` whenever the given legacy interrupt line is raised, and unmasks it.
+pub fn set_irq_handler(irq: u8, handler: fn()) {
+    let mut handlers = [None; MAX_SHARED_HANDLERS];
+    handlers[0] = Some(handler);
+    IRQ_HANDLERS.lock()[irq as usize] = handlers;
+    pic::unmask(irq);
+}
+
+/// Calls `handler` whenever the given legacy interrupt line is raised, as well as the handlers that
+/// it already has, and unmasks it. PCI devices may share lines, so each handler must check whether
+/// its own device interrupted. Returns `false` if the line already has as many handlers as it can.
+pub fn add_irq_handler(irq: u8, handler: fn()) -> bool {
+    let mut handlers = IRQ_HANDLERS.lock();
+    let Some(slot) = handlers[irq as usize]
+        .iter_mut()
+        .find(|slot| slot.is_none())
+    else {
+        return false;
+    };
+    *slot = Some(handler);
+    pic::unmask(irq);
+    true
+}
+
+/// Finds an unused message signalled interrupt vector, and calls `handler` whenever it is raised.
+/// Returns `None` if every vector is in use.
+pub fn allocate_msi_vector(handler: Arc<dyn Fn() + Send + Sync>) -> Option<u8> {
+    let mut handlers = MSI_HANDLERS.lock();
+    let index = handlers.iter().position(Option::is_none)?;
+    handlers[index] = Some(handler);
+    Some(MSI_VECTOR_START + index as u8)
+}
+
+/// Returns a vector from [allocate_msi_vector], once the device will no longer raise it.
+pub fn free_msi_vector(vector: u8) {
+    MSI_HANDLERS.lock()[(vector - MSI_VECTOR_START) as usize] = None;
+}
+
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    if frame.from_user_mode() {
+        current_task().enter_kernel();
+    }
+    COUNTS[frame.vector as u8 as usize].fetch_add(1, Ordering::Relaxed);
+
+    match frame.vector as u8 {
+        vector @ pic::PIC_1_OFFSET..pic::PIC_2_END => {
+            let irq = vector - pic::PIC_1_OFFSET;
+            if !pic::is_spurious(irq) {
+                let handlers = IRQ_HANDLERS.lock()[irq as usize];
+                for handler in handlers.into_iter().flatten() {
+                    handler();
+                }
+                pic::end_of_interrupt(irq);
+            }
+            // The kernel is not preemptible, but user code is.
+            if irq == timer::TIMER_IRQ && frame.from_user_mode() {
+                scheduler::yield_now();
+            }
+        }
+        vector @ MSI_VECTOR_START..MSI_VECTOR_END => {
+            let handler = MSI_HANDLERS.lock()[(vector - MSI_VECTOR_START) as usize].clone();
+            if let Some(handler) = handler {
+                handler();
+            }
+            apic::end_of_interrupt();
+        }
+        syscall::SYSCALL_VECTOR => syscall::dispatch(frame),
+        DIVIDE_ERROR => handle_fault(frame, SIGFPE, "DIVIDE ERROR"),
+        // User code single-stepping itself with the trap flag.
+        DEBUG => handle_fault(frame, SIGTRAP, "DEBUG"),
+        INVALID_OPCODE => handle_fault(frame, SIGILL, "INVALID OPCODE"),
+        SEGMENT_NOT_PRESENT => handle_fault(frame, SIGSEGV, "SEGMENT NOT PRESENT"),
+        // Such as pushing with a non-canonical stack pointer.
+        STACK_SEGMENT => handle_fault(frame, SIGBUS, "STACK SEGMENT FAULT"),
+        GENERAL_PROTECTION => handle_fault(frame, SIGSEGV, "GENERAL PROTECTION FAULT"),
+        PAGE_FAULT => handle_page_fault(frame),
+        // Unmasked floating point exceptions, reported natively since `fpu::init` sets `CR0.NE`.
+        X87_FLOATING_POINT => handle_fault(frame, SIGFPE, "X87 FLOATING POINT EXCEPTION"),
+        ALIGNMENT_CHECK => handle_fault(frame, SIGBUS, "ALIGNMENT CHECK"),
+        SIMD_FLOATING_POINT => handle_fault(frame, SIGFPE, "SIMD FLOATING POINT EXCEPTION"),
+        vector => panic!("unexpected trap {vector}\n{frame:#?}"),
+    }
+
+    interrupts::disable();
+    if frame.from_user_mode() {
+        process::before_return_to_user(frame);
+        current_task().leave_kernel();
+    }
+}
+
+fn current_task() -> Arc<Task> {
+    scheduler::current().expect("user mode trap outside of a task")
+}
+
+/// Maps the page that user code tried to touch, if it is allowed to.
+/// Otherwise, this is handled like any other fault.
+fn handle_page_fault(frame: &TrapFrame) {
+    // Read this before interrupts are enabled, as another page fault would overwrite it.
+    let address = Cr2::read_raw();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let mut signal = SIGSEGV;
+    if frame.from_user_mode() {
+        let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        let process = scheduler::current_process().expect("user mode fault outside of a process");
+        // Filling the page might block.
+        interrupts::enable();
+        match process.fault_in(address, access) {
+            Ok(()) => return,
+            Err(Errno::EFAULT) => {}
+            // The page could not be filled, for example because a file could not be read.
+            Err(_) => signal = SIGBUS,
+        }
+    }
+    let detail = alloc::format!("{error_code:?} at {address:#x}");
+    report_fault(frame, signal, "PAGE FAULT", &detail);
+}
+
+/// Sends a signal to the current process if it caused the fault, or panics if the kernel did.
+fn handle_fault(frame: &TrapFrame, signal: u32, name: &str) {
+    report_fault(
+        frame,
+        signal,
+        name,
+        &alloc::format!("code {}", frame.error_code),
+    );
+}
+
+fn report_fault(frame: &TrapFrame, signal: u32, name: &str, detail: &str) {
+    if !frame.from_user_mode() {
+        panic!("EXCEPTION: {name}, {detail}\n{frame:#?}");
+    }
+    let process = scheduler::current_process().expect("user mode fault outside of a process");
+    serial_println!(
+        "Process {} ({}) caused a {}, {}, at {:#x}.",
+        process.pid(),
+        process.name(),
+        name,
+        detail,
+        frame.rip
+    );
+    process.force_signal(signal);
+}
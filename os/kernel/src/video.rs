@@ -48,6 +48,16 @@ impl VideoBuffer {
         self.height
     }
 
+    /// Returns the number of bytes from the start of one row of pixels to the start of the next.
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    /// Returns the address of the first pixel. The buffer is `height * pitch` bytes long.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.addr.as_raw_ptr().as_ptr().cast()
+    }
+
     pub fn screen_rect(&self) -> Rect<usize> {
         Rect::new_zero_to_max(Vec2::new(self.width, self.height))
     }
@@ -0,0 +1,165 @@
+//! Virtio devices, which are what QEMU offers when it has no real hardware to pretend to be.
+//!
+//! Every virtio device works the same way: the driver and the device agree on a set of features,
+//! then exchange requests through [queue::Virtqueue]s in shared memory. Only the requests differ
+//! between kinds of device. We find virtio devices on the PCI bus, through the [transport].
+
+pub mod blk;
+pub mod net;
+pub mod queue;
+pub mod transport;
+
+use alloc::vec::Vec;
+
+use queue::Virtqueue;
+use transport::{Transport, NO_VECTOR};
+
+use crate::{
+    errno::Errno,
+    pci::{
+        self,
+        msi::{self, MsiX},
+        PciDevice,
+    },
+    trap,
+};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// Kinds of device.
+pub const DEVICE_NET: u16 = 1;
+pub const DEVICE_BLOCK: u16 = 2;
+
+/// Legacy and transitional devices have PCI device IDs in this range, and give their kind as
+/// their subsystem ID. Modern devices have the ID [MODERN_DEVICE_ID_BASE] plus their kind.
+const TRANSITIONAL_DEVICE_IDS: core::ops::Range<u16> = 0x1000..0x1040;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// Bits of the device status.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Set by devices that follow the virtio 1.0 specification, rather than the legacy interface.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// The PCI device IDs that a device of the given kind might have.
+pub const fn device_ids(kind: u16) -> [pci::Match; 2] {
+    [
+        pci::Match::Device(VENDOR_ID, TRANSITIONAL_DEVICE_IDS.start + kind - 1),
+        pci::Match::Device(VENDOR_ID, MODERN_DEVICE_ID_BASE + kind),
+    ]
+}
+
+fn is_transitional(device: &PciDevice) -> bool {
+    device.vendor_id == VENDOR_ID && TRANSITIONAL_DEVICE_IDS.contains(&device.device_id)
+}
+
+/// Registers the drivers for each kind of virtio device that we support.
+pub fn init() {
+    pci::register_driver(&blk::DRIVER);
+    pci::register_driver(&net::DRIVER);
+}
+
+/// A virtio device that has been reset and has agreed on features with us.
+pub struct Device {
+    pub transport: Transport,
+    /// The features that both the device and the driver support.
+    pub features: u64,
+    msix: Option<MsiX>,
+    vectors: Vec<u8>,
+}
+
+impl Device {
+    /// Resets the device, and agrees to use those of `supported` that it offers.
+    pub fn new(pci: &PciDevice, supported: u64) -> Result<Self, Errno> {
+        pci.enable_bus_master();
+        let msix = pci.enable_msix().ok();
+        let transport = Transport::new(pci, msix.is_some())?;
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut device = Self {
+            transport,
+            features: 0,
+            msix,
+            vectors: Vec::new(),
+        };
+        let offered = device.transport.device_features();
+        if device.transport.is_modern() && offered & F_VERSION_1 == 0 {
+            return Err(Errno::ENODEV);
+        }
+        let supported = if device.transport.is_modern() {
+            supported | F_VERSION_1
+        } else {
+            supported
+        };
+        device.features = offered & supported;
+        device.transport.set_driver_features(device.features)?;
+        device.transport.set_config_msix_entry(NO_VECTOR);
+        Ok(device)
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// Sets up the queue with the given index, with at most `max_size` descriptors.
+    ///
+    /// If the device has an MSI-X entry to spare, `handler` is called whenever the device uses
+    /// a chain. Otherwise, the device is asked not to interrupt at all,
+    /// and the driver must poll.
+    pub fn setup_queue(
+        &mut self,
+        index: u16,
+        max_size: u16,
+        handler: impl Fn() + Send + Sync + 'static,
+    ) -> Result<Virtqueue, Errno> {
+        let offered = self.transport.max_queue_size(index);
+        if offered == 0 {
+            return Err(Errno::ENODEV);
+        }
+        let size = if self.transport.is_modern() {
+            // Any power of two up to the device's maximum will do.
+            1 << offered.min(max_size).ilog2()
+        } else {
+            offered
+        };
+        if !size.is_power_of_two() {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut queue = Virtqueue::new(index, size)?;
+        match &self.msix {
+            Some(msix) if index < msix.len() => {
+                let vector = msi::allocate_vector(handler)?;
+                self.vectors.push(vector);
+                msix.route(index, vector);
+                self.transport.setup_queue(&mut queue, index)?;
+            }
+            _ => {
+                queue.set_interrupts(false);
+                self.transport.setup_queue(&mut queue, NO_VECTOR)?;
+            }
+        }
+        Ok(queue)
+    }
+
+    /// Tells the device that the driver is ready, so it may start using the queues.
+    pub fn ready(&self) {
+        self.transport.add_status(STATUS_DRIVER_OK);
+    }
+}
+
+impl Drop for Device {
+    /// Drivers keep their devices forever, so this only happens when a driver gives up on one.
+    fn drop(&mut self) {
+        self.transport.add_status(STATUS_FAILED);
+        self.transport.reset();
+        for &vector in &self.vectors {
+            trap::free_msi_vector(vector);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Virtio block devices, which QEMU attaches with `-drive if=virtio`.
+//!
+//! Each request is a chain of three buffers: a header saying what to do and where,
+//! the data, and a byte in which the device reports whether it worked.
+//! Requests go through a bounce buffer, since the caller's buffer need not be physically contiguous,
+//! and we only make one at a time.
+
+use alloc::{format, sync::Arc, vec::Vec};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Mutex;
+
+use super::{
+    queue::{Buffer, Virtqueue},
+    Device, DEVICE_BLOCK,
+};
+use crate::{
+    block::{self, BlockDevice},
+    errno::Errno,
+    memory::dma::DmaBuffer,
+    pci::{self, PciDevice},
+    sync,
+};
+
+const MATCHES: [pci::Match; 2] = super::device_ids(DEVICE_BLOCK);
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    matches: &MATCHES,
+    probe,
+};
+
+/// Feature bits.
+const F_SIZE_MAX: u64 = 1 << 1;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Offsets in the device configuration.
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+
+/// Kinds of request.
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+/// Values of the status byte.
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Capacities and requests are always counted in these, whatever the device's block size.
+const SECTOR_SIZE: usize = 512;
+
+/// The most that a single request will move.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// Where each part of a request lives in the bounce buffer.
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = SECTOR_SIZE;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// The virtio disks found so far, in order of their names.
+static DISKS: Mutex<Vec<Arc<VirtioBlk>>> = Mutex::new(Vec::new());
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    let mut device = Device::new(pci, F_SIZE_MAX | F_RO | F_FLUSH)?;
+    // The device interrupting is enough to wake up a driver waiting for it.
+    let queue = device.setup_queue(0, 64, || {})?;
+    device.ready();
+
+    let max_transfer = if device.has_feature(F_SIZE_MAX) {
+        let size_max = device.transport.config_u32(CONFIG_SIZE_MAX) as usize;
+        (size_max - size_max % SECTOR_SIZE).clamp(SECTOR_SIZE, MAX_TRANSFER)
+    } else {
+        MAX_TRANSFER
+    };
+    let disk = Arc::new(VirtioBlk {
+        sector_count: device.transport.config_u64(CONFIG_CAPACITY),
+        read_only: device.has_feature(F_RO),
+        max_transfer,
+        inner: Mutex::new(Inner {
+            queue,
+            buffer: DmaBuffer::new(DATA_OFFSET + max_transfer).map_err(|_| Errno::ENOMEM)?,
+            device,
+        }),
+    });
+
+    let name = {
+        let mut disks = DISKS.lock();
+        disks.push(disk.clone());
+        format!("vd{}", (b'a' + disks.len() as u8 - 1) as char)
+    };
+    block::add_disk(&name, disk);
+    Ok(())
+}
+
+pub struct VirtioBlk {
+    sector_count: u64,
+    read_only: bool,
+    max_transfer: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    device: Device,
+    queue: Virtqueue,
+    buffer: DmaBuffer,
+}
+
+impl Inner {
+    /// Makes a request with `len` bytes of data, already in the bounce buffer if it is a write,
+    /// and waits for the device to finish it.
+    fn request(&mut self, kind: u32, sector: u64, len: usize) -> Result<(), Errno> {
+        self.buffer.write(
+            HEADER_OFFSET,
+            RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            },
+        );
+        self.buffer.write::<u8>(STATUS_OFFSET, 0xff);
+
+        let header = Buffer {
+            addr: self.buffer.phys(HEADER_OFFSET),
+            len: size_of::<RequestHeader>() as u32,
+            writable: false,
+        };
+        let data = Buffer {
+            addr: self.buffer.phys(DATA_OFFSET),
+            len: len as u32,
+            writable: kind == REQUEST_IN,
+        };
+        let status = Buffer {
+            addr: self.buffer.phys(STATUS_OFFSET),
+            len: 1,
+            writable: true,
+        };
+        if len == 0 {
+            self.queue.add(&[header, status])?;
+        } else {
+            self.queue.add(&[header, data, status])?;
+        }
+        self.device.transport.notify(&self.queue);
+        sync::wait_for_device(|| self.queue.pop_used());
+
+        match self.buffer.read::<u8>(STATUS_OFFSET) {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(Errno::EINVAL),
+            _ => Err(Errno::EIO),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks_mut(self.max_transfer).enumerate() {
+            let start = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+            inner.request(REQUEST_IN, start, chunk.len())?;
+            inner.buffer.read_bytes(DATA_OFFSET, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check_range(self, sector, buf.len())?;
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks(self.max_transfer).enumerate() {
+            let start = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+            inner.buffer.write_bytes(DATA_OFFSET, chunk);
+            inner.request(REQUEST_OUT, start, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        let mut inner = self.inner.lock();
+        if !inner.device.has_feature(F_FLUSH) || self.read_only {
+            return Ok(());
+        }
+        inner.request(REQUEST_FLUSH, 0, 0)
+    }
+}
+
+#[test_case]
+fn test_virtio_blk() {
+    use alloc::vec;
+
+    // The test runner attaches the ext2 image as a virtio disk, if it could build one.
+    let Some(disk) = DISKS.lock().first().cloned() else {
+        return;
+    };
+    let mut buf = vec![0; 2 * SECTOR_SIZE];
+    disk.read_sectors(2, &mut buf).unwrap();
+    assert_eq!(buf[56..58], 0xef53u16.to_le_bytes());
+
+    // Nothing lives in the first sector of an ext2 filesystem, and QEMU throws the change away.
+    let data: Vec<u8> = (0..MAX_TRANSFER + SECTOR_SIZE)
+        .map(|i| (i / 3) as u8)
+        .collect();
+    let mut saved = vec![0; data.len()];
+    disk.read_sectors(0, &mut saved).unwrap();
+    disk.write_sectors(0, &data[..SECTOR_SIZE]).unwrap();
+    disk.flush().unwrap();
+    disk.read_sectors(0, &mut buf[..SECTOR_SIZE]).unwrap();
+    assert_eq!(buf[..SECTOR_SIZE], data[..SECTOR_SIZE]);
+    // A transfer that needs more than one request.
+    let mut whole = vec![0; data.len()];
+    disk.read_sectors(0, &mut whole).unwrap();
+    assert_eq!(whole[..SECTOR_SIZE], data[..SECTOR_SIZE]);
+    assert_eq!(whole[SECTOR_SIZE..], saved[SECTOR_SIZE..]);
+    disk.write_sectors(0, &saved[..SECTOR_SIZE]).unwrap();
+
+    assert_eq!(
+        disk.read_sectors(disk.sector_count(), &mut buf),
+        Err(Errno::ENXIO)
+    );
+}
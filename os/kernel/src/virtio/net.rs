@@ -0,0 +1,236 @@
+//! Virtio network cards, which QEMU attaches with `-device virtio-net-pci`.
+//!
+//! The device fills the buffers that we put on the receive queue with the frames that arrive,
+//! and sends the frames that we put on the transmit queue. Each frame is preceded by a header
+//! describing checksum and segmentation offloads. We don't ask for any, so ours are all zero.
+//! Each queue has one buffer per descriptor, all carved out of a single DMA buffer,
+//! so sending and receiving frames never allocates device memory.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::Mutex;
+
+use super::{
+    queue::{Buffer, Virtqueue},
+    Device, DEVICE_NET, F_VERSION_1,
+};
+use crate::{
+    errno::Errno,
+    memory::dma::DmaBuffer,
+    net::{self, MacAddress, NetworkInterface, ReceiveCallback, Receiver},
+    pci::{self, PciDevice},
+    sync,
+};
+
+const MATCHES: [pci::Match; 2] = super::device_ids(DEVICE_NET);
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-net",
+    matches: &MATCHES,
+    probe,
+};
+
+/// Feature bits.
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+/// Offsets in the device configuration.
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+
+/// Bits of the link status.
+const STATUS_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// Legacy devices leave out the last field of the header,
+/// which counts merged receive buffers, unless that feature is agreed on.
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+/// Every buffer has room for a header and the largest frame.
+const BUFFER_SIZE: usize = 2048;
+
+/// The virtio network cards found so far.
+static CARDS: Mutex<Vec<Arc<VirtioNet>>> = Mutex::new(Vec::new());
+
+fn probe(pci: &Arc<PciDevice>) -> Result<(), Errno> {
+    let mut device = Device::new(pci, F_MAC | F_STATUS)?;
+    let receive = device.setup_queue(RECEIVE_QUEUE, MAX_QUEUE_SIZE, net::wake)?;
+    // Sent buffers are taken back when we next need one, so there is no need to hear about them.
+    let transmit = device.setup_queue(TRANSMIT_QUEUE, MAX_QUEUE_SIZE, || {})?;
+    transmit.set_interrupts(false);
+
+    let mac = if device.has_feature(F_MAC) {
+        MacAddress(core::array::from_fn(|i| {
+            device.transport.config_u8(CONFIG_MAC + i)
+        }))
+    } else {
+        // A locally administered address that is unique to this machine.
+        let address = pci.address;
+        MacAddress([0x02, 0, 0, address.bus, address.device, address.function])
+    };
+    let header_size = if device.has_feature(F_VERSION_1) {
+        HEADER_SIZE
+    } else {
+        LEGACY_HEADER_SIZE
+    };
+
+    let mut receive = Queue::new(receive)?;
+    for slot in 0..receive.slots.len() {
+        receive.add(slot, BUFFER_SIZE, true)?;
+    }
+    let transmit = Queue::new(transmit)?;
+    device.ready();
+    device.transport.notify(&receive.queue);
+
+    let card = Arc::new(VirtioNet {
+        mac,
+        header_size,
+        device,
+        receive: Mutex::new(receive),
+        transmit: Mutex::new(transmit),
+        receiver: Receiver::new(),
+    });
+    CARDS.lock().push(card.clone());
+    net::add_interface(card);
+    Ok(())
+}
+
+pub struct VirtioNet {
+    mac: MacAddress,
+    header_size: usize,
+    device: Device,
+    receive: Mutex<Queue>,
+    transmit: Mutex<Queue>,
+    receiver: Receiver,
+}
+
+/// A virtqueue, and the buffers that its descriptors point to.
+struct Queue {
+    queue: Virtqueue,
+    buffers: DmaBuffer,
+    /// The buffer that each descriptor in use points to.
+    slots: Vec<usize>,
+    /// The buffers that the device doesn't have.
+    free: Vec<usize>,
+}
+
+impl Queue {
+    fn new(queue: Virtqueue) -> Result<Self, Errno> {
+        let size = queue.size() as usize;
+        Ok(Self {
+            buffers: DmaBuffer::new(size * BUFFER_SIZE).map_err(|_| Errno::ENOMEM)?,
+            queue,
+            slots: vec![0; size],
+            free: (0..size).collect(),
+        })
+    }
+
+    /// Gives the device the first `len` bytes of a buffer.
+    /// The caller must notify the device.
+    fn add(&mut self, slot: usize, len: usize, writable: bool) -> Result<(), Errno> {
+        let head = self.queue.add(&[Buffer {
+            addr: self.buffers.phys(slot * BUFFER_SIZE),
+            len: len as u32,
+            writable,
+        }])?;
+        self.slots[head as usize] = slot;
+        self.free.retain(|&free| free != slot);
+        Ok(())
+    }
+
+    /// Takes back a buffer that the device is done with,
+    /// returning it and the number of bytes that the device wrote to it.
+    fn pop_used(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let slot = self.slots[head as usize];
+        self.free.push(slot);
+        Some((slot, len as usize))
+    }
+}
+
+impl VirtioNet {
+    /// Takes the next frame that has arrived, and gives its buffer back to the device.
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut receive = self.receive.lock();
+        let (slot, len) = receive.pop_used()?;
+        let mut frame = vec![0; len.saturating_sub(self.header_size)];
+        receive
+            .buffers
+            .read_bytes(slot * BUFFER_SIZE + self.header_size, &mut frame);
+        // There was a descriptor for this buffer a moment ago, so there still is.
+        receive.add(slot, BUFFER_SIZE, true).unwrap();
+        self.device.transport.notify(&receive.queue);
+        Some(frame)
+    }
+}
+
+impl NetworkInterface for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.device.has_feature(F_STATUS)
+            || self.device.transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Errno> {
+        if frame.len() > net::MAX_FRAME_SIZE {
+            return Err(Errno::EMSGSIZE);
+        }
+        if !self.link_up() {
+            return Err(Errno::ENETDOWN);
+        }
+
+        let mut transmit = self.transmit.lock();
+        while transmit.pop_used().is_some() {}
+        if transmit.free.is_empty() {
+            // Every buffer is queued, so one will come back soon.
+            sync::wait_for_device(|| transmit.pop_used());
+        }
+        let slot = transmit.free[0];
+        let offset = slot * BUFFER_SIZE;
+        let len = self.header_size + frame.len().max(net::MIN_FRAME_SIZE);
+        transmit
+            .buffers
+            .write_bytes(offset, &[0; HEADER_SIZE][..self.header_size]);
+        transmit
+            .buffers
+            .write_bytes(offset + self.header_size, frame);
+        if frame.len() < net::MIN_FRAME_SIZE {
+            transmit.buffers.write_bytes(
+                offset + self.header_size + frame.len(),
+                &[0; net::MIN_FRAME_SIZE][frame.len()..],
+            );
+        }
+        transmit.add(slot, len, false)?;
+        self.device.transport.notify(&transmit.queue);
+        Ok(())
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        self.receiver.set(callback);
+    }
+
+    fn poll(&self) -> usize {
+        let mut count = 0;
+        while let Some(frame) = self.receive() {
+            self.receiver.deliver(&frame);
+            count += 1;
+        }
+        count
+    }
+}
+
+#[test_case]
+fn test_virtio_net() {
+    // The test runner gives QEMU's user-mode network a virtio card.
+    let Some(card) = CARDS.lock().first().cloned() else {
+        return;
+    };
+    net::test_card(&*card);
+}
@@ -0,0 +1,275 @@
+//! Split virtqueues, which carry requests from the driver to the device and back.
+//!
+//! A queue is three arrays in memory that both sides can see. The driver describes each request
+//! as a chain of descriptors, each pointing at a buffer that the device either reads or writes,
+//! and puts the first descriptor of the chain in the available ring. When the device is done
+//! with a chain, it puts it in the used ring, along with how many bytes it wrote.
+
+use core::sync::atomic::{fence, Ordering};
+
+use bytemuck::{Pod, Zeroable};
+use x86_64::PhysAddr;
+
+use crate::{errno::Errno, memory::dma::DmaBuffer};
+
+/// Legacy devices need the used ring to start on a page boundary.
+const USED_ALIGN: usize = 4096;
+
+/// Bits of [Descriptor::flags].
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+/// Set in the available ring's flags to ask the device not to interrupt when it uses a chain.
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// One buffer of a request.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer, rather than reading from it.
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    /// The first descriptor that is not part of a chain, with the rest linked through their `next` fields.
+    free_head: u16,
+    free_count: u16,
+    /// Our copy of the available ring's index.
+    avail_index: u16,
+    /// The number of used elements that we have taken.
+    used_index: u16,
+    /// Where to write to tell the device about new requests, which depends on the transport.
+    pub(super) notify_offset: usize,
+}
+
+impl Virtqueue {
+    /// Allocates a queue with the given number of descriptors, which must be a power of two.
+    pub fn new(index: u16, size: u16) -> Result<Self, Errno> {
+        assert!(
+            size.is_power_of_two(),
+            "virtqueue size must be a power of two"
+        );
+        let n = size as usize;
+        let avail_offset = n * size_of::<Descriptor>();
+        let used_offset = (avail_offset + 6 + 2 * n).next_multiple_of(USED_ALIGN);
+        let memory = DmaBuffer::new(used_offset + 6 + n * size_of::<UsedElement>())
+            .map_err(|_| Errno::ENOMEM)?;
+        for i in 0..size {
+            memory.write(
+                i as usize * size_of::<Descriptor>(),
+                Descriptor {
+                    next: i.wrapping_add(1),
+                    ..Zeroable::zeroed()
+                },
+            );
+        }
+        Ok(Self {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            used_index: 0,
+            notify_offset: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The physical address of the descriptor table, which is also the start of the whole queue.
+    pub fn descriptors_phys(&self) -> PhysAddr {
+        self.memory.phys(0)
+    }
+
+    pub fn avail_phys(&self) -> PhysAddr {
+        self.memory.phys(self.avail_offset)
+    }
+
+    pub fn used_phys(&self) -> PhysAddr {
+        self.memory.phys(self.used_offset)
+    }
+
+    /// Whether the device should interrupt when it uses a chain.
+    pub fn set_interrupts(&self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAIL_NO_INTERRUPT };
+        self.memory.write::<u16>(self.avail_offset, flags);
+    }
+
+    /// The number of descriptors that are not in use.
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    fn descriptor_offset(&self, index: u16) -> usize {
+        index as usize * size_of::<Descriptor>()
+    }
+
+    /// Puts a request made of the given buffers in the available ring, and returns the index of its
+    /// first descriptor, which identifies it when the device is done with it.
+    /// The device must then be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, Errno> {
+        if buffers.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+        if buffers.len() > self.free_count as usize {
+            return Err(Errno::ENOSPC);
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let offset = self.descriptor_offset(index);
+            let next = self.memory.read::<Descriptor>(offset).next;
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            self.memory.write(
+                offset,
+                Descriptor {
+                    addr: buffer.addr.as_u64(),
+                    len: buffer.len,
+                    flags,
+                    next,
+                },
+            );
+            if i + 1 < buffers.len() {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = self.avail_offset + 4 + (self.avail_index % self.size) as usize * 2;
+        self.memory.write::<u16>(slot, head);
+        // The device must see the descriptors and the ring entry before the new index.
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        self.memory
+            .write::<u16>(self.avail_offset + 2, self.avail_index);
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether the device has finished with a chain that we have not yet taken.
+    pub fn has_used(&self) -> bool {
+        self.memory.read::<u16>(self.used_offset + 2) != self.used_index
+    }
+
+    /// Takes the next chain that the device is done with, returning the index of its first
+    /// descriptor and the number of bytes that the device wrote. Its descriptors are freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        // Read the element only after seeing the index.
+        fence(Ordering::SeqCst);
+        let slot = self.used_offset + 4 + (self.used_index % self.size) as usize * 8;
+        let element = self.memory.read::<UsedElement>(slot);
+        self.used_index = self.used_index.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            let descriptor = self
+                .memory
+                .read::<Descriptor>(self.descriptor_offset(index));
+            self.free_count += 1;
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                // Put the whole chain back at the front of the free list.
+                self.memory.write(
+                    self.descriptor_offset(index),
+                    Descriptor {
+                        next: self.free_head,
+                        ..descriptor
+                    },
+                );
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+        Some((head, element.len))
+    }
+}
+
+#[test_case]
+fn test_virtqueue() {
+    let mut queue = Virtqueue::new(0, 4).unwrap();
+    let buffer = |addr, writable| Buffer {
+        addr: PhysAddr::new(addr),
+        len: 16,
+        writable,
+    };
+    let first = queue
+        .add(&[buffer(0x1000, false), buffer(0x2000, true)])
+        .unwrap();
+    let second = queue
+        .add(&[buffer(0x3000, false), buffer(0x4000, true)])
+        .unwrap();
+    assert_eq!(queue.free_count(), 0);
+    assert_eq!(queue.add(&[buffer(0x5000, false)]), Err(Errno::ENOSPC));
+    assert_eq!(queue.memory.read::<u16>(queue.avail_offset + 2), 2);
+    let descriptor = queue
+        .memory
+        .read::<Descriptor>(queue.descriptor_offset(first));
+    assert_eq!(descriptor.flags, DESCRIPTOR_NEXT);
+    let descriptor = queue
+        .memory
+        .read::<Descriptor>(queue.descriptor_offset(descriptor.next));
+    assert_eq!(
+        (descriptor.addr, descriptor.flags),
+        (0x2000, DESCRIPTOR_WRITE)
+    );
+
+    // Pretend to be the device, finishing the second request first.
+    assert!(!queue.has_used());
+    queue.memory.write(
+        queue.used_offset + 4,
+        UsedElement {
+            id: second as u32,
+            len: 7,
+        },
+    );
+    queue.memory.write::<u16>(queue.used_offset + 2, 1);
+    assert_eq!(queue.pop_used(), Some((second, 7)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free_count(), 2);
+
+    // The freed descriptors are reused.
+    let third = queue.add(&[buffer(0x6000, false)]).unwrap();
+    assert_eq!(third, second);
+    assert_eq!(queue.free_count(), 1);
+}
@@ -0,0 +1,357 @@
+//! Talking to a virtio device over PCI.
+//!
+//! Modern devices describe where their registers are with vendor-specific PCI capabilities,
+//! each pointing at part of a memory BAR. Legacy devices, which are what QEMU gives us with
+//! `disable-modern=on`, have a fixed layout of registers in I/O space instead.
+//! Transitional devices, QEMU's default, offer both, in which case we use the modern interface.
+
+use x86_64::instructions::port::Port;
+
+use super::{queue::Virtqueue, STATUS_FEATURES_OK};
+use crate::{
+    errno::Errno,
+    memory::mmio::Mmio,
+    pci::{PciDevice, CAPABILITY_VENDOR},
+};
+
+/// Values of the `cfg_type` field of a virtio capability.
+const COMMON_CONFIG: u8 = 1;
+const NOTIFY_CONFIG: u8 = 2;
+const ISR_CONFIG: u8 = 3;
+const DEVICE_CONFIG: u8 = 4;
+
+/// Offsets of registers in the modern common configuration structure.
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0c;
+    pub const CONFIG_MSIX_VECTOR: usize = 0x10;
+    pub const NUM_QUEUES: usize = 0x12;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR: usize = 0x1a;
+    pub const QUEUE_ENABLE: usize = 0x1c;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1e;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+}
+
+/// Offsets of registers in the legacy I/O space.
+mod legacy {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const GUEST_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0c;
+    pub const QUEUE_SELECT: u16 = 0x0e;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const ISR_STATUS: u16 = 0x13;
+    pub const CONFIG_MSIX_VECTOR: u16 = 0x14;
+    pub const QUEUE_MSIX_VECTOR: u16 = 0x16;
+    /// The device-specific configuration starts here, or after the MSI-X registers if MSI-X is on.
+    pub const CONFIG: u16 = 0x14;
+    pub const CONFIG_WITH_MSIX: u16 = 0x18;
+    /// Queue addresses are given as page numbers.
+    pub const QUEUE_ADDRESS_SHIFT: u32 = 12;
+}
+
+/// Written to an MSI-X vector register to have no interrupt at all.
+pub const NO_VECTOR: u16 = 0xffff;
+
+pub enum Transport {
+    Modern {
+        common: Mmio,
+        notify: Mmio,
+        notify_multiplier: u32,
+        isr: Mmio,
+        device: Option<Mmio>,
+    },
+    Legacy {
+        port: u16,
+        msix: bool,
+    },
+}
+
+impl Transport {
+    /// Finds the device's registers. Legacy devices lay them out differently when MSI-X is enabled,
+    /// so this must be told whether it is.
+    pub fn new(device: &PciDevice, msix: bool) -> Result<Self, Errno> {
+        if let Some(modern) = Self::modern(device)? {
+            return Ok(modern);
+        }
+        if !super::is_transitional(device) {
+            return Err(Errno::ENODEV);
+        }
+        Ok(Self::Legacy {
+            port: device.io_bar(0)?,
+            msix,
+        })
+    }
+
+    fn modern(device: &PciDevice) -> Result<Option<Self>, Errno> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+        let mut bars: [Option<Mmio>; 6] = [None; 6];
+        for (id, offset) in device.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let kind = device.read_u8(offset + 3);
+            let bar = device.read_u8(offset + 4) as usize;
+            let start = device.read_u32(offset + 8) as usize;
+            let len = device.read_u32(offset + 12) as usize;
+            let slot = match kind {
+                COMMON_CONFIG => &mut common,
+                NOTIFY_CONFIG => &mut notify,
+                ISR_CONFIG => &mut isr,
+                DEVICE_CONFIG => &mut config,
+                _ => continue,
+            };
+            // Use the first capability of each type that refers to a memory BAR.
+            if slot.is_none() && bar < 6 {
+                if bars[bar].is_none() {
+                    bars[bar] = device.map_bar(bar).ok();
+                }
+                if let Some(mapped) = bars[bar].filter(|mapped| start + len <= mapped.size()) {
+                    *slot = Some((mapped.slice(start, len), offset));
+                }
+            }
+        }
+        let (Some((common, _)), Some((notify, notify_capability)), Some((isr, _))) =
+            (common, notify, isr)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(Self::Modern {
+            common,
+            notify,
+            notify_multiplier: device.read_u32(notify_capability + 16),
+            isr,
+            device: config.map(|(config, _)| config),
+        }))
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Self::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Self::Modern { common, .. } => common.read(common::DEVICE_STATUS),
+            Self::Legacy { port, .. } => unsafe { Port::new(port + legacy::DEVICE_STATUS).read() },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self {
+            Self::Modern { common, .. } => common.write(common::DEVICE_STATUS, status),
+            Self::Legacy { port, .. } => unsafe {
+                Port::new(port + legacy::DEVICE_STATUS).write(status)
+            },
+        }
+    }
+
+    /// Sets the given bits of the device status, keeping those that are already set.
+    pub fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    /// Puts the device back into its initial state, forgetting its queues and features.
+    pub fn reset(&self) {
+        self.set_status(0);
+        // Modern devices may take a while to finish resetting.
+        while self.is_modern() && self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// The features that the device offers. Legacy devices only have 32 feature bits.
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Self::Modern { common, .. } => {
+                let mut features = 0;
+                for half in 0..2 {
+                    common.write::<u32>(common::DEVICE_FEATURE_SELECT, half);
+                    features |= (common.read::<u32>(common::DEVICE_FEATURE) as u64) << (half * 32);
+                }
+                features
+            }
+            Self::Legacy { port, .. } => unsafe {
+                Port::<u32>::new(port + legacy::DEVICE_FEATURES).read() as u64
+            },
+        }
+    }
+
+    /// Tells the device which of its features we will use, and checks that it accepts them.
+    pub fn set_driver_features(&self, features: u64) -> Result<(), Errno> {
+        match self {
+            Self::Modern { common, .. } => {
+                for half in 0..2 {
+                    common.write::<u32>(common::DRIVER_FEATURE_SELECT, half);
+                    common.write::<u32>(common::DRIVER_FEATURE, (features >> (half * 32)) as u32);
+                }
+                self.add_status(STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    return Err(Errno::ENODEV);
+                }
+            }
+            Self::Legacy { port, .. } => unsafe {
+                Port::<u32>::new(port + legacy::GUEST_FEATURES).write(features as u32);
+            },
+        }
+        Ok(())
+    }
+
+    /// The number of queues that the device has, if it says.
+    pub fn queue_count(&self) -> Option<u16> {
+        match self {
+            Self::Modern { common, .. } => Some(common.read(common::NUM_QUEUES)),
+            Self::Legacy { .. } => None,
+        }
+    }
+
+    /// The largest size that the given queue can be, or 0 if there is no such queue.
+    /// Legacy queues must be exactly this size.
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        match self {
+            Self::Modern { common, .. } => {
+                common.write(common::QUEUE_SELECT, index);
+                common.read(common::QUEUE_SIZE)
+            }
+            Self::Legacy { port, .. } => unsafe {
+                Port::new(port + legacy::QUEUE_SELECT).write(index);
+                Port::new(port + legacy::QUEUE_SIZE).read()
+            },
+        }
+    }
+
+    /// Tells the device where the queue is, and which MSI-X table entry it should raise
+    /// when it uses a chain. The queue can be used once the device is ready.
+    pub fn setup_queue(&self, queue: &mut Virtqueue, msix_entry: u16) -> Result<(), Errno> {
+        let index = queue.index();
+        match self {
+            Self::Modern {
+                common,
+                notify_multiplier,
+                ..
+            } => {
+                common.write(common::QUEUE_SELECT, index);
+                common.write(common::QUEUE_SIZE, queue.size());
+                for (offset, addr) in [
+                    (common::QUEUE_DESC, queue.descriptors_phys()),
+                    (common::QUEUE_DRIVER, queue.avail_phys()),
+                    (common::QUEUE_DEVICE, queue.used_phys()),
+                ] {
+                    // Devices need only accept 32-bit accesses.
+                    common.write::<u32>(offset, addr.as_u64() as u32);
+                    common.write::<u32>(offset + 4, (addr.as_u64() >> 32) as u32);
+                }
+                common.write(common::QUEUE_MSIX_VECTOR, msix_entry);
+                if common.read::<u16>(common::QUEUE_MSIX_VECTOR) != msix_entry {
+                    return Err(Errno::EBUSY);
+                }
+                queue.notify_offset = common.read::<u16>(common::QUEUE_NOTIFY_OFF) as usize
+                    * *notify_multiplier as usize;
+                common.write::<u16>(common::QUEUE_ENABLE, 1);
+            }
+            Self::Legacy { port, msix } => unsafe {
+                Port::new(port + legacy::QUEUE_SELECT).write(index);
+                if Port::<u16>::new(port + legacy::QUEUE_SIZE).read() != queue.size() {
+                    return Err(Errno::EINVAL);
+                }
+                if *msix {
+                    let mut vector = Port::new(port + legacy::QUEUE_MSIX_VECTOR);
+                    vector.write(msix_entry);
+                    if vector.read() != msix_entry {
+                        return Err(Errno::EBUSY);
+                    }
+                }
+                Port::new(port + legacy::QUEUE_ADDRESS).write(
+                    (queue.descriptors_phys().as_u64() >> legacy::QUEUE_ADDRESS_SHIFT) as u32,
+                );
+            },
+        }
+        Ok(())
+    }
+
+    /// Chooses the MSI-X table entry raised when the device configuration changes.
+    pub fn set_config_msix_entry(&self, msix_entry: u16) {
+        match self {
+            Self::Modern { common, .. } => common.write(common::CONFIG_MSIX_VECTOR, msix_entry),
+            Self::Legacy { port, msix: true } => unsafe {
+                Port::new(port + legacy::CONFIG_MSIX_VECTOR).write(msix_entry)
+            },
+            Self::Legacy { msix: false, .. } => {}
+        }
+    }
+
+    /// Tells the device that there are new chains in the available ring of the given queue.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match self {
+            Self::Modern { notify, .. } => notify.write(queue.notify_offset, queue.index()),
+            Self::Legacy { port, .. } => unsafe {
+                Port::new(port + legacy::QUEUE_NOTIFY).write(queue.index())
+            },
+        }
+    }
+
+    /// Reads and clears the interrupt status, which says why the device raised its interrupt line.
+    /// This is not needed with MSI-X.
+    pub fn read_isr(&self) -> u8 {
+        match self {
+            Self::Modern { isr, .. } => isr.read(0),
+            Self::Legacy { port, .. } => unsafe { Port::new(port + legacy::ISR_STATUS).read() },
+        }
+    }
+
+    /// Reads a byte of the device-specific configuration.
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match self {
+            Self::Modern { device, .. } => device.as_ref().map_or(0, |config| config.read(offset)),
+            Self::Legacy { port, msix } => {
+                let start = if *msix {
+                    legacy::CONFIG_WITH_MSIX
+                } else {
+                    legacy::CONFIG
+                };
+                unsafe { Port::new(port + start + offset as u16).read() }
+            }
+        }
+    }
+
+    pub fn config_u16(&self, offset: usize) -> u16 {
+        match self {
+            Self::Modern {
+                device: Some(config),
+                ..
+            } => config.read(offset),
+            _ => u16::from_le_bytes([self.config_u8(offset), self.config_u8(offset + 1)]),
+        }
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match self {
+            Self::Modern {
+                device: Some(config),
+                ..
+            } => config.read(offset),
+            _ => self.config_u16(offset) as u32 | (self.config_u16(offset + 2) as u32) << 16,
+        }
+    }
+
+    /// Reads a 64-bit field, which the device might change between reading its two halves.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let high = self.config_u32(offset + 4);
+            let low = self.config_u32(offset);
+            if self.config_u32(offset + 4) == high {
+                return low as u64 | (high as u64) << 32;
+            }
+        }
+    }
+}
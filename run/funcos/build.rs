@@ -1,14 +1,41 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use bootloader::BootConfig;
+use bootloader::{BootConfig, DiskImageBuilder};
 
 fn main() {
     let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let root = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap())
+        .join("..")
+        .join("..");
+
+    // The `rootfs` directory and the user programs are packed into a `tar` archive,
+    // which is passed to the kernel as a ramdisk and mounted at `/`.
+    let rootfs = root.join("rootfs");
+    let user_programs = root
+        .join("user")
+        .join("target")
+        .join("x86_64-funcos-user")
+        .join("debug");
+    println!("cargo::rerun-if-changed={}", rootfs.display());
+    println!("cargo::rerun-if-changed={}", user_programs.display());
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    let has_ramdisk = build_ramdisk(&rootfs, &user_programs, &ramdisk_path);
+
+    // A copy of the `rootfs` directory is also formatted as ext2, and attached as a second disk.
+    let ext2_path = out_dir.join("ext2.img");
+    let ext2_path = match build_ext2_image(&rootfs, &ext2_path) {
+        true => ext2_path.display().to_string(),
+        false => String::new(),
+    };
+    println!("cargo:rustc-env=EXT2_PATH={ext2_path}");
+
+    // The files in the `boot` directory go on the boot partition next to the kernel,
+    // which mounts the partition at `/boot`.
+    let boot_files = root.join("boot");
+    println!("cargo::rerun-if-changed={}", boot_files.display());
 
     for (name, suffix) in [("kernel.elf", ""), ("kernel-tests.elf", "_TESTS")] {
-        let kernel = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap())
-            .join("..")
-            .join("..")
+        let kernel = root
             .join("os")
             .join("target")
             .join("x86_64-funcos")
@@ -34,16 +61,170 @@ fn main() {
             continue;
         }
 
-        let config = BootConfig::default();
+        let mut image = DiskImageBuilder::new(kernel);
+        image.set_boot_config(&BootConfig::default());
+        // The tests don't run user programs.
+        if suffix.is_empty() && has_ramdisk {
+            image.set_ramdisk(ramdisk_path.clone());
+        }
+        if let Ok(dir) = std::fs::read_dir(&boot_files) {
+            for entry in dir {
+                let path = entry.unwrap().path();
+                if path.is_file() {
+                    let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+                    image.set_file(name, path);
+                }
+            }
+        }
+
+        image.create_uefi_image(&uefi_path).unwrap();
+        image.create_bios_image(&bios_path).unwrap();
+    }
+}
+
+/// Packs the contents of the root directory into a `tar` archive,
+/// along with every program in the programs directory under `bin/`.
+/// Returns false if there was nothing to pack.
+fn build_ramdisk(rootfs: &Path, programs: &Path, archive_path: &Path) -> bool {
+    let mut archive = Vec::new();
+    if rootfs.is_dir() {
+        append_tree(&mut archive, rootfs, "");
+    }
+
+    let mut files = Vec::new();
+    if let Ok(dir) = std::fs::read_dir(programs) {
+        for entry in dir {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "elf") {
+                println!("cargo::rerun-if-changed={}", path.display());
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                files.push((format!("bin/{name}"), std::fs::read(&path).unwrap()));
+            }
+        }
+    }
+    files.sort();
+    if !files.is_empty() {
+        append_tar_entry(&mut archive, "bin/", b'5', 0o755, "", &[]);
+    }
+    for (name, contents) in &files {
+        append_tar_entry(&mut archive, name, b'0', 0o755, "", contents);
+    }
+
+    if archive.is_empty() {
+        return false;
+    }
+    // The archive ends with two empty blocks.
+    archive.resize(archive.len() + 1024, 0);
+    std::fs::write(archive_path, archive).unwrap();
+    true
+}
+
+/// Appends every directory, file and symbolic link under `dir` to the archive,
+/// with names starting with `prefix`. Directories come before their contents.
+fn append_tree(archive: &mut Vec<u8>, dir: &Path, prefix: &str) {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = format!("{prefix}{}", entry.file_name().to_str().unwrap());
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        let mode = permissions(&metadata);
+        if metadata.is_symlink() {
+            let target = std::fs::read_link(&path).unwrap();
+            let target = target.to_str().unwrap();
+            append_tar_entry(archive, &name, b'2', 0o777, target, &[]);
+        } else if metadata.is_dir() {
+            let name = format!("{name}/");
+            append_tar_entry(archive, &name, b'5', mode, "", &[]);
+            append_tree(archive, &path, &name);
+        } else {
+            let contents = std::fs::read(&path).unwrap();
+            append_tar_entry(archive, &name, b'0', mode, "", &contents);
+        }
+    }
+}
+
+/// Formats a disk image as ext2, filled with the contents of the root directory,
+/// using `mke2fs` from the host. Returns false if that didn't work.
+fn build_ext2_image(rootfs: &Path, image_path: &Path) -> bool {
+    let _ = std::fs::remove_file(image_path);
+    let status = std::process::Command::new("mke2fs")
+        .args(["-q", "-F", "-t", "ext2", "-L", "funcos", "-d"])
+        .arg(rootfs)
+        .arg(image_path)
+        .arg("8M")
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        _ => {
+            println!("cargo::warning=Could not make an ext2 image with mke2fs");
+            false
+        }
+    }
+}
 
-        bootloader::UefiBoot::new(&kernel)
-            .set_boot_config(&config)
-            .create_disk_image(&uefi_path)
-            .unwrap();
+#[cfg(unix)]
+fn permissions(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
 
-        bootloader::BiosBoot::new(&kernel)
-            .set_boot_config(&config)
-            .create_disk_image(&bios_path)
-            .unwrap();
+#[cfg(not(unix))]
+fn permissions(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o755
+    } else {
+        0o644
     }
 }
+
+/// Appends a `ustar` header followed by the given contents, padded to a multiple of 512 bytes.
+/// Names longer than 100 bytes are split between the name and prefix fields.
+fn append_tar_entry(
+    archive: &mut Vec<u8>,
+    name: &str,
+    kind: u8,
+    mode: u32,
+    link_target: &str,
+    contents: &[u8],
+) {
+    let mut header = [0u8; 512];
+    let (prefix, name) = match name.len() {
+        0..100 => ("", name),
+        _ => {
+            // The prefix is joined to the name with a slash, so split at one.
+            let split = name[..name.len().min(156)]
+                .rfind('/')
+                .filter(|&split| name.len() - split <= 100)
+                .unwrap_or_else(|| panic!("file name {name} is too long"));
+            (&name[..split], &name[split + 1..])
+        }
+    };
+    assert!(
+        link_target.len() < 100,
+        "link target {link_target} is too long"
+    );
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    header[157..157 + link_target.len()].copy_from_slice(link_target.as_bytes());
+    header[100..107].copy_from_slice(format!("{mode:07o}").as_bytes());
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field itself filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().next_multiple_of(512), 0);
+}
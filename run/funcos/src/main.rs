@@ -8,6 +8,29 @@ struct Args {
 
     #[arg(short, long)]
     test: bool,
+
+    /// Emulates the newer `q35` machine, whose disks sit behind an AHCI controller rather than IDE.
+    #[arg(long)]
+    q35: bool,
+
+    /// Attaches a raw disk image from the host as a virtio drive. May be given more than once.
+    #[arg(long, value_name = "IMAGE")]
+    disk: Vec<String>,
+
+    /// Attaches a raw disk image from the host as an NVMe drive. May be given more than once.
+    #[arg(long, value_name = "IMAGE")]
+    nvme: Vec<String>,
+
+    /// The network card to emulate, connected to QEMU's user-mode network,
+    /// such as `virtio-net-pci`, `e1000` or `rtl8139`.
+    #[arg(long, value_name = "MODEL", default_value = "virtio-net-pci")]
+    nic: String,
+
+    /// Connects the network card to a tap device on the host instead of the user-mode network,
+    /// so that the host can reach the machine, for example with `ping 10.0.2.15`.
+    /// Unless something on the host runs a DHCP server, give the card an address in `funcos.cfg`.
+    #[arg(long, value_name = "IFNAME")]
+    tap: Option<String>,
 }
 
 // Clippy doesn't understand that we'll add new env vars in `build.rs`.
@@ -37,8 +60,8 @@ fn main() {
     cmd.args(["-d", "int"]);
     cmd.args(["-m", "128M"]);
     cmd.arg("-no-reboot");
-    if !args.test {
-        cmd.arg("-no-shutdown");
+    if args.q35 {
+        cmd.args(["-machine", "q35"]);
     }
     if args.debug {
         cmd.args(["-S", "-s"]);
@@ -52,6 +75,49 @@ fn main() {
         cmd.arg("-drive")
             .arg(format!("format=raw,file={bios_path}"));
     }
+    // The drive options of each NVMe drive, which needs a controller of its own.
+    let mut nvme = Vec::new();
+    // The ext2 disk, if the build could make one.
+    // Writes to it are thrown away when QEMU exits, so every run starts from the same image.
+    let ext2_path = env!("EXT2_PATH");
+    if !ext2_path.is_empty() {
+        cmd.arg("-drive").arg(format!(
+            "format=raw,file={ext2_path},if=ide,index=1,snapshot=on"
+        ));
+        // The tests also read it through the virtio and NVMe drivers.
+        if args.test {
+            cmd.arg("-drive")
+                .arg(format!("format=raw,file={ext2_path},if=virtio,snapshot=on"));
+            nvme.push(format!("file={ext2_path},snapshot=on"));
+        }
+    }
+    // Unlike the ext2 disk, changes to these are written back to the image.
+    for disk in &args.disk {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={disk},if=virtio"));
+    }
+    nvme.extend(args.nvme.iter().map(|disk| format!("file={disk}")));
+    for (index, drive) in nvme.iter().enumerate() {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,{drive},if=none,id=nvme{index}"));
+        cmd.arg("-device")
+            .arg(format!("nvme,serial=funcos{index},drive=nvme{index}"));
+    }
+    // QEMU's user-mode network puts us behind a NAT, with the host at 10.0.2.2.
+    let mut nics = vec![args.nic.as_str()];
+    // The tests try each kind of card that we have a driver for, each on a network of its own.
+    if args.test {
+        nics = vec!["virtio-net-pci", "e1000", "rtl8139"];
+    }
+    for (index, nic) in nics.iter().enumerate() {
+        match &args.tap {
+            Some(tap) if index == 0 => cmd
+                .arg("-netdev")
+                .arg(format!("tap,id=net0,ifname={tap},script=no,downscript=no")),
+            _ => cmd.arg("-netdev").arg(format!("user,id=net{index}")),
+        };
+        cmd.arg("-device").arg(format!("{nic},netdev=net{index}"));
+    }
     let mut child = cmd.spawn().unwrap();
     child.wait().unwrap();
 }
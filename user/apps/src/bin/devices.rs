@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use rt::fs::{self, O_RDONLY, O_RDWR, O_WRONLY, TYPE_CHAR_DEVICE};
+use rt::syscall::{self, Errno, MAP_SHARED, PROT_READ, PROT_WRITE};
+
+rt::entry!(main);
+
+/// Uses the device files in `/dev`, and draws a square in the corner of the screen.
+fn main(_args: rt::Args) -> i32 {
+    match use_devices().and_then(|()| draw()) {
+        Ok(()) => 0,
+        Err(err) => {
+            rt::eprintln!("devices: {err:?}");
+            1
+        }
+    }
+}
+
+fn use_devices() -> Result<(), Errno> {
+    let names: Vec<_> = fs::read_dir("/dev")?
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    rt::println!("devices: /dev contains {names:?}");
+    assert_eq!(fs::stat("/dev/null")?.kind, TYPE_CHAR_DEVICE);
+
+    let null = fs::open("/dev/null", O_RDWR, 0)?;
+    assert_eq!(syscall::write(null, b"nobody hears this")?, 17);
+    assert_eq!(syscall::read(null, &mut [0; 8])?, 0);
+    assert_eq!(
+        syscall::ioctl(null, rt::framebuffer::FB_GET_INFO, 0).err(),
+        Some(Errno::ENOTTY)
+    );
+    syscall::close(null)?;
+
+    let mut buf = [0xff; 16];
+    let zero = fs::open("/dev/zero", O_RDONLY, 0)?;
+    assert_eq!(syscall::read(zero, &mut buf)?, 16);
+    assert_eq!(buf, [0; 16]);
+    syscall::close(zero)?;
+
+    let random = fs::open("/dev/random", O_RDONLY, 0)?;
+    syscall::read(random, &mut buf)?;
+    syscall::close(random)?;
+    rt::println!("devices: some random bytes: {buf:02x?}");
+
+    // This only shows up on the screen, not on the serial port.
+    let console = fs::open("/dev/console", O_WRONLY, 0)?;
+    syscall::write(console, b"devices: hello from /dev/console\n")?;
+    syscall::close(console)?;
+    Ok(())
+}
+
+/// The size of the square, in pixels.
+const SQUARE: u32 = 64;
+
+fn draw() -> Result<(), Errno> {
+    let fd = match fs::open("/dev/fb0", O_RDWR, 0) {
+        Ok(fd) => fd,
+        Err(Errno::ENOENT) => {
+            rt::println!("devices: there is no framebuffer");
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    let info = rt::framebuffer::info(fd)?;
+    rt::println!(
+        "devices: the framebuffer is {}x{} with a pitch of {} bytes",
+        info.width,
+        info.height,
+        info.pitch
+    );
+    let pixels = syscall::mmap(0, info.len(), PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)?;
+    syscall::close(fd)?;
+
+    // Fade from red to blue across the square, and from dark to light down it.
+    let left = info.width.saturating_sub(SQUARE);
+    for y in 0..SQUARE.min(info.height) {
+        for x in left..info.width {
+            let across = (x - left) * 255 / SQUARE;
+            let down = y * 255 / SQUARE;
+            let red = (255 - across) * down / 255;
+            let blue = across * down / 255;
+            let colour = (red << 16) | blue;
+            let pixel = pixels + y as u64 * info.pitch as u64 + x as u64 * 4;
+            unsafe { (pixel as *mut u32).write_volatile(0xff00_0000 | colour) };
+        }
+    }
+    syscall::munmap(pixels, info.len())?;
+    rt::println!("devices: drew a square in the top right corner");
+    Ok(())
+}
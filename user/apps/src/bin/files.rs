@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use rt::fs::{
+    self, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_SET, TYPE_SYMLINK,
+};
+use rt::syscall::{self, Errno};
+
+rt::entry!(main);
+
+/// Looks around the root filesystem, which comes from the ramdisk,
+/// and then makes some files in `/tmp`.
+fn main(_args: rt::Args) -> i32 {
+    match explore().and_then(|()| use_tmp()) {
+        Ok(()) => 0,
+        Err(err) => {
+            rt::eprintln!("files: {err:?}");
+            1
+        }
+    }
+}
+
+fn explore() -> Result<(), Errno> {
+    let names: Vec<_> = fs::read_dir("/")?
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    rt::println!("files: / contains {names:?}");
+
+    // `/etc/issue` is a symbolic link to `motd`.
+    assert_eq!(fs::readlink("/etc/issue")?, "motd");
+    assert_eq!(fs::lstat("/etc/issue")?.kind, TYPE_SYMLINK);
+    let fd = fs::open("/etc/issue", O_RDONLY, 0)?;
+    let motd = rt::io::read_to_end(fd)?;
+    assert_eq!(fs::fstat(fd)?.size, motd.len() as u64);
+    // Read it again from the middle.
+    fs::seek(fd, 3, SEEK_SET)?;
+    let rest = rt::io::read_to_end(fd)?;
+    assert_eq!(rest, motd[3..]);
+    syscall::close(fd)?;
+    rt::print!(
+        "files: /etc/motd says {}",
+        core::str::from_utf8(&motd).unwrap()
+    );
+
+    // Relative paths start from the current directory.
+    fs::chdir("/etc")?;
+    assert_eq!(fs::getcwd()?, "/etc");
+    assert!(fs::stat("hostname").is_ok());
+    assert!(fs::stat("../bin/files").is_ok());
+    assert_eq!(fs::stat("missing").err(), Some(Errno::ENOENT));
+    assert_eq!(fs::chdir("motd").err(), Some(Errno::ENOTDIR));
+
+    // The root filesystem can't be changed.
+    assert_eq!(
+        fs::open("new", O_WRONLY | O_CREAT, 0o644).err(),
+        Some(Errno::EROFS)
+    );
+    rt::println!("files: the root filesystem works");
+    Ok(())
+}
+
+fn use_tmp() -> Result<(), Errno> {
+    fs::mkdir("/tmp/files", 0o755)?;
+    fs::chdir("/tmp/files")?;
+
+    let fd = fs::open("log", O_WRONLY | O_CREAT | O_EXCL, 0o644)?;
+    syscall::write(fd, b"one\n")?;
+    syscall::close(fd)?;
+    let fd = fs::open("log", O_WRONLY | O_APPEND, 0)?;
+    syscall::write(fd, b"two\n")?;
+    syscall::close(fd)?;
+    assert_eq!(
+        fs::open("log", O_WRONLY | O_CREAT | O_EXCL, 0o644).err(),
+        Some(Errno::EEXIST)
+    );
+
+    // A hard link is another name for the same file, which survives the first name going away.
+    fs::link("log", "copy")?;
+    fs::rename("log", "/tmp/moved")?;
+    assert_eq!(fs::stat("copy")?.links, 2);
+    fs::unlink("/tmp/moved")?;
+    assert_eq!(fs::stat("copy")?.links, 1);
+    let fd = fs::open("copy", O_RDWR, 0)?;
+    assert_eq!(rt::io::read_to_end(fd)?, b"one\ntwo\n");
+    fs::ftruncate(fd, 3)?;
+    syscall::close(fd)?;
+    let fd = fs::open("copy", O_RDWR | O_TRUNC, 0)?;
+    assert_eq!(fs::fstat(fd)?.size, 0);
+    syscall::close(fd)?;
+
+    fs::symlink("copy", "link")?;
+    let names: Vec<_> = fs::read_dir(".")?
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, [".", "..", "copy", "link"]);
+    assert_eq!(fs::rmdir("/tmp/files").err(), Some(Errno::ENOTEMPTY));
+    fs::unlink("copy")?;
+    fs::unlink("link")?;
+    fs::chdir("..")?;
+    fs::rmdir("files")?;
+    rt::println!("files: /tmp works");
+    Ok(())
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, hint::black_box};
+use rt::{
+    signal::{self, Handler, SIGFPE},
+    syscall,
+};
+
+extern crate alloc;
+
+rt::entry!(main);
+
+/// The numbers of terms that the children sum. Different sums leave different values in the registers.
+const TERMS: [u32; 2] = [2_000_000, 3_000_000];
+
+/// Checks that floating point registers survive preemption,
+/// by running several processes that use them at the same time.
+///
+/// Run without arguments, this works out the expected results, and then starts a child for each,
+/// which is run as `float <terms> <expected result in hex>`.
+/// It also checks that an unmasked floating point exception raises `SIGFPE`, in a child run as `float trap`.
+fn main(args: rt::Args) -> i32 {
+    if args.len() == 3 {
+        return child(args.get(1).unwrap(), args.get(2).unwrap());
+    }
+    if args.len() == 2 && args.get(1) == Some("trap") {
+        return trap();
+    }
+
+    let mut pids = [syscall::Pid(0); TERMS.len()];
+    for (pid, terms) in pids.iter_mut().zip(TERMS) {
+        let terms = alloc::format!("{terms}");
+        let expected = alloc::format!("{:x}", leibniz(terms.parse().unwrap()).to_bits());
+        *pid = syscall::spawn("/bin/float", &["float", &terms, &expected]).unwrap();
+    }
+    let failures = pids
+        .iter()
+        .filter(|&&pid| syscall::wait(pid) != Ok(0))
+        .count();
+    if failures > 0 {
+        rt::eprintln!("float: {failures} children got different results");
+        return 1;
+    }
+    let pid = syscall::spawn("/bin/float", &["float", "trap"]).unwrap();
+    if syscall::wait(pid) != Ok(0) {
+        rt::eprintln!("float: dividing by zero did not raise SIGFPE");
+        return 1;
+    }
+    rt::println!("float: pi is about {}", leibniz(TERMS[0]));
+    0
+}
+
+fn child(terms: &str, expected: &str) -> i32 {
+    let terms = terms.parse().unwrap();
+    let expected = u64::from_str_radix(expected, 16).unwrap();
+    let result = leibniz(terms);
+    if result.to_bits() == expected {
+        0
+    } else {
+        rt::eprintln!("float: expected {}, got {result}", f64::from_bits(expected));
+        1
+    }
+}
+
+/// Unmasks the SSE divide by zero exception, and then divides by zero.
+fn trap() -> i32 {
+    signal::set_handler(SIGFPE, Handler::Function(on_sigfpe)).unwrap();
+    let mut mxcsr = 0u32;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+        // Clear the divide by zero mask.
+        mxcsr &= !(1 << 9);
+        asm!("ldmxcsr [{}]", in(reg) &mxcsr);
+    }
+    let result = black_box(1.0f64) / black_box(0.0);
+    rt::eprintln!("float: dividing by zero gave {result}");
+    1
+}
+
+/// Returning would run the division again, so this ends the process instead.
+extern "C" fn on_sigfpe(_signal: u32) {
+    syscall::exit(0);
+}
+
+/// Approximates pi with the first `terms` terms of the Leibniz series.
+fn leibniz(terms: u32) -> f64 {
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    for k in 0..terms {
+        sum += sign / (2 * k + 1) as f64;
+        sign = -sign;
+    }
+    4.0 * sum
+}
@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+
+rt::entry!(main);
+
+fn main(args: rt::Args) -> i32 {
+    rt::println!("Hello from user space!");
+
+    // Exercise the heap.
+    let words: Vec<String> = args.iter().map(String::from).collect();
+    rt::println!(
+        "I was started with {} argument(s): {:?}",
+        words.len(),
+        words
+    );
+    0
+}
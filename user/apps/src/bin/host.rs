@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+rt::entry!(main);
+
+/// Prints the addresses of each name given as an argument.
+fn main(args: rt::Args) -> i32 {
+    if args.len() < 2 {
+        rt::eprintln!("usage: host NAME...");
+        return 2;
+    }
+    let mut status = 0;
+    for name in args.iter().skip(1) {
+        match rt::net::resolve(name) {
+            Ok(addresses) => {
+                for address in addresses {
+                    rt::println!("{name} has address {address}");
+                }
+            }
+            Err(err) => {
+                rt::eprintln!("host: {name}: {err:?}");
+                status = 1;
+            }
+        }
+    }
+    status
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{format, vec::Vec};
+use core::net::SocketAddrV4;
+
+use rt::{
+    io::{read_to_end, write_all},
+    net::{self, SOCK_STREAM},
+    syscall::{self, Errno, STDOUT},
+};
+
+rt::entry!(main);
+
+/// Fetches a URL such as `http://localhost:8080/etc/motd` with a GET request,
+/// and prints the body of the response. Fails if the response isn't a success.
+fn main(args: rt::Args) -> i32 {
+    let Some((host, port, path)) = args.get(1).and_then(parse_url) else {
+        rt::eprintln!("usage: http http://HOST[:PORT][/PATH]");
+        return 2;
+    };
+    let response = match fetch(host, port, path) {
+        Ok(response) => response,
+        Err(err) => {
+            rt::eprintln!("http: {host}:{port}: {err:?}");
+            return 1;
+        }
+    };
+
+    let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+        rt::eprintln!("http: incomplete response");
+        return 1;
+    };
+    let head = core::str::from_utf8(&response[..end]).unwrap_or("");
+    let status = head.lines().next().unwrap_or("");
+    if status
+        .split(' ')
+        .nth(1)
+        .is_none_or(|code| !code.starts_with('2'))
+    {
+        rt::eprintln!("http: {status}");
+        return 1;
+    }
+    let _ = write_all(STDOUT, &response[end + 4..]);
+    0
+}
+
+/// Splits a URL into its host, port and path.
+fn parse_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 80),
+    };
+    (!host.is_empty()).then_some((host, port, path))
+}
+
+/// Sends the request, and reads the whole response, which ends when the server closes the connection.
+fn fetch(host: &str, port: u16, path: &str) -> Result<Vec<u8>, Errno> {
+    let address = *net::resolve(host)?.first().ok_or(Errno::ENOENT)?;
+    let fd = net::socket(SOCK_STREAM)?;
+    let request = format!(
+        "GET {path} HTTP/1.0\r\n\
+         Host: {host}\r\n\
+         Connection: close\r\n\r\n"
+    );
+    let response = net::connect(fd, SocketAddrV4::new(address, port))
+        .and_then(|()| write_all(fd, request.as_bytes()))
+        .and_then(|()| read_to_end(fd));
+    let _ = syscall::close(fd);
+    response
+}
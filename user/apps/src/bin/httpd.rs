@@ -0,0 +1,181 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{format, string::String, vec, vec::Vec};
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use rt::{
+    fs,
+    io::{read_to_end, write_all},
+    net::{self, PollFd, POLLIN, SOCK_STREAM},
+    syscall::{self, Errno},
+};
+
+rt::entry!(main);
+
+/// The longest request that is read. Only the request line matters, so this is plenty.
+const MAX_REQUEST: usize = 8192;
+
+/// A connection whose request hasn't all arrived yet.
+struct Client {
+    fd: u64,
+    request: Vec<u8>,
+}
+
+/// Serves the filesystem over HTTP on the port given as an argument, 8080 by default.
+/// Directories are listed, and files are sent as they are.
+///
+/// Many clients can be connected at once: poll says which of them have sent something, and each
+/// is answered as soon as its request is complete. Sending the response blocks, though,
+/// so a client that is slow to read it holds up the others.
+fn main(args: rt::Args) -> i32 {
+    let port = match args.get(1).map_or(Ok(8080), str::parse) {
+        Ok(port) => port,
+        Err(_) => {
+            rt::eprintln!("usage: httpd [PORT]");
+            return 2;
+        }
+    };
+    let listener = match listen(port) {
+        Ok(listener) => listener,
+        Err(err) => {
+            rt::eprintln!("httpd: could not listen on port {port}: {err:?}");
+            return 1;
+        }
+    };
+    rt::println!("httpd: listening on port {port}");
+
+    let mut clients: Vec<Client> = Vec::new();
+    loop {
+        let mut fds = vec![PollFd::new(listener, POLLIN)];
+        fds.extend(clients.iter().map(|client| PollFd::new(client.fd, POLLIN)));
+        // Signals are delivered when a system call returns, so wake up now and then to be stopped.
+        if let Err(err) = net::poll(&mut fds, 1000) {
+            rt::eprintln!("httpd: poll failed: {err:?}");
+            return 1;
+        }
+
+        let mut index = 0;
+        clients.retain_mut(|client| {
+            index += 1;
+            fds[index].revents == 0 || !receive(client)
+        });
+        if fds[0].revents & POLLIN != 0 {
+            match net::accept(listener) {
+                Ok((fd, _)) => clients.push(Client {
+                    fd,
+                    request: Vec::new(),
+                }),
+                Err(err) => rt::eprintln!("httpd: accept failed: {err:?}"),
+            }
+        }
+    }
+}
+
+fn listen(port: u16) -> Result<u64, Errno> {
+    let listener = net::socket(SOCK_STREAM)?;
+    net::bind(listener, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+    net::listen(listener, 8)?;
+    Ok(listener)
+}
+
+/// Reads what a client has sent, and answers it once the whole request has arrived.
+/// Returns whether the client is finished with, and has been closed.
+fn receive(client: &mut Client) -> bool {
+    let mut buf = [0; 1024];
+    let len = match net::recv(client.fd, &mut buf) {
+        Ok(len) => len as usize,
+        Err(_) => 0,
+    };
+    client.request.extend_from_slice(&buf[..len]);
+    let complete = client
+        .request
+        .windows(4)
+        .any(|window| window == b"\r\n\r\n");
+    if len == 0 || complete || client.request.len() >= MAX_REQUEST {
+        if complete {
+            let response = respond(&client.request);
+            let _ = write_all(client.fd, &response);
+        }
+        let _ = syscall::close(client.fd);
+        return true;
+    }
+    false
+}
+
+/// Makes the response to a request.
+fn respond(request: &[u8]) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    let mut words = request.lines().next().unwrap_or("").split(' ');
+    let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    if !target.starts_with('/') {
+        return response(400, "Bad Request", "text/plain", b"Bad request\n");
+    }
+    if method != "GET" {
+        return response(
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"Only GET is allowed\n",
+        );
+    }
+    let path = target.split('?').next().unwrap();
+    rt::println!("httpd: GET {path}");
+    match serve(path) {
+        Ok((content_type, body)) => response(200, "OK", content_type, &body),
+        Err(Errno::ENOENT | Errno::ENOTDIR) => {
+            response(404, "Not Found", "text/plain", b"Not found\n")
+        }
+        Err(err) => {
+            let body = format!("{err:?}\n");
+            response(500, "Internal Server Error", "text/plain", body.as_bytes())
+        }
+    }
+}
+
+/// Reads the file at a path, or lists the directory. Returns the type of content and the content.
+fn serve(path: &str) -> Result<(&'static str, Vec<u8>), Errno> {
+    if !fs::stat(path)?.is_dir() {
+        let fd = fs::open(path, fs::O_RDONLY, 0)?;
+        let contents = read_to_end(fd);
+        let _ = syscall::close(fd);
+        let contents = contents?;
+        let content_type = if core::str::from_utf8(&contents).is_ok() {
+            "text/plain"
+        } else {
+            "application/octet-stream"
+        };
+        return Ok((content_type, contents));
+    }
+    let mut entries = fs::read_dir(path)?;
+    entries.retain(|entry| entry.name != ".");
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let dir = path.trim_end_matches('/');
+    let mut body = format!("<html><body><h1>Index of {path}</h1><ul>\n");
+    for entry in entries {
+        let slash = if entry.kind == fs::TYPE_DIRECTORY {
+            "/"
+        } else {
+            ""
+        };
+        let name = &entry.name;
+        body += &format!("<li><a href=\"{dir}/{name}{slash}\">{name}{slash}</a></li>\n");
+    }
+    body += "</ul></body></html>\n";
+    Ok(("text/html", body.into_bytes()))
+}
+
+fn response(status: u16, reason: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.0 {status} {reason}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use rt::{
+    net::{self, SOCK_STREAM},
+    signal::SIGTERM,
+    syscall::{self, Errno},
+};
+
+rt::entry!(main);
+
+/// The programs that init runs, one after another.
+const PROGRAMS: &[&str] = &[
+    "/bin/hello",
+    "/bin/signals",
+    "/bin/pipes",
+    "/bin/memory",
+    "/bin/float",
+    "/bin/tls",
+    "/bin/files",
+    "/bin/devices",
+    "/bin/procinfo",
+];
+
+/// The port that the web server is started on, for the client to fetch a file from.
+const HTTP_PORT: u16 = 8080;
+
+fn main(_args: rt::Args) -> i32 {
+    rt::println!("init: starting up");
+    for &path in PROGRAMS {
+        run(path, &[path, "from init"]);
+    }
+
+    // Serve files over HTTP, and fetch one.
+    let port = format!("{HTTP_PORT}");
+    match syscall::spawn("/bin/httpd", &["/bin/httpd", &port]) {
+        Ok(server) => {
+            if wait_for_server() {
+                let url = format!("http://localhost:{HTTP_PORT}/etc/motd");
+                run("/bin/http", &["/bin/http", &url]);
+            } else {
+                rt::println!("init: /bin/httpd didn't start listening");
+            }
+            let _ = syscall::kill(server, SIGTERM);
+            let _ = syscall::wait(server);
+        }
+        Err(err) => rt::println!("init: could not start /bin/httpd: {err:?}"),
+    }
+    // Exiting successfully asks the kernel to power off.
+    0
+}
+
+fn run(path: &str, args: &[&str]) {
+    match syscall::spawn(path, args) {
+        Ok(pid) => match syscall::wait(pid) {
+            Ok(status) => rt::println!("init: {path} exited with status {status}"),
+            Err(err) => rt::println!("init: could not wait for {path}: {err:?}"),
+        },
+        Err(err) => rt::println!("init: could not start {path}: {err:?}"),
+    }
+}
+
+/// Waits for up to a few seconds for the web server to accept connections.
+fn wait_for_server() -> bool {
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, HTTP_PORT);
+    for _ in 0..50 {
+        let Ok(fd) = net::socket(SOCK_STREAM) else {
+            return false;
+        };
+        let result = net::connect(fd, address);
+        let _ = syscall::close(fd);
+        match result {
+            Ok(()) => return true,
+            // Nothing is listening yet, so sleep for a while by polling nothing.
+            Err(Errno::ECONNREFUSED) => {
+                let _ = net::poll(&mut [], 100);
+            }
+            Err(_) => return false,
+        }
+    }
+    false
+}
@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use rt::{
+    signal::{self, Handler, SIGSEGV},
+    syscall::{self, MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE},
+};
+
+rt::entry!(main);
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The page that the next fault is expected on.
+static FAULT_PAGE: AtomicU64 = AtomicU64::new(0);
+static FAULTS: AtomicU32 = AtomicU32::new(0);
+
+/// Makes the faulting page readable and writable again, so that the access is retried and succeeds.
+extern "C" fn on_fault(_signal: u32) {
+    FAULTS.fetch_add(1, Ordering::Relaxed);
+    let page = FAULT_PAGE.load(Ordering::Relaxed);
+    if syscall::mprotect(page, PAGE_SIZE, PROT_READ | PROT_WRITE).is_err() {
+        // The page isn't mapped at all, so map a fresh one.
+        syscall::mmap(
+            page,
+            PAGE_SIZE,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED,
+            0,
+            0,
+        )
+        .unwrap();
+    }
+}
+
+/// Checks that mapped memory works, and that protection changes cause faults exactly where expected.
+fn main(_args: rt::Args) -> i32 {
+    signal::set_handler(SIGSEGV, Handler::Function(on_fault)).unwrap();
+
+    let base = syscall::mmap(
+        0,
+        3 * PAGE_SIZE,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        0,
+        0,
+    )
+    .unwrap();
+    let word = |page: u64| (base + page * PAGE_SIZE) as *mut u64;
+    for page in 0..3 {
+        unsafe {
+            assert_eq!(word(page).read_volatile(), 0);
+            word(page).write_volatile(page + 1);
+        }
+    }
+    assert_eq!(FAULTS.load(Ordering::Relaxed), 0);
+
+    // Writing to a read-only page faults, but reading it doesn't.
+    FAULT_PAGE.store(base + PAGE_SIZE, Ordering::Relaxed);
+    syscall::mprotect(base + PAGE_SIZE, PAGE_SIZE, PROT_READ).unwrap();
+    unsafe {
+        assert_eq!(word(1).read_volatile(), 2);
+        assert_eq!(FAULTS.load(Ordering::Relaxed), 0);
+        word(1).write_volatile(20);
+        assert_eq!(FAULTS.load(Ordering::Relaxed), 1);
+        assert_eq!(word(1).read_volatile(), 20);
+    }
+
+    // Any access to a page with no access faults. Its neighbours are unaffected.
+    FAULT_PAGE.store(base + 2 * PAGE_SIZE, Ordering::Relaxed);
+    syscall::mprotect(base + 2 * PAGE_SIZE, PAGE_SIZE, PROT_NONE).unwrap();
+    unsafe {
+        assert_eq!(word(0).read_volatile(), 1);
+        assert_eq!(word(2).read_volatile(), 3);
+    }
+    assert_eq!(FAULTS.load(Ordering::Relaxed), 2);
+
+    // Unmapped memory faults, and comes back zeroed when it is mapped again.
+    FAULT_PAGE.store(base, Ordering::Relaxed);
+    syscall::munmap(base, PAGE_SIZE).unwrap();
+    unsafe {
+        assert_eq!(word(0).read_volatile(), 0);
+    }
+    assert_eq!(FAULTS.load(Ordering::Relaxed), 3);
+
+    syscall::munmap(base, 3 * PAGE_SIZE).unwrap();
+    rt::println!("memory: protection faults happened where expected");
+    0
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use rt::ipc::{self, Handle, Message};
+use rt::syscall::Errno;
+
+rt::entry!(main);
+
+/// The endpoint that the kernel gives us, which `pong` receives from.
+const PONG: Handle = Handle(0);
+const ROUNDS: u32 = 5;
+
+/// Plays ping-pong with `pong`, then hands it a new endpoint to say goodbye on.
+fn main(_args: rt::Args) -> i32 {
+    match run() {
+        Ok(()) => 0,
+        Err(err) => {
+            rt::eprintln!("ping: {err:?}");
+            1
+        }
+    }
+}
+
+fn run() -> Result<(), Errno> {
+    for round in 0..ROUNDS {
+        let mut data = [b'p', b'i', b'n', b'g', b' ', b'0' + round as u8];
+        let reply = ipc::call(PONG, &Message::new(&data, &[]))?;
+        data[1] = b'o';
+        if reply.data() != data {
+            rt::eprintln!("ping: unexpected reply {:?}", reply.data());
+            return Err(Errno::EINVAL);
+        }
+        rt::println!("ping: round {round} ok");
+    }
+
+    // Pass a send-only copy of a new endpoint, and wait for pong to use it.
+    let endpoint = ipc::endpoint_create()?;
+    let sender = ipc::duplicate(endpoint, ipc::SEND)?;
+    ipc::send(PONG, &Message::new(b"bye", &[sender]))?;
+    ipc::close_handle(sender)?;
+    let message = ipc::receive(endpoint)?;
+    if message.data() != b"done" {
+        return Err(Errno::EINVAL);
+    }
+    rt::println!("ping: pong finished");
+    Ok(())
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use rt::syscall::{self, Errno, Pid, STDIN, STDOUT};
+
+rt::entry!(main);
+
+/// Runs the pipeline `hello | upper`, as a shell would.
+fn main(_args: rt::Args) -> i32 {
+    match run_pipeline(&[&["/bin/hello", "pipeline"], &["/bin/upper"]]) {
+        Ok(status) => status,
+        Err(err) => {
+            rt::eprintln!("pipes: {err:?}");
+            1
+        }
+    }
+}
+
+/// Runs each command with its standard output connected to the standard input of the next,
+/// and returns the exit status of the last one.
+fn run_pipeline(commands: &[&[&str]]) -> Result<i32, Errno> {
+    // Children inherit our file descriptors, so keep copies of the originals to restore afterwards.
+    const SAVED_STDIN: u64 = 10;
+    const SAVED_STDOUT: u64 = 11;
+    syscall::dup2(STDIN, SAVED_STDIN)?;
+    syscall::dup2(STDOUT, SAVED_STDOUT)?;
+
+    let mut pids: Vec<Pid> = Vec::new();
+    for (i, command) in commands.iter().enumerate() {
+        let last = i + 1 == commands.len();
+        let next_stdin = if last {
+            syscall::dup2(SAVED_STDOUT, STDOUT)?;
+            None
+        } else {
+            let (read_end, write_end) = syscall::pipe()?;
+            syscall::dup2(write_end, STDOUT)?;
+            syscall::close(write_end)?;
+            Some(read_end)
+        };
+
+        pids.push(syscall::spawn(command[0], command)?);
+
+        if let Some(read_end) = next_stdin {
+            syscall::dup2(read_end, STDIN)?;
+            syscall::close(read_end)?;
+        }
+    }
+
+    // Close our copies of the pipe ends, so that the readers see the end of the file.
+    syscall::dup2(SAVED_STDIN, STDIN)?;
+    syscall::dup2(SAVED_STDOUT, STDOUT)?;
+    syscall::close(SAVED_STDIN)?;
+    syscall::close(SAVED_STDOUT)?;
+
+    let mut status = 0;
+    for pid in pids {
+        status = syscall::wait(pid)?;
+    }
+    Ok(status)
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+use rt::ipc::{self, Handle, Message};
+use rt::syscall::Errno;
+
+rt::entry!(main);
+
+/// The endpoint that the kernel gives us, which `ping` sends to.
+const PING: Handle = Handle(0);
+
+/// Answers each "ping" with a "pong", until it is given an endpoint to report back on.
+fn main(_args: rt::Args) -> i32 {
+    match run() {
+        Ok(()) => 0,
+        Err(err) => {
+            rt::eprintln!("pong: {err:?}");
+            1
+        }
+    }
+}
+
+fn run() -> Result<(), Errno> {
+    loop {
+        let message = ipc::receive(PING)?;
+        if let Some(reply) = message.reply_handle() {
+            let mut data = [0; ipc::MAX_DATA];
+            let data = &mut data[..message.data().len()];
+            data.copy_from_slice(message.data());
+            if let Some(byte) = data.get_mut(1) {
+                *byte = b'o';
+            }
+            ipc::reply(reply, &Message::new(data, &[]))?;
+        } else if let Some(endpoint) = message.handles().next() {
+            ipc::send(endpoint, &Message::new(b"done", &[]))?;
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{format, string::String};
+use rt::fs::{self, O_RDONLY};
+use rt::syscall::{self, Errno};
+
+rt::entry!(main);
+
+/// Reads some of the files in `/proc`, which the kernel makes up as they are read.
+fn main(_args: rt::Args) -> i32 {
+    match show() {
+        Ok(()) => 0,
+        Err(err) => {
+            rt::eprintln!("procinfo: {err:?}");
+            1
+        }
+    }
+}
+
+fn read(path: &str) -> Result<String, Errno> {
+    let fd = fs::open(path, O_RDONLY, 0)?;
+    let contents = rt::io::read_to_end(fd);
+    syscall::close(fd)?;
+    Ok(String::from_utf8_lossy(&contents?).into())
+}
+
+fn show() -> Result<(), Errno> {
+    // `/proc/self` always points at the directory of whoever is looking.
+    let pid = syscall::getpid();
+    assert_eq!(fs::readlink("/proc/self")?, format!("{}", pid.0));
+
+    let status = read("/proc/self/status")?;
+    assert!(status.contains("Name:\tprocinfo"));
+    rt::print!("procinfo: /proc/self/status says\n{status}");
+    rt::print!(
+        "procinfo: /proc/self/maps says\n{}",
+        read("/proc/self/maps")?
+    );
+    rt::print!("procinfo: up for {}", read("/proc/uptime")?);
+    for line in read("/proc/meminfo")?.lines().take(2) {
+        rt::println!("procinfo: {line}");
+    }
+    let model = read("/proc/cpuinfo")?;
+    if let Some(line) = model.lines().find(|line| line.starts_with("model name")) {
+        rt::println!("procinfo: {line}");
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use rt::{
+    signal::{self, Handler, SIGSEGV, SIGUSR1},
+    syscall,
+};
+
+rt::entry!(main);
+
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_signal(signal: u32) {
+    RECEIVED.store(signal, Ordering::Relaxed);
+}
+
+fn main(_args: rt::Args) -> i32 {
+    signal::set_handler(SIGUSR1, Handler::Function(on_signal)).unwrap();
+
+    // A blocked signal waits until it is unblocked.
+    signal::block(&[SIGUSR1]).unwrap();
+    syscall::kill(syscall::getpid(), SIGUSR1).unwrap();
+    assert_eq!(RECEIVED.load(Ordering::Relaxed), 0);
+    signal::unblock(&[SIGUSR1]).unwrap();
+    assert_eq!(RECEIVED.load(Ordering::Relaxed), SIGUSR1);
+    rt::println!("signals: handled SIGUSR1");
+
+    // Faults are delivered as signals too. With the default action, this kills the process,
+    // so that init sees an exit status of 128 + SIGSEGV.
+    rt::println!("signals: about to fault");
+    signal::set_handler(SIGSEGV, Handler::Default).unwrap();
+    unsafe {
+        core::ptr::null_mut::<u64>().write_volatile(1);
+    }
+    rt::println!("signals: survived a fault, which should not happen");
+    1
+}
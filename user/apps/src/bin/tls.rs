@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+#![feature(thread_local)]
+
+use core::cell::Cell;
+
+rt::entry!(main);
+
+#[thread_local]
+static COUNTER: Cell<u64> = Cell::new(40);
+
+#[thread_local]
+static ZEROED: Cell<[u64; 4]> = Cell::new([0; 4]);
+
+/// Checks that thread-local variables start with their initial values, and can be changed.
+fn main(_args: rt::Args) -> i32 {
+    assert_eq!(COUNTER.get(), 40);
+    assert_eq!(ZEROED.get(), [0; 4]);
+    COUNTER.set(COUNTER.get() + 2);
+    ZEROED.set([1, 2, 3, 4]);
+    assert_eq!(COUNTER.get(), 42);
+    assert_eq!(ZEROED.get(), [1, 2, 3, 4]);
+    rt::println!("tls: thread-local variables work");
+    0
+}
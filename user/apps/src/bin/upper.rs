@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use rt::{
+    io,
+    syscall::{STDIN, STDOUT},
+};
+
+rt::entry!(main);
+
+/// Copies the standard input to the standard output in upper case.
+fn main(_args: rt::Args) -> i32 {
+    let mut buf = [0; 256];
+    loop {
+        match rt::syscall::read(STDIN, &mut buf) {
+            Ok(0) => return 0,
+            Ok(len) => {
+                let bytes = &mut buf[..len as usize];
+                bytes.make_ascii_uppercase();
+                if io::write_all(STDOUT, bytes).is_err() {
+                    return 1;
+                }
+            }
+            Err(_) => return 1,
+        }
+    }
+}
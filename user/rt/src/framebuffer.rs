@@ -0,0 +1,39 @@
+//! The framebuffer, `/dev/fb0`.
+//!
+//! The framebuffer can be read and written like a file, or mapped with [crate::syscall::mmap]
+//! using `MAP_SHARED` to draw on the screen directly. The kernel's console draws into the same memory.
+
+use crate::syscall::{self, Errno};
+
+/// The [syscall::ioctl] request that fills in a [FrameBufferInfo].
+pub const FB_GET_INFO: u64 = 0x4600;
+
+/// The layout of the framebuffer. Each pixel is a little-endian `0xAARRGGBB` value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameBufferInfo {
+    /// The size of the screen in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The number of bytes from the start of one row to the start of the next.
+    pub pitch: u32,
+    pub bits_per_pixel: u32,
+}
+
+impl FrameBufferInfo {
+    /// The number of bytes in the framebuffer.
+    pub fn len(&self) -> u64 {
+        self.pitch as u64 * self.height as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Asks the framebuffer open at `fd` for its layout.
+pub fn info(fd: u64) -> Result<FrameBufferInfo, Errno> {
+    let mut info = FrameBufferInfo::default();
+    syscall::ioctl(fd, FB_GET_INFO, &mut info as *mut FrameBufferInfo as u64)?;
+    Ok(info)
+}
@@ -0,0 +1,271 @@
+//! Files and directories.
+//!
+//! Paths are relative to the current directory unless they start with `/`.
+//! Open files are read and written with [crate::syscall::read] and [crate::syscall::write],
+//! and closed with [crate::syscall::close].
+
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::syscall::{check, number, syscall, Errno};
+
+/// Flags for [open]. Files are opened for reading only unless `O_WRONLY` or `O_RDWR` is given.
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_DIRECTORY: u64 = 0o200000;
+pub const O_NOFOLLOW: u64 = 0o400000;
+
+/// Where [seek] measures from.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// The kinds of file, as found in [Stat::kind] and [DirEntry::kind].
+pub const TYPE_FIFO: u32 = 1;
+pub const TYPE_CHAR_DEVICE: u32 = 2;
+pub const TYPE_DIRECTORY: u32 = 4;
+pub const TYPE_BLOCK_DEVICE: u32 = 6;
+pub const TYPE_REGULAR: u32 = 8;
+pub const TYPE_SYMLINK: u32 = 10;
+pub const TYPE_SOCKET: u32 = 12;
+
+/// Tells [stat] to describe a symbolic link rather than what it points to.
+const STAT_NO_FOLLOW: u64 = 0x100;
+
+/// Information about a file, laid out as the kernel expects.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub device: u64,
+    pub inode: u64,
+    pub kind: u32,
+    pub mode: u32,
+    pub links: u64,
+    pub size: u64,
+    pub blocks: u64,
+    /// Timestamps, in nanoseconds since boot.
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.kind == TYPE_DIRECTORY
+    }
+}
+
+/// Opens the file at the given path, and returns its file descriptor.
+/// `mode` gives the permissions of a file created with `O_CREAT`.
+pub fn open(path: &str, flags: u64, mode: u32) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::OPEN,
+            [
+                path.as_ptr() as u64,
+                path.len() as u64,
+                flags,
+                mode as u64,
+                0,
+                0,
+            ],
+        )
+    })
+}
+
+/// Moves the position of an open file, and returns the new position.
+pub fn seek(fd: u64, offset: i64, whence: u64) -> Result<u64, Errno> {
+    check(unsafe { syscall(number::SEEK, [fd, offset as u64, whence, 0, 0, 0]) })
+}
+
+fn stat_with(path: &str, flags: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    check(unsafe {
+        syscall(
+            number::STAT,
+            [
+                path.as_ptr() as u64,
+                path.len() as u64,
+                &mut stat as *mut Stat as u64,
+                flags,
+                0,
+                0,
+            ],
+        )
+    })?;
+    Ok(stat)
+}
+
+/// Returns information about the file at the given path, following symbolic links.
+pub fn stat(path: &str) -> Result<Stat, Errno> {
+    stat_with(path, 0)
+}
+
+/// Returns information about the file at the given path, or the symbolic link that is there.
+pub fn lstat(path: &str) -> Result<Stat, Errno> {
+    stat_with(path, STAT_NO_FOLLOW)
+}
+
+/// Returns information about an open file.
+pub fn fstat(fd: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    check(unsafe {
+        syscall(
+            number::FSTAT,
+            [fd, &mut stat as *mut Stat as u64, 0, 0, 0, 0],
+        )
+    })?;
+    Ok(stat)
+}
+
+/// An entry in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub kind: u32,
+    pub name: String,
+}
+
+/// Lists the directory at the given path, including `.` and `..`.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Errno> {
+    let fd = open(path, O_RDONLY | O_DIRECTORY, 0)?;
+    let entries = read_dir_fd(fd);
+    crate::syscall::close(fd)?;
+    entries
+}
+
+/// Lists the rest of an open directory.
+pub fn read_dir_fd(fd: u64) -> Result<Vec<DirEntry>, Errno> {
+    let mut entries = Vec::new();
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let len = check(unsafe {
+            syscall(
+                number::GETDENTS,
+                [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0],
+            )
+        })? as usize;
+        if len == 0 {
+            return Ok(entries);
+        }
+        // Each record holds the inode number, an unused offset, the record's length, the type,
+        // and the name with a null terminator.
+        let mut records = &buf[..len];
+        while !records.is_empty() {
+            let record_len = u16::from_ne_bytes([records[16], records[17]]) as usize;
+            let name = &records[19..record_len];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            entries.push(DirEntry {
+                inode: u64::from_ne_bytes(records[..8].try_into().unwrap()),
+                kind: records[18] as u32,
+                name: String::from_utf8_lossy(name).into(),
+            });
+            records = &records[record_len..];
+        }
+    }
+}
+
+/// Makes a system call whose only arguments are a path.
+fn path_call(number: u64, path: &str, extra: u64) -> Result<(), Errno> {
+    check(unsafe {
+        syscall(
+            number,
+            [path.as_ptr() as u64, path.len() as u64, extra, 0, 0, 0],
+        )
+    })
+    .map(|_| ())
+}
+
+/// Makes a system call whose arguments are two paths.
+fn two_path_call(number: u64, first: &str, second: &str) -> Result<(), Errno> {
+    check(unsafe {
+        syscall(
+            number,
+            [
+                first.as_ptr() as u64,
+                first.len() as u64,
+                second.as_ptr() as u64,
+                second.len() as u64,
+                0,
+                0,
+            ],
+        )
+    })
+    .map(|_| ())
+}
+
+pub fn mkdir(path: &str, mode: u32) -> Result<(), Errno> {
+    path_call(number::MKDIR, path, mode as u64)
+}
+
+/// Removes a name for a file that is not a directory.
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    path_call(number::UNLINK, path, 0)
+}
+
+/// Removes an empty directory.
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    path_call(number::RMDIR, path, 0)
+}
+
+/// Changes the current directory.
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    path_call(number::CHDIR, path, 0)
+}
+
+/// Returns the absolute path of the current directory.
+pub fn getcwd() -> Result<String, Errno> {
+    let mut buf = vec![0u8; 4096];
+    let len = check(unsafe {
+        syscall(
+            number::GETCWD,
+            [buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0, 0],
+        )
+    })?;
+    buf.truncate(len as usize);
+    Ok(String::from_utf8_lossy(&buf).into())
+}
+
+/// Creates a symbolic link at `path` that points to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), Errno> {
+    two_path_call(number::SYMLINK, target, path)
+}
+
+/// Returns the target of the symbolic link at the given path.
+pub fn readlink(path: &str) -> Result<String, Errno> {
+    let mut buf = vec![0u8; 4096];
+    let len = check(unsafe {
+        syscall(
+            number::READLINK,
+            [
+                path.as_ptr() as u64,
+                path.len() as u64,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                0,
+                0,
+            ],
+        )
+    })?;
+    buf.truncate(len as usize);
+    Ok(String::from_utf8_lossy(&buf).into())
+}
+
+/// Creates a hard link at `new_path` to the file at `old_path`.
+pub fn link(old_path: &str, new_path: &str) -> Result<(), Errno> {
+    two_path_call(number::LINK, old_path, new_path)
+}
+
+/// Moves a file, replacing whatever is at `new_path`.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), Errno> {
+    two_path_call(number::RENAME, old_path, new_path)
+}
+
+/// Sets the size of an open regular file.
+pub fn ftruncate(fd: u64, size: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::FTRUNCATE, [fd, size, 0, 0, 0, 0]) }).map(|_| ())
+}
@@ -0,0 +1,182 @@
+//! The heap used by the `alloc` crate.
+//!
+//! This is a first-fit allocator over a sorted linked list of free blocks,
+//! in the same style as the kernel heap.
+//! When no free block is large enough, the heap asks the kernel to move the program break.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::syscall;
+
+/// The least amount of memory requested each time the heap needs to grow.
+const MIN_GROWTH: usize = 64 * 1024;
+
+/// Every block is a multiple of this size and aligned to it,
+/// which guarantees that the space left over after an allocation can hold a [FreeBlock].
+const BLOCK_ALIGN: usize = 16;
+
+/// A free block of memory, stored at the start of the memory it describes.
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// # Invariants
+///
+/// The free list is sorted by address, and no two free blocks are adjacent.
+/// Every free block lies below `end`, which is the program break once the heap has been used.
+struct Heap {
+    head: Option<NonNull<FreeBlock>>,
+    end: usize,
+}
+
+/// A heap protected by a spin lock.
+pub struct LockedHeap {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap>,
+}
+
+/// The heap is only accessed while the lock is held.
+unsafe impl Sync for LockedHeap {}
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap {
+    locked: AtomicBool::new(false),
+    heap: UnsafeCell::new(Heap { head: None, end: 0 }),
+};
+
+impl LockedHeap {
+    fn with<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// Rounds the layout up so that blocks are always multiples of [BLOCK_ALIGN].
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(BLOCK_ALIGN);
+    let size = layout.size().max(1).next_multiple_of(BLOCK_ALIGN);
+    (size, align)
+}
+
+impl Heap {
+    /// Moves the program break up by at least `size` bytes, and adds the new memory to the free list.
+    fn grow(&mut self, size: usize) -> Option<()> {
+        if self.end == 0 {
+            self.end = (syscall::brk(0) as usize).next_multiple_of(BLOCK_ALIGN);
+        }
+        let start = self.end;
+        let new_end = start.checked_add(size.max(MIN_GROWTH))?;
+        if syscall::brk(new_end as u64) as usize != new_end {
+            return None;
+        }
+        self.end = new_end;
+        unsafe {
+            self.free(start, new_end - start);
+        }
+        Some(())
+    }
+
+    /// Tries to carve an allocation out of the free list.
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut current = self.head;
+        while let Some(block) = current {
+            let block_start = block.as_ptr() as usize;
+            let block_end = block_start + block.as_ref().size;
+            let next = block.as_ref().next;
+
+            let alloc_start = block_start.next_multiple_of(align);
+            let alloc_end = alloc_start + size;
+            if alloc_end <= block_end {
+                // Unlink this block, then give back whatever we didn't use.
+                match prev {
+                    Some(mut prev) => prev.as_mut().next = next,
+                    None => self.head = next,
+                }
+                if alloc_start > block_start {
+                    self.free(block_start, alloc_start - block_start);
+                }
+                if block_end > alloc_end {
+                    self.free(alloc_end, block_end - alloc_end);
+                }
+                return NonNull::new(alloc_start as *mut u8);
+            }
+
+            prev = current;
+            current = next;
+        }
+        None
+    }
+
+    /// Adds the given range to the free list, merging it with its neighbours.
+    ///
+    /// # Safety
+    ///
+    /// The range must be unused heap memory, aligned to [BLOCK_ALIGN].
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut next = self.head;
+        while let Some(block) = next {
+            if block.as_ptr() as usize > start {
+                break;
+            }
+            prev = next;
+            next = block.as_ref().next;
+        }
+
+        let mut block = NonNull::new_unchecked(start as *mut FreeBlock);
+        block.write(FreeBlock { size, next });
+
+        if let Some(mut next) = next {
+            if start + size == next.as_ptr() as usize {
+                block.as_mut().size += next.as_ref().size;
+                block.as_mut().next = next.as_mut().next;
+            }
+        }
+
+        match prev {
+            Some(mut prev) => {
+                if prev.as_ptr() as usize + prev.as_ref().size == start {
+                    prev.as_mut().size += block.as_ref().size;
+                    prev.as_mut().next = block.as_ref().next;
+                } else {
+                    prev.as_mut().next = Some(block);
+                }
+            }
+            None => self.head = Some(block),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        self.with(|heap| loop {
+            if let Some(ptr) = heap.allocate(size, align) {
+                return ptr.as_ptr();
+            }
+            if heap.grow(size + align).is_none() {
+                return null_mut();
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.with(|heap| heap.free(ptr as usize, size));
+    }
+}
@@ -0,0 +1,82 @@
+//! Printing to the standard output and standard error, and reading from the standard input.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::syscall::{self, Errno, STDERR, STDOUT};
+
+/// A [Write] implementation for a file descriptor.
+pub struct FileWriter(pub u64);
+
+impl Write for FileWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match syscall::write(self.0, bytes) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => bytes = &bytes[written as usize..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads from a file descriptor until the end of the file.
+/// Reads that are interrupted by a signal handler are tried again.
+pub fn read_to_end(fd: u64) -> Result<Vec<u8>, Errno> {
+    let mut contents = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        match syscall::read(fd, &mut buf) {
+            Ok(0) => return Ok(contents),
+            Ok(len) => contents.extend_from_slice(&buf[..len as usize]),
+            Err(Errno::EINTR) => {}
+            Err(errno) => return Err(errno),
+        }
+    }
+}
+
+/// Writes all of `bytes` to a file descriptor.
+/// Writes that are interrupted by a signal handler are tried again.
+pub fn write_all(fd: u64, mut bytes: &[u8]) -> Result<(), Errno> {
+    while !bytes.is_empty() {
+        match syscall::write(fd, bytes) {
+            Ok(written) => bytes = &bytes[written as usize..],
+            Err(Errno::EINTR) => {}
+            Err(errno) => return Err(errno),
+        }
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = FileWriter(STDOUT).write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    let _ = FileWriter(STDERR).write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
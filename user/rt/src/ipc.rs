@@ -0,0 +1,148 @@
+//! Message passing through kernel endpoints.
+//!
+//! A [Handle] names a capability in this process's capability table: either an endpoint,
+//! with some [rights](SEND), or the right to reply to a call exactly once.
+//! Handles can be passed to other processes in messages, if the sender has the [GRANT] right.
+
+use crate::syscall::{check, number, syscall, Errno};
+
+/// The most bytes of data that a message can carry.
+pub const MAX_DATA: usize = 64;
+/// The most handles that a message can carry.
+pub const MAX_HANDLES: usize = 4;
+
+/// May send messages and make calls.
+pub const SEND: u64 = 1 << 0;
+/// May receive messages.
+pub const RECEIVE: u64 = 1 << 1;
+/// May pass handles along with messages.
+pub const GRANT: u64 = 1 << 2;
+
+/// An index into this process's capability table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle(pub u64);
+
+/// Marks unused handle slots in a [Message].
+const NO_HANDLE: u64 = u64::MAX;
+
+/// A message, laid out as the kernel expects.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Message {
+    len: u64,
+    data: [u8; MAX_DATA],
+    handle_count: u64,
+    handles: [u64; MAX_HANDLES],
+    reply: u64,
+}
+
+impl Message {
+    /// Creates a message with the given data and handles.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is more than [MAX_DATA] bytes of data or more than [MAX_HANDLES] handles.
+    pub fn new(data: &[u8], handles: &[Handle]) -> Self {
+        assert!(data.len() <= MAX_DATA, "message data too long");
+        assert!(handles.len() <= MAX_HANDLES, "too many handles in message");
+        let mut message = Self {
+            len: data.len() as u64,
+            handle_count: handles.len() as u64,
+            ..Default::default()
+        };
+        message.data[..data.len()].copy_from_slice(data);
+        for (slot, handle) in message.handles.iter_mut().zip(handles) {
+            *slot = handle.0;
+        }
+        message
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// The handles that were transferred with this message, which now belong to this process.
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        self.handles[..self.handle_count as usize]
+            .iter()
+            .map(|&handle| Handle(handle))
+    }
+
+    /// The handle to reply with, if this message was sent by [call].
+    pub fn reply_handle(&self) -> Option<Handle> {
+        (self.reply != NO_HANDLE).then_some(Handle(self.reply))
+    }
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            data: [0; MAX_DATA],
+            handle_count: 0,
+            handles: [NO_HANDLE; MAX_HANDLES],
+            reply: NO_HANDLE,
+        }
+    }
+}
+
+/// Creates an endpoint, and returns a handle to it with every right.
+pub fn endpoint_create() -> Result<Handle, Errno> {
+    check(unsafe { syscall(number::ENDPOINT_CREATE, [0; 6]) }).map(Handle)
+}
+
+/// Copies a handle with the given rights, which must be a subset of the handle's rights.
+pub fn duplicate(handle: Handle, rights: u64) -> Result<Handle, Errno> {
+    check(unsafe { syscall(number::HANDLE_DUPLICATE, [handle.0, rights, 0, 0, 0, 0]) }).map(Handle)
+}
+
+pub fn close_handle(handle: Handle) -> Result<(), Errno> {
+    check(unsafe { syscall(number::HANDLE_CLOSE, [handle.0, 0, 0, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Sends a message, blocking until it is received.
+pub fn send(endpoint: Handle, message: &Message) -> Result<(), Errno> {
+    check(unsafe {
+        syscall(
+            number::IPC_SEND,
+            [endpoint.0, message as *const Message as u64, 0, 0, 0, 0],
+        )
+    })
+    .map(|_| ())
+}
+
+/// Blocks until a message arrives at the endpoint, and returns it.
+pub fn receive(endpoint: Handle) -> Result<Message, Errno> {
+    let mut message = Message::default();
+    check(unsafe {
+        syscall(
+            number::IPC_RECEIVE,
+            [endpoint.0, &mut message as *mut Message as u64, 0, 0, 0, 0],
+        )
+    })?;
+    Ok(message)
+}
+
+/// Sends a message, and blocks until the receiver replies.
+/// Fails with `EPIPE` if the receiver closes the reply handle without replying.
+pub fn call(endpoint: Handle, message: &Message) -> Result<Message, Errno> {
+    let mut message = *message;
+    check(unsafe {
+        syscall(
+            number::IPC_CALL,
+            [endpoint.0, &mut message as *mut Message as u64, 0, 0, 0, 0],
+        )
+    })?;
+    Ok(message)
+}
+
+/// Replies to a call, using up the reply handle.
+pub fn reply(reply: Handle, message: &Message) -> Result<(), Errno> {
+    check(unsafe {
+        syscall(
+            number::IPC_REPLY,
+            [reply.0, message as *const Message as u64, 0, 0, 0, 0],
+        )
+    })
+    .map(|_| ())
+}
@@ -0,0 +1,39 @@
+//! The runtime for FuncOS user programs.
+//!
+//! This provides the program entry point, wrappers for the kernel's system calls,
+//! printing to the standard output, and a heap for the `alloc` crate.
+//! A program uses it like this:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! rt::entry!(main);
+//!
+//! fn main(args: rt::Args) -> i32 {
+//!     rt::println!("Hello, world!");
+//!     0
+//! }
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod framebuffer;
+pub mod fs;
+pub mod heap;
+pub mod io;
+pub mod ipc;
+pub mod net;
+pub mod signal;
+pub mod start;
+pub mod syscall;
+
+pub use start::Args;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(101);
+}
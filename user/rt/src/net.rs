@@ -0,0 +1,282 @@
+//! Networking through the kernel's protocol stack.
+//!
+//! Sockets are file descriptors, so besides the calls here they can be read and written with
+//! [crate::syscall::read] and [crate::syscall::write], and closed with [crate::syscall::close].
+//! Only IPv4 is supported.
+
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::syscall::{check, number, syscall, Errno};
+
+/// The types of socket for [socket]: TCP streams and UDP datagrams.
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+/// Added to the type of a socket so that calls fail with `EAGAIN` rather than blocking,
+/// and [connect] fails with `EINPROGRESS` while the connection is made in the background.
+pub const SOCK_NONBLOCK: u64 = 0o4000;
+const AF_INET: u64 = 2;
+
+/// Events for [poll]. `POLLERR`, `POLLHUP` and `POLLNVAL` are reported whether or not they were asked for.
+pub const POLLIN: u16 = 0x001;
+pub const POLLOUT: u16 = 0x004;
+pub const POLLERR: u16 = 0x008;
+pub const POLLHUP: u16 = 0x010;
+pub const POLLNVAL: u16 = 0x020;
+
+/// An address and port, laid out as the kernel expects.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SockAddrIn {
+    family: u16,
+    port: [u8; 2],
+    address: [u8; 4],
+    zero: [u8; 8],
+}
+
+impl From<SocketAddrV4> for SockAddrIn {
+    fn from(address: SocketAddrV4) -> Self {
+        Self {
+            family: AF_INET as u16,
+            port: address.port().to_be_bytes(),
+            address: address.ip().octets(),
+            zero: [0; 8],
+        }
+    }
+}
+
+impl From<SockAddrIn> for SocketAddrV4 {
+    fn from(address: SockAddrIn) -> Self {
+        SocketAddrV4::new(
+            Ipv4Addr::from(address.address),
+            u16::from_be_bytes(address.port),
+        )
+    }
+}
+
+const SOCKADDR_LEN: u64 = size_of::<SockAddrIn>() as u64;
+
+/// Creates a socket of the given type, and returns its file descriptor.
+pub fn socket(kind: u64) -> Result<u64, Errno> {
+    check(unsafe { syscall(number::SOCKET, [AF_INET, kind, 0, 0, 0, 0]) })
+}
+
+/// Gives a socket a local address. Port 0 picks a free port.
+pub fn bind(fd: u64, address: SocketAddrV4) -> Result<(), Errno> {
+    let address = SockAddrIn::from(address);
+    check(unsafe {
+        syscall(
+            number::BIND,
+            [fd, &address as *const _ as u64, SOCKADDR_LEN, 0, 0, 0],
+        )
+    })
+    .map(|_| ())
+}
+
+/// Listens for connections on a stream socket, keeping up to `backlog` waiting to be accepted.
+pub fn listen(fd: u64, backlog: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::LISTEN, [fd, backlog, 0, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Accepts a connection on a listening socket.
+/// Returns a file descriptor for it, which blocks, and the address of the other side.
+pub fn accept(fd: u64) -> Result<(u64, SocketAddrV4), Errno> {
+    let mut address = SockAddrIn::default();
+    let mut len = SOCKADDR_LEN as u32;
+    let connection = check(unsafe {
+        syscall(
+            number::ACCEPT,
+            [
+                fd,
+                &mut address as *mut _ as u64,
+                &mut len as *mut _ as u64,
+                0,
+                0,
+                0,
+            ],
+        )
+    })?;
+    Ok((connection, address.into()))
+}
+
+/// Connects a stream socket to an address, or sets where a datagram socket sends to by default,
+/// and the only address it receives from.
+pub fn connect(fd: u64, address: SocketAddrV4) -> Result<(), Errno> {
+    let address = SockAddrIn::from(address);
+    check(unsafe {
+        syscall(
+            number::CONNECT,
+            [fd, &address as *const _ as u64, SOCKADDR_LEN, 0, 0, 0],
+        )
+    })
+    .map(|_| ())
+}
+
+/// Sends bytes on a connected socket, and returns how many were sent.
+pub fn send(fd: u64, bytes: &[u8]) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::SEND,
+            [fd, bytes.as_ptr() as u64, bytes.len() as u64, 0, 0, 0],
+        )
+    })
+}
+
+/// Sends a datagram to an address.
+pub fn send_to(fd: u64, bytes: &[u8], address: SocketAddrV4) -> Result<u64, Errno> {
+    let address = SockAddrIn::from(address);
+    check(unsafe {
+        syscall(
+            number::SEND,
+            [
+                fd,
+                bytes.as_ptr() as u64,
+                bytes.len() as u64,
+                0,
+                &address as *const _ as u64,
+                SOCKADDR_LEN,
+            ],
+        )
+    })
+}
+
+/// Receives bytes from a socket into `buf`, and returns how many there were.
+/// A stream returns zero once the other side has closed it.
+pub fn recv(fd: u64, buf: &mut [u8]) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::RECV,
+            [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0],
+        )
+    })
+}
+
+/// Receives a datagram into `buf`. Returns how much of it fitted, and where it came from.
+pub fn recv_from(fd: u64, buf: &mut [u8]) -> Result<(u64, SocketAddrV4), Errno> {
+    let mut address = SockAddrIn::default();
+    let mut len = SOCKADDR_LEN as u32;
+    let received = check(unsafe {
+        syscall(
+            number::RECV,
+            [
+                fd,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                0,
+                &mut address as *mut _ as u64,
+                &mut len as *mut _ as u64,
+            ],
+        )
+    })?;
+    Ok((received, address.into()))
+}
+
+/// A file descriptor for [poll], and the events to wait for on it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: u16,
+    /// The events that happened, filled in by [poll].
+    pub revents: u16,
+}
+
+impl PollFd {
+    pub fn new(fd: u64, events: u16) -> Self {
+        Self {
+            fd: fd as i32,
+            events,
+            revents: 0,
+        }
+    }
+}
+
+/// Waits until any of the file descriptors is ready for one of its events, or `timeout_ms`
+/// milliseconds pass. A negative timeout waits for as long as it takes.
+/// Returns how many file descriptors have events, which is zero if the timeout ran out.
+pub fn poll(fds: &mut [PollFd], timeout_ms: i64) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::POLL,
+            [
+                fds.as_mut_ptr() as u64,
+                fds.len() as u64,
+                timeout_ms as u64,
+                0,
+                0,
+                0,
+            ],
+        )
+    })
+}
+
+/// A set of file descriptors for [select].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FdSet([u64; 4]);
+
+impl FdSet {
+    /// The most file descriptors that a set can hold, which is as many as a process can have open.
+    pub const CAPACITY: u64 = 256;
+
+    pub fn insert(&mut self, fd: u64) {
+        self.0[fd as usize / 64] |= 1 << (fd % 64);
+    }
+
+    pub fn contains(&self, fd: u64) -> bool {
+        fd < Self::CAPACITY && self.0[fd as usize / 64] & 1 << (fd % 64) != 0
+    }
+}
+
+/// Waits until any of the file descriptors in the sets can be read from without blocking,
+/// written to without blocking, or has an error, or until `timeout_ms` milliseconds pass.
+/// A negative timeout waits for as long as it takes. Only file descriptors below `nfds` are looked at.
+/// The sets are replaced by the file descriptors in them that are ready,
+/// and the number of those is returned, counting one for each set.
+pub fn select(
+    nfds: u64,
+    read: Option<&mut FdSet>,
+    write: Option<&mut FdSet>,
+    except: Option<&mut FdSet>,
+    timeout_ms: i64,
+) -> Result<u64, Errno> {
+    let pointer = |set: Option<&mut FdSet>| set.map_or(0, |set| set.0.as_mut_ptr() as u64);
+    check(unsafe {
+        syscall(
+            number::SELECT,
+            [
+                nfds,
+                pointer(read),
+                pointer(write),
+                pointer(except),
+                timeout_ms as u64,
+                0,
+            ],
+        )
+    })
+}
+
+/// The most addresses that [resolve] returns for one name.
+const MAX_ADDRESSES: usize = 16;
+
+/// Looks up the IPv4 addresses of a name with the kernel's DNS resolver, which caches answers.
+/// Addresses written as numbers, such as `10.0.2.2`, are returned as they are.
+pub fn resolve(name: &str) -> Result<Vec<Ipv4Addr>, Errno> {
+    let mut octets = [[0u8; 4]; MAX_ADDRESSES];
+    let count = check(unsafe {
+        syscall(
+            number::RESOLVE,
+            [
+                name.as_ptr() as u64,
+                name.len() as u64,
+                octets.as_mut_ptr() as u64,
+                MAX_ADDRESSES as u64,
+                0,
+                0,
+            ],
+        )
+    })?;
+    Ok(octets[..count as usize]
+        .iter()
+        .map(|&octets| Ipv4Addr::from(octets))
+        .collect())
+}
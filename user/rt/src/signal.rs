@@ -0,0 +1,135 @@
+//! Signals, which the kernel uses to interrupt a process.
+//!
+//! A handler runs on the process's stack, in the middle of whatever the process was doing,
+//! so it should only do simple things like setting an atomic flag.
+
+use core::arch::global_asm;
+
+use crate::syscall::{self, check, number, Errno};
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+
+/// A process that is killed by a signal exits with this plus the signal number.
+pub const KILLED_STATUS_BASE: i32 = 128;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+const SA_RESTORER: u64 = 0x0400_0000;
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+/// The layout of the argument to the `sigaction` system call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct SigAction {
+    handler: u64,
+    flags: u64,
+    restorer: u64,
+    mask: u64,
+}
+
+/// What to do when a signal arrives.
+#[derive(Debug, Clone, Copy)]
+pub enum Handler {
+    /// The kernel's default action: usually ending the process.
+    Default,
+    Ignore,
+    /// Call the given function with the signal number.
+    Function(extern "C" fn(u32)),
+}
+
+global_asm!(
+    ".global __rt_sigreturn",
+    "__rt_sigreturn:",
+    "mov eax, {sigreturn}",
+    "int 0x80",
+    "ud2",
+    sigreturn = const number::SIGRETURN,
+);
+
+extern "C" {
+    /// Signal handlers return here, and this restores the state from before the signal.
+    fn __rt_sigreturn();
+}
+
+/// Sets what happens when the given signal arrives.
+/// While a handler runs, the signal that it is handling is blocked.
+pub fn set_handler(signal: u32, handler: Handler) -> Result<(), Errno> {
+    let action = match handler {
+        Handler::Default => SigAction {
+            handler: SIG_DFL,
+            ..Default::default()
+        },
+        Handler::Ignore => SigAction {
+            handler: SIG_IGN,
+            ..Default::default()
+        },
+        Handler::Function(function) => SigAction {
+            handler: function as *const () as u64,
+            flags: SA_RESTORER,
+            restorer: __rt_sigreturn as *const () as u64,
+            mask: 0,
+        },
+    };
+    check(unsafe {
+        syscall::syscall(
+            number::SIGACTION,
+            [
+                signal as u64,
+                &action as *const SigAction as u64,
+                0,
+                0,
+                0,
+                0,
+            ],
+        )
+    })
+    .map(|_| ())
+}
+
+fn sigprocmask(how: u64, signals: u64) -> Result<u64, Errno> {
+    check(unsafe { syscall::syscall(number::SIGPROCMASK, [how, signals, 0, 0, 0, 0]) })
+}
+
+/// Delays the delivery of the given signals until they are unblocked.
+/// Returns the previous set of blocked signals.
+pub fn block(signals: &[u32]) -> Result<u64, Errno> {
+    sigprocmask(SIG_BLOCK, mask(signals))
+}
+
+/// Delivers any of the given signals that were blocked and are pending.
+/// Returns the previous set of blocked signals.
+pub fn unblock(signals: &[u32]) -> Result<u64, Errno> {
+    sigprocmask(SIG_UNBLOCK, mask(signals))
+}
+
+/// Replaces the set of blocked signals, as returned by [block] or [unblock].
+pub fn set_blocked(blocked: u64) -> Result<u64, Errno> {
+    sigprocmask(SIG_SETMASK, blocked)
+}
+
+fn mask(signals: &[u32]) -> u64 {
+    signals.iter().fold(0, |mask, &signal| mask | 1 << signal)
+}
@@ -0,0 +1,82 @@
+//! The program entry point.
+//!
+//! The kernel starts a program at `_start`, with the stack pointer pointing at `argc`,
+//! followed by `argc` pointers to null-terminated strings, a null pointer,
+//! and then a list of environment variables ending with a null pointer.
+
+use core::{
+    arch::global_asm,
+    ffi::{c_char, CStr},
+};
+
+use crate::syscall;
+
+global_asm!(
+    ".global _start",
+    "_start:",
+    // Mark the outermost stack frame for debuggers.
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+extern "Rust" {
+    /// Defined by the [entry] macro.
+    fn __rt_main(args: Args) -> i32;
+}
+
+extern "C" fn start(stack: *const u64) -> ! {
+    let args = unsafe {
+        let argc = *stack as usize;
+        Args {
+            argv: core::slice::from_raw_parts(stack.add(1) as *const *const c_char, argc),
+        }
+    };
+    let status = unsafe { __rt_main(args) };
+    syscall::exit(status);
+}
+
+/// Declares the main function of the program.
+/// It is given the program's arguments, and returns its exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "__rt_main"]
+        fn __rt_main(args: $crate::Args) -> i32 {
+            // Check the signature of the main function.
+            let main: fn($crate::Args) -> i32 = $main;
+            main(args)
+        }
+    };
+}
+
+/// The arguments that the program was started with.
+/// By convention, the first argument is the name of the program.
+#[derive(Clone, Copy)]
+pub struct Args {
+    argv: &'static [*const c_char],
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.argv.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argv.is_empty()
+    }
+
+    /// Returns the argument at the given index.
+    /// Arguments that are not valid UTF-8 are returned as empty strings.
+    pub fn get(&self, index: usize) -> Option<&'static str> {
+        let arg = unsafe { CStr::from_ptr(*self.argv.get(index)?) };
+        Some(arg.to_str().unwrap_or_default())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        let args = *self;
+        (0..args.len()).filter_map(move |index| args.get(index))
+    }
+}
@@ -0,0 +1,264 @@
+//! Wrappers for the kernel's system calls.
+//!
+//! System calls are made with `int 0x80`.
+//! The system call number is passed in `rax`, and up to six arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, in that order.
+//! The result is returned in `rax`: a negative value `-e` means that the call failed with error number `e`.
+
+use core::arch::asm;
+
+/// System call numbers.
+/// These must be kept in sync with the kernel.
+pub mod number {
+    pub const EXIT: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const BRK: u64 = 2;
+    pub const SPAWN: u64 = 3;
+    pub const WAIT: u64 = 4;
+    pub const SIGACTION: u64 = 5;
+    pub const SIGPROCMASK: u64 = 6;
+    pub const SIGRETURN: u64 = 7;
+    pub const KILL: u64 = 8;
+    pub const GETPID: u64 = 9;
+    pub const READ: u64 = 10;
+    pub const PIPE: u64 = 11;
+    pub const DUP2: u64 = 12;
+    pub const CLOSE: u64 = 13;
+    pub const ENDPOINT_CREATE: u64 = 14;
+    pub const HANDLE_DUPLICATE: u64 = 15;
+    pub const HANDLE_CLOSE: u64 = 16;
+    pub const IPC_SEND: u64 = 17;
+    pub const IPC_RECEIVE: u64 = 18;
+    pub const IPC_CALL: u64 = 19;
+    pub const IPC_REPLY: u64 = 20;
+    pub const MMAP: u64 = 21;
+    pub const MUNMAP: u64 = 22;
+    pub const MPROTECT: u64 = 23;
+    pub const OPEN: u64 = 24;
+    pub const SEEK: u64 = 25;
+    pub const STAT: u64 = 26;
+    pub const FSTAT: u64 = 27;
+    pub const GETDENTS: u64 = 28;
+    pub const MKDIR: u64 = 29;
+    pub const UNLINK: u64 = 30;
+    pub const RMDIR: u64 = 31;
+    pub const CHDIR: u64 = 32;
+    pub const GETCWD: u64 = 33;
+    pub const SYMLINK: u64 = 34;
+    pub const READLINK: u64 = 35;
+    pub const LINK: u64 = 36;
+    pub const RENAME: u64 = 37;
+    pub const FTRUNCATE: u64 = 38;
+    pub const IOCTL: u64 = 39;
+    pub const RESOLVE: u64 = 40;
+    pub const SOCKET: u64 = 41;
+    pub const BIND: u64 = 42;
+    pub const LISTEN: u64 = 43;
+    pub const ACCEPT: u64 = 44;
+    pub const CONNECT: u64 = 45;
+    pub const SEND: u64 = 46;
+    pub const RECV: u64 = 47;
+    pub const POLL: u64 = 48;
+    pub const SELECT: u64 = 49;
+}
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Memory protections for [mmap] and [mprotect].
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Flags for [mmap]. Exactly one of `MAP_SHARED` and `MAP_PRIVATE` must be given.
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// An error number returned by a failed system call.
+/// The values match those used by Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const ENOENT: Self = Self(2);
+    pub const EINTR: Self = Self(4);
+    pub const ENXIO: Self = Self(6);
+    pub const EAGAIN: Self = Self(11);
+    pub const EEXIST: Self = Self(17);
+    pub const EXDEV: Self = Self(18);
+    pub const ENOTDIR: Self = Self(20);
+    pub const EISDIR: Self = Self(21);
+    pub const EINVAL: Self = Self(22);
+    pub const ENOTTY: Self = Self(25);
+    pub const EROFS: Self = Self(30);
+    pub const EPIPE: Self = Self(32);
+    pub const ENOTEMPTY: Self = Self(39);
+    pub const ELOOP: Self = Self(40);
+    pub const EADDRINUSE: Self = Self(98);
+    pub const ENETUNREACH: Self = Self(101);
+    pub const ECONNRESET: Self = Self(104);
+    pub const ENOTCONN: Self = Self(107);
+    pub const ETIMEDOUT: Self = Self(110);
+    pub const ECONNREFUSED: Self = Self(111);
+    pub const EINPROGRESS: Self = Self(115);
+}
+
+/// Makes a system call with up to six arguments.
+///
+/// # Safety
+///
+/// The arguments must be valid for the given system call.
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> u64 {
+    let result;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        options(nostack),
+    );
+    result
+}
+
+/// Splits a system call return value into a result and an error.
+pub(crate) fn check(result: u64) -> Result<u64, Errno> {
+    let signed = result as i64;
+    if signed < 0 {
+        Err(Errno(-signed))
+    } else {
+        Ok(result)
+    }
+}
+
+/// Ends the current process with the given status.
+pub fn exit(status: i32) -> ! {
+    unsafe {
+        syscall(number::EXIT, [status as u64, 0, 0, 0, 0, 0]);
+    }
+    unreachable!("the kernel returned from exit");
+}
+
+/// Writes bytes to a file descriptor, and returns how many were written.
+pub fn write(fd: u64, bytes: &[u8]) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::WRITE,
+            [fd, bytes.as_ptr() as u64, bytes.len() as u64, 0, 0, 0],
+        )
+    })
+}
+
+/// Reads bytes from a file descriptor into `buf`, and returns how many were read.
+/// Blocks until at least one byte is available, and returns zero at the end of the file.
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<u64, Errno> {
+    check(unsafe {
+        syscall(
+            number::READ,
+            [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0],
+        )
+    })
+}
+
+/// Creates a pipe, and returns the file descriptors of its read and write ends.
+pub fn pipe() -> Result<(u64, u64), Errno> {
+    let mut fds = [0u32; 2];
+    check(unsafe { syscall(number::PIPE, [fds.as_mut_ptr() as u64, 0, 0, 0, 0, 0]) })?;
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+/// Makes `new_fd` refer to the same file as `old_fd`, closing whatever it referred to before.
+pub fn dup2(old_fd: u64, new_fd: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::DUP2, [old_fd, new_fd, 0, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Closes a file descriptor.
+pub fn close(fd: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::CLOSE, [fd, 0, 0, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Moves the program break to `addr`, and returns the new program break.
+/// If the break could not be moved, it stays where it was, so `brk(0)` returns the current break.
+pub fn brk(addr: u64) -> u64 {
+    unsafe { syscall(number::BRK, [addr, 0, 0, 0, 0, 0]) }
+}
+
+/// Maps `len` bytes of memory, and returns its address.
+/// Anonymous memory is zeroed; otherwise it is read from the file `fd` starting at `offset`.
+/// The address is only a hint unless `MAP_FIXED` is given.
+pub fn mmap(
+    addr: u64,
+    len: u64,
+    protection: u64,
+    flags: u64,
+    fd: u64,
+    offset: u64,
+) -> Result<u64, Errno> {
+    check(unsafe { syscall(number::MMAP, [addr, len, protection, flags, fd, offset]) })
+}
+
+/// Unmaps every page in `addr..addr + len`. The address must be page-aligned.
+pub fn munmap(addr: u64, len: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::MUNMAP, [addr, len, 0, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Changes the protection of every page in `addr..addr + len`, which must all be mapped.
+pub fn mprotect(addr: u64, len: u64, protection: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(number::MPROTECT, [addr, len, protection, 0, 0, 0]) }).map(|_| ())
+}
+
+/// Makes a request that is specific to the device that `fd` refers to.
+/// `arg` is usually the address of a structure that the kernel reads or fills in.
+pub fn ioctl(fd: u64, request: u64, arg: u64) -> Result<u64, Errno> {
+    check(unsafe { syscall(number::IOCTL, [fd, request, arg, 0, 0, 0]) })
+}
+
+/// A process ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u32);
+
+/// Starts the program at the given path with the given arguments, as a child of this process.
+pub fn spawn(path: &str, args: &[&str]) -> Result<Pid, Errno> {
+    // The kernel expects the arguments as (pointer, length) pairs.
+    let args: alloc::vec::Vec<[u64; 2]> = args
+        .iter()
+        .map(|arg| [arg.as_ptr() as u64, arg.len() as u64])
+        .collect();
+    check(unsafe {
+        syscall(
+            number::SPAWN,
+            [
+                path.as_ptr() as u64,
+                path.len() as u64,
+                args.as_ptr() as u64,
+                args.len() as u64,
+                0,
+                0,
+            ],
+        )
+    })
+    .map(|pid| Pid(pid as u32))
+}
+
+/// Waits for the given child process to exit, and returns its exit status.
+pub fn wait(pid: Pid) -> Result<i32, Errno> {
+    check(unsafe { syscall(number::WAIT, [pid.0 as u64, 0, 0, 0, 0, 0]) })
+        .map(|status| status as i32)
+}
+
+/// Returns the ID of the current process.
+pub fn getpid() -> Pid {
+    Pid(unsafe { syscall(number::GETPID, [0; 6]) } as u32)
+}
+
+/// Sends a signal to a process.
+pub fn kill(pid: Pid, signal: u32) -> Result<(), Errno> {
+    check(unsafe { syscall(number::KILL, [pid.0 as u64, signal as u64, 0, 0, 0, 0]) }).map(|_| ())
+}